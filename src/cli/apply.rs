@@ -0,0 +1,233 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Component, Path},
+};
+
+use itertools::Itertools;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use similar::TextDiff;
+
+use crate::{
+    cli::{
+        build, prompt,
+        share_config::{self, Bundle, BUNDLE_FILE_NAME, BUNDLE_VERSION},
+        ApplyArgs, Args,
+    },
+    config::Config,
+    devcontainer::DevContainer,
+    exec, log, state, trust,
+};
+
+pub fn main(config: &Config, args: &Args, apply_args: &ApplyArgs) -> Result<()> {
+    let contents = read_bundle(&apply_args.bundle)?;
+    let bundle: Bundle = serde_json::from_str(&contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to parse bundle {}", apply_args.bundle))?;
+    if bundle.version > BUNDLE_VERSION {
+        bail!(
+            help = "update dockim to apply it",
+            "the bundle is of a newer version ({})",
+            bundle.version
+        );
+    }
+
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let workspace_folder = dc.workspace_folder();
+    for path in bundle.files.keys() {
+        check_path(path)?;
+    }
+
+    // Check everything first, so that nothing is half applied
+    let lock_path =
+        state::workspace_state_dir(workspace_folder)?.join(build::NIX_FLAKE_LOCK_STATE_FILE);
+    let mut conflicts = bundle
+        .files
+        .iter()
+        .filter(|(path, contents)| differs(&workspace_folder.join(path), contents))
+        .map(|(path, _)| path.clone())
+        .collect_vec();
+    if let Some(lock) = &bundle.nix_flake_lock {
+        if differs(&lock_path, lock) {
+            conflicts.push("the locked nixpkgs revision".to_string());
+        }
+    }
+    if !conflicts.is_empty() && !apply_args.force {
+        bail!(
+            help = "pass --force to overwrite them",
+            "these differ from the bundle: {}",
+            conflicts.join(", ")
+        );
+    }
+
+    // The files configure what runs in the devcontainer, so show them before writing anything
+    let changes = bundle
+        .files
+        .iter()
+        .filter_map(|(path, contents)| {
+            let current = fs::read_to_string(workspace_folder.join(path)).unwrap_or_default();
+            (current != *contents).then_some((path, current, contents))
+        })
+        .collect_vec();
+    for (path, current, contents) in &changes {
+        let diff = TextDiff::from_lines(current, *contents);
+        print!(
+            "{}",
+            diff.unified_diff()
+                .header(&format!("a/{path}"), &format!("b/{path}"))
+        );
+    }
+    if !changes.is_empty()
+        && !apply_args.yes
+        && !prompt::confirm(&format!("Write these {} file(s)?", changes.len()))?
+    {
+        log!("Aborted": "no changes were made");
+        return Ok(());
+    }
+
+    for (path, _, contents) in changes {
+        let host_path = workspace_folder.join(path);
+        if let Some(dir) = host_path.parent() {
+            fs::create_dir_all(dir)
+                .into_diagnostic()
+                .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+        }
+        fs::write(&host_path, contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", host_path.display()))?;
+        log!("Wrote" ("apply"): "{path}");
+    }
+
+    if let Some(lock) = &bundle.nix_flake_lock {
+        state::ensure_workspace_state_dir(workspace_folder)?;
+        fs::write(&lock_path, lock)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", lock_path.display()))?;
+        log!("Wrote" ("apply"): "the locked nixpkgs revision");
+    }
+
+    for forward in &bundle.ports {
+        dc.register_forward_to(
+            &forward.host_port,
+            &forward.container_port,
+            forward.https,
+            forward.service.as_deref(),
+        )?;
+        dc.set_forward_healthcheck(&forward.host_port, forward.healthcheck.as_deref())?;
+    }
+    if !bundle.ports.is_empty() {
+        log!(
+            "Registered" ("apply"): "port forwards {}; `dockim up` starts them",
+            bundle.ports.iter().map(|forward| &forward.host_port).join(", ")
+        );
+    }
+
+    if bundle
+        .files
+        .keys()
+        .any(|path| path.starts_with(trust::LOCAL_CONFIG_DIR))
+    {
+        log!("Hint": "review .dockim/config.toml; dockim asks to trust it on the next run");
+    }
+    log!("Hint": "run `dockim up --rebuild` to recreate the devcontainer with this setup");
+
+    Ok(())
+}
+
+/// Reads the bundle from a gist URL, another URL, a file, or stdin for `-`.
+fn read_bundle(bundle: &str) -> Result<String> {
+    if bundle == "-" {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .into_diagnostic()
+            .wrap_err("failed to read the bundle from stdin")?;
+        return Ok(contents);
+    }
+    if bundle.starts_with("https://gist.github.com/") {
+        return exec::capturing_stdout(&[
+            "gh",
+            "gist",
+            "view",
+            bundle,
+            "--raw",
+            "--filename",
+            BUNDLE_FILE_NAME,
+        ])
+        .wrap_err_with(|| {
+            miette!(
+                help = "install the GitHub CLI and run `gh auth login`",
+                "failed to fetch the gist {bundle}"
+            )
+        });
+    }
+    if bundle.starts_with("https://") || bundle.starts_with("http://") {
+        return exec::capturing_stdout(&["curl", "-fsSL", bundle])
+            .wrap_err_with(|| miette!("failed to download {bundle}"));
+    }
+
+    fs::read_to_string(bundle)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {bundle}"))
+}
+
+/// Only lets the bundle write devcontainer and dockim configuration inside the workspace, as
+/// `dockim share-config` collects it.
+fn check_path(path: &str) -> Result<()> {
+    let is_inside = Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    let local_config = format!("{}/config.toml", trust::LOCAL_CONFIG_DIR);
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let is_config = path == ".devcontainer.json"
+        || (path.starts_with(".devcontainer/") && share_config::is_shareable(file_name))
+        || path == local_config;
+    if !is_inside || !is_config {
+        bail!(
+            help = format!(
+                "bundles only contain devcontainer.json, Dockerfiles, compose and package files in .devcontainer/, and {local_config}"
+            ),
+            "refusing to write {path} from the bundle"
+        );
+    }
+
+    Ok(())
+}
+
+fn differs(path: &Path, contents: &str) -> bool {
+    path.exists() && fs::read_to_string(path).ok().as_deref() != Some(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_path_accepts_config_files() {
+        assert!(check_path(".devcontainer.json").is_ok());
+        assert!(check_path(".devcontainer/devcontainer.json").is_ok());
+        assert!(check_path(".devcontainer/Dockerfile").is_ok());
+        assert!(check_path(".devcontainer/compose.yaml").is_ok());
+        assert!(check_path(".devcontainer/packages.txt").is_ok());
+        assert!(check_path(".dockim/config.toml").is_ok());
+    }
+
+    #[test]
+    fn check_path_rejects_paths_outside_the_workspace() {
+        assert!(check_path("../devcontainer.json").is_err());
+        assert!(check_path(".devcontainer/../devcontainer.json").is_err());
+        assert!(check_path(".devcontainer/../.bashrc").is_err());
+        assert!(check_path("/etc/devcontainer.json").is_err());
+        assert!(check_path("./.devcontainer.json").is_err());
+    }
+
+    #[test]
+    fn check_path_rejects_other_files() {
+        assert!(check_path(".env").is_err());
+        assert!(check_path(".devcontainer/.env").is_err());
+        assert!(check_path(".devcontainer/post-create.sh").is_err());
+        assert!(check_path(".dockim/env.toml").is_err());
+        assert!(check_path(".dockim/bin/hook").is_err());
+        assert!(check_path("Dockerfile").is_err());
+    }
+}
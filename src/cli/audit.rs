@@ -0,0 +1,225 @@
+use std::{fs, path::Path};
+
+use itertools::Itertools;
+use miette::Result;
+
+use crate::{
+    cli::{build, gc, init_docker, ssh, Args, AuditArgs},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, git_credentials, log, state,
+    vm_provider::VmProvider,
+};
+
+/// Something dockim changed, and how to undo it.
+struct Change {
+    what: String,
+    location: String,
+    revert: String,
+}
+
+pub fn main(config: &Config, args: &Args, _audit_args: &AuditArgs) -> Result<()> {
+    let host = host_changes()?;
+    print_changes("Host", &host);
+
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    if dc.container_status()?.as_deref() == Some("running") {
+        let container = container_changes(config, &dc)?;
+        print_changes("Container", &container);
+        if !container.is_empty() {
+            println!("Recreating the container with `dockim up --rebuild` reverts all of them.");
+        }
+    } else {
+        log!("Skipping" ("container"): "the devcontainer of this workspace is not running");
+    }
+
+    Ok(())
+}
+
+fn print_changes(title: &str, changes: &[Change]) {
+    println!("{title}:");
+    if changes.is_empty() {
+        println!("  (none)");
+    }
+    for change in changes {
+        println!("  {}: {}", change.what, change.location);
+        println!("    revert: {}", change.revert);
+    }
+}
+
+fn host_changes() -> Result<Vec<Change>> {
+    let mut changes = vec![];
+
+    let docker_config = init_docker::docker_config_path()?;
+    if init_docker::changes_path(&docker_config).exists() {
+        changes.push(Change {
+            what: "Docker config (dockim init-docker)".to_string(),
+            location: docker_config.display().to_string(),
+            revert: "dockim init-docker --undo".to_string(),
+        });
+    }
+
+    let ssh_config = ssh::ssh_config_path()?;
+    let aliases = fs::read_to_string(&ssh_config)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.strip_prefix(ssh::SSH_CONFIG_BEGIN_MARKER))
+        .map(str::to_string)
+        .collect_vec();
+    for alias in aliases {
+        changes.push(Change {
+            what: format!("SSH config entry `{alias}` (dockim ssh enable)"),
+            location: ssh_config.display().to_string(),
+            revert: format!(
+                "sed -i.bak '/^# dockim: begin {alias}$/,/^# dockim: end {alias}$/d' {}",
+                exec::shell_quote(&ssh_config.to_string_lossy())
+            ),
+        });
+    }
+
+    let schedule_files = gc::schedule_files()?
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect_vec();
+    if !schedule_files.is_empty() {
+        let files = schedule_files
+            .iter()
+            .map(|path| exec::shell_quote(&path.to_string_lossy()))
+            .join(" ");
+        let unload = if cfg!(target_os = "macos") {
+            format!("launchctl unload -w {files}")
+        } else {
+            format!("systemctl --user disable --now {}", gc::SYSTEMD_TIMER)
+        };
+        changes.push(Change {
+            what: "daily gc schedule (dockim gc install-schedule)".to_string(),
+            location: schedule_files.iter().map(|path| path.display()).join(", "),
+            revert: format!("{unload} && rm {files}"),
+        });
+    }
+
+    for dir in state::workspace_state_dirs()? {
+        let workspace = state::recorded_workspace_folder(&dir)
+            .map(|workspace| workspace.display().to_string())
+            .unwrap_or_else(|| "unknown workspace".to_string());
+        changes.push(state_dir_change(&format!("state of {workspace}"), &dir));
+    }
+    let shared_dir = state::shared_state_dir()?;
+    if shared_dir.exists() {
+        changes.push(state_dir_change(
+            "shared state (trust, port leases, tokens)",
+            &shared_dir,
+        ));
+    }
+
+    let images = exec::capturing_stdout(&[
+        "docker",
+        "images",
+        "--filter",
+        "reference=dockim-apt-*",
+        "--format",
+        "{{.Repository}}",
+    ])
+    .unwrap_or_default();
+    for image in images.lines().unique() {
+        changes.push(Change {
+            what: "apt layer image (dockim build --apt-layer)".to_string(),
+            location: image.to_string(),
+            revert: format!("docker rmi {image}"),
+        });
+    }
+
+    let networks = exec::capturing_stdout(&[
+        "docker",
+        "network",
+        "ls",
+        "--filter",
+        "name=dockim-",
+        "--format",
+        "{{.Name}}",
+    ])
+    .unwrap_or_default();
+    for network in networks.lines() {
+        changes.push(Change {
+            what: "restricted network (network.mode)".to_string(),
+            location: network.to_string(),
+            revert: format!("dockim down, or docker network rm {network}"),
+        });
+    }
+
+    Ok(changes)
+}
+
+fn state_dir_change(what: &str, dir: &Path) -> Change {
+    Change {
+        what: what.to_string(),
+        location: dir.display().to_string(),
+        revert: format!("rm -rf {}", exec::shell_quote(&dir.to_string_lossy())),
+    }
+}
+
+/// Probes for what `dockim build` and `dockim up` put into the container, one line per finding.
+fn container_changes(config: &Config, dc: &DevContainer) -> Result<Vec<Change>> {
+    let neovim = format!("{}/bin/nvim", config.build.neovim_prefix);
+    let probe = dc.exec_script_capturing_stdout(&format!(
+        r#"grep -qs host.docker.internal /etc/hosts && echo hosts
+[ -e {neovim} ] && echo neovim
+[ -d /opt/dotfiles ] && echo dotfiles
+[ -d {nix} ] && echo nix
+[ -e "{helper}" ] && echo credentials
+[ -d ~/.config/github-copilot ] && echo copilot
+true"#,
+        nix = build::NIX_FLAKE_DIR,
+        helper = git_credentials::HELPER_PATH,
+    ))?;
+
+    let sudo = "dockim exec -u root --";
+    let mut changes = vec![];
+    for finding in probe.lines() {
+        let (what, location, revert) = match finding {
+            // Only ours where the provider doesn't add the entry itself
+            "hosts" if !VmProvider::detect().has_host_docker_internal() => (
+                "host.docker.internal entry",
+                "/etc/hosts".to_string(),
+                // /etc/hosts is bind-mounted, so it must be rewritten in place rather than replaced
+                format!("{sudo} sh -c 'grep -v host.docker.internal /etc/hosts > /tmp/hosts && cat /tmp/hosts > /etc/hosts && rm /tmp/hosts'"),
+            ),
+            "neovim" => (
+                "Neovim",
+                neovim.clone(),
+                format!("{sudo} rm -f {neovim}"),
+            ),
+            "dotfiles" => (
+                "dotfiles",
+                "/opt/dotfiles".to_string(),
+                format!("{sudo} rm -rf /opt/dotfiles"),
+            ),
+            "nix" => (
+                "Nix flake",
+                build::NIX_FLAKE_DIR.to_string(),
+                format!("dockim exec -- sh -c 'rm -rf {}'", build::NIX_FLAKE_DIR),
+            ),
+            "credentials" => (
+                "git credential helper",
+                git_credentials::HELPER_PATH.to_string(),
+                format!(
+                    "dockim exec -- sh -c 'git config --global --unset-all credential.helper; rm -f \"{}\"'",
+                    git_credentials::HELPER_PATH
+                ),
+            ),
+            "copilot" => (
+                "GitHub Copilot settings",
+                "~/.config/github-copilot".to_string(),
+                "dockim exec -- sh -c 'rm -rf ~/.config/github-copilot'".to_string(),
+            ),
+            _ => continue,
+        };
+        changes.push(Change {
+            what: what.to_string(),
+            location,
+            revert,
+        });
+    }
+
+    Ok(changes)
+}
@@ -0,0 +1,24 @@
+use miette::Result;
+
+use crate::{
+    cli::{Args, AuthArgs, AuthCommand},
+    config::Config,
+    devcontainer::DevContainer,
+    git_credentials,
+};
+
+pub fn main(config: &Config, args: &Args, auth_args: &AuthArgs) -> Result<()> {
+    match auth_args.command {
+        AuthCommand::Setup => {
+            let dc = DevContainer::new(args.workspace_folder.clone(), config);
+            let lock = dc.lock()?;
+            dc.ensure_up()?;
+            drop(lock);
+            git_credentials::install(config, &dc)
+        }
+        AuthCommand::Serve => {
+            let dc = DevContainer::new(args.workspace_folder.clone(), config);
+            git_credentials::serve(config, &dc)
+        }
+    }
+}
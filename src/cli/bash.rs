@@ -2,15 +2,36 @@ use crate::{
     cli::{Args, BashArgs},
     config::Config,
     devcontainer::DevContainer,
+    git_credentials,
+    recording::Recording,
 };
 use miette::{miette, Result, WrapErr};
 
 pub fn main(config: &Config, args: &Args, shell_args: &BashArgs) -> Result<()> {
-    let dc = DevContainer::new(args.workspace_folder.clone());
+    let dc = DevContainer::new(args.workspace_folder.clone(), config)
+        .with_user(shell_args.user.clone())
+        .with_no_up(shell_args.no_up)
+        .with_saved_env()?;
+    // Wait for a concurrent `up` instead of racing it, but don't block others while attached
+    let lock = dc.lock()?;
+    dc.ensure_up()?;
+    drop(lock);
+    git_credentials::spawn_bridge(config, &dc);
+    let workdir = dc.current_dir_in_container()?;
+    let dc = dc.with_workdir(workdir);
 
     let mut args = vec!["bash"];
     args.extend(shell_args.args.iter().map(|s| s.as_str()));
-    dc.exec(&args).wrap_err(miette!(
+    let recording = shell_args
+        .record
+        .as_deref()
+        .map(|path| Recording::prepare(&dc, path))
+        .transpose()?;
+    let result = dc.exec(&Recording::wrap_if(recording.as_ref(), &args));
+    if let Some(recording) = recording {
+        recording.finish(&dc)?;
+    }
+    result.wrap_err(miette!(
         help = "try `dockim build --rebuild` first",
         "failed to execute `{}` on the container",
         config.shell
@@ -1,75 +1,699 @@
+use std::{fs, path::PathBuf, thread, time::Duration};
+
 use dirs::home_dir;
 use itertools::{chain, Itertools};
-use miette::{miette, Result, WrapErr};
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
 
 use crate::{
     cli::{Args, BuildArgs},
-    config::Config,
+    config::{BuildBackend, Config},
     devcontainer::{DevContainer, UpOutput},
-    exec,
+    devcontainer_config, exec, git_credentials,
+    github::{self, Asset, Release},
+    log, notify,
+    override_config::{AptLayer, APT_LAYER_STATE_FILE},
+    package_file::PackageFile,
+    progress::{self, Operation, Progress},
+    scripting::{self, Scripts},
+    state,
+    vm_provider::VmProvider,
 };
 
+const GITHUB_CLI_REPO: &str = "cli/cli";
+
+const PREREQUISITES: &[&str] = &[
+    "zsh",
+    "curl",
+    "fzf",
+    "ripgrep",
+    "tree",
+    "git",
+    "xclip",
+    "python3",
+    "python3-pip",
+    "python3-pynvim",
+    "tzdata",
+    "ninja-build",
+    "gettext",
+    "libtool",
+    "libtool-bin",
+    "autoconf",
+    "automake",
+    "cmake",
+    "g++",
+    "pkg-config",
+    "zip",
+    "unzip",
+    "git-secrets",
+];
+
+/// nixpkgs attributes installed by the `nix` backend in place of the prerequisites, Neovim and
+/// GitHub CLI
+const NIX_PACKAGES: &[&str] = &[
+    "zsh",
+    "curl",
+    "fzf",
+    "ripgrep",
+    "tree",
+    "git",
+    "xclip",
+    "(python3.withPackages (ps: [ ps.pynvim ]))",
+    "tzdata",
+    "zip",
+    "unzip",
+    "git-secrets",
+    "neovim",
+    "gh",
+];
+
+const NIX_FLAKE_TEMPLATE: &str = r#"# Generated by `dockim build`; set `build.nix_packages` in the dockim config instead of editing
+{
+  inputs.nixpkgs.url = "@NIXPKGS@";
+
+  outputs = { nixpkgs, ... }:
+    let
+      systems = [ "x86_64-linux" "aarch64-linux" ];
+    in
+    {
+      packages = nixpkgs.lib.genAttrs systems (system:
+        let
+          pkgs = nixpkgs.legacyPackages.${system};
+        in
+        {
+          default = pkgs.buildEnv {
+            name = "dockim-env";
+            paths = with pkgs; [
+@PACKAGES@            ];
+          };
+        });
+    };
+}
+"#;
+
+/// Where Neovim versions live side by side when `build.neovim_keep_versions` is set
+const NEOVIM_VERSIONS_DIR: &str = "/opt/nvim";
+
+/// Where the `nix` backend puts things on the container
+const NIX: &str = "~/.nix-profile/bin/nix";
+pub const NIX_FLAKE_DIR: &str = "~/.config/dockim/flake";
+const NIX_ENV_LINK: &str = "~/.local/state/dockim/nix-env";
+const NIX_INSTALLER: &str = "/tmp/nix-install";
+
+/// Nix release installed by the `nix` backend. Its installer checks the tarball it downloads
+/// against hashes of its own, so verifying the installer covers both.
+const NIX_VERSION: &str = "2.24.9";
+
+/// Directory in the workspace state with the output of each step of the last quiet build
+const BUILD_LOGS_DIR: &str = "build-logs";
+
+/// flake.lock of the last `nix` build, kept so that rebuilt containers get the same packages
+pub const NIX_FLAKE_LOCK_STATE_FILE: &str = "flake.lock";
+
+/// Limits to restore after `build.cpu_limit`, kept until they are restored
+const CPU_LIMIT_STATE_FILE: &str = "cpu-limit.json";
+
+/// Patterns registered with git-secrets in addition to its AWS provider
+const SECRET_PATTERNS: &[(&str, &str)] = &[
+    ("GitHub token", "gh[pousr]_[A-Za-z0-9]{36}"),
+    ("GitHub fine-grained token", "github_pat_[A-Za-z0-9_]{82}"),
+    ("Slack token", "xox[baprs]-[A-Za-z0-9-]{10,}"),
+    ("Google API key", "AIza[0-9A-Za-z_-]{35}"),
+    // Avoid a leading `-` so that it isn't taken as an option
+    ("private key", "[-]----BEGIN [A-Z ]*PRIVATE KEY-----"),
+];
+
 pub fn main(config: &Config, args: &Args, build_args: &BuildArgs) -> Result<()> {
-    let dc = DevContainer::new(args.workspace_folder.clone());
+    check_backend(config, build_args)?;
+
+    if build_args.dry_run {
+        return print_plan(config, args, build_args);
+    }
+
+    let build = if build_args.verbose {
+        build(
+            config,
+            args.workspace_folder.clone(),
+            build_args,
+            &Progress::none(),
+        )
+    } else {
+        build_quietly(config, args, build_args)
+    };
+    notify::finished(config, "dockim build", build)
+}
 
-    let up_cont = devcontainer_up(&dc, build_args.rebuild, build_args.no_cache)?;
+/// Runs `build` showing only its steps. The output of each step is kept in its own file, and the
+/// end of the one that failed is shown.
+fn build_quietly(config: &Config, args: &Args, build_args: &BuildArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let log_dir = state::ensure_workspace_state_dir(dc.workspace_folder())?.join(BUILD_LOGS_DIR);
+    // Only the logs of the last build are kept
+    if log_dir.exists() {
+        fs::remove_dir_all(&log_dir)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to remove {}", log_dir.display()))?;
+    }
+    fs::create_dir_all(&log_dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", log_dir.display()))?;
+
+    let (progress, events) = Progress::quiet(log_dir.clone());
+    let renderer = thread::spawn(move || progress::render(events));
+    let result = build(config, args.workspace_folder.clone(), build_args, &progress);
+    // Stops capturing the output and ends the events
+    drop(progress);
+    let last_step = renderer.join().ok().flatten();
+
+    let Some(step) = last_step else {
+        return result;
+    };
+    step.finish(result.is_ok());
+    if result.is_err() {
+        let log_path = progress::step_log_path(&log_dir, step.index, &step.description);
+        if let Ok(tail) = exec::read_tail(&log_path, exec::QUIET_TAIL_BYTES) {
+            eprintln!("{}", tail.trim_end());
+        }
+        log!("Hint": "the full output of this step is in {}; pass --verbose to stream it", log_path.display());
+    }
+
+    result
+}
+
+/// Runs `build` in the background for frontends embedding dockim.
+pub fn spawn(
+    config: &Config,
+    workspace_folder: Option<PathBuf>,
+    build_args: BuildArgs,
+) -> Operation<()> {
+    let config = config.clone();
+    progress::spawn(move |progress| {
+        check_backend(&config, &build_args)?;
+        build(&config, workspace_folder, &build_args, progress)
+    })
+}
+
+fn check_backend(config: &Config, build_args: &BuildArgs) -> Result<()> {
+    if (build_args.apt_layer || config.build.apt_layer) && config.build.backend == BuildBackend::Nix
+    {
+        bail!(
+            help = "set `build.backend` to `apt` or drop `--apt-layer`",
+            "`--apt-layer` is not supported by the nix backend"
+        );
+    }
+
+    Ok(())
+}
+
+fn build(
+    config: &Config,
+    workspace_folder: Option<PathBuf>,
+    build_args: &BuildArgs,
+    progress: &Progress,
+) -> Result<()> {
+    // The recreated container must come from the layer even if the config doesn't ask for it
+    let keeps_apt_layer = config.build.apt_layer;
+    let mut config = config.clone();
+    config.build.apt_layer |= build_args.apt_layer;
+    let config = &config;
+
+    let cpu_limit = config
+        .build
+        .cpu_limit
+        .as_deref()
+        .map(parse_cpu_limit)
+        .transpose()?;
+    let dc =
+        DevContainer::new(workspace_folder, config).with_command_prefix(priority_prefix(config)?);
+    let _lock = dc.lock()?;
+    restore_cpu_limit(&dc)?;
+    let project_packages = load_project_packages(&dc)?;
+
+    let is_apt = config.build.backend == BuildBackend::Apt;
+    progress.steps(
+        6 + usize::from(config.build.apt_layer)
+            + usize::from(config.build.git_security)
+            + usize::from(config.build.plugin_sync)
+            + usize::from(config.auth.git_credentials)
+            + if is_apt {
+                1 + usize::from(!config.build.apt_layer)
+            } else {
+                1
+            },
+    );
+
+    // Look up the releases to download while the container starts
+    if is_apt {
+        let neovim_version = config.neovim_version.clone();
+        thread::spawn(move || {
+            let _ = github::neovim_release(&neovim_version);
+            let _ = github::release(GITHUB_CLI_REPO, "latest");
+        });
+    }
+
+    progress.step("start the devcontainer")?;
+    let mut up_cont = devcontainer_up(&dc, build_args.rebuild, build_args.no_cache)?;
+
+    if config.build.apt_layer {
+        progress.step("build the apt layer")?;
+        build_apt_layer(&dc, &up_cont, &project_packages)?;
+        up_cont = devcontainer_up(&dc, true, false)?;
+        if !keeps_apt_layer {
+            log!("Hint": "set `build.apt_layer = true` in the config to keep creating containers from the apt layer");
+        }
+    }
 
     let needs_sudo = up_cont.remote_user != "root";
 
-    enable_host_docker_internal_in_rancher_desktop_on_lima(&dc)?;
-    install_prerequisites(&dc, needs_sudo)?;
-    install_neovim(config, &dc, needs_sudo)?;
-    install_github_cli(&dc)?;
+    progress.step("prepare the container")?;
+    let _cpu_limit = cpu_limit
+        .map(|cpus| CpuLimit::apply(&dc, cpus))
+        .transpose()?;
+    dc.grant_docker_socket_access()?;
+    dc.own_excluded_mounts()?;
+    check_disk_space(config, &dc, !config.build.apt_layer)?;
+    let size_before = container_size(&up_cont);
+
+    VmProvider::detect().enable_host_docker_internal(&dc, needs_sudo)?;
+    match config.build.backend {
+        BuildBackend::Apt => {
+            if !config.build.apt_layer {
+                progress.step("install prerequisites")?;
+                install_prerequisites(config, &dc, needs_sudo, &project_packages)?;
+            }
+            progress.step("install Neovim and GitHub CLI")?;
+            let (neovim, github_cli) = thread::scope(|scope| {
+                let github_cli = scope.spawn(|| install_github_cli(config, &dc));
+                (install_neovim(config, &dc, needs_sudo), github_cli.join())
+            });
+            neovim?;
+            github_cli.map_err(|_| miette!("GitHub CLI installation panicked"))??;
+        }
+        BuildBackend::Nix => {
+            progress.step("provision with Nix")?;
+            provision_with_nix(config, &dc, needs_sudo, &project_packages)?;
+        }
+    }
+    progress.step("set up GitHub CLI and Copilot")?;
     login_to_gh(&dc)?;
     copy_copilot(&dc)?;
+    if config.auth.git_credentials {
+        progress.step("set up the git credential bridge")?;
+        git_credentials::install(config, &dc)?;
+    }
+    if config.build.git_security {
+        progress.step("set up git security")?;
+        setup_git_security(config, &dc)?;
+    }
 
+    progress.step("prepare /opt")?;
     prepare_opt_dir(&dc, needs_sudo, &up_cont.remote_user)?;
+    progress.step("install dotfiles")?;
     install_dotfiles(config, &dc)?;
+    if config.build.plugin_sync {
+        progress.step("sync Neovim plugins")?;
+        sync_neovim_plugins(config, &dc)?;
+    }
+    progress.step("run build scripts")?;
+    Scripts::load()?.run_hook(scripting::ON_BUILD, &dc)?;
+
+    if let (Some(before), Some(after)) = (size_before, container_size(&up_cont)) {
+        log!(
+            "Finished" ("build"):
+            "container size {} -> {} ({:+} MB)",
+            format_mb(before),
+            format_mb(after),
+            (after as i64 - before as i64) / MB as i64
+        );
+    }
 
     Ok(())
 }
 
-fn enable_host_docker_internal_in_rancher_desktop_on_lima(dc: &DevContainer) -> Result<()> {
-    if exec::exec(&["rdctl", "version"]).is_err() {
-        // Not using Rancher Desktop, skipping
-        return Ok(());
-    }
+/// Prints what `build` would do. The container is only inspected, and only if already running.
+fn print_plan(config: &Config, args: &Args, build_args: &BuildArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let is_running = dc.container_status()?.as_deref() == Some("running");
+    let probe = if is_running && !build_args.rebuild {
+        dc.exec_script_capturing_stdout(&neovim_probe_script(&neovim_install_dir(config)))
+            .ok()
+            .and_then(|probe| probe.lines().next().map(str::to_string))
+    } else {
+        None
+    };
 
-    let container_hosts = dc
-        .exec_capturing_stdout(&["cat", "/etc/hosts"])
-        .wrap_err("failed to read /etc/hosts")?;
+    // (description, estimated download in MB)
+    let mut steps: Vec<(String, Option<u64>)> = vec![];
 
-    if container_hosts.contains("host.docker.internal") {
-        // host.docker.internal already exists in /etc/hosts, skipping
-        return Ok(());
+    steps.push((
+        match (build_args.rebuild, build_args.no_cache) {
+            (_, true) => "recreate the devcontainer, building the image without cache".to_string(),
+            (true, false) => "recreate the devcontainer".to_string(),
+            (false, false) if is_running => "reuse the running devcontainer".to_string(),
+            (false, false) => "start the devcontainer".to_string(),
+        },
+        None,
+    ));
+
+    let project_packages = PackageFile::load(dc.workspace_folder())?
+        .map(|package_file| package_file.packages)
+        .unwrap_or_default();
+    let prerequisites = format!(
+        "{} packages: {}",
+        PREREQUISITES.len() + project_packages.len(),
+        chain!(
+            PREREQUISITES.iter().copied(),
+            project_packages.iter().map(String::as_str)
+        )
+        .join(" ")
+    );
+    if config.build.backend == BuildBackend::Nix {
+        let lock =
+            state::workspace_state_dir(dc.workspace_folder())?.join(NIX_FLAKE_LOCK_STATE_FILE);
+        let packages = nix_packages(config, &project_packages);
+        steps.push((
+            "install Nix (single-user) unless installed".to_string(),
+            Some(NIX_SIZE_MB),
+        ));
+        steps.push((
+            format!(
+                "build a flake of {} nixpkgs packages from {}: {}",
+                packages.len(),
+                if lock.exists() {
+                    "the locked revision"
+                } else {
+                    &config.build.nixpkgs
+                },
+                packages.join(" ")
+            ),
+            Some(NIX_PACKAGES_SIZE_MB),
+        ));
+        steps.push((
+            "log in to GitHub CLI with the host's token".to_string(),
+            None,
+        ));
+    } else if build_args.apt_layer || config.build.apt_layer {
+        steps.push((
+            format!("build a derived image with {prerequisites}, then recreate the devcontainer"),
+            Some(PREREQUISITES_SIZE_MB),
+        ));
+    } else {
+        steps.push((
+            format!("apt-get install {prerequisites}"),
+            Some(PREREQUISITES_SIZE_MB),
+        ));
     }
 
-    let host_ip_addr = {
-        let vm_hosts = exec::capturing_stdout(&["rdctl", "shell", "cat", "/etc/hosts"])
-            .wrap_err("failed to read /etc/hosts on Rancher Desktop VM")?;
-        let Some(ip_addr) = vm_hosts.lines().find_map(|line| {
-            let parts = line.split_whitespace().collect_vec();
-            if parts[1] == "host.lima.internal" {
-                Some(parts[0].to_string())
-            } else {
-                None
-            }
-        }) else {
-            // host.lima.internal not found in /etc/hosts, skipping
-            return Ok(());
+    if config.build.backend == BuildBackend::Apt {
+        steps.push(match probe.as_deref() {
+            Some("installed") => ("Neovim: skip (already installed)".to_string(), None),
+            arch => plan_neovim(config, arch.unwrap_or(std::env::consts::ARCH)),
+        });
+
+        steps.push((
+            "install GitHub CLI from its latest release and log in with the host's token"
+                .to_string(),
+            Some(15),
+        ));
+    }
+    steps.push((
+        "copy GitHub Copilot settings from the host".to_string(),
+        None,
+    ));
+    if config.auth.git_credentials {
+        steps.push((
+            "configure git to ask the host for HTTPS credentials".to_string(),
+            None,
+        ));
+    }
+    if config.build.git_security {
+        let gitleaks = if config.build.gitleaks {
+            " and install the latest gitleaks"
+        } else {
+            ""
         };
+        steps.push((
+            format!("register git-secrets patterns and hooks{gitleaks}"),
+            config.build.gitleaks.then_some(10),
+        ));
+    }
+    steps.push((
+        format!(
+            "clone `dotfiles` (default branch) into /opt/dotfiles and run `{}`",
+            config.dotfiles_install_command
+        ),
+        None,
+    ));
+    if config.build.plugin_sync {
+        steps.push((
+            format!(
+                "download Neovim plugins with `{}`",
+                config.build.plugin_sync_command
+            ),
+            None,
+        ));
+    }
+
+    for (i, (description, download_mb)) in steps.iter().enumerate() {
+        match download_mb {
+            Some(mb) => println!("{:>2}. {description} (~{mb} MB)", i + 1),
+            None => println!("{:>2}. {description}", i + 1),
+        }
+    }
+    let total_mb: u64 = steps.iter().filter_map(|(_, mb)| *mb).sum();
+    println!("Estimated download: ~{total_mb} MB");
+
+    let limits = [
+        config
+            .build
+            .cpu_limit
+            .as_ref()
+            .map(|cpus| format!("{cpus} CPUs")),
+        config.build.nice.map(|nice| format!("nice {nice}")),
+        config.build.ionice.map(|ionice| format!("ionice {ionice}")),
+    ]
+    .into_iter()
+    .flatten()
+    .join(", ");
+    if !limits.is_empty() {
+        println!("Resource limits: {limits}");
+    }
+
+    Ok(())
+}
 
-        ip_addr
+/// Resolves how Neovim would be installed on `arch`, without downloading it.
+fn plan_neovim(config: &Config, arch: &str) -> (String, Option<u64>) {
+    let source = |version: &str| {
+        (
+            format!("Neovim {version}: build from source"),
+            Some(NEOVIM_SOURCE_BUILD_SIZE_MB),
+        )
     };
 
-    dc.exec(&[
-        "sh",
-        "-c",
-        &format!(
-            "echo '{host_ip_addr} host.docker.internal' | tee -a /etc/hosts",
-            host_ip_addr = host_ip_addr
+    let release = match github::neovim_release(&config.neovim_version) {
+        Ok(release) => release,
+        Err(_) => return source(&config.neovim_version),
+    };
+    let version = release.name.as_deref().unwrap_or(&release.tag_name);
+
+    let (binaries, appimages): (&[&str], &[&str]) = match arch {
+        "x86_64" => (
+            &["nvim-linux-x86_64.tar.gz", "nvim-linux64.tar.gz"],
+            &["nvim-linux-x86_64.appimage", "nvim.appimage"],
         ),
-    ])?;
+        "aarch64" | "arm64" => (&["nvim-linux-arm64.tar.gz"], &["nvim-linux-arm64.appimage"]),
+        _ => (&[], &[]),
+    };
+    match release
+        .find_asset(binaries)
+        .or_else(|| release.find_asset(appimages))
+    {
+        Some(asset) => (
+            format!("Neovim {version}: download {}", asset.name),
+            Some(asset.size.div_ceil(MB).max(1)),
+        ),
+        None => source(&release.tag_name),
+    }
+}
+
+const MB: u64 = 1024 * 1024;
+
+/// Rough sizes of what the build installs
+const PREREQUISITES_SIZE_MB: u64 = 400;
+const NEOVIM_BINARY_SIZE_MB: u64 = 40;
+const NEOVIM_SOURCE_BUILD_SIZE_MB: u64 = 1024;
+const NIX_SIZE_MB: u64 = 100;
+const NIX_PACKAGES_SIZE_MB: u64 = 600;
+
+/// Free space where Docker keeps images and containers, where that is on the host's filesystem.
+/// Elsewhere it is inside a VM, and only the container itself tells.
+fn docker_storage_free_mb() -> Option<u64> {
+    if !matches!(VmProvider::detect(), VmProvider::Native { .. }) {
+        return None;
+    }
+
+    let root_dir =
+        exec::capturing_stdout(&["docker", "info", "--format", "{{ .DockerRootDir }}"]).ok()?;
+    exec::capturing_stdout(&["df", "-Pk", root_dir.trim()])
+        .ok()
+        .and_then(|df| parse_df_available_kb(&df))
+        .map(|kb| kb / 1024)
+}
+
+/// Aborts if the container is estimated to run out of space during the build and warns if little
+/// would be left afterwards.
+fn check_disk_space(config: &Config, dc: &DevContainer, with_prerequisites: bool) -> Result<()> {
+    let has_neovim = dc
+        .exec_capturing_stdout(&[
+            format!("{}/bin/nvim", neovim_install_dir(config)),
+            "--version".to_string(),
+        ])
+        .is_ok();
+    let mut estimate_mb = if with_prerequisites {
+        PREREQUISITES_SIZE_MB
+    } else {
+        0
+    };
+    if !has_neovim {
+        estimate_mb += NEOVIM_BINARY_SIZE_MB;
+    }
+
+    let container_free_mb = dc
+        .exec_capturing_stdout(&["df", "-Pk", "/"])
+        .ok()
+        .and_then(|df| parse_df_available_kb(&df))
+        .map(|kb| kb / 1024);
+    let storage_free_mb = docker_storage_free_mb();
+
+    for (place, free_mb) in [
+        ("container", container_free_mb),
+        ("Docker's storage", storage_free_mb),
+    ] {
+        let Some(free_mb) = free_mb else {
+            continue;
+        };
+
+        if free_mb < estimate_mb {
+            bail!(
+                help = "free up disk space (e.g. `docker system prune`) and try again",
+                "not enough disk space on the {place}: {free_mb} MB free, about {estimate_mb} MB needed"
+            );
+        }
+        if free_mb < estimate_mb + config.build.min_free_mb {
+            log!("Warning": "only {free_mb} MB free on the {place}, about {estimate_mb} MB will be used by the build");
+        }
+    }
+
+    if config.build.backend == BuildBackend::Apt
+        && !has_neovim
+        && container_free_mb.is_some_and(|free_mb| free_mb < NEOVIM_SOURCE_BUILD_SIZE_MB)
+    {
+        log!("Warning": "building Neovim from source, if needed, takes about {NEOVIM_SOURCE_BUILD_SIZE_MB} MB");
+    }
+
+    Ok(())
+}
+
+/// Returns the "Available" column of `df -P` output.
+fn parse_df_available_kb(df: &str) -> Option<u64> {
+    df.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()
+}
+
+/// Returns the size of the writable layer of the container in bytes.
+fn container_size(up_cont: &UpOutput) -> Option<u64> {
+    exec::capturing_stdout(&[
+        "docker",
+        "inspect",
+        "--size",
+        "--format",
+        "{{ .SizeRw }}",
+        &up_cont.container_id,
+    ])
+    .ok()?
+    .trim()
+    .parse()
+    .ok()
+}
+
+fn format_mb(bytes: u64) -> String {
+    format!("{} MB", bytes / MB)
+}
+
+/// Runs a network-bound step, retrying with exponential backoff per `[build.retries]`.
+fn with_retries<T>(config: &Config, what: &str, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let retries = &config.build.retries;
+    let mut backoff = Duration::from_secs(retries.initial_backoff_secs);
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < retries.attempts => {
+                log!(
+                    "Retrying": "{what} in {}s (attempt {}/{} failed: {e})",
+                    backoff.as_secs(),
+                    attempt,
+                    retries.attempts
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Downloads a release asset to `dest` on the container and checks it against the published
+/// checksum, if any, before anything uses it.
+fn download_verified(
+    config: &Config,
+    dc: &DevContainer,
+    release: &Release,
+    asset: &Asset,
+    dest: &str,
+) -> Result<()> {
+    let expected = release.sha256(asset)?;
+    if expected.is_none() {
+        if config.build.require_checksums {
+            bail!(
+                code = "dockim::build::checksum_missing",
+                help = "unset `build.require_checksums` to install it anyway",
+                "{} has no published checksum",
+                asset.name
+            );
+        }
+        log!("Warning": "{} has no published checksum; it won't be verified", asset.name);
+    }
+
+    let url = exec::shell_quote(&asset.browser_download_url);
+    with_retries(config, &format!("{} download", asset.name), || {
+        dc.exec_script(&format!("rm -f {dest}\ncurl -fsSL -o {dest} {url}"))
+    })?;
+
+    match expected {
+        Some(expected) => verify_sha256(dc, &asset.name, dest, &expected),
+        None => Ok(()),
+    }
+}
+
+/// Checks a file downloaded to `dest` on the container against `expected`, removing it on a
+/// mismatch.
+fn verify_sha256(dc: &DevContainer, name: &str, dest: &str, expected: &str) -> Result<()> {
+    let actual = dc
+        .exec_script_capturing_stdout(&format!(
+            "(sha256sum {dest} 2>/dev/null || shasum -a 256 {dest}) | cut -d ' ' -f 1"
+        ))
+        .wrap_err_with(|| miette!("failed to compute the checksum of {name}"))?;
+    let actual = actual.trim().to_ascii_lowercase();
+    if actual != expected {
+        let _ = dc.exec(&["rm", "-f", dest]);
+        bail!(
+            code = "dockim::build::checksum_mismatch",
+            help = "the download may be corrupted or tampered with; try again later",
+            "checksum mismatch for {name}: expected {expected}, got {actual}"
+        );
+    }
+    log!("Verified" ("sha256"): "{name}");
 
     Ok(())
 }
@@ -80,67 +704,471 @@ fn devcontainer_up(dc: &DevContainer, rebuild: bool, no_cache: bool) -> Result<U
     dc.up_and_inspect()
 }
 
-fn install_prerequisites(dc: &DevContainer, needs_sudo: bool) -> Result<()> {
-    macro_rules! sudo {
-        ($($arg:expr),*$(,)?) => {{
-            let mut sudo = if needs_sudo { vec!["sudo".to_string()] } else { vec![] };
-            $(
-                sudo.push($arg.to_string());
-            )*
-
-            sudo
-        }};
-    }
-
-    let prerequisites = &[
-        "zsh",
-        "curl",
-        "fzf",
-        "ripgrep",
-        "tree",
-        "git",
-        "xclip",
-        "python3",
-        "python3-pip",
-        "python3-pynvim",
-        "tzdata",
-        "ninja-build",
-        "gettext",
-        "libtool",
-        "libtool-bin",
-        "autoconf",
-        "automake",
-        "cmake",
-        "g++",
-        "pkg-config",
-        "zip",
-        "unzip",
-        "git-secrets",
+fn parse_cpu_limit(cpus: &str) -> Result<f64> {
+    match cpus.parse::<f64>() {
+        Ok(cpus) if cpus > 0.0 && cpus.is_finite() => Ok(cpus),
+        _ => bail!(
+            help = "set `build.cpu_limit` to a positive number of CPUs such as \"1.5\"",
+            "invalid CPU limit: {cpus}"
+        ),
+    }
+}
+
+/// Wraps the build's commands on the container with `nice` and `ionice` as configured. Either is
+/// skipped on images that lack it, e.g. `ionice` on slim Alpine images.
+fn priority_prefix(config: &Config) -> Result<Vec<String>> {
+    let mut script = String::new();
+    if let Some(nice) = config.build.nice {
+        if nice > 19 {
+            bail!(help = "use 0 to 19", "invalid `build.nice`: {nice}");
+        }
+        script.push_str(&format!(
+            "command -v nice >/dev/null && set -- nice -n {nice} \"$@\"\n"
+        ));
+    }
+    if let Some(ionice) = config.build.ionice {
+        if ionice > 7 {
+            bail!(help = "use 0 to 7", "invalid `build.ionice`: {ionice}");
+        }
+        script.push_str(&format!(
+            "command -v ionice >/dev/null && set -- ionice -c 2 -n {ionice} \"$@\"\n"
+        ));
+    }
+    if script.is_empty() {
+        return Ok(vec![]);
+    }
+    script.push_str("exec \"$@\"");
+
+    Ok(["sh", "-c", &script, "sh"].map(str::to_string).to_vec())
+}
+
+/// Caps the CPUs of the devcontainer while it is built. `docker exec` has no resource limits of
+/// its own, so the container's cgroup is updated and its previous limit restored on drop. The
+/// previous limit is also saved, for [`restore_cpu_limit`] after an interrupted build.
+struct CpuLimit {
+    dc: DevContainer,
+    /// Container IDs and their previous limits, as for `docker update --cpus` (0 for none)
+    previous: Vec<(String, String)>,
+}
+
+impl CpuLimit {
+    fn apply(dc: &DevContainer, cpus: f64) -> Result<Self> {
+        let mut limit = CpuLimit {
+            dc: dc.clone(),
+            previous: vec![],
+        };
+        for container_id in dc.find_container_ids()? {
+            let nano_cpus = exec::capturing_stdout(&[
+                "docker",
+                "inspect",
+                "--format",
+                "{{ .HostConfig.NanoCpus }}",
+                &container_id,
+            ])
+            .wrap_err("failed to get the CPU limit of the devcontainer")?;
+            let previous = nano_cpus.trim().parse::<u64>().unwrap_or(0) as f64 / 1e9;
+            limit
+                .previous
+                .push((container_id.clone(), previous.to_string()));
+            state::save(dc.workspace_folder(), CPU_LIMIT_STATE_FILE, &limit.previous)?;
+
+            exec::capturing_stdout(&[
+                "docker",
+                "update",
+                "--cpus",
+                &cpus.to_string(),
+                &container_id,
+            ])
+            .wrap_err("failed to limit the CPUs of the devcontainer")?;
+        }
+        log!("Limiting" ("build"): "the devcontainer to {cpus} CPUs");
+
+        Ok(limit)
+    }
+}
+
+/// Restores the CPU limits saved by a build that was interrupted before it could.
+pub fn restore_cpu_limit(dc: &DevContainer) -> Result<()> {
+    let previous: Vec<(String, String)> = state::load(dc.workspace_folder(), CPU_LIMIT_STATE_FILE)?;
+    if previous.is_empty() {
+        return Ok(());
+    }
+
+    log!("Restoring" ("build"): "the CPU limit left by an interrupted build");
+    drop(CpuLimit {
+        dc: dc.clone(),
+        previous,
+    });
+
+    Ok(())
+}
+
+impl Drop for CpuLimit {
+    fn drop(&mut self) {
+        let mut restored = true;
+        for (container_id, cpus) in &self.previous {
+            // A container removed in the meantime has no limit to restore
+            if let Err(e) =
+                exec::capturing_stdout(&["docker", "update", "--cpus", cpus, container_id])
+            {
+                restored &= self
+                    .dc
+                    .find_container_ids()
+                    .is_ok_and(|ids| !ids.contains(container_id));
+                log!("Warning": "failed to restore the CPU limit of {container_id}: {e}");
+            }
+        }
+        if restored {
+            let _ = state::save(
+                self.dc.workspace_folder(),
+                CPU_LIMIT_STATE_FILE,
+                &Vec::<(String, String)>::new(),
+            );
+        }
+    }
+}
+
+/// Bakes the prerequisites into an image derived from the current one so that rebuilds can reuse
+/// Docker's layer and BuildKit apt caches instead of installing them into the container each time.
+fn build_apt_layer(
+    dc: &DevContainer,
+    up_cont: &UpOutput,
+    project_packages: &[String],
+) -> Result<()> {
+    if devcontainer_config::load(dc.workspace_folder())?.is_compose() {
+        bail!("--apt-layer is not supported for Docker Compose based devcontainers");
+    }
+
+    let current_image = exec::capturing_stdout(&[
+        "docker",
+        "inspect",
+        "--format",
+        "{{.Config.Image}}",
+        &up_cont.container_id,
+    ])
+    .wrap_err("failed to get devcontainer image")?
+    .trim()
+    .to_string();
+
+    // Don't stack layers on top of our own previously derived image
+    let previous: Option<AptLayer> = state::load(dc.workspace_folder(), APT_LAYER_STATE_FILE)?;
+    let base_image = match previous {
+        Some(apt_layer) if apt_layer.image == current_image => apt_layer.base_image,
+        _ => current_image,
+    };
+
+    let image_user = exec::capturing_stdout(&[
+        "docker",
+        "image",
+        "inspect",
+        "--format",
+        "{{.Config.User}}",
+        &base_image,
+    ])
+    .wrap_err("failed to inspect devcontainer image")?
+    .trim()
+    .to_string();
+
+    let mut dockerfile = vec![
+        "# syntax=docker/dockerfile:1".to_string(),
+        format!("FROM {base_image}"),
+        "USER root".to_string(),
+        format!(
+            "RUN --mount=type=cache,target=/var/cache/apt,sharing=locked --mount=type=cache,target=/var/lib/apt,sharing=locked rm -f /etc/apt/apt.conf.d/docker-clean && apt-get update && apt-get -y install {}",
+            apt_packages(project_packages)
+        ),
     ];
+    if !image_user.is_empty() {
+        dockerfile.push(format!("USER {image_user}"));
+    }
+
+    let build_dir = state::ensure_workspace_state_dir(dc.workspace_folder())?.join("apt-layer");
+    fs::create_dir_all(&build_dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", build_dir.display()))?;
+    let dockerfile_path = build_dir.join("Dockerfile");
+    fs::write(&dockerfile_path, dockerfile.join("\n") + "\n")
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", dockerfile_path.display()))?;
 
+    let image = format!("dockim-apt-{}", state::workspace_id(dc.workspace_folder())?);
+    exec::exec(&[
+        "docker",
+        "build",
+        "-t",
+        &image,
+        "-f",
+        &dockerfile_path.to_string_lossy(),
+        &build_dir.to_string_lossy(),
+    ])
+    .wrap_err("failed to build apt layer image")?;
+
+    state::save(
+        dc.workspace_folder(),
+        APT_LAYER_STATE_FILE,
+        &AptLayer {
+            base_image,
+            image,
+            inputs_hash: devcontainer_config::inputs_hash(dc.workspace_folder())?,
+        },
+    )
+}
+
+/// Reads the system packages the project declares next to devcontainer.json.
+fn load_project_packages(dc: &DevContainer) -> Result<Vec<String>> {
+    let Some(package_file) = PackageFile::load(dc.workspace_folder())? else {
+        return Ok(vec![]);
+    };
+    log!(
+        "Found" ("packages"):
+        "{} in {}",
+        package_file.packages.len(),
+        package_file.path.display()
+    );
+
+    Ok(package_file.packages)
+}
+
+/// The prerequisites and the project's packages, as arguments of `apt-get install`.
+fn apt_packages(project_packages: &[String]) -> String {
+    chain!(
+        PREREQUISITES.iter().copied(),
+        project_packages.iter().map(String::as_str)
+    )
+    .unique()
+    .join(" ")
+}
+
+fn install_prerequisites(
+    config: &Config,
+    dc: &DevContainer,
+    needs_sudo: bool,
+    project_packages: &[String],
+) -> Result<()> {
     // Sometimes apt-get update fails without 777 permissions on /tmp
-    dc.exec(&sudo!["mkdir", "-p", "/tmp"])?;
-    dc.exec(&sudo!["chmod", "777", "/tmp"])?;
-    dc.exec(&sudo!["apt-get", "update"])?;
-    dc.exec(
-        &chain![
-            sudo!["apt-get", "-y", "install"],
-            prerequisites.iter().map(|s| s.to_string())
-        ]
-        .collect_vec(),
-    )?;
+    let sudo = if needs_sudo { "sudo " } else { "" };
+    dc.exec_script(&format!("{sudo}mkdir -p /tmp\n{sudo}chmod 777 /tmp"))?;
+    with_retries(config, "apt-get update", || {
+        dc.exec_script_quietly(&format!("{sudo}apt-get update"))
+    })?;
+    with_retries(config, "apt-get install", || {
+        dc.exec_script_quietly(&format!(
+            "{sudo}apt-get -y install {}",
+            apt_packages(project_packages)
+        ))
+    })?;
 
     Ok(())
 }
 
+/// Where `build` puts the configured Neovim version
+fn neovim_install_dir(config: &Config) -> String {
+    if config.build.neovim_keep_versions {
+        neovim_version_dir(&config.neovim_version)
+    } else {
+        config.build.neovim_prefix.clone()
+    }
+}
+
+fn neovim_version_dir(version: &str) -> String {
+    format!("{NEOVIM_VERSIONS_DIR}/{version}")
+}
+
 fn install_neovim(config: &Config, dc: &DevContainer, needs_sudo: bool) -> Result<()> {
-    if dc
-        .exec_capturing_stdout(&["/usr/local/bin/nvim", "--version"])
-        .is_ok()
+    let dir = neovim_install_dir(config);
+    install_neovim_into(config, dc, needs_sudo, &config.neovim_version, &dir)?;
+
+    if config.build.neovim_keep_versions {
+        let sudo = if needs_sudo { "sudo " } else { "" };
+        let prefix = &config.build.neovim_prefix;
+        dc.exec_script(&format!(
+            "{sudo}mkdir -p {prefix}/bin\n{sudo}ln -sfn {dir}/bin/nvim {prefix}/bin/nvim"
+        ))?;
+        log!("Selected" ("neovim"): "{} ({dir})", config.neovim_version);
+    }
+
+    Ok(())
+}
+
+/// Makes sure Neovim `version` is installed side by side with the others and returns the path of
+/// its binary on the container.
+pub fn ensure_neovim_version(config: &Config, dc: &DevContainer, version: &str) -> Result<String> {
+    if version.is_empty()
+        || !version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
     {
+        bail!("invalid Neovim version: {version}");
+    }
+
+    let needs_sudo = dc.up_and_inspect()?.remote_user != "root";
+    let dir = neovim_version_dir(version);
+    install_neovim_into(config, dc, needs_sudo, version, &dir)?;
+
+    Ok(format!("{dir}/bin/nvim"))
+}
+
+/// Prints `installed` if Neovim in `dir` works. Otherwise prints the architecture, followed by
+/// what Neovim printed if it is there but fails.
+fn neovim_probe_script(dir: &str) -> String {
+    format!(
+        r#"if {dir}/bin/nvim --version >/dev/null 2>&1; then
+    echo installed
+else
+    uname -m
+    if [ -e {dir}/bin/nvim ]; then {dir}/bin/nvim --version 2>&1 | head -n 5 || true; fi
+fi"#
+    )
+}
+
+/// Installs Neovim `version` into `dir` unless it is already there.
+fn install_neovim_into(
+    config: &Config,
+    dc: &DevContainer,
+    needs_sudo: bool,
+    version: &str,
+    dir: &str,
+) -> Result<()> {
+    // Check for an existing installation and get the architecture in one round trip
+    let probe = dc
+        .exec_script_capturing_stdout(&neovim_probe_script(dir))
+        .wrap_err("failed to check for Neovim and the architecture of the container")?;
+    let (arch, error) = probe.split_once('\n').unwrap_or((&probe, ""));
+    let arch = arch.trim();
+    if arch == "installed" {
         return Ok(());
     }
+    if !error.trim().is_empty() {
+        log!("Warning": "{dir}/bin/nvim is there but fails, so it is reinstalled: {}", error.trim());
+    }
+
+    let release = match github::neovim_release(version) {
+        Ok(release) => release,
+        Err(e) => {
+            log!("Warning": "failed to resolve Neovim release, building from source: {e}");
+            return install_neovim_from_source(config, dc, needs_sudo, version, dir);
+        }
+    };
+    log!(
+        "Resolved" ("neovim"):
+        "{} -> {}",
+        version,
+        release.name.as_deref().unwrap_or(&release.tag_name)
+    );
+
+    if install_neovim_from_binary(config, dc, needs_sudo, &release, arch, dir)? {
+        return Ok(());
+    }
+
+    if install_neovim_from_appimage(config, dc, needs_sudo, &release, arch, dir)? {
+        return Ok(());
+    }
+
+    install_neovim_from_source(config, dc, needs_sudo, &release.tag_name, dir)
+}
+
+fn install_neovim_from_binary(
+    config: &Config,
+    dc: &DevContainer,
+    needs_sudo: bool,
+    release: &Release,
+    arch: &str,
+    dir: &str,
+) -> Result<bool> {
+    // Release assets were renamed in v0.10.4
+    let candidates: &[&str] = match arch {
+        "x86_64" => &["nvim-linux-x86_64.tar.gz", "nvim-linux64.tar.gz"],
+        "aarch64" | "arm64" => &["nvim-linux-arm64.tar.gz"],
+        _ => &[],
+    };
+    let Some(asset) = release.find_asset(candidates) else {
+        return Ok(false);
+    };
+
+    let sudo = if needs_sudo { "sudo " } else { "" };
+    download_verified(config, dc, release, asset, "/tmp/nvim.tar.gz")
+        .wrap_err("failed to download Neovim release")?;
+    dc.exec_script(
+        "rm -rf /tmp/nvim-dist\nmkdir -p /tmp/nvim-dist\ntar -C /tmp/nvim-dist --strip-components=1 -xzf /tmp/nvim.tar.gz",
+    )?;
+
+    // The prebuilt binary needs a recent enough glibc; fall back to other methods if it can't run
+    if dc
+        .exec_capturing_stdout(&["/tmp/nvim-dist/bin/nvim", "--version"])
+        .is_err()
+    {
+        log!("Skipping" ("neovim"): "prebuilt binary does not run on the container");
+        let _ = dc.exec(&["rm", "-rf", "/tmp/nvim-dist", "/tmp/nvim.tar.gz"]);
+        return Ok(false);
+    }
 
+    dc.exec_script(&format!(
+        "{sudo}mkdir -p {dir}\n{sudo}cp -r /tmp/nvim-dist/. {dir}/\nrm -rf /tmp/nvim-dist /tmp/nvim.tar.gz"
+    ))?;
+
+    Ok(true)
+}
+
+fn install_neovim_from_appimage(
+    config: &Config,
+    dc: &DevContainer,
+    needs_sudo: bool,
+    release: &Release,
+    arch: &str,
+    dir: &str,
+) -> Result<bool> {
+    let candidates: &[&str] = match arch {
+        "x86_64" => &["nvim-linux-x86_64.appimage", "nvim.appimage"],
+        "aarch64" | "arm64" => &["nvim-linux-arm64.appimage"],
+        _ => &[],
+    };
+    let Some(asset) = release.find_asset(candidates) else {
+        return Ok(false);
+    };
+
+    let sudo = if needs_sudo { "sudo " } else { "" };
+    // Extract rather than run the AppImage directly since containers usually lack FUSE
+    dc.exec_script("rm -rf /tmp/nvim-appimage\nmkdir -p /tmp/nvim-appimage")?;
+    download_verified(
+        config,
+        dc,
+        release,
+        asset,
+        "/tmp/nvim-appimage/nvim.appimage",
+    )
+    .wrap_err("failed to download Neovim AppImage")?;
+    dc.exec_script(
+        "cd /tmp/nvim-appimage\nchmod +x nvim.appimage\n./nvim.appimage --appimage-extract >/dev/null",
+    )?;
+
+    if dc
+        .exec_capturing_stdout(&["/tmp/nvim-appimage/squashfs-root/AppRun", "--version"])
+        .is_err()
+    {
+        log!("Skipping" ("neovim"): "AppImage does not run on the container");
+        let _ = dc.exec(&["rm", "-rf", "/tmp/nvim-appimage"]);
+        return Ok(false);
+    }
+
+    let cmds = [
+        format!("{sudo}rm -rf {dir}/lib/nvim-appimage"),
+        format!("{sudo}mkdir -p {dir}/lib {dir}/bin"),
+        format!("{sudo}mv /tmp/nvim-appimage/squashfs-root {dir}/lib/nvim-appimage"),
+        format!("{sudo}ln -sf {dir}/lib/nvim-appimage/AppRun {dir}/bin/nvim"),
+        "rm -rf /tmp/nvim-appimage".to_string(),
+    ];
+    dc.exec_script(&cmds.join("\n"))?;
+
+    Ok(true)
+}
+
+fn install_neovim_from_source(
+    config: &Config,
+    dc: &DevContainer,
+    needs_sudo: bool,
+    version: &str,
+    dir: &str,
+) -> Result<()> {
     let sudo = |cmd: &str| {
         if needs_sudo {
             "sudo ".to_string() + cmd
@@ -149,34 +1177,192 @@ fn install_neovim(config: &Config, dc: &DevContainer, needs_sudo: bool) -> Resul
         }
     };
 
-    let _ = dc.exec(&["rm", "-rf", "/tmp/neovim"]);
-    dc.exec(&["mkdir", "-p", "/tmp/neovim"])?;
-
-    dc.exec(&[
-        "git",
-        "clone",
-        "--depth",
-        "1",
-        "--no-single-branch",
-        "https://github.com/neovim/neovim",
-        "/tmp/neovim",
-    ])?;
+    with_retries(config, "Neovim clone", || {
+        dc.exec_script(concat!(
+            "rm -rf /tmp/neovim\n",
+            "mkdir -p /tmp/neovim\n",
+            "git clone --depth 1 --no-single-branch https://github.com/neovim/neovim /tmp/neovim",
+        ))
+    })?;
 
     let cmds = [
         "cd /tmp/neovim".to_string(),
-        format!("(git checkout {} || true)", config.neovim_version),
-        "make -j4".to_string(),
+        format!("(git checkout {} || true)", version),
+        format!("make -j4 CMAKE_INSTALL_PREFIX={dir}"),
         sudo("make install"),
+        "rm -rf /tmp/neovim".to_string(),
     ];
 
-    dc.exec(&["sh", "-c", &cmds.join(" && ")])?;
-    dc.exec(&["rm", "-rf", "/tmp/neovim"])?;
+    dc.exec_script_quietly(&cmds.join("\n"))?;
 
     Ok(())
 }
 
-fn install_github_cli(dc: &DevContainer) -> Result<()> {
-    dc.exec(&["sh", "-c", "curl -sS https://webi.sh/gh | sh"])
+fn nix_packages<'a>(config: &'a Config, project_packages: &'a [String]) -> Vec<&'a str> {
+    let gitleaks = (config.build.git_security && config.build.gitleaks).then_some("gitleaks");
+
+    chain!(
+        NIX_PACKAGES.iter().copied(),
+        gitleaks,
+        config
+            .build
+            .nix_packages
+            .iter()
+            .map(|package| package.as_str()),
+        project_packages.iter().map(String::as_str)
+    )
+    .unique()
+    .collect()
+}
+
+fn nix_flake(config: &Config, project_packages: &[String]) -> String {
+    let packages = nix_packages(config, project_packages)
+        .iter()
+        .map(|package| format!("              {package}\n"))
+        .join("");
+
+    NIX_FLAKE_TEMPLATE
+        .replace("@NIXPKGS@", &config.build.nixpkgs)
+        .replace("@PACKAGES@", &packages)
+}
+
+/// Installs the tools from a generated flake and links them to where the apt backend puts them.
+fn provision_with_nix(
+    config: &Config,
+    dc: &DevContainer,
+    needs_sudo: bool,
+    project_packages: &[String],
+) -> Result<()> {
+    install_nix(config, dc, needs_sudo)?;
+
+    let lock_path =
+        state::ensure_workspace_state_dir(dc.workspace_folder())?.join(NIX_FLAKE_LOCK_STATE_FILE);
+    dc.exec_script(&format!(
+        "mkdir -p {NIX_FLAKE_DIR}\nrm -f {NIX_FLAKE_DIR}/flake.lock"
+    ))?;
+    dc.exec_with_bytes_stdin(
+        &["sh", "-c", &format!("cat > {NIX_FLAKE_DIR}/flake.nix")],
+        nix_flake(config, project_packages).as_bytes(),
+    )?;
+    if let Ok(lock) = fs::read(&lock_path) {
+        dc.exec_with_bytes_stdin(
+            &["sh", "-c", &format!("cat > {NIX_FLAKE_DIR}/flake.lock")],
+            &lock,
+        )?;
+    }
+
+    with_retries(config, "Nix build", || {
+        dc.exec_script_quietly(&format!(
+            "mkdir -p \"$(dirname {NIX_ENV_LINK})\"\n{NIX} build {NIX_FLAKE_DIR} --out-link {NIX_ENV_LINK}"
+        ))
+    })
+    .wrap_err("failed to build the Nix environment")?;
+
+    // Link the tools to where Neovim would be installed by the apt backend
+    let sudo = if needs_sudo { "sudo " } else { "" };
+    let prefix = &config.build.neovim_prefix;
+    dc.exec_script(&format!(
+        "{sudo}mkdir -p {prefix}/bin\nfor bin in {NIX_ENV_LINK}/bin/*; do {sudo}ln -sf \"$bin\" {prefix}/bin/; done\nmkdir -p ~/.local/bin\nln -sf {NIX_ENV_LINK}/bin/gh ~/.local/bin/gh"
+    ))?;
+
+    let lock = dc
+        .exec_capturing_stdout(&["sh", "-c", &format!("cat {NIX_FLAKE_DIR}/flake.lock")])
+        .wrap_err("failed to read flake.lock")?;
+    fs::write(&lock_path, lock)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", lock_path.display()))?;
+    log!("Locked" ("nix"): "{}", lock_path.display());
+
+    Ok(())
+}
+
+fn install_nix(config: &Config, dc: &DevContainer, needs_sudo: bool) -> Result<()> {
+    if dc
+        .exec_capturing_stdout(&["sh", "-c", &format!("test -x {NIX}")])
+        .is_ok()
+    {
+        return Ok(());
+    }
+
+    // A single-user install needs no daemon, which containers usually can't run
+    let sudo = if needs_sudo { "sudo " } else { "" };
+    dc.exec_script(&format!(
+        "{sudo}mkdir -m 0755 -p /nix\n{sudo}chown \"$(id -u):$(id -g)\" /nix"
+    ))?;
+    let url = format!("https://releases.nixos.org/nix/nix-{NIX_VERSION}/install");
+    let name = format!("Nix {NIX_VERSION} installer");
+    let expected = with_retries(config, &format!("{name} checksum lookup"), || {
+        dc.exec_script_capturing_stdout(&format!("curl -fsSL {url}.sha256"))
+    })?;
+    with_retries(config, &format!("{name} download"), || {
+        dc.exec_script(&format!(
+            "rm -f {NIX_INSTALLER}\ncurl -fsSL -o {NIX_INSTALLER} {url}"
+        ))
+    })?;
+    let expected = expected
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    verify_sha256(dc, &name, NIX_INSTALLER, &expected)?;
+    with_retries(config, "Nix install", || {
+        dc.exec_script_quietly(&format!("sh {NIX_INSTALLER} --no-daemon"))
+    })
+    .wrap_err(miette!(
+        help = "the installer needs curl, tar and xz on the container",
+        "failed to install Nix"
+    ))?;
+
+    // Unprivileged containers can't set up the build sandbox
+    dc.exec_script(concat!(
+        "mkdir -p ~/.config/nix\n",
+        "grep -qs flakes ~/.config/nix/nix.conf || printf 'experimental-features = nix-command flakes\\nsandbox = false\\n' >> ~/.config/nix/nix.conf",
+    ))?;
+
+    Ok(())
+}
+
+fn install_github_cli(config: &Config, dc: &DevContainer) -> Result<()> {
+    let release = match with_retries(config, "GitHub CLI release lookup", || {
+        github::release(GITHUB_CLI_REPO, "latest")
+    }) {
+        Ok(release) => release,
+        Err(e) => {
+            log!("Warning": "failed to resolve GitHub CLI release, installing with webi: {e}");
+            return install_github_cli_with_webi(config, dc);
+        }
+    };
+
+    let version = release.tag_name.trim_start_matches('v');
+    let arch = dc
+        .exec_capturing_stdout(&["uname", "-m"])
+        .wrap_err("failed to get container architecture")?;
+    let arch = match arch.trim() {
+        "x86_64" => "amd64",
+        "aarch64" | "arm64" => "arm64",
+        arch => arch,
+    };
+    let Some(asset) = release.find_asset(&[&format!("gh_{version}_linux_{arch}.tar.gz")]) else {
+        log!("Warning": "no GitHub CLI release asset found for {arch}, installing with webi");
+        return install_github_cli_with_webi(config, dc);
+    };
+
+    download_verified(config, dc, &release, asset, "/tmp/gh.tar.gz")
+        .wrap_err("failed to download GitHub CLI")?;
+    dc.exec_script(concat!(
+        "rm -rf /tmp/gh-dist\n",
+        "mkdir -p /tmp/gh-dist ~/.local/bin\n",
+        "tar -C /tmp/gh-dist --strip-components=1 -xzf /tmp/gh.tar.gz\n",
+        "cp /tmp/gh-dist/bin/gh ~/.local/bin/gh\n",
+        "rm -rf /tmp/gh-dist /tmp/gh.tar.gz",
+    ))
+    .wrap_err("failed to install GitHub CLI")
+}
+
+fn install_github_cli_with_webi(config: &Config, dc: &DevContainer) -> Result<()> {
+    with_retries(config, "GitHub CLI install", || {
+        dc.exec(&["sh", "-c", "curl -sS https://webi.sh/gh | sh"])
+    })
 }
 
 fn login_to_gh(dc: &DevContainer) -> Result<()> {
@@ -190,17 +1376,24 @@ fn login_to_gh(dc: &DevContainer) -> Result<()> {
 }
 
 fn copy_copilot(dc: &DevContainer) -> Result<()> {
-    dc.exec(&["sh", "-c", "mkdir -p ~/.config/github-copilot"])?;
-
-    let local_home = home_dir().ok_or_else(|| miette!("failed to get local home directory"))?;
+    // Copilot keeps its config in %LOCALAPPDATA% on Windows
+    let local_dir = if cfg!(windows) {
+        dirs::data_local_dir()
+    } else {
+        home_dir().map(|home| home.join(".config"))
+    }
+    .ok_or_else(|| miette!("failed to get local config directory"))?
+    .join("github-copilot");
     let remote_home = dc
-        .exec_capturing_stdout(&["sh", "-c", "readlink -f $(echo $HOME)"])
+        .exec_script_capturing_stdout(
+            "mkdir -p ~/.config/github-copilot\nreadlink -f $(echo $HOME)",
+        )
         .wrap_err("failed to get remote home directory")?
         .trim()
         .to_string();
 
     for file in ["apps.json", "hosts.json", "versions.json"] {
-        let local_path = local_home.join(".config").join("github-copilot").join(file);
+        let local_path = local_dir.join(file);
         if !local_path.exists() {
             continue;
         }
@@ -212,36 +1405,113 @@ fn copy_copilot(dc: &DevContainer) -> Result<()> {
     Ok(())
 }
 
+fn setup_git_security(config: &Config, dc: &DevContainer) -> Result<()> {
+    let mut configured = vec![];
+
+    let providers = dc
+        .exec_capturing_stdout(&[
+            "git",
+            "config",
+            "--global",
+            "--get-all",
+            "secrets.providers",
+        ])
+        .unwrap_or_default();
+    if !providers.contains("aws-provider") {
+        dc.exec(&["git", "secrets", "--register-aws", "--global"])
+            .wrap_err("failed to register AWS patterns with git-secrets")?;
+    }
+    configured.push("AWS credentials".to_string());
+
+    let patterns = dc
+        .exec_capturing_stdout(&["git", "config", "--global", "--get-all", "secrets.patterns"])
+        .unwrap_or_default();
+    for (name, pattern) in SECRET_PATTERNS {
+        if !patterns.lines().any(|line| line == *pattern) {
+            dc.exec(&[
+                "git",
+                "config",
+                "--global",
+                "--add",
+                "secrets.patterns",
+                pattern,
+            ])
+            .wrap_err_with(|| miette!("failed to register {name} pattern with git-secrets"))?;
+        }
+        configured.push(name.to_string());
+    }
+
+    if dc
+        .exec_capturing_stdout(&["git", "rev-parse", "--is-inside-work-tree"])
+        .is_ok()
+    {
+        dc.exec(&["git", "secrets", "--install", "-f"])
+            .wrap_err("failed to install git-secrets hooks in the workspace repository")?;
+        configured.push("commit hooks in the workspace repository".to_string());
+    } else {
+        log!("Skipping" ("git security"): "workspace is not a git repository, no hooks installed");
+    }
+
+    if config.build.gitleaks {
+        install_gitleaks(config, dc)?;
+        configured.push("gitleaks".to_string());
+    }
+
+    for item in configured {
+        log!("Configured" ("git security"): "{item}");
+    }
+
+    Ok(())
+}
+
+fn install_gitleaks(config: &Config, dc: &DevContainer) -> Result<()> {
+    if dc
+        .exec_capturing_stdout(&[
+            "sh",
+            "-c",
+            "command -v gitleaks || test -x ~/.local/bin/gitleaks",
+        ])
+        .is_ok()
+    {
+        return Ok(());
+    }
+
+    let release = with_retries(config, "gitleaks release lookup", || {
+        github::release("gitleaks/gitleaks", "latest")
+    })?;
+    let version = release.tag_name.trim_start_matches('v');
+    let arch = dc
+        .exec_capturing_stdout(&["uname", "-m"])
+        .wrap_err("failed to get container architecture")?;
+    let arch = match arch.trim() {
+        "x86_64" => "x64",
+        "aarch64" | "arm64" => "arm64",
+        arch => arch,
+    };
+    let asset = release
+        .find_asset(&[&format!("gitleaks_{version}_linux_{arch}.tar.gz")])
+        .ok_or_else(|| miette!("no gitleaks release asset found for {arch}"))?;
+
+    download_verified(config, dc, &release, asset, "/tmp/gitleaks.tar.gz")
+        .wrap_err("failed to download gitleaks")?;
+    dc.exec_script(
+        "mkdir -p ~/.local/bin\ntar -C ~/.local/bin -xzf /tmp/gitleaks.tar.gz gitleaks\nrm -f /tmp/gitleaks.tar.gz",
+    )
+    .wrap_err("failed to install gitleaks")
+}
+
 fn prepare_opt_dir(dc: &DevContainer, needs_sudo: bool, owner_user: &str) -> Result<()> {
-    macro_rules! sudo {
-        ($($arg:expr),*$(,)?) => {{
-            let mut sudo = if needs_sudo { vec!["sudo".to_string()] } else { vec![] };
-            $(
-                sudo.push($arg.to_string());
-            )*
-
-            sudo
-        }};
-    }
-
-    dc.exec(&sudo!["mkdir", "-p", "/opt"])?;
-    dc.exec(&sudo![
-        "chown",
-        "-R",
-        format!("{owner_user}:{owner_user}"),
-        "/opt"
-    ])?;
+    let sudo = if needs_sudo { "sudo " } else { "" };
+    let owner = exec::shell_quote(&format!("{owner_user}:{owner_user}"));
+    dc.exec_script(&format!("{sudo}mkdir -p /opt\n{sudo}chown -R {owner} /opt"))?;
 
     Ok(())
 }
 
 fn install_dotfiles(config: &Config, dc: &DevContainer) -> Result<()> {
-    let _ = dc.exec(&["rm", "-rf", "/opt/dotfiles"]);
-    dc.exec(&[
-        "sh",
-        "-c",
-        "~/.local/bin/gh repo clone dotfiles /opt/dotfiles",
-    ])?;
+    dc.exec_script(
+        "rm -rf /opt/dotfiles || true\n~/.local/bin/gh repo clone dotfiles /opt/dotfiles",
+    )?;
     dc.exec(&[
         "sh",
         "-c",
@@ -250,3 +1520,42 @@ fn install_dotfiles(config: &Config, dc: &DevContainer) -> Result<()> {
 
     Ok(())
 }
+
+fn sync_neovim_plugins(config: &Config, dc: &DevContainer) -> Result<()> {
+    let command = &config.build.plugin_sync_command;
+    with_retries(config, "plugin sync", || {
+        dc.exec(&["sh", "-c", command])
+    })
+    .wrap_err(miette!(
+        help = "check the command by running it with `dockim exec`, or set `build.plugin_sync_command`",
+        "failed to sync Neovim plugins with `{command}`"
+    ))?;
+    log!("Synced" ("Neovim plugins"): "{command}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_df_available_kb_reads_the_available_column() {
+        let df = "Filesystem     1024-blocks     Used Available Capacity Mounted on\n\
+                  /dev/sda1        102400000 51200000  46080000      53% /\n";
+        assert_eq!(parse_df_available_kb(df), Some(46_080_000));
+    }
+
+    #[test]
+    fn parse_df_available_kb_rejects_unexpected_output() {
+        assert_eq!(parse_df_available_kb(""), None);
+        assert_eq!(
+            parse_df_available_kb("Filesystem 1024-blocks Used Available Capacity Mounted on\n"),
+            None
+        );
+        assert_eq!(
+            parse_df_available_kb("Filesystem 1024-blocks Used Available\n/dev/sda1 100 50 -\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,531 @@
+use std::{
+    env,
+    net::{IpAddr, SocketAddr, TcpListener},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use itertools::Itertools;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use scopeguard::defer;
+use serde_json::{json, Value};
+
+use crate::{
+    cli::{Args, ClipboardArgs, ClipboardCommand},
+    config::Config,
+    devcontainer::DevContainer,
+    exec,
+    host_port::{self, Subnet},
+    log, pairing, remote, secret, state,
+};
+
+/// Commands to access the clipboard on the host.
+struct HostProvider {
+    name: &'static str,
+    copy: &'static [&'static str],
+    paste: &'static [&'static str],
+}
+
+const HOST_PROVIDERS: &[HostProvider] = &[
+    HostProvider {
+        name: "pbcopy",
+        copy: &["pbcopy"],
+        paste: &["pbpaste"],
+    },
+    HostProvider {
+        name: "powershell",
+        copy: &[
+            "powershell.exe",
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            "$input | Set-Clipboard",
+        ],
+        paste: &[
+            "powershell.exe",
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            "Get-Clipboard -Raw",
+        ],
+    },
+    HostProvider {
+        name: "wl-clipboard",
+        copy: &["wl-copy"],
+        paste: &["wl-paste", "--no-newline"],
+    },
+    HostProvider {
+        name: "xclip",
+        copy: &["xclip", "-selection", "clipboard"],
+        paste: &["xclip", "-selection", "clipboard", "-o"],
+    },
+    HostProvider {
+        name: "xsel",
+        copy: &["xsel", "--clipboard", "--input"],
+        paste: &["xsel", "--clipboard", "--output"],
+    },
+];
+
+/// Bodies larger than this are rejected with 413 Payload Too Large
+const MAX_CLIPBOARD_BYTES: usize = 16 * 1024 * 1024;
+
+/// Port of `dockim clipboard serve` unless given; `dockim neovim` takes any free one
+pub const DEFAULT_PORT: &str = "55232";
+
+const TOKEN_STATE_FILE: &str = "clipboard-token.json";
+
+/// Variables with which `dockim clipboard serve` tells commands on the container where it is
+const URL_ENV: &str = "DOCKIM_CLIPBOARD_URL";
+const TOKEN_ENV: &str = "DOCKIM_CLIPBOARD_TOKEN";
+
+#[derive(Clone)]
+struct ServerState {
+    provider: &'static HostProvider,
+    token: Arc<str>,
+    allowed: Option<Arc<[Subnet]>>,
+}
+
+impl ServerState {
+    fn is_allowed(&self, peer: IpAddr) -> bool {
+        self.allowed
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|subnet| subnet.contains(peer)))
+    }
+
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| secret::token_matches(&self.token, token))
+    }
+}
+
+pub fn main(config: &Config, args: &Args, clipboard_args: &ClipboardArgs) -> Result<()> {
+    match clipboard_args.command {
+        ClipboardCommand::Status => status(config, args),
+        ClipboardCommand::Serve { port } => serve(config, args, port),
+    }
+}
+
+fn status(config: &Config, args: &Args) -> Result<()> {
+    let mut problems = 0;
+
+    // The provider whose paste works is the one in use; its output is restored after the test
+    let host = HOST_PROVIDERS.iter().find_map(|provider| {
+        let contents = exec::capturing_stdout_bytes(provider.paste).ok()?;
+        Some((provider, contents))
+    });
+    match &host {
+        Some((provider, _)) => {
+            log!("Ok" ("clipboard"): "host clipboard is accessed with {}", provider.name)
+        }
+        None => {
+            problems += 1;
+            log!("Problem" ("clipboard"): "no clipboard command works on the host");
+            log!("Hint": "install wl-clipboard, xclip or xsel");
+        }
+    }
+
+    let mut dc = DevContainer::new(args.workspace_folder.clone(), config);
+    dc.ensure_up()?;
+
+    // The same server and provider as `dockim neovim`, on a port of its own
+    let server = match Server::bind(config, &dc, 0) {
+        Ok(server) => server,
+        Err(e) => {
+            log!("Problem" ("clipboard"): "failed to start the clipboard server: {e}");
+            bail!("{} problem(s) found", problems + 1);
+        }
+    };
+    let port = server.port().to_string();
+    log!("Ok" ("clipboard"): "the server listens on port {port} and lets in {}", server.allowed());
+    defer! {
+        if let Some(ssh_host) = &config.runtime.ssh_host {
+            let _ = remote::cancel_reverse_forward(ssh_host, &port);
+        }
+    }
+    for (name, value) in server.env() {
+        dc = dc.with_env_var(name, value);
+    }
+    server.spawn();
+
+    let container_provider = dc.exec_capturing_stdout(&[
+        "nvim",
+        "--headless",
+        "--cmd",
+        &neovim_provider(),
+        "+lua io.stdout:write(vim.fn['provider#clipboard#Executable']())",
+        "+qa!",
+    ]);
+    match container_provider.as_deref().map(str::trim) {
+        Ok("") => {
+            problems += 1;
+            log!("Problem" ("clipboard"): "Neovim on the container has no clipboard provider");
+            log!("Hint": "install curl on the container");
+        }
+        Ok(provider) => {
+            log!("Ok" ("clipboard"): "Neovim on the container uses the {provider} provider")
+        }
+        Err(e) => {
+            problems += 1;
+            // The error carries the end of Neovim's stderr, which tells why it didn't start
+            log!("Problem" ("clipboard"): "failed to run Neovim on the container: {e}");
+            log!("Hint": "run `dockim build` first");
+        }
+    }
+
+    if let (Some((provider, saved)), Ok(_)) = (&host, &container_provider) {
+        problems += round_trip(&dc, provider);
+
+        // Put back what the user had copied
+        let _ = exec::with_bytes_stdin(provider.copy, saved);
+    }
+
+    if problems > 0 {
+        bail!("{problems} problem(s) found");
+    }
+
+    Ok(())
+}
+
+/// Copies on one side and pastes on the other, in both directions. Returns the number of failed
+/// directions.
+fn round_trip(dc: &DevContainer, provider: &HostProvider) -> usize {
+    let mut problems = 0;
+
+    let token = test_token("container");
+    let copied = dc.exec_capturing_stdout(&[
+        "nvim",
+        "--headless",
+        "--cmd",
+        &neovim_provider(),
+        &format!("+call setreg('+', '{token}')"),
+        "+qa!",
+    ]);
+    // Some copy commands hand the contents over to a background process
+    thread::sleep(Duration::from_millis(300));
+    let pasted = exec::capturing_stdout(provider.paste).unwrap_or_default();
+    if copied.is_ok() && pasted.trim() == token {
+        log!("Ok" ("clipboard"): "copy in the container, paste on the host");
+    } else {
+        problems += 1;
+        log!("Problem" ("clipboard"): "text copied in the container did not reach the host");
+    }
+
+    let token = test_token("host");
+    let pasted = exec::with_bytes_stdin(provider.copy, token.as_bytes())
+        .and_then(|_| {
+            thread::sleep(Duration::from_millis(300));
+            dc.exec_capturing_stdout(&[
+                "nvim",
+                "--headless",
+                "--cmd",
+                &neovim_provider(),
+                "+lua io.stdout:write(vim.fn.getreg('+'))",
+                "+qa!",
+            ])
+        })
+        .unwrap_or_default();
+    if pasted.trim() == token {
+        log!("Ok" ("clipboard"): "copy on the host, paste in the container");
+    } else {
+        problems += 1;
+        log!("Problem" ("clipboard"): "text copied on the host did not reach the container");
+    }
+
+    if problems > 0 {
+        log!("Hint": "check that the container can reach ${URL_ENV} on the host");
+    }
+
+    problems
+}
+
+fn test_token(side: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+
+    format!(
+        "dockim-clipboard-test-{side}-{}-{nanos}",
+        env::var("USER").unwrap_or_default()
+    )
+}
+
+/// Serves the host clipboard until interrupted, for clients other than `dockim neovim`.
+fn serve(config: &Config, args: &Args, port: u16) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let server = Server::bind(config, &dc, port)?;
+    publish_url(&dc, &server)?;
+
+    server.run()
+}
+
+/// The host clipboard over HTTP: `GET /clipboard` pastes, `POST` or `PUT /clipboard` copies the
+/// body, and `/health` and `/version` let clients find out what they are talking to.
+pub struct Server {
+    listener: TcpListener,
+    provider: &'static HostProvider,
+    /// Where the container reaches the server
+    url: String,
+    /// Required as `Authorization: Bearer <token>`, since every container reaches the server
+    token: String,
+    /// Addresses connections are accepted from, or any
+    allowed: Option<Vec<Subnet>>,
+}
+
+impl Server {
+    /// Listens where the devcontainer can connect, on an ephemeral port if `port` is 0.
+    pub fn bind(config: &Config, dc: &DevContainer, port: u16) -> Result<Self> {
+        let provider = HOST_PROVIDERS
+            .iter()
+            .find(|provider| exec::capturing_stdout(provider.paste).is_ok())
+            .ok_or_else(|| {
+                miette!(
+                    help = "install wl-clipboard, xclip or xsel",
+                    "no clipboard command works on the host"
+                )
+            })?;
+
+        let address = host_port::container_listen_address(config, dc);
+        let listener = TcpListener::bind((address, port))
+            .into_diagnostic()
+            .wrap_err_with(|| {
+                miette!(
+                    help = "another `dockim clipboard serve` may be running already",
+                    "failed to listen on {address}:{port}"
+                )
+            })?;
+        listener.set_nonblocking(true).into_diagnostic()?;
+        let port = listener.local_addr().into_diagnostic()?.port();
+        if let Some(ssh_host) = &config.runtime.ssh_host {
+            remote::reverse_forward(ssh_host, &port.to_string())?;
+        }
+
+        let host = if address.is_loopback() || address.is_unspecified() {
+            "host.docker.internal".to_string()
+        } else {
+            address.to_string()
+        };
+        // Containers on other networks reach the bridge address as well
+        let subnets = host_port::bridge_subnets(dc);
+        let allowed = (!address.is_loopback() && !address.is_unspecified() && !subnets.is_empty())
+            .then(|| {
+                [Subnet::LOOPBACK_V4, Subnet::LOOPBACK_V6]
+                    .into_iter()
+                    .chain(subnets)
+                    .collect()
+            });
+        log!("Serving" ("clipboard"): "on {address}:{port} with {}", provider.name);
+        if address.is_unspecified() {
+            log!("Warning": "the clipboard is reachable from the network, guarded only by its token, since the Docker bridge can't be bound");
+        }
+
+        Ok(Server {
+            listener,
+            provider,
+            url: format!("http://{host}:{port}"),
+            token: token()?,
+            allowed,
+        })
+    }
+
+    /// Describes who may connect, besides needing the token.
+    pub fn allowed(&self) -> String {
+        match &self.allowed {
+            Some(allowed) => allowed.iter().join(", "),
+            None => "any address".to_string(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener
+            .local_addr()
+            .map_or(0, |address| address.port())
+    }
+
+    /// Variables that tell commands on the container where the server is.
+    pub fn env(&self) -> [(&'static str, String); 2] {
+        [(URL_ENV, self.url.clone()), (TOKEN_ENV, self.token.clone())]
+    }
+
+    /// Serves on a background thread for as long as dockim runs.
+    pub fn spawn(self) {
+        thread::spawn(move || {
+            if let Err(e) = self.run() {
+                log!("Warning": "the clipboard server stopped: {e}");
+            }
+        });
+    }
+
+    fn run(self) -> Result<()> {
+        let app = Router::new()
+            .route("/clipboard", get(paste).post(copy).put(copy))
+            .route("/health", get(|| async { "ok\n" }))
+            .route("/version", get(version))
+            .layer(DefaultBodyLimit::max(MAX_CLIPBOARD_BYTES))
+            .with_state(ServerState {
+                provider: self.provider,
+                token: Arc::from(self.token),
+                allowed: self.allowed.map(Arc::from),
+            });
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .into_diagnostic()?
+            .block_on(async {
+                let listener = tokio::net::TcpListener::from_std(self.listener)?;
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await
+            })
+            .into_diagnostic()
+            .wrap_err("clipboard server failed")
+    }
+}
+
+/// Neovim's `g:clipboard` talking to the server through curl, as a `--cmd` argument. The user's
+/// own `g:clipboard` in init.lua or init.vim still takes precedence.
+pub fn neovim_provider() -> String {
+    let auth = format!("Authorization: Bearer ${TOKEN_ENV}");
+    let copy = format!("curl -fsS -X POST --data-binary @- -H \"{auth}\" \"${URL_ENV}/clipboard\"");
+    let paste = format!("curl -fsS -H \"{auth}\" \"${URL_ENV}/clipboard\"");
+    let provider = json!({
+        "name": "dockim",
+        "copy": { "+": ["sh", "-c", copy], "*": ["sh", "-c", copy] },
+        "paste": { "+": ["sh", "-c", paste], "*": ["sh", "-c", paste] },
+    });
+
+    // A JSON object is also a valid Vim script dictionary
+    format!("let g:clipboard = {provider}")
+}
+
+/// Saves where the container reaches the server and its token for later `dockim exec`, `shell`
+/// and `bash`.
+fn publish_url(dc: &DevContainer, server: &Server) -> Result<()> {
+    let mut env = dc.saved_env()?;
+    for (name, value) in server.env() {
+        env.insert(name.to_string(), value);
+    }
+    dc.save_env(&env)?;
+    log!("Saved" ("env"): "{URL_ENV}={} for later `dockim exec`, `shell` and `bash`", server.url);
+    log!("Hint": "clients on the container must send `Authorization: Bearer ${TOKEN_ENV}`");
+
+    Ok(())
+}
+
+fn token() -> Result<String> {
+    let token: Option<String> = state::load_shared(TOKEN_STATE_FILE)?;
+    if let Some(token) = token {
+        return Ok(token);
+    }
+
+    let token = secret::random_token()?;
+    state::save_shared(TOKEN_STATE_FILE, &Some(&token))?;
+
+    Ok(token)
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        format!("send `Authorization: Bearer ${TOKEN_ENV}`\n"),
+    )
+        .into_response()
+}
+
+fn forbidden_peer(peer: IpAddr) -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        format!("connections from {peer} are not allowed\n"),
+    )
+        .into_response()
+}
+
+async fn paste(
+    State(server): State<ServerState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if !server.is_allowed(peer.ip()) {
+        return forbidden_peer(peer.ip());
+    }
+    if !server.is_authorized(&headers) {
+        return unauthorized();
+    }
+    // The peer of `dockim pair` must not read the host's clipboard
+    if pairing::is_active() {
+        return (
+            StatusCode::FORBIDDEN,
+            "the host clipboard can't be read while pairing\n",
+        )
+            .into_response();
+    }
+
+    let provider = server.provider;
+    let contents =
+        tokio::task::spawn_blocking(move || exec::capturing_stdout_bytes(provider.paste)).await;
+    match contents {
+        Ok(Ok(contents)) => contents.into_response(),
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to paste with {}\n", provider.name),
+        )
+            .into_response(),
+    }
+}
+
+async fn copy(
+    State(server): State<ServerState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !server.is_allowed(peer.ip()) {
+        return forbidden_peer(peer.ip());
+    }
+    if !server.is_authorized(&headers) {
+        return unauthorized();
+    }
+    // Nor overwrite it, which could plant a command for the user to paste
+    if pairing::is_active() {
+        return (
+            StatusCode::FORBIDDEN,
+            "the host clipboard can't be written while pairing\n",
+        )
+            .into_response();
+    }
+
+    let provider = server.provider;
+    let copied =
+        tokio::task::spawn_blocking(move || exec::with_bytes_stdin(provider.copy, &body)).await;
+    match copied {
+        Ok(Ok(())) => StatusCode::NO_CONTENT.into_response(),
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to copy with {}\n", provider.name),
+        )
+            .into_response(),
+    }
+}
+
+async fn version() -> Json<Value> {
+    Json(json!({
+        "name": "dockim",
+        "version": env!("CARGO_PKG_VERSION"),
+        "routes": ["/clipboard", "/health", "/version"],
+    }))
+}
@@ -0,0 +1,103 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use itertools::chain;
+use miette::{bail, miette, Result, WrapErr};
+
+use crate::{
+    cli::{build, init, prompt, up, Args, BuildArgs, CloneArgs, InitArgs, Subcommand, UpArgs},
+    config::Config,
+    exec, log,
+    override_config::devcontainer_json_path,
+    state, trust,
+};
+
+pub fn main(config: &Config, args: &Args, clone_args: &CloneArgs) -> Result<()> {
+    let dir = match &clone_args.dir {
+        Some(dir) => dir.clone(),
+        None => PathBuf::from(repository_name(&clone_args.url)?),
+    };
+    if dir
+        .read_dir()
+        .is_ok_and(|mut entries| entries.next().is_some())
+    {
+        bail!(
+            help = "pass another directory as the second argument",
+            "{} already exists and is not empty",
+            dir.display()
+        );
+    }
+
+    let dir_str = dir.to_string_lossy();
+    let branch_args = clone_args
+        .branch
+        .iter()
+        .flat_map(|branch| ["--branch", branch.as_str()]);
+    let git_clone =
+        chain!(["git", "clone"], branch_args, [&*clone_args.url, &*dir_str]).collect::<Vec<_>>();
+    exec::exec(&git_clone).wrap_err_with(|| miette!("failed to clone {}", clone_args.url))?;
+
+    // The other subcommands work on the clone from here
+    let args = Args {
+        subcommand: Subcommand::Clone(clone_args.clone()),
+        workspace_folder: Some(dir.clone()),
+        variant: args.variant.clone(),
+        no_check: args.no_check,
+        config_overrides: args.config_overrides.clone(),
+    };
+
+    if devcontainer_json_path(&dir).is_none() {
+        log!("Initializing": "no devcontainer.json found in the repository");
+        let template_args = clone_args
+            .template
+            .iter()
+            .flat_map(|template| ["--template", template.as_str()]);
+        init::main(
+            config,
+            &args,
+            &InitArgs::parse_from(chain!(["init"], template_args)),
+        )?;
+    }
+
+    state::ensure_workspace_state_dir(&dir)?;
+    log!("Registered": "{}", dir.display());
+    if dir.join(trust::LOCAL_CONFIG_DIR).exists() {
+        log!("Hint": "the repository has local configuration in {}, which is ignored until you run `dockim trust` in it", trust::LOCAL_CONFIG_DIR);
+    }
+
+    // Building runs the repository's Dockerfile and features, so let the user look first
+    let prompt = format!("Build and start the devcontainer of {}?", clone_args.url);
+    if !clone_args.yes && !prompt::confirm(&prompt)? {
+        log!("Skipping": "review {}, then run `dockim up` in it", dir.display());
+        return Ok(());
+    }
+
+    up::main(config, &args, &UpArgs::parse_from(["up"]))?;
+    if clone_args.build {
+        build::main(config, &args, &BuildArgs::parse_from(["build"]))?;
+    }
+
+    log!("Ready": "cd {} && dockim neovim", dir.display());
+
+    Ok(())
+}
+
+/// Returns the directory `git clone` would create, e.g. `repo` for `git@host:user/repo.git`.
+fn repository_name(url: &str) -> Result<String> {
+    let url = url.trim_end_matches('/');
+    let name = url
+        .strip_suffix("/.git")
+        .unwrap_or(url)
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default();
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    if name.is_empty() {
+        bail!(
+            help = "pass the directory to clone into as the second argument",
+            "could not tell the repository name from `{url}`"
+        );
+    }
+
+    Ok(name.to_string())
+}
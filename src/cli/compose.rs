@@ -0,0 +1,31 @@
+use std::path::Path;
+
+use miette::Result;
+
+use crate::{
+    cli::{Args, ComposeArgs},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, log,
+    orchestrator::Orchestrator,
+};
+
+pub fn main(config: &Config, args: &Args, compose_args: &ComposeArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+
+    let mut project = dc.compose_project()?;
+    // Files generated by the devcontainer CLI may have been cleaned up from the temp directory
+    project.files.retain(|file| {
+        let exists = Path::new(file).exists();
+        if !exists {
+            log!("Skipping" ("missing compose file"): "{file}");
+        }
+        exists
+    });
+
+    let mut args = Orchestrator::detect()?.command();
+    args.extend(project.compose_args());
+    args.extend(compose_args.args.iter().cloned());
+
+    exec::exec(&args)
+}
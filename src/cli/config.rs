@@ -0,0 +1,582 @@
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+use miette::{bail, miette, IntoDiagnostic, LabeledSpan, NamedSource, Result, WrapErr};
+use serde_json::{Map, Value};
+use toml::{Table, Value as TomlValue};
+use toml_edit::DocumentMut;
+
+use crate::{
+    cli::{prompt, Args, ConfigArgs, ConfigCommand},
+    config::{self, Config, LocalConfig, CONFIG_SCHEMA_VERSION},
+    exec, jsonc, log, override_config, trust,
+};
+
+pub fn main(config: &Config, args: &Args, config_args: &ConfigArgs) -> Result<()> {
+    match &config_args.command {
+        ConfigCommand::Show { defaults, origin } => show(config, args, *defaults, *origin),
+        ConfigCommand::Get { key } => get(config, key),
+        ConfigCommand::Set { key, value } => set(key, value),
+        ConfigCommand::Edit { project } => edit(args, *project),
+        ConfigCommand::Migrate { dry_run } => migrate(*dry_run),
+        ConfigCommand::SyncVscode { settings, dry_run } => {
+            sync_vscode(config, args, *settings, *dry_run)
+        }
+    }
+}
+
+fn show(config: &Config, args: &Args, defaults: bool, origin: bool) -> Result<()> {
+    let config = if defaults {
+        Config::default()
+    } else {
+        config.clone()
+    };
+    let mut effective = to_table(&config)?;
+
+    if !origin {
+        print!("{}", toml::to_string_pretty(&effective).into_diagnostic()?);
+        return Ok(());
+    }
+
+    // Listed from the highest precedence down
+    let (file, project, env, cli) = if defaults {
+        (Table::new(), Table::new(), Table::new(), Table::new())
+    } else {
+        let workspace_folder = args.workspace_folder.clone().unwrap_or_else(|| ".".into());
+        let project = Table::try_from(LocalConfig::load(&workspace_folder)?).into_diagnostic()?;
+        let mut env = Table::new();
+        for (key, value) in config::env_overrides() {
+            config::set_key(&mut env, &key, config::parse_value(&value));
+        }
+        let mut cli = Table::new();
+        for arg in &args.config_overrides {
+            let (key, value) = config::parse_cli_override(arg)?;
+            config::set_key(&mut cli, &key, config::parse_value(&value));
+        }
+        (read_config_file()?, project, env, cli)
+    };
+
+    // The project's settings are applied where they are used, so merge them in here
+    let mut project_leaves = vec![];
+    flatten("", &project, &mut project_leaves);
+    for (key, value) in project_leaves {
+        if lookup(&env, &key).is_none() && lookup(&cli, &key).is_none() {
+            config::set_key(&mut effective, &key, value);
+        }
+    }
+
+    let mut leaves = vec![];
+    flatten("", &effective, &mut leaves);
+    for (key, value) in leaves {
+        let origin = if lookup(&cli, &key).is_some() {
+            "--config"
+        } else if lookup(&env, &key).is_some() {
+            "environment"
+        } else if lookup(&project, &key).is_some() {
+            "project config file"
+        } else if lookup(&file, &key).is_some() {
+            "global config file"
+        } else {
+            "default"
+        };
+        println!("{key} = {value}  # {origin}");
+    }
+
+    Ok(())
+}
+
+fn get(config: &Config, key: &str) -> Result<()> {
+    let effective = to_table(config)?;
+    let Some(value) = lookup(&effective, key) else {
+        if !is_known_key(config, key)? {
+            bail!(
+                help = "run `dockim config show` to list the available keys",
+                "unknown config key `{key}`"
+            );
+        }
+        log!("Unset" ("config"): "{key}");
+        return Ok(());
+    };
+
+    match value {
+        TomlValue::String(s) => println!("{s}"),
+        TomlValue::Table(table) => print!("{}", toml::to_string_pretty(table).into_diagnostic()?),
+        value => println!("{value}"),
+    }
+
+    Ok(())
+}
+
+/// Whether `key` is a setting, including the optional ones that are unset and so missing in TOML.
+fn is_known_key(config: &Config, key: &str) -> Result<bool> {
+    let mut value = &serde_json::to_value(config).into_diagnostic()?;
+    for part in key.split('.') {
+        match value.get(part) {
+            Some(next) => value = next,
+            None => return Ok(false),
+        }
+    }
+
+    Ok(true)
+}
+
+fn set(key: &str, value: &str) -> Result<()> {
+    let path = Config::config_file_path()?;
+    let original = if path.exists() {
+        fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display()))?
+    } else {
+        String::new()
+    };
+    // Edit the document itself so that comments and formatting are kept
+    let mut document: DocumentMut = original
+        .parse()
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to parse {}", path.display()))?;
+    if document.is_empty() {
+        document["schema_version"] = toml_edit::value(i64::from(CONFIG_SCHEMA_VERSION));
+    }
+
+    // Parsed by toml_edit itself so that inline tables and arrays are kept as written, falling
+    // back to a string like `config::parse_value`
+    let mut value = format!("value = {value}")
+        .parse::<DocumentMut>()
+        .ok()
+        .and_then(|mut document| document.remove("value"))
+        .and_then(|item| item.into_value().ok())
+        .unwrap_or_else(|| toml_edit::Value::from(value));
+    value.decor_mut().clear();
+    let (parents, name) = match key.rsplit_once('.') {
+        Some((parents, name)) => (parents.split('.').collect::<Vec<_>>(), name),
+        None => (vec![], key),
+    };
+    let mut item = document.as_item_mut();
+    for parent in parents {
+        if !item.get(parent).is_some_and(toml_edit::Item::is_table_like) {
+            item[parent] = toml_edit::table();
+        }
+        item = &mut item[parent];
+    }
+    item[name] = toml_edit::value(value.clone());
+    let contents = document.to_string();
+
+    // Reject unknown keys and wrong types before touching the file
+    let config: Config = toml::from_str(&contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("invalid value for `{key}`"))?;
+    if lookup(&to_table(&config)?, key).is_none() {
+        bail!(
+            help = "run `dockim config show` to list the available keys",
+            "unknown config key `{key}`"
+        );
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+    }
+    fs::write(&path, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    log!("Set" ("config"): "{key} = {value}");
+
+    Ok(())
+}
+
+fn edit(args: &Args, project: bool) -> Result<()> {
+    let workspace_folder = args.workspace_folder.clone().unwrap_or_else(|| ".".into());
+    let path = if project {
+        workspace_folder
+            .join(trust::LOCAL_CONFIG_DIR)
+            .join("config.toml")
+    } else {
+        Config::config_file_path()?
+    };
+    let original = if path.exists() {
+        fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display()))?
+    } else if project {
+        String::new()
+    } else {
+        format!("schema_version = {CONFIG_SCHEMA_VERSION}\n")
+    };
+    // A broken config file counts as the defaults
+    let before = match effective_settings(&path, &original, project) {
+        Ok(before) => before,
+        Err(_) if project => BTreeMap::new(),
+        Err(_) => {
+            let mut settings = vec![];
+            flatten("", &to_table(&Config::default())?, &mut settings);
+            settings.into_iter().collect()
+        }
+    };
+
+    // Edit a copy so that the config file only ever holds a valid config
+    let draft = tempfile::Builder::new()
+        .prefix("dockim-config-")
+        .suffix(".toml")
+        .tempfile()
+        .into_diagnostic()
+        .wrap_err("failed to create a draft of the config file")?;
+    let draft = draft.path();
+    let mut contents = original.clone();
+    let after = loop {
+        fs::write(draft, &contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", draft.display()))?;
+        exec::shell(&format!(
+            "{} {}",
+            editor(),
+            exec::shell_quote(&draft.to_string_lossy())
+        ))
+        .wrap_err("the editor failed; the config file is unchanged")?;
+        contents = fs::read_to_string(draft)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", draft.display()))?;
+
+        match effective_settings(&path, &contents, project) {
+            Ok(after) => break after,
+            Err(e) => {
+                eprintln!("{e:?}");
+                if !prompt::confirm("Edit it again?")? {
+                    bail!(
+                        "discarded the invalid config; {} is unchanged",
+                        path.display()
+                    );
+                }
+            }
+        }
+    };
+
+    if contents == original {
+        log!("Unchanged" ("config"): "{}", path.display());
+        return Ok(());
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+    }
+    fs::write(&path, &contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    print_changes(&before, &after);
+    log!("Saved" ("config"): "{}", path.display());
+    if project && !trust::is_trusted(&workspace_folder)? {
+        log!("Hint": "run `dockim trust` for the workspace to use it");
+    }
+
+    Ok(())
+}
+
+fn editor() -> String {
+    env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| if cfg!(windows) { "notepad" } else { "vi" }.to_string())
+}
+
+/// Parses a config file, pointing at the offending part of it on errors, and returns the settings
+/// it results in: all of them for the global config, the overrides for a project one.
+fn effective_settings(
+    path: &Path,
+    contents: &str,
+    project: bool,
+) -> Result<BTreeMap<String, TomlValue>> {
+    let located = |e: toml::de::Error| {
+        let labels = e
+            .span()
+            .map(|span| LabeledSpan::at(span, "here"))
+            .into_iter()
+            .collect::<Vec<_>>();
+        miette!(labels = labels, "{}", e.message().trim_end()).with_source_code(NamedSource::new(
+            path.display().to_string(),
+            contents.to_string(),
+        ))
+    };
+
+    let mut file: Table = toml::from_str(contents).map_err(located)?;
+    let mut settings = vec![];
+    if project {
+        toml::from_str::<LocalConfig>(contents).map_err(located)?;
+        flatten("", &file, &mut settings);
+        for (key, _) in &settings {
+            if !key.starts_with("cli.") {
+                log!("Warning": "`{key}` is ignored; only `cli` settings can be set per project");
+            }
+        }
+    } else {
+        // Deserialize the text itself where possible, since errors in a table have no location
+        let config: Config = if config::migrate(&mut file).is_empty() {
+            toml::from_str(contents).map_err(located)?
+        } else {
+            file.clone().try_into().into_diagnostic()?
+        };
+        let effective = to_table(&config)?;
+        let mut keys = vec![];
+        flatten("", &file, &mut keys);
+        for (key, _) in keys {
+            if lookup(&effective, &key).is_none() {
+                log!("Warning": "unknown key `{key}` is ignored");
+            }
+        }
+        flatten("", &effective, &mut settings);
+    }
+
+    Ok(settings.into_iter().collect())
+}
+
+fn print_changes(before: &BTreeMap<String, TomlValue>, after: &BTreeMap<String, TomlValue>) {
+    for (key, value) in before {
+        match after.get(key) {
+            None => println!("- {key} = {value}"),
+            Some(new) if new != value => println!("~ {key} = {value} -> {new}"),
+            Some(_) => {}
+        }
+    }
+    for (key, value) in after {
+        if !before.contains_key(key) {
+            println!("+ {key} = {value}");
+        }
+    }
+}
+
+fn migrate(dry_run: bool) -> Result<()> {
+    let path = Config::config_file_path()?;
+    if !path.exists() {
+        log!("Skipping" ("migrate"): "no config file at {}", path.display());
+        return Ok(());
+    }
+
+    let mut file = read_config_file()?;
+    let version = file
+        .get("schema_version")
+        .and_then(TomlValue::as_integer)
+        .unwrap_or(0);
+    if version > i64::from(CONFIG_SCHEMA_VERSION) {
+        bail!(
+            help = "upgrade dockim to use this config file",
+            "{} has schema version {version}, but this dockim only knows up to {CONFIG_SCHEMA_VERSION}",
+            path.display()
+        );
+    }
+    if version == i64::from(CONFIG_SCHEMA_VERSION) {
+        log!("Skipping" ("migrate"): "{} is already at schema version {version}", path.display());
+        return Ok(());
+    }
+
+    let changes = config::migrate(&mut file);
+    let migrated: Config = file
+        .clone()
+        .try_into()
+        .into_diagnostic()
+        .wrap_err("the migrated config is still invalid; fix it by hand")?;
+
+    let effective = to_table(&migrated)?;
+    let mut leaves = vec![];
+    flatten("", &file, &mut leaves);
+    let unknown = leaves
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| lookup(&effective, key).is_none())
+        .collect::<Vec<_>>();
+
+    let contents = toml::to_string_pretty(&file).into_diagnostic()?;
+    if dry_run {
+        print!("{contents}");
+    } else {
+        let original = fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+        let backup = path.with_extension(format!("v{version}.toml.bak"));
+        fs::write(&backup, &original)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", backup.display()))?;
+        if original
+            .lines()
+            .any(|line| line.trim_start().starts_with('#'))
+        {
+            log!("Warning": "comments in {} are not preserved", path.display());
+        }
+        fs::write(&path, contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+        log!("Backed up" ("config"): "{}", backup.display());
+    }
+
+    for change in &changes {
+        log!("Migrated": "{change}");
+    }
+    for key in &unknown {
+        log!("Warning": "unknown key `{key}` is ignored");
+    }
+    log!(
+        "Finished" ("migrate"):
+        "schema version {version} -> {CONFIG_SCHEMA_VERSION}, {} change(s){}",
+        changes.len(),
+        if dry_run { " (dry run)" } else { "" }
+    );
+
+    Ok(())
+}
+
+fn to_table(config: &Config) -> Result<Table> {
+    Table::try_from(config)
+        .into_diagnostic()
+        .wrap_err("failed to serialize config")
+}
+
+fn read_config_file() -> Result<Table> {
+    let path = Config::config_file_path()?;
+    if !path.exists() {
+        return Ok(Table::new());
+    }
+
+    let contents = fs::read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+
+    toml::from_str(&contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to parse {}", path.display()))
+}
+
+fn lookup<'a>(table: &'a Table, key: &str) -> Option<&'a TomlValue> {
+    let (first, rest) = match key.split_once('.') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (key, None),
+    };
+
+    match (table.get(first)?, rest) {
+        (value, None) => Some(value),
+        (TomlValue::Table(table), Some(rest)) => lookup(table, rest),
+        _ => None,
+    }
+}
+
+/// Collects the non-table values in `table` with their dotted keys.
+fn flatten(prefix: &str, table: &Table, leaves: &mut Vec<(String, TomlValue)>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match value {
+            TomlValue::Table(table) => flatten(&key, table, leaves),
+            value => leaves.push((key, value.clone())),
+        }
+    }
+}
+
+fn sync_vscode(config: &Config, args: &Args, sync_settings: bool, dry_run: bool) -> Result<()> {
+    let workspace_folder = args.workspace_folder.clone().unwrap_or_else(|| ".".into());
+    let (path, mut devcontainer_json) = override_config::read_devcontainer_json(&workspace_folder)?;
+
+    let extensions = match &config.vscode.extensions {
+        Some(extensions) => extensions.clone(),
+        None => host_extensions()?,
+    };
+    let host_settings = if sync_settings {
+        host_settings()?
+    } else {
+        Map::new()
+    };
+
+    let vscode = jsonc::object_entry(
+        jsonc::object_entry(&mut devcontainer_json, "customizations"),
+        "vscode",
+    );
+
+    let current = vscode
+        .get("extensions")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    let added = extensions
+        .iter()
+        .filter(|extension| {
+            !current.iter().any(|current| {
+                current
+                    .as_str()
+                    .is_some_and(|c| c.eq_ignore_ascii_case(extension))
+            })
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut merged = current;
+    merged.extend(added.iter().cloned().map(Value::String));
+    vscode.insert("extensions".to_string(), Value::Array(merged));
+
+    // Settings already in devcontainer.json are container-specific, so they take precedence
+    let settings = jsonc::object_entry(vscode, "settings");
+    let mut added_settings = 0;
+    for (key, value) in host_settings {
+        if !settings.contains_key(&key) {
+            settings.insert(key, value);
+            added_settings += 1;
+        }
+    }
+    if settings.is_empty() {
+        vscode.remove("settings");
+    }
+
+    // Only `customizations` is rewritten; comments and formatting elsewhere are kept
+    let original = fs::read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+    let contents = jsonc::update_top_level(&original, &devcontainer_json)
+        .wrap_err_with(|| miette!("failed to update {}", path.display()))?;
+    if dry_run {
+        print!("{contents}");
+        return Ok(());
+    }
+
+    fs::write(&path, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    for extension in &added {
+        log!("Added" ("extension"): "{extension}");
+    }
+    if added_settings > 0 {
+        log!("Added" ("settings"): "{added_settings} setting(s) from the host");
+    }
+    log!("Synced": "{}", path.display());
+
+    Ok(())
+}
+
+fn host_extensions() -> Result<Vec<String>> {
+    let code = if cfg!(windows) { "code.cmd" } else { "code" };
+    let output = exec::capturing_stdout(&[code, "--list-extensions"]).wrap_err(miette!(
+        help = "install the `code` command or set `vscode.extensions` in the config",
+        "failed to list VS Code extensions on the host"
+    ))?;
+
+    Ok(output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn host_settings() -> Result<Map<String, Value>> {
+    let path = dirs::config_dir()
+        .ok_or_else(|| miette!("could not find config directory"))?
+        .join("Code")
+        .join("User")
+        .join("settings.json");
+    let contents = fs::read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+
+    jsonc::from_str(&contents).wrap_err_with(|| miette!("failed to parse {}", path.display()))
+}
@@ -0,0 +1,55 @@
+use miette::{bail, IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    cli::{
+        ssh::{self, SshState, SSH_STATE_FILE},
+        Args, DescribeArgs, DescribeFormat,
+    },
+    config::Config,
+    devcontainer::DevContainer,
+    host_path, state,
+};
+
+pub fn main(config: &Config, args: &Args, describe_args: &DescribeArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+
+    match describe_args.format {
+        DescribeFormat::Nvim => {
+            let server = format!("localhost:{}", describe_args.host_port);
+            println!("{server}");
+            println!("nvim --server {server} --remote-ui");
+            println!(":lua vim.fn.sockconnect('tcp', '{server}', {{ rpc = true }})");
+        }
+        DescribeFormat::Vscode => {
+            let up_output = dc
+                .up_and_inspect()
+                .wrap_err("failed to get devcontainer status")?;
+            let local_folder = host_path::canonicalize(dc.workspace_folder())
+                .into_diagnostic()
+                .wrap_err("failed to resolve workspace folder")?;
+            let encoded_folder: String = local_folder
+                .to_string_lossy()
+                .bytes()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            let uri = format!(
+                "vscode-remote://dev-container+{encoded_folder}{}",
+                up_output.remote_workspace_folder
+            );
+            println!("{uri}");
+            println!("code --folder-uri {uri}");
+        }
+        DescribeFormat::Sshconfig => {
+            let ssh_state: Option<SshState> = state::load(dc.workspace_folder(), SSH_STATE_FILE)?;
+            let Some(ssh_state) = ssh_state else {
+                bail!(
+                    help = "run `dockim ssh enable` first",
+                    "ssh mode is not enabled for this workspace"
+                );
+            };
+            println!("{}", ssh::ssh_config_entry(&ssh_state));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use colored::Colorize;
+use itertools::Itertools;
+use miette::{Result, WrapErr};
+
+use crate::{
+    cli::{Args, DiffArgs},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, log,
+};
+
+/// A change reported by `docker diff`: `A`dded, `C`hanged or `D`eleted
+#[derive(Debug, Clone)]
+struct Change {
+    kind: char,
+    path: String,
+}
+
+pub fn main(config: &Config, args: &Args, diff_args: &DiffArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let up_output = dc.up_and_inspect()?;
+
+    let output = exec::capturing_stdout(&["docker", "diff", &up_output.container_id])
+        .wrap_err("failed to get changes of the container")?;
+    let changes = output
+        .lines()
+        .filter_map(|line| {
+            let (kind, path) = line.split_once(' ')?;
+            Some(Change {
+                kind: kind.chars().next()?,
+                path: path.to_string(),
+            })
+        })
+        .collect_vec();
+
+    // Parents of changed paths are reported as changed too, which says nothing new
+    let changes = changes
+        .iter()
+        .filter(|change| {
+            change.kind != 'C'
+                || !changes.iter().any(|other| {
+                    other
+                        .path
+                        .strip_prefix(&change.path)
+                        .is_some_and(|rest| rest.starts_with('/'))
+                })
+        })
+        .collect_vec();
+
+    let (ignored, changes): (Vec<_>, Vec<_>) = changes.into_iter().partition(|change| {
+        !diff_args.all
+            && config
+                .diff
+                .ignore
+                .iter()
+                .any(|pattern| glob_match(pattern, &change.path))
+    });
+
+    let groups = changes
+        .into_iter()
+        .into_group_map_by(|change| group_of(&change.path, diff_args.depth))
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b));
+    let mut any_outside = false;
+    for (group, changes) in groups {
+        let in_workspace = group == up_output.remote_workspace_folder
+            || group.starts_with(&format!("{}/", up_output.remote_workspace_folder));
+        let header = format!("{group} ({} change(s))", changes.len());
+        if in_workspace {
+            println!("{header}");
+        } else {
+            any_outside = true;
+            println!("{}", header.yellow());
+        }
+
+        if diff_args.summary {
+            continue;
+        }
+        for change in changes {
+            println!("  {} {}", change.kind, change.path);
+        }
+    }
+
+    if !ignored.is_empty() {
+        log!("Ignored": "{} change(s) matching `diff.ignore` (--all to show them)", ignored.len());
+    }
+    if any_outside {
+        log!("Hint": "directories in yellow are outside the workspace and are lost when the container is rebuilt");
+    }
+
+    Ok(())
+}
+
+/// Returns the first `depth` components of `path`, or its parent if it is shallower.
+fn group_of(path: &str, depth: usize) -> String {
+    let components = path.split('/').filter(|c| !c.is_empty()).collect_vec();
+    let depth = depth.min(components.len().saturating_sub(1)).max(1);
+
+    format!("/{}", components.iter().take(depth).join("/"))
+}
+
+/// Matches `path` against a glob where `*` and `?` stay within a path component and `**` spans
+/// any number of them.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.split('/').filter(|c| !c.is_empty()).collect_vec();
+    let path = path.split('/').filter(|c| !c.is_empty()).collect_vec();
+
+    match_components(&pattern, &path)
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(&"**"), _) => {
+            match_components(&pattern[1..], path)
+                || (!path.is_empty() && match_components(pattern, &path[1..]))
+        }
+        (Some(p), Some(c)) => {
+            match_component(p.as_bytes(), c.as_bytes())
+                && match_components(&pattern[1..], &path[1..])
+        }
+        _ => false,
+    }
+}
+
+fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            match_component(&pattern[1..], name)
+                || (!name.is_empty() && match_component(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => match_component(&pattern[1..], &name[1..]),
+        (Some(p), Some(c)) => p == c && match_component(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
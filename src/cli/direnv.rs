@@ -0,0 +1,278 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use serde_json::Value;
+
+use crate::{
+    cli::{Args, DirenvArgs, DirenvCommand},
+    config::Config,
+    exec, host_path, log, override_config, trust, variant,
+};
+
+/// First line of every shim after the shebang, telling generated files from the user's own
+const SHIM_MARKER: &str = "# Generated by `dockim direnv sync`";
+
+const ENVRC_BEGIN_MARKER: &str = "# dockim: begin";
+const ENVRC_END_MARKER: &str = "# dockim: end";
+
+/// Commands that get a shim when devcontainer.json has the feature of the same name, as in
+/// `ghcr.io/devcontainers/features/rust:1`
+const FEATURE_COMMANDS: &[(&str, &[&str])] = &[
+    ("rust", &["cargo", "rustc", "rustup"]),
+    ("node", &["node", "npm", "npx"]),
+    ("python", &["python", "python3", "pip", "pip3"]),
+    ("go", &["go"]),
+    ("java", &["java", "javac"]),
+    ("ruby", &["ruby", "gem", "bundle"]),
+    ("dotnet", &["dotnet"]),
+];
+
+pub fn main(config: &Config, args: &Args, direnv_args: &DirenvArgs) -> Result<()> {
+    let workspace_folder = args.workspace_folder.clone().unwrap_or_else(|| ".".into());
+    let workspace_folder = host_path::canonicalize(&workspace_folder)
+        .into_diagnostic()
+        .wrap_err("failed to resolve workspace folder")?;
+
+    match direnv_args.command {
+        DirenvCommand::Install => install(config, &workspace_folder),
+        DirenvCommand::Sync => sync(config, &workspace_folder),
+    }
+}
+
+fn install(config: &Config, workspace_folder: &Path) -> Result<()> {
+    // `.envrc` puts the directory on PATH, where a committed `ls` or `git` would shadow the host's
+    let bin_dir = workspace_folder.join(trust::LOCAL_CONFIG_DIR).join("bin");
+    let foreign = foreign_files(&bin_dir);
+    if !foreign.is_empty() {
+        bail!(
+            help = format!("review and remove them from {}", bin_dir.display()),
+            "refusing to put {} on PATH, which has files not generated by dockim: {}",
+            bin_dir.display(),
+            foreign.join(", ")
+        );
+    }
+
+    let envrc_path = workspace_folder.join(".envrc");
+    let current = if envrc_path.exists() {
+        fs::read_to_string(&envrc_path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", envrc_path.display()))?
+    } else {
+        String::new()
+    };
+
+    // Replace our previous block, if any
+    let mut lines = vec![];
+    let mut in_block = false;
+    for line in current.lines() {
+        if line == ENVRC_BEGIN_MARKER {
+            in_block = true;
+        } else if line == ENVRC_END_MARKER {
+            in_block = false;
+        } else if !in_block {
+            lines.push(line);
+        }
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() {
+        updated.push_str("\n\n");
+    }
+    updated.push_str(&envrc_block(workspace_folder));
+    updated.push('\n');
+    fs::write(&envrc_path, updated)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", envrc_path.display()))?;
+    log!("Updated": "{}", envrc_path.display());
+
+    sync(config, workspace_folder)?;
+    log!("Hint": "run `direnv allow` to load it");
+
+    Ok(())
+}
+
+/// Reloads on changes to devcontainer.json, keeping the shims in sync with its features.
+fn envrc_block(workspace_folder: &Path) -> String {
+    let dockim = match variant::selected() {
+        Some(name) => format!("dockim --variant {name}"),
+        None => "dockim".to_string(),
+    };
+    let mut lines = vec![ENVRC_BEGIN_MARKER.to_string()];
+    if let Some(config_path) = override_config::devcontainer_json_path(workspace_folder) {
+        let config_path = config_path
+            .strip_prefix(workspace_folder)
+            .unwrap_or(&config_path);
+        lines.push(format!(
+            "watch_file {}",
+            exec::shell_quote(&config_path.to_string_lossy())
+        ));
+    }
+    lines.push(format!("{dockim} direnv sync"));
+    lines.push(format!("PATH_add {}/bin", trust::LOCAL_CONFIG_DIR));
+    lines.push(ENVRC_END_MARKER.to_string());
+
+    lines.join("\n")
+}
+
+fn sync(config: &Config, workspace_folder: &Path) -> Result<()> {
+    let commands = shim_commands(config, workspace_folder)?;
+    let bin_dir = workspace_folder.join(trust::LOCAL_CONFIG_DIR).join("bin");
+    fs::create_dir_all(&bin_dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", bin_dir.display()))?;
+    let gitignore = bin_dir.join(".gitignore");
+    if !gitignore.exists() {
+        fs::write(&gitignore, "*\n")
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", gitignore.display()))?;
+    }
+
+    let mut removed = vec![];
+    for path in generated_shims(&bin_dir)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !commands.iter().any(|command| *command == name) {
+            fs::remove_file(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| miette!("failed to remove {}", path.display()))?;
+            removed.push(name.to_string());
+        }
+    }
+
+    let mut added = vec![];
+    for command in &commands {
+        let path = bin_dir.join(command);
+        let contents = shim(workspace_folder, command);
+        if fs::read_to_string(&path).ok().as_deref() == Some(&contents) {
+            continue;
+        }
+        if path.exists() && !is_generated(&path) {
+            log!("Skipping" ("shim"): "{} is not generated by dockim", path.display());
+            continue;
+        }
+        fs::write(&path, contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+                .into_diagnostic()
+                .wrap_err_with(|| miette!("failed to make {} executable", path.display()))?;
+        }
+        added.push(command.clone());
+    }
+
+    let foreign = foreign_files(&bin_dir);
+    if !foreign.is_empty() {
+        log!(
+            "Warning": "{} is on PATH and has files not generated by dockim, which shadow host commands: {}",
+            bin_dir.display(),
+            foreign.join(", ")
+        );
+    }
+
+    // Quiet when nothing changed, since `.envrc` runs this on every load
+    if !added.is_empty() {
+        log!("Added" ("shims"): "{}", added.join(" "));
+    }
+    if !removed.is_empty() {
+        log!("Removed" ("shims"): "{}", removed.join(" "));
+    }
+    if commands.is_empty() {
+        log!("Hint": "add toolchain features to devcontainer.json or set `direnv.shims` in the config");
+    }
+
+    Ok(())
+}
+
+/// The commands of the devcontainer's features, followed by the configured ones.
+fn shim_commands(config: &Config, workspace_folder: &Path) -> Result<Vec<String>> {
+    let (_, devcontainer_json) = override_config::read_devcontainer_json(workspace_folder)?;
+    let features = devcontainer_json
+        .get("features")
+        .and_then(Value::as_object)
+        .map(|features| features.keys().map(|id| feature_name(id)).collect_vec())
+        .unwrap_or_default();
+
+    let commands = FEATURE_COMMANDS
+        .iter()
+        .filter(|(feature, _)| features.contains(feature))
+        .flat_map(|(_, commands)| commands.iter().map(|command| command.to_string()))
+        .chain(config.direnv.shims.iter().cloned())
+        .unique()
+        .collect_vec();
+    for command in &commands {
+        let is_valid = !command.is_empty()
+            && !command.starts_with('.')
+            && command
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c));
+        if !is_valid {
+            bail!(
+                help = "set `direnv.shims` to command names, not paths",
+                "invalid shim name: {command}"
+            );
+        }
+    }
+
+    Ok(commands)
+}
+
+/// Returns `rust` for `ghcr.io/devcontainers/features/rust:1` and the like.
+fn feature_name(id: &str) -> &str {
+    let name = id.rsplit('/').next().unwrap_or(id);
+    name.split([':', '@']).next().unwrap_or(name)
+}
+
+fn shim(workspace_folder: &Path, command: &str) -> String {
+    let variant = variant::selected()
+        .map(|name| format!(" --variant {name}"))
+        .unwrap_or_default();
+
+    format!(
+        "#!/bin/sh\n{SHIM_MARKER}; runs `{command}` in the devcontainer\nexec dockim -w {}{variant} exec -- {command} \"$@\"\n",
+        exec::shell_quote(&workspace_folder.to_string_lossy())
+    )
+}
+
+fn generated_shims(bin_dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = fs::read_dir(bin_dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", bin_dir.display()))?;
+
+    Ok(entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_generated(path))
+        .collect())
+}
+
+/// Names of the files in `bin_dir` other than the shims and their `.gitignore`.
+fn foreign_files(bin_dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(bin_dir) else {
+        return vec![];
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name() != ".gitignore" && !is_generated(&entry.path()))
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .sorted()
+        .collect()
+}
+
+fn is_generated(path: &Path) -> bool {
+    fs::read_to_string(path).is_ok_and(|contents| {
+        contents
+            .lines()
+            .nth(1)
+            .is_some_and(|line| line.starts_with(SHIM_MARKER))
+    })
+}
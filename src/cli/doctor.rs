@@ -0,0 +1,250 @@
+use std::{fs, path::Path};
+
+use miette::{bail, Result};
+
+use crate::{
+    cli::{Args, DoctorArgs},
+    config::{Config, DockerAccess},
+    devcontainer::DevContainer,
+    display::DisplayServer,
+    exec, log, platform,
+    vm_provider::VmProvider,
+};
+
+pub fn main(config: &Config, args: &Args, doctor_args: &DoctorArgs) -> Result<()> {
+    let mut problems = 0;
+
+    if DevContainer::is_cli_installed() {
+        log!("Ok" ("doctor"): "devcontainer CLI is installed");
+    } else {
+        problems += 1;
+        log!("Problem" ("doctor"): "devcontainer CLI is not installed");
+        log!("Hint": "run `npm install -g @devcontainers/cli` to install it");
+    }
+
+    if exec::capturing_stdout(&["docker", "--version"]).is_ok() {
+        log!("Ok" ("doctor"): "Docker is available");
+    } else {
+        problems += 1;
+        log!("Problem" ("doctor"): "Docker is not installed or not running");
+        log!("Hint": "install or start Docker Desktop first");
+    }
+
+    problems += check_vm_provider();
+    problems += check_security_modules(config);
+    problems += check_display_server(config);
+    problems += check_docker_access(config, args);
+    problems += check_socat_image(config);
+    problems += check_emulation(config, args, doctor_args.fix_binfmt)?;
+
+    if problems > 0 {
+        bail!("{problems} problem(s) found");
+    }
+
+    Ok(())
+}
+
+/// Reports what runs Docker and the quirks dockim works around.
+fn check_vm_provider() -> usize {
+    let provider = VmProvider::detect();
+    log!("Ok" ("doctor"): "Docker runs on {}", provider.name());
+
+    match provider {
+        VmProvider::RancherDesktop if exec::capturing_stdout(&["rdctl", "version"]).is_err() => {
+            log!("Problem" ("doctor"): "rdctl is not available, so host.docker.internal can't be set up");
+            log!("Hint": "add Rancher Desktop's `rdctl` to PATH");
+            return 1;
+        }
+        VmProvider::PodmanMachine { rootless: true } => {
+            log!("Hint": "rootless Podman can't publish host ports below 1024");
+        }
+        VmProvider::Native { rootless: true } => {
+            log!("Hint": "rootless Docker forwards ports with one container per port, since its host network is not the host's");
+        }
+        _ => {}
+    }
+    if !provider.has_host_docker_internal() {
+        log!("Hint": "{} doesn't provide host.docker.internal; `dockim build` adds it to the container", provider.name());
+    }
+    if provider.forwards_ports_lazily() {
+        log!("Hint": "{} forwards published ports to the host with a short delay", provider.name());
+    }
+
+    0
+}
+
+/// Reports whether port forwarding has to pull its image first.
+fn check_socat_image(config: &Config) -> usize {
+    let image = &config.port.socat_image;
+    if DevContainer::is_image_available(image) {
+        log!("Ok" ("doctor"): "port-forwarding image {image} is available");
+    } else {
+        log!("Hint": "port-forwarding image {image} is pulled on the first forward; run `dockim port preload-image` to fetch it now");
+    }
+
+    0
+}
+
+/// Reports whether images for another architecture can run, and whether the devcontainer's does.
+fn check_emulation(config: &Config, args: &Args, fix_binfmt: bool) -> Result<usize> {
+    let Some(engine) = platform::engine_arch() else {
+        return Ok(0);
+    };
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let container = dc
+        .find_container_ids()
+        .ok()
+        .and_then(|ids| ids.first().and_then(|id| platform::container_arch(id)));
+    let mut problems = 0;
+    let foreign = match container {
+        Some(container) if container != engine => {
+            problems += 1;
+            log!("Problem" ("doctor"): "the devcontainer image is linux/{container} and runs emulated on linux/{engine}, which is much slower");
+            log!("Hint": "use an image published for linux/{engine}, or pin it with `\"runArgs\": [\"--platform=linux/{engine}\"]` in devcontainer.json");
+            container
+        }
+        _ => platform::foreign_arch(&engine).to_string(),
+    };
+
+    if platform::has_builtin_emulation() {
+        if fix_binfmt {
+            log!("Skipping" ("binfmt"): "{} sets up emulation itself", VmProvider::detect().name());
+        }
+        return Ok(problems);
+    }
+    let emulated = platform::emulated_archs();
+    if emulated
+        .as_ref()
+        .is_some_and(|archs| archs.contains(&foreign))
+    {
+        log!("Ok" ("doctor"): "linux/{foreign} images can run through QEMU");
+        return Ok(problems);
+    }
+
+    if !fix_binfmt {
+        if emulated.is_some() {
+            log!("Hint": "linux/{foreign} images can't run on this linux/{engine} engine; run `dockim doctor --fix-binfmt` to install QEMU binfmt handlers for them");
+        } else {
+            log!("Hint": "if linux/{foreign} images fail with `exec format error`, run `dockim doctor --fix-binfmt`");
+        }
+        return Ok(problems);
+    }
+
+    let command = platform::install_binfmt_command(&foreign);
+    log!("Running" ("binfmt"): "{}", command.join(" "));
+    if exec::exec(&command).is_ok() {
+        log!("Ok" ("doctor"): "installed the QEMU binfmt handler for linux/{foreign}");
+        log!("Hint": "the handler is lost on reboot unless your distribution's qemu-user-static package registers it");
+    } else {
+        problems += 1;
+        log!("Problem" ("doctor"): "failed to install the QEMU binfmt handler for linux/{foreign}");
+        log!("Hint": "install your distribution's qemu-user-static package instead");
+    }
+
+    Ok(problems)
+}
+
+/// Reports the Linux security modules which may deny access from the container.
+fn check_security_modules(config: &Config) -> usize {
+    let mut problems = 0;
+    let lsms = host_security_modules();
+
+    if lsms.iter().any(|lsm| lsm == "selinux") {
+        let mode = exec::capturing_stdout(&["getenforce"])
+            .map(|mode| mode.trim().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        if mode != "Enforcing" {
+            log!("Ok" ("doctor"): "SELinux is {mode}");
+        } else if config.container.selinux_relabel {
+            log!("Ok" ("doctor"): "SELinux is enforcing and the workspace mount is relabeled");
+        } else {
+            problems += 1;
+            log!("Problem" ("doctor"): "SELinux is enforcing but the workspace mount is not relabeled");
+            log!("Hint": "set `selinux_relabel = true` under `[container]` in the config and run `dockim up --rebuild`");
+        }
+    }
+
+    let apparmor_in_docker =
+        exec::capturing_stdout(&["docker", "info", "--format", "{{ json .SecurityOptions }}"])
+            .is_ok_and(|options| options.contains("apparmor"));
+    if lsms.iter().any(|lsm| lsm == "apparmor") || apparmor_in_docker {
+        if config.container.apparmor_unconfined {
+            log!("Ok" ("doctor"): "AppArmor is active and the container runs unconfined");
+        } else {
+            log!("Ok" ("doctor"): "AppArmor is active");
+            log!("Hint": "if commands in the container hit AppArmor denials, set `apparmor_unconfined = true` under `[container]` in the config");
+        }
+    }
+
+    problems
+}
+
+/// Reports the display server GUI apps in the container would use.
+fn check_display_server(config: &Config) -> usize {
+    match DisplayServer::detect() {
+        Some(display_server) => {
+            log!("Ok" ("doctor"): "display server found: {}", display_server.name());
+            if display_server == DisplayServer::RemoteX11 {
+                log!("Hint": "GUI apps connect over TCP; allow network clients in {}", display_server.name());
+            }
+            0
+        }
+        None if config.container.gui => {
+            log!("Problem" ("doctor"): "GUI forwarding is enabled but no display server was found");
+            log!("Hint": "set DISPLAY or WAYLAND_DISPLAY, or disable `gui` under `[container]` in the config");
+            1
+        }
+        None => {
+            log!("Ok" ("doctor"): "no display server found; `dockim up --gui` won't work");
+            0
+        }
+    }
+}
+
+/// Reports whether `docker ps` works in the container when it should.
+fn check_docker_access(config: &Config, args: &Args) -> usize {
+    if config.container.docker_access == DockerAccess::None {
+        return 0;
+    }
+
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    if dc.container_status().ok().flatten().as_deref() != Some("running") {
+        log!("Ok" ("doctor"): "devcontainer is not running; Docker access in it was not checked");
+        return 0;
+    }
+
+    match dc.exec_capturing_stdout(&["docker", "ps", "--quiet"]) {
+        Ok(_) => {
+            log!("Ok" ("doctor"): "`docker ps` works in the container");
+            0
+        }
+        Err(_) => {
+            log!("Problem" ("doctor"): "`docker ps` fails in the container");
+            match config.container.docker_access {
+                DockerAccess::Socket => {
+                    log!("Hint": "make sure the image has the docker CLI, then run `dockim up --rebuild`");
+                }
+                _ => log!("Hint": "run `dockim up --rebuild` to add the docker-in-docker feature"),
+            }
+            1
+        }
+    }
+}
+
+fn host_security_modules() -> Vec<String> {
+    if let Ok(lsm) = fs::read_to_string("/sys/kernel/security/lsm") {
+        return lsm.trim().split(',').map(str::to_string).collect();
+    }
+
+    // securityfs is not always mounted, so fall back to the module-specific interfaces
+    let mut lsms = vec![];
+    if Path::new("/sys/fs/selinux/enforce").exists() {
+        lsms.push("selinux".to_string());
+    }
+    if fs::read_to_string("/sys/module/apparmor/parameters/enabled").is_ok_and(|s| s.trim() == "Y")
+    {
+        lsms.push("apparmor".to_string());
+    }
+
+    lsms
+}
@@ -0,0 +1,90 @@
+use itertools::Itertools;
+use miette::{Result, WrapErr};
+
+use crate::{
+    cli::{prompt, ssh, stop, Args, DownArgs},
+    config::Config,
+    devcontainer::DevContainer,
+    log, network, remote, shared_services, state,
+};
+
+pub fn main(config: &Config, args: &Args, down_args: &DownArgs) -> Result<()> {
+    if down_args.all {
+        down_all(config, down_args.yes)?;
+    } else {
+        let dc = DevContainer::new(args.workspace_folder.clone(), config);
+        let _lock = dc.lock()?;
+
+        if down_args.force {
+            // The entry would point at a key that goes away with the workspace state
+            ssh::remove_ssh_config(&dc)?;
+            dc.force_down()?;
+        } else {
+            stop::shut_down_gracefully(config, &dc)?;
+            dc.down()?;
+        }
+    }
+
+    // The connection to a remote host stays open for forwards until no devcontainer runs there
+    if let Some(ssh_host) = &config.runtime.ssh_host {
+        let in_use = DevContainer::list_all()?
+            .iter()
+            .any(|container| container.state == "running");
+        if !in_use {
+            remote::disconnect(ssh_host)?;
+        }
+    }
+
+    match &config.services.shared {
+        Some(shared) if down_args.with_shared => shared_services::stop(shared)?,
+        Some(shared) => {
+            log!("Keeping" ("shared services"): "{} (use --with-shared to stop it)", shared.project)
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Removes the devcontainers of all workspaces, found by dockim's labels.
+fn down_all(config: &Config, yes: bool) -> Result<()> {
+    let containers = DevContainer::list_all()?;
+    if containers.is_empty() {
+        log!("Skipping": "no devcontainer found");
+        return Ok(());
+    }
+
+    for container in &containers {
+        log!("Found": "{} ({})", container.name, container.workspace_folder.display());
+    }
+    if !yes && !prompt::confirm(&format!("Remove these {} container(s)?", containers.len()))? {
+        log!("Skipping": "nothing was removed");
+        return Ok(());
+    }
+
+    let by_workspace = containers
+        .iter()
+        .into_group_map_by(|container| container.workspace_folder.clone());
+    for (workspace_folder, containers) in by_workspace {
+        // Workspaces which no longer exist can't be locked, nor in use by dockim
+        let dc = DevContainer::new(Some(workspace_folder.clone()), config);
+        let _lock = workspace_folder.exists().then(|| dc.lock()).transpose()?;
+        for container in containers {
+            log!("Removing": "{} ({})", container.name, container.workspace_folder.display());
+            DevContainer::remove_container(&container.id)?;
+        }
+    }
+
+    // Workspaces which no longer exist are left to `dockim gc`
+    let workspace_folders = containers
+        .iter()
+        .map(|container| &container.workspace_folder)
+        .unique()
+        .filter(|workspace_folder| workspace_folder.exists());
+    for workspace_folder in workspace_folders {
+        network::remove_restricted(workspace_folder)?;
+        state::clear(workspace_folder).wrap_err("failed to clear workspace state")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+
+use miette::{bail, miette, Result, WrapErr};
+
+use crate::{
+    cli::{Args, EnvArgs, EnvFormat},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, log,
+};
+
+/// Separates the environment before and after the command in the captured output
+const MARKER: &str = "--dockim-env--";
+
+/// Variables the shell changes by itself
+const IGNORED: &[&str] = &["_", "PWD", "OLDPWD", "SHLVL"];
+
+pub fn main(config: &Config, args: &Args, env_args: &EnvArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+
+    if env_args.clear {
+        dc.save_env(&BTreeMap::new())?;
+        log!("Cleared" ("env"): "saved environment variables");
+        return Ok(());
+    }
+
+    let Some(command) = &env_args.export else {
+        // Without a command, print what later execs get
+        for (key, value) in dc.saved_env()? {
+            println!("{}", export(env_args.format, &key, Some(&value)));
+        }
+        return Ok(());
+    };
+
+    dc.ensure_up()?;
+    let changes = capture(&dc, command)?;
+    for (key, value) in &changes {
+        println!("{}", export(env_args.format, key, value.as_deref()));
+    }
+
+    if env_args.save {
+        let mut saved = dc.saved_env()?;
+        for (key, value) in &changes {
+            match value {
+                Some(value) => {
+                    saved.insert(key.clone(), value.clone());
+                }
+                None => {
+                    saved.remove(key);
+                }
+            }
+        }
+        dc.save_env(&saved)?;
+        log!("Saved" ("env"): "{} variable(s) for later `dockim exec`, `shell` and `bash`", changes.len());
+        if changes.values().any(Option::is_none) {
+            log!("Hint": "unset variables are not unset in later execs, only no longer set by dockim");
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `command` in a shell on the container and returns the variables it set (`Some`) or unset
+/// (`None`).
+fn capture(dc: &DevContainer, command: &str) -> Result<BTreeMap<String, Option<String>>> {
+    // The command's own output goes to stderr so that it doesn't mix with the environment
+    let script =
+        format!("env -0; printf '\\0{MARKER}\\0'; {{ {command}\n}} >&2 || exit $?; env -0");
+    let output = dc
+        .exec_capturing_stdout_bytes(&[
+            "sh",
+            "-c",
+            "if command -v bash >/dev/null; then exec bash -c \"$1\"; else exec sh -c \"$1\"; fi",
+            "sh",
+            &script,
+        ])
+        .wrap_err_with(|| miette!("`{command}` failed on the container"))?;
+    let output = String::from_utf8_lossy(&output);
+
+    let Some((before, after)) = output.split_once(&format!("\0{MARKER}\0")) else {
+        bail!("failed to capture the environment of `{command}`");
+    };
+    let before = parse_env(before);
+    let after = parse_env(after);
+
+    let mut changes = BTreeMap::new();
+    for (key, value) in &after {
+        if before.get(key) != Some(value) {
+            changes.insert(key.to_string(), Some(value.to_string()));
+        }
+    }
+    for key in before.keys().filter(|key| !after.contains_key(*key)) {
+        changes.insert(key.to_string(), None);
+    }
+    changes.retain(|key, _| !IGNORED.contains(&key.as_str()));
+
+    Ok(changes)
+}
+
+fn parse_env(env: &str) -> BTreeMap<&str, &str> {
+    env.split('\0')
+        .filter_map(|entry| entry.split_once('='))
+        .collect()
+}
+
+fn export(format: EnvFormat, key: &str, value: Option<&str>) -> String {
+    match (format, value) {
+        (EnvFormat::Sh, Some(value)) => format!("export {key}={}", exec::shell_quote(value)),
+        (EnvFormat::Sh, None) => format!("unset {key}"),
+        (EnvFormat::Fish, Some(value)) => format!(
+            "set -gx {key} '{}'",
+            value.replace('\\', "\\\\").replace('\'', "\\'")
+        ),
+        (EnvFormat::Fish, None) => format!("set -e {key}"),
+        (EnvFormat::Powershell, Some(value)) => {
+            format!("$env:{key} = '{}'", value.replace('\'', "''"))
+        }
+        (EnvFormat::Powershell, None) => format!("Remove-Item Env:{key} -ErrorAction Ignore"),
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+};
+
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use serde::Deserialize;
+
+use crate::{
+    cli::{Args, EventsArgs},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, host_path, log,
+};
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    #[serde(rename = "Action")]
+    action: String,
+
+    #[serde(rename = "Actor")]
+    actor: Actor,
+}
+
+#[derive(Debug, Deserialize)]
+struct Actor {
+    #[serde(rename = "ID")]
+    id: String,
+
+    #[serde(rename = "Attributes", default)]
+    attributes: HashMap<String, String>,
+}
+
+pub fn main(config: &Config, args: &Args, events_args: &EventsArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+
+    let local_folder = host_path::canonicalize(dc.workspace_folder())
+        .into_diagnostic()
+        .wrap_err("failed to resolve workspace folder")?;
+    let label_filter = format!("label=devcontainer.local_folder={}", local_folder.display());
+
+    let mut docker_events = exec::spawn_capturing_stdout(&[
+        "docker",
+        "events",
+        "--format",
+        "{{json .}}",
+        "--filter",
+        "type=container",
+        "--filter",
+        &label_filter,
+    ])?;
+    let stdout = docker_events
+        .stdout
+        .take()
+        .ok_or_else(|| miette!("failed to read docker events"))?;
+
+    log!("Watching": "container events of {}", local_folder.display());
+
+    for line in BufReader::new(stdout).lines() {
+        let line = line
+            .into_diagnostic()
+            .wrap_err("failed to read docker events")?;
+        let Ok(event) = serde_json::from_str::<Event>(&line) else {
+            continue;
+        };
+
+        let Some(message) = describe(&event) else {
+            continue;
+        };
+        let name = event
+            .actor
+            .attributes
+            .get("name")
+            .cloned()
+            .unwrap_or_else(|| event.actor.id.chars().take(12).collect());
+        log!("Event": "{name}: {message}");
+
+        let action = event_kind(&event.action);
+        if events_args.reconcile_forwards && action == "start" {
+            match dc.reconcile_forwards() {
+                Ok(summary) => log!(
+                    "Reconciled" ("port forwards"):
+                    "{} restored, {} dropped, {} unchanged",
+                    summary.restored,
+                    summary.dropped,
+                    summary.unchanged
+                ),
+                Err(e) => log!("Warning": "failed to reconcile port forwards: {e}"),
+            }
+        }
+
+        if let Some(hook) = config.events.hooks.get(action) {
+            if let Err(e) = exec::shell(hook) {
+                log!("Warning": "hook for `{action}` failed: {e}");
+            }
+        }
+    }
+
+    docker_events.wait().into_diagnostic()?;
+
+    Ok(())
+}
+
+/// Strips the detail from actions such as `health_status: healthy` or `exec_start: sh`.
+fn event_kind(action: &str) -> &str {
+    action.split(':').next().unwrap_or(action).trim()
+}
+
+fn describe(event: &Event) -> Option<String> {
+    let message = match event_kind(&event.action) {
+        "create" => "created".to_string(),
+        "start" => "started".to_string(),
+        "restart" => "restarted".to_string(),
+        "stop" => "stopped".to_string(),
+        "pause" => "paused".to_string(),
+        "unpause" => "resumed".to_string(),
+        "destroy" => "removed".to_string(),
+        "oom" => "killed: out of memory".to_string(),
+        "die" => match event.actor.attributes.get("exitCode") {
+            Some(exit_code) => format!("exited with code {exit_code}"),
+            None => "exited".to_string(),
+        },
+        "health_status" => format!(
+            "health status changed to {}",
+            event.action.split(':').nth(1).unwrap_or("unknown").trim()
+        ),
+        _ => return None,
+    };
+
+    Some(message)
+}
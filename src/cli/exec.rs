@@ -1,14 +1,58 @@
+use std::process;
+
 use crate::{
-    cli::{Args, ExecArgs},
+    cli::{jobs, Args, ExecArgs},
     config::Config,
     devcontainer::DevContainer,
+    git_credentials, log,
+    recording::Recording,
 };
 use miette::{miette, Result, WrapErr};
 
-pub fn main(_config: &Config, args: &Args, exec_args: &ExecArgs) -> Result<()> {
-    let dc = DevContainer::new(args.workspace_folder.clone());
+pub fn main(config: &Config, args: &Args, exec_args: &ExecArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config)
+        .with_user(exec_args.user.clone())
+        .with_no_up(exec_args.no_up)
+        .with_saved_env()?;
+    // Wait for a concurrent `up` instead of racing it, but don't block others while attached
+    let lock = dc.lock()?;
+    dc.ensure_up()?;
+    drop(lock);
+    git_credentials::spawn_bridge(config, &dc);
+    let workdir = dc.current_dir_in_container()?;
+    let dc = dc.with_workdir(workdir);
+
+    let recording = exec_args
+        .record
+        .as_deref()
+        .map(|path| Recording::prepare(&dc, path))
+        .transpose()?;
+    let command = Recording::wrap_if(recording.as_ref(), &exec_args.args);
+
+    if exec_args.interactive {
+        let status = dc.exec_interactive(&command);
+        if let Some(recording) = recording {
+            recording.finish(&dc)?;
+        }
+        let status = status?;
+        if !status.success() {
+            process::exit(status.code().unwrap_or(1));
+        }
+
+        return Ok(());
+    }
+
+    if exec_args.detach {
+        let job = jobs::start(&dc, &exec_args.args)?;
+        log!("Started" ("job"): "{} (pid {}), output goes to {} on the container", job.id, job.pid, job.log);
+        return Ok(());
+    }
 
-    dc.exec(&exec_args.args).wrap_err(miette!(
+    let result = dc.exec(&command);
+    if let Some(recording) = recording {
+        recording.finish(&dc)?;
+    }
+    result.wrap_err(miette!(
         help = "try `dockim build --rebuild` first",
         "failed to execute `{:?}` on the container",
         exec_args.args,
@@ -0,0 +1,295 @@
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    cli::{Args, GcArgs, GcCommand},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, log, state,
+};
+
+pub fn main(config: &Config, _args: &Args, gc_args: &GcArgs) -> Result<()> {
+    if let Some(GcCommand::InstallSchedule) = gc_args.command {
+        return install_schedule();
+    }
+
+    let max_age = Duration::from_secs(config.gc.max_age_days * 24 * 60 * 60);
+    let mut removed = 0;
+
+    for container_id in exited_devcontainers(max_age, gc_args.dry_run)? {
+        removed += 1;
+        remove_container(&container_id, "exited devcontainer", gc_args.dry_run)?;
+    }
+
+    for container_id in orphaned_socat_containers()? {
+        removed += 1;
+        remove_container(
+            &container_id,
+            "orphaned port-forwarding container",
+            gc_args.dry_run,
+        )?;
+    }
+
+    for dir in state::workspace_state_dirs()? {
+        let is_stale = match state::recorded_workspace_folder(&dir) {
+            Some(workspace_folder) => !workspace_folder.exists(),
+            None => true,
+        };
+        if !is_stale {
+            continue;
+        }
+
+        removed += 1;
+        log!("Removing" ("stale state"): "{}", dir.display());
+        if !gc_args.dry_run {
+            fs::remove_dir_all(&dir)
+                .into_diagnostic()
+                .wrap_err_with(|| miette!("failed to remove {}", dir.display()))?;
+        }
+    }
+
+    if removed == 0 {
+        log!("Clean": "nothing to remove");
+    }
+
+    Ok(())
+}
+
+fn remove_container(container_id: &str, kind: &str, dry_run: bool) -> Result<()> {
+    log!("Removing": "{kind} {container_id}");
+    if dry_run {
+        return Ok(());
+    }
+
+    exec::exec(&["docker", "rm", "-fv", container_id])
+        .wrap_err_with(|| miette!("failed to remove {kind} {container_id}"))
+}
+
+/// Finds exited devcontainers which stopped more than `max_age` ago. A missing workspace alone
+/// is no reason, since it may be on a drive or share that is just not mounted.
+fn exited_devcontainers(max_age: Duration, dry_run: bool) -> Result<Vec<String>> {
+    let containers = DevContainer::list_all()?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .into_diagnostic()?
+        .as_secs();
+
+    let mut expired = vec![];
+    for container in containers.iter().filter(|c| c.state == "exited") {
+        let container_id = container.id.as_str();
+        let finished_at = exec::capturing_stdout(&[
+            "docker",
+            "inspect",
+            "--format",
+            "{{.State.FinishedAt}}",
+            container_id,
+        ])
+        .wrap_err("failed to inspect devcontainer")?;
+
+        let Some(finished_at) = parse_docker_timestamp(finished_at.trim()) else {
+            continue;
+        };
+        if now.saturating_sub(finished_at) >= max_age.as_secs() {
+            expired.push(container_id.to_string());
+        } else if dry_run && !container.workspace_folder.exists() {
+            log!(
+                "Keeping" ("exited devcontainer"): "{container_id}, whose workspace {} is missing, until it has been stopped for {} day(s)",
+                container.workspace_folder.display(),
+                max_age.as_secs() / (24 * 60 * 60)
+            );
+        }
+    }
+
+    Ok(expired)
+}
+
+/// Finds port-forwarding containers whose devcontainer no longer exists.
+fn orphaned_socat_containers() -> Result<Vec<String>> {
+    let all_containers = exec::capturing_stdout(&["docker", "ps", "-aq", "--no-trunc"])
+        .wrap_err("failed to enumerate containers")?;
+    let all_containers: HashSet<_> = all_containers.split_whitespace().collect();
+
+    let socat_containers = exec::capturing_stdout(&[
+        "docker",
+        "ps",
+        "-a",
+        "--filter",
+        "name=dockim-",
+        "--format",
+        "{{.ID}} {{.Names}}",
+    ])
+    .wrap_err("failed to enumerate port-forwarding containers")?;
+
+    Ok(socat_containers
+        .lines()
+        .filter_map(|line| {
+            let (id, name) = line.split_once(' ')?;
+            let name = name.strip_prefix("dockim-")?;
+            let devcontainer_id = match name.split_once("-socat-") {
+                Some((devcontainer_id, _)) => devcontainer_id,
+                // The multiplexer of the devcontainer's forwards
+                None => name.strip_suffix("-socat")?,
+            };
+            (!all_containers.contains(devcontainer_id)).then(|| id.to_string())
+        })
+        .collect())
+}
+
+/// Parses timestamps such as `2024-01-02T03:04:05.123456789Z` into seconds since the Unix epoch.
+fn parse_docker_timestamp(s: &str) -> Option<u64> {
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.split('-').map(|part| part.parse::<i64>());
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let time = time.trim_end_matches('Z');
+    let time = time.split(['.', '+']).next()?;
+    let mut time = time.split(':').map(|part| part.parse::<i64>());
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60 + second).ok()
+}
+
+const SYSTEMD_SERVICE: &str = "dockim-gc.service";
+pub const SYSTEMD_TIMER: &str = "dockim-gc.timer";
+
+pub fn launchd_plist_path() -> Result<PathBuf> {
+    Ok(dirs::home_dir()
+        .ok_or_else(|| miette!("failed to get local home directory"))?
+        .join("Library")
+        .join("LaunchAgents")
+        .join("dev.dockim.gc.plist"))
+}
+
+pub fn systemd_units_dir() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .ok_or_else(|| miette!("could not find config directory"))?
+        .join("systemd")
+        .join("user"))
+}
+
+/// The files `gc install-schedule` writes on this platform.
+pub fn schedule_files() -> Result<Vec<PathBuf>> {
+    if cfg!(target_os = "macos") {
+        Ok(vec![launchd_plist_path()?])
+    } else {
+        let units_dir = systemd_units_dir()?;
+        Ok(vec![
+            units_dir.join(SYSTEMD_SERVICE),
+            units_dir.join(SYSTEMD_TIMER),
+        ])
+    }
+}
+
+fn install_schedule() -> Result<()> {
+    let exe = env::current_exe()
+        .into_diagnostic()
+        .wrap_err("failed to locate dockim executable")?;
+    let exe = exe.to_string_lossy();
+
+    if cfg!(target_os = "macos") {
+        let plist_path = launchd_plist_path()?;
+        if let Some(agents_dir) = plist_path.parent() {
+            fs::create_dir_all(agents_dir).into_diagnostic()?;
+        }
+
+        let plist = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>dev.dockim.gc</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{exe}</string>
+        <string>gc</string>
+    </array>
+    <key>StartInterval</key>
+    <integer>86400</integer>
+</dict>
+</plist>
+"#
+        );
+        fs::write(&plist_path, plist)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", plist_path.display()))?;
+
+        exec::exec(&["launchctl", "load", "-w", &plist_path.to_string_lossy()])
+            .wrap_err("failed to load launchd agent")?;
+    } else if cfg!(target_os = "linux") {
+        let units_dir = systemd_units_dir()?;
+        fs::create_dir_all(&units_dir).into_diagnostic()?;
+
+        // Quoted, so that a path with spaces stays one argument
+        let exec_start = exe
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%");
+        let service = format!(
+            "[Unit]\nDescription=Clean up dead dockim resources\n\n[Service]\nType=oneshot\nExecStart=\"{exec_start}\" gc\n"
+        );
+        let timer = "[Unit]\nDescription=Clean up dead dockim resources daily\n\n[Timer]\nOnCalendar=daily\nPersistent=true\n\n[Install]\nWantedBy=timers.target\n";
+        for (name, contents) in [(SYSTEMD_SERVICE, &*service), (SYSTEMD_TIMER, timer)] {
+            let path = units_dir.join(name);
+            fs::write(&path, contents)
+                .into_diagnostic()
+                .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+        }
+
+        exec::exec(&["systemctl", "--user", "daemon-reload"])?;
+        exec::exec(&["systemctl", "--user", "enable", "--now", "dockim-gc.timer"])
+            .wrap_err("failed to enable systemd timer")?;
+    } else {
+        bail!("scheduling is only supported with systemd or launchd");
+    }
+
+    log!("Installed": "daily `dockim gc` schedule");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_docker_timestamp_reads_utc_times() {
+        assert_eq!(parse_docker_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_docker_timestamp("2024-01-02T03:04:05Z"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(
+            parse_docker_timestamp("2024-01-02T03:04:05.123456789Z"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(
+            parse_docker_timestamp("2024-02-29T00:00:00Z"),
+            Some(1_709_164_800)
+        );
+    }
+
+    #[test]
+    fn parse_docker_timestamp_rejects_unset_and_malformed_times() {
+        // What docker reports for a container that never finished
+        assert_eq!(parse_docker_timestamp("0001-01-01T00:00:00Z"), None);
+        assert_eq!(parse_docker_timestamp(""), None);
+        assert_eq!(parse_docker_timestamp("2024-01-02"), None);
+        assert_eq!(parse_docker_timestamp("2024-01-02T03:04Z"), None);
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use serde_json::{json, Value};
+
+use crate::{
+    cli::{Args, InitArgs},
+    config::Config,
+    exec, host_path, log,
+    override_config::WORKSPACE_ENV_FILE,
+    platform,
+    scripting::Scripts,
+    trust::LOCAL_CONFIG_DIR,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Template {
+    Base,
+    Rust,
+    Node,
+    Python,
+    Go,
+}
+
+impl Template {
+    const ALL: &'static [Template] = &[
+        Template::Base,
+        Template::Rust,
+        Template::Node,
+        Template::Python,
+        Template::Go,
+    ];
+
+    /// Templates detected by the presence of a manifest file in the workspace
+    const DETECTABLE: &'static [(Template, &'static str)] = &[
+        (Template::Rust, "Cargo.toml"),
+        (Template::Node, "package.json"),
+        (Template::Python, "pyproject.toml"),
+        (Template::Go, "go.mod"),
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Template::Base => "base",
+            Template::Rust => "rust",
+            Template::Node => "node",
+            Template::Python => "python",
+            Template::Go => "go",
+        }
+    }
+
+    fn devcontainer_json(self, workspace_name: &str) -> Value {
+        // Keep build outputs and caches in volumes, which are much faster than bind mounts on
+        // Docker Desktop. Per-workspace volumes are keyed by the devcontainer ID, which unlike the
+        // folder name is unique and always a valid volume name.
+        let (image, extensions, mounts): (&str, &[&str], Vec<String>) = match self {
+            Template::Base => ("mcr.microsoft.com/devcontainers/base:ubuntu", &[], vec![]),
+            Template::Rust => (
+                "mcr.microsoft.com/devcontainers/rust:1",
+                &["rust-lang.rust-analyzer", "tamasfe.even-better-toml"],
+                vec![
+                    volume_mount("cargo-registry", "/usr/local/cargo/registry"),
+                    volume_mount(
+                        "${devcontainerId}-target",
+                        "${containerWorkspaceFolder}/target",
+                    ),
+                ],
+            ),
+            Template::Node => (
+                "mcr.microsoft.com/devcontainers/javascript-node:20",
+                &["dbaeumer.vscode-eslint", "esbenp.prettier-vscode"],
+                vec![volume_mount(
+                    "${devcontainerId}-node-modules",
+                    "${containerWorkspaceFolder}/node_modules",
+                )],
+            ),
+            Template::Python => (
+                "mcr.microsoft.com/devcontainers/python:3",
+                &["ms-python.python"],
+                vec![volume_mount("pip-cache", "/home/vscode/.cache/pip")],
+            ),
+            Template::Go => (
+                "mcr.microsoft.com/devcontainers/go:1",
+                &["golang.go"],
+                vec![volume_mount("go-mod-cache", "/go/pkg/mod")],
+            ),
+        };
+
+        let mut config = json!({
+            "name": workspace_name,
+            "image": image,
+        });
+        if !mounts.is_empty() {
+            // New volumes belong to root, so hand them to the user who builds and installs into them
+            let targets = mounts
+                .iter()
+                .filter_map(|mount| {
+                    mount
+                        .split(',')
+                        .find_map(|part| part.strip_prefix("target="))
+                })
+                .map(|target| format!("\"{target}\""))
+                .join(" ");
+            config["mounts"] = json!(mounts);
+            config["postCreateCommand"] = json!(format!("sudo chown $(id -u):$(id -g) {targets}"));
+        }
+        if !extensions.is_empty() {
+            config["customizations"] = json!({ "vscode": { "extensions": extensions } });
+        }
+
+        config
+    }
+
+    /// .editorconfig sections for the sources of the template's language
+    fn editorconfig_sections(self) -> &'static [(&'static str, &'static [&'static str])] {
+        match self {
+            Template::Base => &[],
+            Template::Rust => &[("*.rs", &["indent_style = space", "indent_size = 4"])],
+            Template::Node => &[(
+                "*.{js,jsx,ts,tsx,mjs,cjs}",
+                &["indent_style = space", "indent_size = 2"],
+            )],
+            Template::Python => &[("*.py", &["indent_style = space", "indent_size = 4"])],
+            Template::Go => &[("*.go", &["indent_style = tab"])],
+        }
+    }
+}
+
+/// .editorconfig sections for every workspace. LF line endings keep scripts runnable in the
+/// container when the workspace is checked out on Windows.
+const COMMON_EDITORCONFIG_SECTIONS: &[(&str, &[&str])] = &[
+    (
+        "*",
+        &[
+            "charset = utf-8",
+            "end_of_line = lf",
+            "insert_final_newline = true",
+            "trim_trailing_whitespace = true",
+        ],
+    ),
+    (
+        "*.{json,jsonc,yml,yaml}",
+        &["indent_style = space", "indent_size = 2"],
+    ),
+];
+
+fn volume_mount(source: &str, target: &str) -> String {
+    format!("source={source},target={target},type=volume")
+}
+
+pub fn main(_config: &Config, args: &Args, init_args: &InitArgs) -> Result<()> {
+    let workspace_folder = args
+        .workspace_folder
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    let path = workspace_folder
+        .join(".devcontainer")
+        .join("devcontainer.json");
+    if path.exists() && !init_args.force {
+        bail!(
+            help = "pass --force to overwrite it",
+            "{} already exists",
+            path.display()
+        );
+    }
+
+    let builtin = match &init_args.template {
+        Some(name) => Template::ALL.iter().copied().find(|t| t.name() == name),
+        None if init_args.no_detect => Some(Template::Base),
+        None => Some(detect_template(&workspace_folder)?),
+    };
+
+    let workspace_name = host_path::canonicalize(&workspace_folder)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to resolve {}", workspace_folder.display()))?
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "workspace".to_string());
+    let (template_name, devcontainer_json) = match (builtin, &init_args.template) {
+        (Some(template), _) => (
+            template.name().to_string(),
+            template.devcontainer_json(&workspace_name),
+        ),
+        (None, Some(name)) => (name.clone(), script_template(name, &workspace_name)?),
+        (None, None) => unreachable!("a built-in template is chosen unless one is named"),
+    };
+    let mut devcontainer_json = devcontainer_json;
+    if let Some(platform) = &init_args.platform {
+        pin_platform(&mut devcontainer_json, platform)?;
+    }
+    let contents = serde_json::to_string_pretty(&devcontainer_json).into_diagnostic()?;
+
+    let dir = workspace_folder.join(".devcontainer");
+    fs::create_dir_all(&dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+    fs::write(&path, contents + "\n")
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    log!("Created" ("devcontainer.json"): "{} from the {} template", path.display(), template_name);
+    if let Some(platform) = &init_args.platform {
+        let engine = platform::engine_arch();
+        if engine.is_some_and(|engine| !platform.ends_with(&format!("/{engine}"))) {
+            log!("Warning": "{platform} differs from the Docker engine's architecture and runs emulated");
+            log!("Hint": "run `dockim doctor` to check that emulation is set up");
+        }
+    }
+
+    if !init_args.no_gitignore {
+        update_gitignore(&workspace_folder)?;
+    }
+    if init_args.editorconfig {
+        update_editorconfig(&workspace_folder, builtin)?;
+    }
+
+    Ok(())
+}
+
+/// Adds `--platform` to `runArgs`, so that multi-arch images are pulled for that platform and
+/// single-arch ones fail early instead of being emulated by surprise.
+fn pin_platform(devcontainer_json: &mut Value, platform: &str) -> Result<()> {
+    let is_valid = platform
+        .split_once('/')
+        .is_some_and(|(os, arch)| !os.is_empty() && !arch.is_empty());
+    if !is_valid {
+        bail!(
+            help = "pass it as os/arch, such as linux/amd64 or linux/arm64",
+            "invalid platform: {platform}"
+        );
+    }
+
+    let Some(object) = devcontainer_json.as_object_mut() else {
+        bail!("the template is not a JSON object");
+    };
+    let run_args = object.entry("runArgs").or_insert_with(|| json!([]));
+    let Some(run_args) = run_args.as_array_mut() else {
+        bail!("`runArgs` of the template is not an array");
+    };
+    // Drop an earlier `--platform=<platform>`, or `--platform` with the value that follows it
+    let mut args = mem::take(run_args).into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            Some("--platform") => {
+                args.next();
+            }
+            Some(arg) if arg.starts_with("--platform=") => {}
+            _ => run_args.push(arg),
+        }
+    }
+    run_args.push(json!(format!("--platform={platform}")));
+
+    Ok(())
+}
+
+/// Ignores the local configuration, which is personal unless the repository deliberately ships one,
+/// and its env.toml in any case.
+/// The override devcontainer.json and other state live outside the workspace.
+fn update_gitignore(workspace_folder: &Path) -> Result<()> {
+    let workspace = workspace_folder.to_string_lossy();
+    let is_git_repository = exec::capturing_stdout(&[
+        "git",
+        "-C",
+        &workspace,
+        "rev-parse",
+        "--is-inside-work-tree",
+    ])
+    .is_ok_and(|output| output.trim() == "true");
+    if !is_git_repository {
+        return Ok(());
+    }
+
+    let path = workspace_folder.join(".gitignore");
+    let contents = read_if_exists(&path)?;
+    let mut entries = vec![];
+    let dir_entry = format!("{LOCAL_CONFIG_DIR}/");
+    let is_dir_ignored = contents.lines().any(|line| {
+        let line = line.trim().trim_start_matches('/');
+        line == dir_entry || line == LOCAL_CONFIG_DIR
+    });
+    if !is_dir_ignored {
+        entries.push(dir_entry);
+    }
+    // env.toml may hold secrets, so it stays ignored even where the rest of the local
+    // configuration is shared
+    let env_entry = format!("{LOCAL_CONFIG_DIR}/{WORKSPACE_ENV_FILE}");
+    let is_env_ignored = exec::capturing_stdout(&[
+        "git",
+        "-C",
+        &workspace,
+        "check-ignore",
+        "--no-index",
+        "-q",
+        &env_entry,
+    ])
+    .is_ok();
+    if is_dir_ignored && !is_env_ignored {
+        entries.push(env_entry);
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    append(
+        &path,
+        &contents,
+        &format!("# dockim\n{}\n", entries.join("\n")),
+    )?;
+    log!("Updated" (".gitignore"): "ignoring {}", entries.join(", "));
+
+    Ok(())
+}
+
+fn update_editorconfig(workspace_folder: &Path, template: Option<Template>) -> Result<()> {
+    let path = workspace_folder.join(".editorconfig");
+    let contents = read_if_exists(&path)?;
+
+    let template_sections = template.map(Template::editorconfig_sections).unwrap_or(&[]);
+    let mut added = String::new();
+    if contents.trim().is_empty() {
+        added.push_str("root = true\n");
+    }
+    let mut count = 0;
+    for (glob, properties) in COMMON_EDITORCONFIG_SECTIONS.iter().chain(template_sections) {
+        let header = format!("[{glob}]");
+        if contents.lines().any(|line| line.trim() == header) {
+            continue;
+        }
+
+        if !added.is_empty() {
+            added.push('\n');
+        }
+        added.push_str(&header);
+        added.push('\n');
+        for property in *properties {
+            added.push_str(property);
+            added.push('\n');
+        }
+        count += 1;
+    }
+
+    if count == 0 {
+        log!("Skipping" (".editorconfig"): "it already has the recommended sections");
+        return Ok(());
+    }
+
+    append(&path, &contents, &added)?;
+    log!("Updated" (".editorconfig"): "added {count} section(s)");
+
+    Ok(())
+}
+
+fn read_if_exists(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display())),
+    }
+}
+
+/// Appends `addition` to a file that currently contains `contents`, separated by a blank line.
+fn append(path: &Path, contents: &str, addition: &str) -> Result<()> {
+    let separator = match contents {
+        "" => "",
+        _ if contents.ends_with("\n\n") => "",
+        _ if contents.ends_with('\n') => "\n",
+        _ => "\n\n",
+    };
+
+    fs::write(path, format!("{contents}{separator}{addition}"))
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))
+}
+
+fn script_template(name: &str, workspace_name: &str) -> Result<Value> {
+    let scripts = Scripts::load()?;
+    if let Some(config) = scripts.template(name, workspace_name)? {
+        return Ok(config);
+    }
+
+    let available = Template::ALL
+        .iter()
+        .map(|t| t.name())
+        .chain(scripts.template_names())
+        .collect::<Vec<_>>();
+    bail!(
+        help = format!("available templates: {}", available.join(", ")),
+        "unknown template `{name}`"
+    );
+}
+
+fn detect_template(workspace_folder: &Path) -> Result<Template> {
+    let detected = Template::DETECTABLE
+        .iter()
+        .filter(|(_, manifest)| workspace_folder.join(manifest).exists())
+        .map(|(template, _)| *template)
+        .collect::<Vec<_>>();
+
+    match *detected {
+        [] => Ok(Template::Base),
+        [template] => {
+            log!("Detected": "{} project", template.name());
+            Ok(template)
+        }
+        _ => choose_template(&detected),
+    }
+}
+
+fn choose_template(candidates: &[Template]) -> Result<Template> {
+    eprintln!("Multiple ecosystems found in the workspace:");
+    for (i, template) in candidates.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, template.name());
+    }
+
+    loop {
+        eprint!("Choose a template [1-{}] ", candidates.len());
+        io::stderr().flush().into_diagnostic()?;
+
+        let mut answer = String::new();
+        if io::stdin()
+            .lock()
+            .read_line(&mut answer)
+            .into_diagnostic()?
+            == 0
+        {
+            bail!("no template chosen");
+        }
+
+        match answer.trim().parse::<usize>() {
+            Ok(n) if (1..=candidates.len()).contains(&n) => return Ok(candidates[n - 1]),
+            _ => eprintln!("Please enter a number between 1 and {}", candidates.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pin_platform_adds_the_platform() {
+        let mut devcontainer_json = json!({ "image": "debian" });
+        pin_platform(&mut devcontainer_json, "linux/amd64").unwrap();
+        assert_eq!(
+            devcontainer_json["runArgs"],
+            json!(["--platform=linux/amd64"])
+        );
+    }
+
+    #[test]
+    fn pin_platform_replaces_an_earlier_platform() {
+        let mut devcontainer_json = json!({
+            "runArgs": ["--init", "--platform", "linux/arm64", "--platform=linux/386", "--privileged"],
+        });
+        pin_platform(&mut devcontainer_json, "linux/amd64").unwrap();
+        assert_eq!(
+            devcontainer_json["runArgs"],
+            json!(["--init", "--privileged", "--platform=linux/amd64"])
+        );
+    }
+
+    #[test]
+    fn pin_platform_rejects_invalid_platforms() {
+        for platform in ["amd64", "linux/", "/amd64", ""] {
+            assert!(
+                pin_platform(&mut json!({}), platform).is_err(),
+                "{platform}"
+            );
+        }
+        assert!(pin_platform(&mut json!({ "runArgs": "--init" }), "linux/amd64").is_err());
+    }
+}
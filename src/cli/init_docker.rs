@@ -0,0 +1,216 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    cli::{prompt, Args, InitDockerArgs},
+    config::Config,
+    exec, jsonc, log,
+};
+
+/// What init-docker changed in the Docker config, so that `--undo` reverts just that
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Changes {
+    /// The config didn't exist before
+    created: bool,
+    /// Values of the changed keys before the first change, `null` for keys that were absent
+    previous: Map<String, Value>,
+}
+
+pub fn main(config: &Config, _args: &Args, init_docker_args: &InitDockerArgs) -> Result<()> {
+    let config_path = docker_config_path()?;
+    let changes_path = changes_path(&config_path);
+
+    if init_docker_args.undo {
+        return undo(&config_path, &changes_path);
+    }
+
+    let original = read_if_exists(&config_path)?;
+    let current: Map<String, Value> = match &original {
+        Some(contents) => serde_json::from_str(contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to parse {}", config_path.display()))?,
+        None => Map::new(),
+    };
+
+    if init_docker_args.check_creds {
+        check_creds_store(&current);
+    }
+
+    let detach_keys = init_docker_args
+        .detach_keys
+        .as_deref()
+        .unwrap_or(&config.docker.detach_keys);
+    let mut proposed = current.clone();
+    proposed.insert(
+        "detachKeys".to_string(),
+        Value::String(detach_keys.to_string()),
+    );
+
+    let changes = diff(&current, &proposed);
+    if changes.is_empty() {
+        log!("Unchanged": "{} is already up to date", config_path.display());
+        return Ok(());
+    }
+
+    println!("Proposed changes to {}:", config_path.display());
+    for change in &changes {
+        println!("{change}");
+    }
+
+    if !init_docker_args.yes && !prompt::confirm("Apply these changes?")? {
+        log!("Aborted": "no changes were made");
+        return Ok(());
+    }
+
+    // Keep the values from before the first run, which is what --undo restores
+    let mut recorded: Changes = match read_if_exists(&changes_path)? {
+        Some(contents) => serde_json::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to parse {}", changes_path.display()))?,
+        None => Changes {
+            created: original.is_none(),
+            ..Changes::default()
+        },
+    };
+    for (key, value) in &proposed {
+        if current.get(key) != Some(value) && !recorded.previous.contains_key(key) {
+            let previous = current.get(key).cloned().unwrap_or(Value::Null);
+            recorded.previous.insert(key.clone(), previous);
+        }
+    }
+
+    if let Some(config_dir) = config_path.parent() {
+        fs::create_dir_all(config_dir).into_diagnostic()?;
+    }
+    let contents = serde_json::to_string_pretty(&recorded).into_diagnostic()?;
+    fs::write(&changes_path, contents + "\n")
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", changes_path.display()))?;
+
+    write_config(&config_path, original.as_deref(), &proposed)?;
+    log!("Updated": "{}", config_path.display());
+
+    Ok(())
+}
+
+/// Puts back the values of the keys init-docker changed, leaving whatever else was written to the
+/// config since, such as `auths` from `docker login`.
+fn undo(config_path: &Path, changes_path: &Path) -> Result<()> {
+    let Some(recorded) = read_if_exists(changes_path)? else {
+        bail!(
+            "init-docker has made no changes to {}",
+            config_path.display()
+        );
+    };
+    let recorded: Changes = serde_json::from_str(&recorded)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to parse {}", changes_path.display()))?;
+
+    let original = read_if_exists(config_path)?;
+    let mut config: Map<String, Value> = match &original {
+        Some(contents) => serde_json::from_str(contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to parse {}", config_path.display()))?,
+        None => Map::new(),
+    };
+    for (key, previous) in recorded.previous {
+        match previous {
+            Value::Null => config.remove(&key),
+            previous => config.insert(key, previous),
+        };
+    }
+
+    if recorded.created && config.is_empty() {
+        if original.is_some() {
+            fs::remove_file(config_path)
+                .into_diagnostic()
+                .wrap_err_with(|| miette!("failed to remove {}", config_path.display()))?;
+        }
+        log!("Removed": "{}, which dockim created", config_path.display());
+    } else if original.is_some() {
+        write_config(config_path, original.as_deref(), &config)?;
+        log!("Restored": "{}", config_path.display());
+    }
+    fs::remove_file(changes_path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to remove {}", changes_path.display()))?;
+
+    Ok(())
+}
+
+/// Where init-docker records what it changed in the Docker config
+pub fn changes_path(config_path: &Path) -> PathBuf {
+    config_path.with_extension("json.dockim-changes")
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<String>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    fs::read_to_string(path)
+        .map(Some)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))
+}
+
+/// Writes `config`, editing the original text so that its formatting is kept.
+fn write_config(path: &Path, original: Option<&str>, config: &Map<String, Value>) -> Result<()> {
+    let contents = match original {
+        Some(original) => jsonc::update_top_level(original, config)?,
+        None => serde_json::to_string_pretty(config).into_diagnostic()? + "\n",
+    };
+
+    fs::write(path, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))
+}
+
+pub fn docker_config_path() -> Result<PathBuf> {
+    if let Some(docker_config) = std::env::var_os("DOCKER_CONFIG") {
+        return Ok(PathBuf::from(docker_config).join("config.json"));
+    }
+
+    Ok(dirs::home_dir()
+        .ok_or_else(|| miette!("failed to get local home directory"))?
+        .join(".docker")
+        .join("config.json"))
+}
+
+fn diff(current: &Map<String, Value>, proposed: &Map<String, Value>) -> Vec<String> {
+    let mut changes = vec![];
+    for (key, value) in proposed {
+        match current.get(key) {
+            Some(current_value) if current_value == value => {}
+            Some(current_value) => {
+                changes.push(format!("- \"{key}\": {current_value}"));
+                changes.push(format!("+ \"{key}\": {value}"));
+            }
+            None => changes.push(format!("+ \"{key}\": {value}")),
+        }
+    }
+
+    changes
+}
+
+fn check_creds_store(config: &Map<String, Value>) {
+    let Some(creds_store) = config.get("credsStore").and_then(|v| v.as_str()) else {
+        log!("Checked" ("credsStore"): "not configured");
+        return;
+    };
+
+    let helper = format!("docker-credential-{creds_store}");
+    if exec::capturing_stdout(&[&*helper, "version"]).is_ok() {
+        log!("Checked" ("credsStore"): "`{helper}` is available");
+    } else {
+        log!(
+            "Warning": "credsStore is `{creds_store}` but `{helper}` was not found; docker pulls may fail"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use itertools::Itertools;
+use miette::{miette, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{Args, JobsArgs},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, log, state,
+};
+
+pub const JOBS_STATE_FILE: &str = "jobs.json";
+
+/// A command started with `dockim exec --detach`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u32,
+    /// Also the process group of the job since it runs in its own session
+    pub pid: u32,
+    pub command: Vec<String>,
+    /// Output of the job on the container
+    pub log: String,
+    pub started_at: u64,
+}
+
+pub fn main(config: &Config, args: &Args, _jobs_args: &JobsArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+
+    let jobs: Vec<Job> = state::load(dc.workspace_folder(), JOBS_STATE_FILE)?;
+    if jobs.is_empty() {
+        log!("Jobs": "none");
+        return Ok(());
+    }
+
+    let running = running_pids(&dc, &jobs)?;
+    println!("{:>4}  {:<8}  {:>7}  COMMAND", "ID", "STATUS", "PID");
+    for job in &jobs {
+        let status = if running.contains(&job.pid) {
+            "running"
+        } else {
+            "exited"
+        };
+        println!(
+            "{:>4}  {:<8}  {:>7}  {}",
+            job.id,
+            status,
+            job.pid,
+            job.command
+                .iter()
+                .map(|arg| exec::shell_quote(arg))
+                .join(" ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Starts `command` in its own session on the container so that it outlives this process, and
+/// records it as a job.
+pub fn start(dc: &DevContainer, command: &[String]) -> Result<Job> {
+    let mut jobs: Vec<Job> = state::load(dc.workspace_folder(), JOBS_STATE_FILE)?;
+    let id = jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+    let log = format!("/tmp/dockim-job-{id}.log");
+
+    let command_line = command.iter().map(|arg| exec::shell_quote(arg)).join(" ");
+    let pid = dc
+        .exec_capturing_stdout(&[
+            "sh",
+            "-c",
+            &format!(
+                "setsid nohup sh -c {} >{log} 2>&1 </dev/null & echo $!",
+                exec::shell_quote(&command_line)
+            ),
+        ])
+        .wrap_err("failed to start detached job on the container")?;
+    let pid = pid
+        .trim()
+        .parse()
+        .map_err(|_| miette!("unexpected pid of detached job: {pid}"))?;
+
+    let job = Job {
+        id,
+        pid,
+        command: command.to_vec(),
+        log,
+        started_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    jobs.push(job.clone());
+    state::save(dc.workspace_folder(), JOBS_STATE_FILE, &jobs)?;
+
+    Ok(job)
+}
+
+pub fn running_pids(dc: &DevContainer, jobs: &[Job]) -> Result<Vec<u32>> {
+    let pids = jobs.iter().map(|job| job.pid).join(" ");
+    let running = dc
+        .exec_capturing_stdout(&[
+            "sh",
+            "-c",
+            &format!("for pid in {pids}; do kill -0 $pid 2>/dev/null && echo $pid; done; true"),
+        ])
+        .wrap_err("failed to check jobs on the container")?;
+
+    Ok(running
+        .split_whitespace()
+        .filter_map(|pid| pid.parse().ok())
+        .collect())
+}
@@ -0,0 +1,42 @@
+use miette::{bail, Result, WrapErr};
+
+use crate::{
+    cli::{
+        jobs::{Job, JOBS_STATE_FILE},
+        Args, KillArgs,
+    },
+    config::Config,
+    devcontainer::DevContainer,
+    log, state,
+};
+
+pub fn main(config: &Config, args: &Args, kill_args: &KillArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+
+    let mut jobs: Vec<Job> = state::load(dc.workspace_folder(), JOBS_STATE_FILE)?;
+    let Some(index) = jobs.iter().position(|job| job.id == kill_args.job) else {
+        bail!(
+            help = "see `dockim jobs` for the list of jobs",
+            "no such job: {}",
+            kill_args.job
+        );
+    };
+    let job = jobs.remove(index);
+
+    // Signal the whole process group so that children of the job are stopped too
+    dc.exec(&[
+        "sh",
+        "-c",
+        &format!(
+            "kill -TERM -- -{pid} 2>/dev/null || ! kill -0 {pid} 2>/dev/null; rm -f {log}",
+            pid = job.pid,
+            log = job.log
+        ),
+    ])
+    .wrap_err("failed to stop job on the container")?;
+
+    state::save(dc.workspace_folder(), JOBS_STATE_FILE, &jobs)?;
+    log!("Killed" ("job"): "{} (pid {})", job.id, job.pid);
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::path::Path;
+
+use itertools::Itertools;
+use miette::Result;
+
+use crate::{
+    cli::{Args, ListArgs},
+    config::Config,
+    devcontainer::DevContainer,
+    log, variant,
+};
+
+pub fn main(_config: &Config, _args: &Args, _list_args: &ListArgs) -> Result<()> {
+    let containers = DevContainer::list_all()?;
+    if containers.is_empty() {
+        log!("Devcontainers": "none");
+        return Ok(());
+    }
+
+    let name_width = containers
+        .iter()
+        .map(|container| container.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("NAME".len());
+    let variants = containers
+        .iter()
+        .map(|container| {
+            container
+                .config
+                .as_deref()
+                .and_then(|config| variant::of_config_path(Path::new(config)))
+                .unwrap_or_else(|| "default".to_string())
+        })
+        .collect_vec();
+    let variant_width = variants
+        .iter()
+        .map(String::len)
+        .max()
+        .unwrap_or(0)
+        .max("VARIANT".len());
+    println!(
+        "{:<name_width$}  {:<10}  {:<variant_width$}  WORKSPACE",
+        "NAME", "STATE", "VARIANT"
+    );
+    for (container, variant) in containers.iter().zip(&variants) {
+        let missing = if container.workspace_folder.exists() {
+            ""
+        } else {
+            " (missing)"
+        };
+        println!(
+            "{:<name_width$}  {:<10}  {variant:<variant_width$}  {}{missing}",
+            container.name,
+            container.state,
+            container.workspace_folder.display()
+        );
+    }
+
+    Ok(())
+}
@@ -1,15 +1,46 @@
 use std::path::PathBuf;
 
-use crate::config::Config;
+use crate::config::{Config, ExistingServer, TunnelBackend};
 
+pub mod apply;
+pub mod audit;
+pub mod auth;
 pub mod bash;
 pub mod build;
+pub mod clipboard;
+pub mod clone;
+pub mod compose;
+pub mod config;
+pub mod describe;
+pub mod diff;
+pub mod direnv;
+pub mod doctor;
+pub mod down;
+pub mod env;
+pub mod events;
 pub mod exec;
+pub mod gc;
+pub mod init;
+pub mod init_docker;
+pub mod jobs;
+pub mod kill;
+pub mod list;
 pub mod neovide;
 pub mod neovim;
+pub mod pair;
+pub mod path;
 pub mod port;
+pub mod profile;
+pub mod prompt;
+pub mod share_config;
 pub mod shell;
+pub mod ssh;
+pub mod stop;
+pub mod top;
+pub mod trust;
+pub mod untrust;
 pub mod up;
+pub mod watch;
 
 #[derive(Debug, clap::Parser)]
 pub struct Args {
@@ -18,6 +49,18 @@ pub struct Args {
 
     #[clap(short = 'w', long)]
     pub workspace_folder: Option<PathBuf>,
+
+    /// Use `.devcontainer/<VARIANT>/devcontainer.json`, with a container and state of its own
+    #[clap(long, global = true)]
+    pub variant: Option<String>,
+
+    /// Skip checking that the devcontainer CLI and Docker are installed
+    #[clap(long, global = true)]
+    pub no_check: bool,
+
+    /// Override a config key for this run, e.g. `--config up.implicit=false`
+    #[clap(long = "config", global = true, value_name = "KEY=VALUE")]
+    pub config_overrides: Vec<String>,
 }
 
 #[derive(Debug, clap::Subcommand)]
@@ -26,6 +69,40 @@ pub enum Subcommand {
 
     Build(BuildArgs),
 
+    /// Reproduce the setup bundled by `dockim share-config` in this workspace
+    Apply(ApplyArgs),
+
+    /// List what dockim changed on the host and in the container, and how to revert it
+    Audit(AuditArgs),
+
+    /// Share the host's git credentials with the container
+    Auth(AuthArgs),
+
+    /// Check clipboard sharing between the host and the container
+    Clipboard(ClipboardArgs),
+
+    /// Clone a repository and start its devcontainer
+    Clone(CloneArgs),
+
+    Compose(ComposeArgs),
+
+    Config(ConfigArgs),
+
+    Describe(DescribeArgs),
+
+    /// Show files changed in the container since it was created
+    Diff(DiffArgs),
+
+    /// Run the container's toolchains from host terminals through direnv
+    Direnv(DirenvArgs),
+
+    Doctor(DoctorArgs),
+
+    Down(DownArgs),
+
+    /// Capture how a command changes the container's environment, e.g. `source ./setup.sh`
+    Env(EnvArgs),
+
     #[clap(alias = "v")]
     Neovim(NeovimArgs),
 
@@ -38,8 +115,116 @@ pub enum Subcommand {
 
     Exec(ExecArgs),
 
+    Events(EventsArgs),
+
+    Gc(GcArgs),
+
+    Init(InitArgs),
+
+    InitDocker(InitDockerArgs),
+
+    Jobs(JobsArgs),
+
+    Kill(KillArgs),
+
+    /// List the devcontainers of all workspaces
+    #[clap(alias = "ls")]
+    List(ListArgs),
+
+    /// Invite one peer into the running Neovim server over a tunnel, or join such a session
+    Pair(PairArgs),
+
+    Path(PathArgs),
+
     #[clap(alias = "p")]
     Port(PortArgs),
+
+    Profile(ProfileArgs),
+
+    /// Bundle devcontainer.json, the local config, port forwards and locked packages for others
+    ShareConfig(ShareConfigArgs),
+
+    Ssh(SshArgs),
+
+    /// Shut down the processes in the container gracefully, then stop it without removing it
+    Stop(StopArgs),
+
+    /// Show the processes in the container, grouped by what started them
+    Top(TopArgs),
+
+    /// Allow the workspace's local configuration (.dockim/) to be used
+    Trust(TrustArgs),
+
+    /// Ignore the workspace's local configuration (.dockim/)
+    Untrust(UntrustArgs),
+
+    /// Rerun a command on the container whenever files on the host change
+    Watch(WatchArgs),
+}
+
+impl Subcommand {
+    /// Whether the config file must be loaded; migrating it must work even if it doesn't parse.
+    pub fn needs_config(&self) -> bool {
+        !matches!(
+            self,
+            Subcommand::Config(ConfigArgs {
+                command: ConfigCommand::Migrate { .. } | ConfigCommand::Edit { .. }
+            })
+        )
+    }
+
+    /// Whether the workspace folder defaults to the nearest directory with a devcontainer
+    /// configuration, rather than the current one which these subcommands set up.
+    pub fn searches_workspace_folder(&self) -> bool {
+        !matches!(
+            self,
+            Subcommand::Apply(_) | Subcommand::Clone(_) | Subcommand::Init(_)
+        )
+    }
+
+    pub fn needs_devcontainer_cli(&self) -> bool {
+        !matches!(
+            self,
+            Subcommand::Apply(_)
+                | Subcommand::Clipboard(ClipboardArgs {
+                    command: ClipboardCommand::Serve { .. }
+                })
+                | Subcommand::Compose(_)
+                | Subcommand::Config(_)
+                | Subcommand::Direnv(_)
+                | Subcommand::Doctor(_)
+                | Subcommand::Events(_)
+                | Subcommand::Gc(_)
+                | Subcommand::Init(_)
+                | Subcommand::InitDocker(_)
+                | Subcommand::List(_)
+                | Subcommand::Pair(PairArgs { join: Some(_), .. })
+                | Subcommand::Profile(_)
+                | Subcommand::ShareConfig(_)
+                | Subcommand::Trust(_)
+                | Subcommand::Untrust(_)
+        )
+    }
+
+    pub fn needs_docker(&self) -> bool {
+        !matches!(
+            self,
+            Subcommand::Apply(_)
+                | Subcommand::Clipboard(ClipboardArgs {
+                    command: ClipboardCommand::Serve { .. }
+                })
+                | Subcommand::Config(_)
+                | Subcommand::Direnv(_)
+                | Subcommand::Doctor(_)
+                | Subcommand::Init(_)
+                | Subcommand::InitDocker(_)
+                | Subcommand::Pair(PairArgs { join: Some(_), .. })
+                | Subcommand::Profile(_)
+                | Subcommand::ShareConfig(_)
+                | Subcommand::Trust(_)
+                | Subcommand::Untrust(_)
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -54,22 +239,349 @@ pub struct UpArgs {
 
     #[clap(long)]
     pub build_no_cache: bool,
+
+    /// Keep the old container running until the rebuilt one is up, then swap them
+    #[clap(long, requires = "rebuild")]
+    pub blue_green: bool,
+
+    /// Create the container without network access (same as `network.mode = "none"`)
+    #[clap(long)]
+    pub offline: bool,
+
+    /// Let GUI apps in the container use the host's display (same as `container.gui = true`)
+    #[clap(long)]
+    pub gui: bool,
+
+    /// Name the container `dockim_<workspace>_<config>` (same as `container.readable_name = true`)
+    #[clap(long)]
+    pub readable_name: bool,
+
+    /// Wait until every compose service with a healthcheck is healthy
+    #[clap(long)]
+    pub wait_healthy: bool,
+
+    /// Don't run initializeCommand of devcontainer.json on the host
+    #[clap(long)]
+    pub no_initialize: bool,
 }
 
-#[derive(Debug, clap::Parser)]
+#[derive(Debug, Clone, clap::Parser)]
 pub struct BuildArgs {
     #[clap(long)]
     pub rebuild: bool,
 
     #[clap(long)]
     pub no_cache: bool,
+
+    /// Preinstall prerequisites into a derived image instead of the running container
+    #[clap(long)]
+    pub apt_layer: bool,
+
+    /// Print the steps and estimated downloads without touching the container
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Stream the output of every command instead of showing only the steps
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clone, clap::Parser)]
+pub struct CloneArgs {
+    /// URL of the repository
+    pub url: String,
+
+    /// Directory to clone into (defaults to the repository name)
+    pub dir: Option<PathBuf>,
+
+    /// Branch to check out
+    #[clap(short, long)]
+    pub branch: Option<String>,
+
+    /// Template for `dockim init` if the repository has no devcontainer.json
+    #[clap(long)]
+    pub template: Option<String>,
+
+    /// Also run `dockim build` once the container is up
+    #[clap(long)]
+    pub build: bool,
+
+    /// Start the devcontainer without asking for confirmation
+    #[clap(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct ComposeArgs {
+    /// Arguments passed to `docker compose`
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
 }
 
 #[derive(Debug, clap::Parser)]
+pub struct ConfigArgs {
+    #[clap(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration
+    Show {
+        /// Print the built-in defaults instead
+        #[clap(long)]
+        defaults: bool,
+
+        /// Annotate each value with where it comes from
+        #[clap(long)]
+        origin: bool,
+    },
+
+    /// Print the value of a key such as `build.retries.attempts`
+    Get { key: String },
+
+    /// Set a key in the config file; the value is parsed as TOML, falling back to a string
+    Set { key: String, value: String },
+
+    /// Open the config file in $VISUAL or $EDITOR, saving it only if it is valid
+    Edit {
+        /// Edit `.dockim/config.toml` of the workspace instead
+        #[clap(long)]
+        project: bool,
+    },
+
+    /// Rewrite the config file to the current schema, keeping a backup of the original
+    Migrate {
+        /// Print the migrated config instead of writing it
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Add the host's VS Code extensions to `customizations.vscode` in devcontainer.json
+    SyncVscode {
+        /// Also copy the host's user settings
+        #[clap(long)]
+        settings: bool,
+
+        /// Print the updated devcontainer.json instead of writing it
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct DescribeArgs {
+    #[clap(long, value_enum, default_value = "nvim")]
+    pub format: DescribeFormat,
+
+    /// Host port of the Neovim server
+    #[clap(long, default_value = "54321")]
+    pub host_port: String,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum DescribeFormat {
+    Nvim,
+    Vscode,
+    Sshconfig,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct DoctorArgs {
+    /// Install QEMU binfmt handlers so that images for another architecture can run
+    #[clap(long)]
+    pub fix_binfmt: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct DownArgs {
+    /// Remove containers found by label even if the devcontainer cannot be inspected
+    #[clap(long)]
+    pub force: bool,
+
+    /// Also stop the shared services declared in `[services.shared]`
+    #[clap(long)]
+    pub with_shared: bool,
+
+    /// Remove every devcontainer created by dockim and its port forwards, not only the workspace's
+    #[clap(long)]
+    pub all: bool,
+
+    /// Remove them with --all without asking for confirmation
+    #[clap(short, long, requires = "all")]
+    pub yes: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct ShareConfigArgs {
+    /// Write the bundle to this file instead of stdout
+    #[clap(short, long, conflicts_with = "gist")]
+    pub output: Option<PathBuf>,
+
+    /// Upload the bundle as a secret gist with the GitHub CLI and print its URL
+    #[clap(long)]
+    pub gist: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct ApplyArgs {
+    /// Bundle written by `dockim share-config`: a file, a gist or other URL, or `-` for stdin
+    pub bundle: String,
+
+    /// Overwrite files that differ from the bundle
+    #[clap(long)]
+    pub force: bool,
+
+    /// Write the files without asking for confirmation
+    #[clap(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct StopArgs {
+    /// Stop the container right away, skipping `stop.commands` and waiting for processes
+    #[clap(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct EnvArgs {
+    /// Shell command to run on the container; prints the variables it sets and unsets
+    #[clap(long, value_name = "CMD")]
+    pub export: Option<String>,
+
+    /// Also apply the captured variables to later `dockim exec`, `shell` and `bash`
+    #[clap(long, requires = "export")]
+    pub save: bool,
+
+    /// Forget the saved variables
+    #[clap(long, conflicts_with_all = ["export", "save"])]
+    pub clear: bool,
+
+    /// Syntax of the printed statements
+    #[clap(long, value_enum, default_value = "sh")]
+    pub format: EnvFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EnvFormat {
+    Sh,
+    Fish,
+    Powershell,
+}
+
+#[derive(Debug, clap::Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct NeovimArgs {
+    #[clap(subcommand)]
+    pub command: Option<NeovimCommand>,
+
+    /// Only start a headless server in the container and print how to attach to it
+    #[clap(long, alias = "headless-only", overrides_with = "no_background")]
+    pub background: bool,
+
+    #[clap(long, overrides_with = "background")]
+    pub no_background: bool,
+
+    /// Start a headless server, print how to attach various clients and wait until one attaches
+    #[clap(long)]
+    pub print_connect: bool,
+
+    /// Serve the host clipboard to Neovim on the container
+    #[clap(long, overrides_with = "no_clipboard")]
+    pub clipboard: bool,
+
+    #[clap(long, overrides_with = "clipboard")]
+    pub no_clipboard: bool,
+
+    /// Run this Neovim version (e.g. `v0.9.5`), installing it under /opt/nvim if needed
+    #[clap(long, value_name = "VERSION")]
+    pub use_version: Option<String>,
+
+    /// Extra arguments passed to Neovim (defaults to `remote.args` in the config)
+    #[clap(long = "args", value_name = "ARGS")]
+    pub extra_args: Option<String>,
+
+    /// Restore the session saved when Neovim last exited or a UI detached
+    #[clap(long, overrides_with = "no_restore")]
+    pub restore: bool,
+
+    #[clap(long, overrides_with = "restore")]
+    pub no_restore: bool,
+
+    /// What to do if the workspace's Neovim server is already running (defaults to
+    /// `remote.existing_server` in the config)
+    #[clap(long, value_name = "MODE")]
+    pub existing_server: Option<ExistingServer>,
+
+    #[clap(long, default_value = "54321")]
+    pub host_port: String,
+
+    #[clap(long, default_value = "54321")]
+    pub container_port: String,
+
     pub args: Vec<String>,
 }
 
+impl NeovimArgs {
+    pub fn background(&self, config: &Config) -> bool {
+        self.print_connect
+            || flag_or(
+                self.background,
+                self.no_background,
+                config.remote.background,
+            )
+    }
+
+    pub fn clipboard(&self, config: &Config) -> bool {
+        flag_or(self.clipboard, self.no_clipboard, config.remote.clipboard)
+    }
+
+    pub fn restore(&self, config: &Config) -> bool {
+        flag_or(self.restore, self.no_restore, config.remote.restore_session)
+    }
+
+    pub fn existing_server(&self, config: &Config) -> ExistingServer {
+        self.existing_server
+            .unwrap_or(config.remote.existing_server)
+    }
+
+    /// Returns the arguments passed to Neovim: the extra ones followed by the positional ones.
+    pub fn nvim_args(&self, config: &Config) -> Vec<String> {
+        let extra_args = self.extra_args.as_deref().unwrap_or(&config.remote.args);
+        extra_args
+            .split_whitespace()
+            .map(|arg| arg.to_string())
+            .chain(self.args.iter().cloned())
+            .collect()
+    }
+}
+
+/// Resolves a `--foo`/`--no-foo` pair, falling back to `default` if neither is given.
+fn flag_or(yes: bool, no: bool, default: bool) -> bool {
+    if yes {
+        true
+    } else if no {
+        false
+    } else {
+        default
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum NeovimCommand {
+    /// Print the output of the Neovim server
+    Logs {
+        /// Keep printing output as it is appended
+        #[clap(short, long)]
+        follow: bool,
+
+        /// Number of trailing lines to print
+        #[clap(short = 'n', long, default_value = "50")]
+        lines: usize,
+    },
+}
+
 #[derive(Debug, clap::Parser)]
 pub struct NeovideArgs {
     #[clap(short, long, default_value = "54321")]
@@ -81,22 +593,283 @@ pub struct NeovideArgs {
 
 #[derive(Debug, clap::Parser)]
 pub struct ShellArgs {
+    /// Run as this user instead of the configured remote user
+    #[clap(short, long)]
+    pub user: Option<String>,
+
+    /// Fail instead of starting the container if it isn't running
+    #[clap(long)]
+    pub no_up: bool,
+
+    /// Run this command line with the shell non-interactively and exit with its status
+    #[clap(short, long, conflicts_with = "args")]
+    pub command: Option<String>,
+
+    /// Record the session into FILE on the host: asciinema's format for `.cast` files, a `script`
+    /// typescript otherwise
+    #[clap(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+
     pub args: Vec<String>,
 }
 
 #[derive(Debug, clap::Parser)]
 pub struct BashArgs {
+    /// Run as this user instead of the configured remote user
+    #[clap(short, long)]
+    pub user: Option<String>,
+
+    /// Fail instead of starting the container if it isn't running
+    #[clap(long)]
+    pub no_up: bool,
+
+    /// Record the session into FILE on the host: asciinema's format for `.cast` files, a `script`
+    /// typescript otherwise
+    #[clap(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+
     pub args: Vec<String>,
 }
 
 #[derive(Debug, clap::Parser)]
 pub struct ExecArgs {
+    /// Run as this user instead of the configured remote user
+    #[clap(short, long)]
+    pub user: Option<String>,
+
+    /// Fail instead of starting the container if it isn't running
+    #[clap(long)]
+    pub no_up: bool,
+
+    /// Run the command in the background, independent of this terminal
+    #[clap(short, long)]
+    pub detach: bool,
+
+    /// Attach the command to a terminal of its own, for REPLs and full-screen programs
+    #[clap(short, long, conflicts_with = "detach")]
+    pub interactive: bool,
+
+    /// Record the session into FILE on the host: asciinema's format for `.cast` files, a `script`
+    /// typescript otherwise
+    #[clap(long, value_name = "FILE", conflicts_with = "detach")]
+    pub record: Option<PathBuf>,
+
     pub args: Vec<String>,
 }
 
 #[derive(Debug, clap::Parser)]
+pub struct EventsArgs {
+    /// Restore registered port forwards whenever the container starts
+    #[clap(long)]
+    pub reconcile_forwards: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct AuthArgs {
+    #[clap(subcommand)]
+    pub command: AuthCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum AuthCommand {
+    /// Configure git on the container to use the host's credentials (also done by `build`)
+    Setup,
+
+    /// Run the credential bridge until interrupted, for containers used without a dockim session
+    Serve,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct ClipboardArgs {
+    #[clap(subcommand)]
+    pub command: ClipboardCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ClipboardCommand {
+    /// Test copy and paste in both directions through the clipboard server of `dockim neovim`
+    Status,
+
+    /// Serve the host clipboard over HTTP to other clients (`/clipboard`, `/health` and `/version`)
+    Serve {
+        /// Port to listen on
+        #[clap(long, default_value = clipboard::DEFAULT_PORT)]
+        port: u16,
+    },
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct DirenvArgs {
+    #[clap(subcommand)]
+    pub command: DirenvCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum DirenvCommand {
+    /// Add `.dockim/bin` to PATH in `.envrc` and generate its shims
+    Install,
+
+    /// Regenerate the shims in `.dockim/bin` for the current features and config (run by `.envrc`)
+    Sync,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct DiffArgs {
+    /// Also show changes matching `diff.ignore` in the config
+    #[clap(long)]
+    pub all: bool,
+
+    /// Number of leading path components changes are grouped by
+    #[clap(long, default_value = "2")]
+    pub depth: usize,
+
+    /// Only print the groups and their number of changes
+    #[clap(long)]
+    pub summary: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct AuditArgs {}
+
+#[derive(Debug, clap::Parser)]
+pub struct GcArgs {
+    #[clap(subcommand)]
+    pub command: Option<GcCommand>,
+
+    /// Only print what would be removed
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum GcCommand {
+    /// Run `dockim gc` daily with a systemd user timer or a launchd agent
+    InstallSchedule,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct InitArgs {
+    /// Use the base template instead of detecting the language of the workspace
+    #[clap(long)]
+    pub no_detect: bool,
+
+    /// Use this template: base, rust, node, python, go, or the name of a user script defining
+    /// `template(workspace_name)`
+    #[clap(long, conflicts_with = "no_detect")]
+    pub template: Option<String>,
+
+    /// Overwrite an existing devcontainer.json
+    #[clap(long)]
+    pub force: bool,
+
+    /// Don't add dockim's local configuration (.dockim/) to .gitignore
+    #[clap(long)]
+    pub no_gitignore: bool,
+
+    /// Add recommended sections for the template to .editorconfig, creating it if needed
+    #[clap(long)]
+    pub editorconfig: bool,
+
+    /// Pin the container to this platform, such as linux/amd64, instead of the Docker engine's
+    #[clap(long)]
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct InitDockerArgs {
+    /// Detach key sequence (defaults to `docker.detach_keys` in the config)
+    #[clap(long)]
+    pub detach_keys: Option<String>,
+
+    /// Revert the keys of ~/.docker/config.json changed by earlier runs
+    #[clap(long)]
+    pub undo: bool,
+
+    /// Apply the changes without asking for confirmation
+    #[clap(short, long)]
+    pub yes: bool,
+
+    /// Check that the configured credential helper is installed
+    #[clap(long)]
+    pub check_creds: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct JobsArgs {}
+
+#[derive(Debug, clap::Parser)]
+pub struct KillArgs {
+    /// Job id shown by `dockim jobs`
+    pub job: u32,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct ListArgs {}
+
+#[derive(Debug, clap::Parser)]
+pub struct TopArgs {
+    /// Refresh periodically until interrupted
+    #[clap(short, long)]
+    pub watch: bool,
+
+    /// Seconds between refreshes with `--watch`
+    #[clap(short = 'n', long, default_value = "2")]
+    pub interval: u64,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct TrustArgs {}
+
+#[derive(Debug, clap::Parser)]
+pub struct UntrustArgs {}
+
+#[derive(Debug, clap::Parser)]
+pub struct WatchArgs {
+    /// Host files or directories to watch (defaults to the workspace folder)
+    #[clap(short, long)]
+    pub path: Vec<PathBuf>,
+
+    /// Wait until files stay unchanged for this long before rerunning
+    #[clap(long, value_name = "MS", default_value = "300")]
+    pub debounce: u64,
+
+    #[clap(required = true, last = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct PairArgs {
+    /// Join the session of the invite printed by `dockim pair`, with Neovim on this machine
+    #[clap(long, value_name = "INVITE")]
+    pub join: Option<String>,
+
+    /// Tunnel provider (defaults to `tunnel.backend` in the config)
+    #[clap(long, value_enum, conflicts_with = "join")]
+    pub backend: Option<TunnelBackend>,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct PathArgs {
+    #[clap(subcommand)]
+    pub command: PathCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum PathCommand {
+    /// Translate a host path into the corresponding container path
+    ToContainer { path: PathBuf },
+
+    /// Translate a container path into the corresponding host path
+    ToHost { path: String },
+}
+
+#[derive(Debug, clap::Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct PortArgs {
-    /// "8080" or "8080:1234" (host:container)
+    #[clap(subcommand)]
+    pub command: Option<PortCommand>,
+
+    /// "8080", "8080:1234" (host:container) or ":1234" to pick a free host port
     pub port_descriptor: Option<String>,
 
     #[clap(long, alias = "rm")]
@@ -104,4 +877,102 @@ pub struct PortArgs {
 
     #[clap(long)]
     pub remove_all: bool,
+
+    /// Serve the port over HTTPS with a locally generated certificate
+    #[clap(long)]
+    pub https: bool,
+
+    /// Forward to this compose service instead of the devcontainer
+    #[clap(long)]
+    pub service: Option<String>,
+
+    /// Fail instead of starting the container if it isn't running
+    #[clap(long)]
+    pub no_up: bool,
+
+    /// HTTP path to probe through the host port, shown in `dockim port ls` (e.g. `/healthz`)
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["remove", "remove_all"])]
+    pub healthcheck: Option<String>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum PortCommand {
+    /// List the registered port forwards and the health of their apps
+    #[clap(alias = "list")]
+    Ls {
+        /// Refresh periodically until interrupted, notifying when an app goes down if
+        /// `notifications.enabled` is set
+        #[clap(short, long)]
+        watch: bool,
+
+        /// Seconds between refreshes with `--watch`
+        #[clap(short = 'n', long, default_value = "5")]
+        interval: u64,
+    },
+
+    /// Expose a container port on a public URL through a tunnel until interrupted
+    Share {
+        container_port: u16,
+
+        /// Tunnel provider (defaults to `tunnel.backend` in the config)
+        #[clap(long, value_enum)]
+        backend: Option<TunnelBackend>,
+    },
+
+    /// Make the port-forwarding image (`port.socat_image`) available without registry access
+    PreloadImage {
+        /// Archive created by `docker save` to load the image from; pulls it if omitted
+        tar: Option<PathBuf>,
+
+        /// Also write the image to this archive, for machines that can't pull it
+        #[clap(long)]
+        save: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct ProfileArgs {
+    #[clap(subcommand)]
+    pub command: ProfileCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ProfileCommand {
+    /// Bundle the config and registered workspaces into a single file
+    Export { file: PathBuf },
+
+    /// Restore a bundle created by `dockim profile export`
+    Import {
+        file: PathBuf,
+
+        /// Import the config file (imports everything if no selection is given)
+        #[clap(long)]
+        config: bool,
+
+        /// Import the registered port forwards of workspaces present on this machine
+        #[clap(long)]
+        forwards: bool,
+    },
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct SshArgs {
+    #[clap(subcommand)]
+    pub command: SshCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum SshCommand {
+    /// Run sshd in the container and register it in ~/.ssh/config
+    Enable {
+        #[clap(long, default_value = "2222")]
+        host_port: String,
+
+        #[clap(long, default_value = "2222")]
+        container_port: String,
+
+        /// Host name for the ~/.ssh/config entry (defaults to `dockim-<workspace name>`)
+        #[clap(long)]
+        host_alias: Option<String>,
+    },
 }
@@ -4,35 +4,40 @@ use miette::{miette, IntoDiagnostic, Result, WrapErr};
 use scopeguard::defer;
 
 use crate::{
-    cli::{Args, NeovideArgs},
+    cli::{neovim, Args, NeovideArgs},
     config::Config,
     devcontainer::DevContainer,
     exec, log,
 };
 
-pub fn main(_config: &Config, args: &Args, neovide_args: &NeovideArgs) -> Result<()> {
-    let dc = DevContainer::new(args.workspace_folder.clone());
+pub fn main(config: &Config, args: &Args, neovide_args: &NeovideArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
 
     dc.exec(&["nvim", "--version"]).wrap_err(miette!(
         help = "try `dockim build --rebuild` first",
         "Neovim not found"
     ))?;
 
-    let listen = format!("0.0.0.0:{}", neovide_args.container_port);
+    let lock = dc.lock()?;
+    let container_port = neovim::ensure_server_port(&dc, &neovide_args.container_port)?;
 
-    let _guard = dc.forward_port(&neovide_args.host_port, &neovide_args.container_port)?;
+    let _guard = dc.forward_port(&neovide_args.host_port, &container_port)?;
+    drop(lock);
 
     defer! {
         // Sanitize terminal
         let _ = exec::exec(&["stty", "sane"]);
     }
 
-    let mut nvim = dc.spawn(&[
-        "nvim".to_string(),
-        "--headless".to_string(),
-        "--listen".to_string(),
-        listen,
-    ])?;
+    let log = neovim::open_server_log(&dc, &container_port)?;
+    let mut nvim = dc.spawn_with_output(
+        &[
+            "sh".to_string(),
+            "-c".to_string(),
+            neovim::server_command(&container_port, "nvim", &[]),
+        ],
+        log,
+    )?;
 
     // Wait for everything to start up
     log!("Waiting": "5 seconds");
@@ -1,47 +1,448 @@
-use std::process::{Command, Stdio};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    mem,
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
 
-use miette::Result;
+use itertools::{chain, Itertools};
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
 use scopeguard::defer;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    cli::{Args, NeovimArgs},
-    config::Config,
+    cli::{build, clipboard, Args, NeovimArgs, NeovimCommand},
+    config::{Config, ExistingServer},
     devcontainer::DevContainer,
-    log,
+    exec, git_credentials, log, remote, state,
 };
 
-pub fn main(_config: &Config, args: &Args, neovim_args: &NeovimArgs) -> Result<()> {
-    let dc = DevContainer::new(args.workspace_folder.clone());
+const SERVER_LOG_FILE: &str = "nvim-server.log";
+
+/// The server log is rotated once it grows larger than this
+const SERVER_LOG_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Session file on the container, which survives container restarts but not rebuilds
+const SESSION_FILE: &str = "~/.local/state/dockim/session.vim";
+
+const SERVER_STATE_FILE: &str = "nvim-server.json";
+
+/// The headless server started by `dockim neovim --background`, which later invocations reuse.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Server {
+    pub host_port: String,
+    pub container_port: String,
+    pub nvim: String,
+}
+
+impl Server {
+    pub fn address(&self) -> String {
+        format!("localhost:{}", self.container_port)
+    }
+}
 
-    // Run csrv for clipboard support if exists
-    let csrv = Command::new("csrv")
-        .env("CSRV_PORT", "55232")
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .ok();
+pub fn main(config: &Config, args: &Args, neovim_args: &NeovimArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
 
-    if csrv.is_some() {
-        log!("Started": "csrv");
+    if let Some(NeovimCommand::Logs { follow, lines }) = &neovim_args.command {
+        return print_server_log(&dc, *follow, *lines);
     }
 
+    let nvim_args = chain!(
+        session_args(config, neovim_args),
+        neovim_args.nvim_args(config)
+    )
+    .collect_vec();
+    let nvim = match &neovim_args.use_version {
+        Some(version) => build::ensure_neovim_version(config, &dc, version)?,
+        None => "nvim".to_string(),
+    };
+
+    let server = running_server(&dc)?;
+    if let Some(server) = &server {
+        match neovim_args.existing_server(config) {
+            ExistingServer::Error => bail!(
+                help = "pass `--existing-server attach-new-ui` or `--existing-server open-in-existing` to use it",
+                "a Neovim server for this workspace is already running on localhost:{}",
+                server.host_port
+            ),
+            ExistingServer::OpenInExisting => {
+                return open_in_new_tab(&dc, server, &neovim_args.args);
+            }
+            ExistingServer::AttachNewUi => {
+                if !file_args(&neovim_args.args).is_empty() {
+                    open_in_new_tab(&dc, server, &neovim_args.args)?;
+                }
+                if neovim_args.background(config) {
+                    let address = format!("localhost:{}", server.host_port);
+                    log!("Listening": "{address} (already running)");
+                    print_connect_info(&address, neovim_args.print_connect);
+                    return Ok(());
+                }
+            }
+        }
+    } else if neovim_args.background(config) {
+        return start_headless_server(&dc, neovim_args, &nvim, &nvim_args);
+    }
+
+    // Serve the host clipboard to this Neovim, which the peer of `dockim pair` can't paste from
+    let mut dc = dc.with_saved_env()?;
+    let mut nvim_args = nvim_args;
+    let clipboard = if server.is_none() && neovim_args.clipboard(config) {
+        match clipboard::Server::bind(config, &dc, 0) {
+            Ok(clipboard) => Some(clipboard),
+            Err(e) => {
+                log!("Warning": "clipboard won't work: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let clipboard_port = clipboard
+        .as_ref()
+        .map(|clipboard| clipboard.port().to_string());
+    if let Some(clipboard) = clipboard {
+        for (name, value) in clipboard.env() {
+            dc = dc.with_env_var(name, value);
+        }
+        nvim_args.splice(0..0, ["--cmd".to_string(), clipboard::neovim_provider()]);
+        clipboard.spawn();
+    }
     defer! {
-        if let Some(mut csrv) = csrv {
-            let _ = csrv.kill();
-            let _ = csrv.wait();
-            log!("Stopped": "csrv");
+        if let (Some(ssh_host), Some(port)) = (&config.runtime.ssh_host, &clipboard_port) {
+            let _ = remote::cancel_reverse_forward(ssh_host, port);
         }
     }
 
+    git_credentials::spawn_bridge(config, &dc);
+
     // Run Neovim in container
     // Set environment variable to indicate that we are directly running Neovim from dockim
-    let mut args = vec![
-        "/usr/bin/env",
-        "DIRECT_NVIM=1",
-        "TERM=screen-256color",
-        "nvim",
-    ];
-    args.extend(neovim_args.args.iter().map(|s| s.as_str()));
-    dc.exec(&args)
+    let mut args = vec!["/usr/bin/env", "DIRECT_NVIM=1", "TERM=screen-256color"];
+    match &server {
+        Some(server) => {
+            log!("Attaching": "to the Neovim server on localhost:{}", server.host_port);
+            let address = server.address();
+            args.extend([&*server.nvim, "--server", &address, "--remote-ui"]);
+            dc.exec(&args)
+        }
+        None => {
+            args.push(&nvim);
+            args.extend(nvim_args.iter().map(|s| s.as_str()));
+            dc.exec(&args)
+        }
+    }
+}
+
+/// Returns the server started by `--background` if it is still running.
+pub fn running_server(dc: &DevContainer) -> Result<Option<Server>> {
+    let server: Option<Server> = state::load(dc.workspace_folder(), SERVER_STATE_FILE)?;
+    let Some(server) = server else {
+        return Ok(None);
+    };
+
+    let is_running = dc.container_status()?.as_deref() == Some("running")
+        && dc
+            .exec_capturing_stdout(&[
+                &server.nvim,
+                "--headless",
+                "--server",
+                &server.address(),
+                "--remote-expr",
+                "1",
+            ])
+            .is_ok();
+    if !is_running {
+        state::save(dc.workspace_folder(), SERVER_STATE_FILE, &None::<Server>)?;
+        return Ok(None);
+    }
+
+    Ok(Some(server))
+}
+
+/// Opens the files among `args` in a new tab of the server, or an empty one if there are none.
+fn open_in_new_tab(dc: &DevContainer, server: &Server, args: &[String]) -> Result<()> {
+    let files = file_args(args);
+    let address = server.address();
+    let mut command = vec![&*server.nvim, "--headless", "--server", &address];
+    if files.is_empty() {
+        command.extend(["--remote-expr", "execute('tabnew')"]);
+    } else {
+        command.push("--remote-tab");
+        command.extend(files.iter().copied());
+    }
+
+    dc.exec_capturing_stdout(&command).wrap_err_with(|| {
+        miette!(
+            "failed to open a tab in the Neovim server on localhost:{}",
+            server.host_port
+        )
+    })?;
+    log!("Opened" ("neovim"): "new tab in the server on localhost:{}", server.host_port);
+
+    Ok(())
+}
+
+/// Positional arguments which are not options, i.e. the files to edit.
+fn file_args(args: &[String]) -> Vec<&str> {
+    args.iter()
+        .map(|arg| arg.as_str())
+        .filter(|arg| !arg.starts_with('-') && !arg.starts_with('+'))
+        .collect()
+}
+
+/// Returns the Neovim arguments that restore and save the session as configured.
+fn session_args(config: &Config, neovim_args: &NeovimArgs) -> Vec<String> {
+    let mut args = vec![];
+
+    if neovim_args.restore(config) {
+        log!("Restoring": "session from {SESSION_FILE} if any");
+        args.extend([
+            "-c".to_string(),
+            format!("if filereadable(expand('{SESSION_FILE}')) | execute 'source' fnameescape(expand('{SESSION_FILE}')) | endif"),
+        ]);
+    }
+
+    if config.remote.save_session {
+        // UILeave is what a server sees when a remote UI goes away
+        args.extend([
+            "-c".to_string(),
+            format!("autocmd VimLeavePre,UILeave * call mkdir(fnamemodify(expand('{SESSION_FILE}'), ':h'), 'p') | execute 'mksession!' fnameescape(expand('{SESSION_FILE}'))"),
+        ]);
+    }
+
+    args
+}
+
+fn start_headless_server(
+    dc: &DevContainer,
+    neovim_args: &NeovimArgs,
+    nvim: &str,
+    nvim_args: &[String],
+) -> Result<()> {
+    dc.exec(&[nvim, "--version"]).wrap_err(miette!(
+        help = "try `dockim build --rebuild` first",
+        "Neovim not found"
+    ))?;
+
+    let lock = dc.lock()?;
+    let container_port = ensure_server_port(dc, &neovim_args.container_port)?;
+
+    // Detach the server from this terminal so that it keeps running after we exit
+    let log = open_server_log(dc, &container_port)?;
+    dc.spawn_detached(
+        &[
+            "sh".to_string(),
+            "-c".to_string(),
+            server_command(&container_port, nvim, nvim_args),
+        ],
+        log,
+    )
+    .wrap_err("failed to start Neovim server on the container")?;
+
+    if !dc.is_forwarding(&neovim_args.host_port)? {
+        // We need to forget because forward_port() returns a guard that will stop forwarding on
+        // drop
+        mem::forget(dc.forward_port(&neovim_args.host_port, &container_port)?);
+    }
+    dc.register_forward(&neovim_args.host_port, &container_port, false)?;
+    let server = Server {
+        host_port: neovim_args.host_port.clone(),
+        container_port: container_port.clone(),
+        nvim: nvim.to_string(),
+    };
+    state::save(dc.workspace_folder(), SERVER_STATE_FILE, &Some(server))?;
+    drop(lock);
+
+    let server = format!("localhost:{}", neovim_args.host_port);
+    log!("Listening": "{server}");
+    print_connect_info(&server, neovim_args.print_connect);
+    if !neovim_args.print_connect {
+        return Ok(());
+    }
+
+    wait_for_ui(dc, nvim, &container_port)
+}
+
+fn print_connect_info(server: &str, verbose: bool) {
+    if !verbose {
+        println!("{server}");
+        println!("nvim --server {server} --remote-ui");
+        println!("neovide --server {server}");
+        return;
+    }
+
+    println!("# Neovim TUI");
+    println!("nvim --server {server} --remote-ui");
+    println!("# Neovide");
+    println!("neovide --server {server}");
+    println!("# Other GUIs and scripts (msgpack-rpc over TCP)");
+    println!("tcp://{server}");
+    println!("# From a running Neovim (Lua)");
+    println!("vim.fn.sockconnect('tcp', '{server}', {{ rpc = true }})");
+}
+
+/// Keeps the session alive until a UI attaches to the server.
+fn wait_for_ui(dc: &DevContainer, nvim: &str, container_port: &str) -> Result<()> {
+    log!("Waiting": "for a client to attach (Ctrl-C to stop waiting; the server keeps running)");
+    let server = format!("localhost:{container_port}");
+    loop {
+        let uis = dc
+            .exec_capturing_stdout(&[
+                nvim,
+                "--headless",
+                "--server",
+                &server,
+                "--remote-expr",
+                "len(nvim_list_uis())",
+            ])
+            .ok()
+            .and_then(|uis| uis.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        if uis > 0 {
+            log!("Attached": "{uis} client(s)");
+            return Ok(());
+        }
+
+        thread::sleep(Duration::from_secs(2));
+    }
+}
+
+fn server_log_path(dc: &DevContainer) -> Result<PathBuf> {
+    Ok(state::workspace_state_dir(dc.workspace_folder())?.join(SERVER_LOG_FILE))
+}
+
+/// Opens the server log for appending, rotating it first if it has grown too large.
+pub fn open_server_log(dc: &DevContainer, container_port: &str) -> Result<File> {
+    let path = state::ensure_workspace_state_dir(dc.workspace_folder())?.join(SERVER_LOG_FILE);
+
+    if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > SERVER_LOG_MAX_BYTES) {
+        fs::rename(&path, path.with_extension("log.1"))
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to rotate {}", path.display()))?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to open {}", path.display()))?;
+    writeln!(
+        file,
+        "--- dockim: starting Neovim server on container port {container_port} ---"
+    )
+    .into_diagnostic()
+    .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    Ok(file)
+}
+
+fn print_server_log(dc: &DevContainer, follow: bool, lines: usize) -> Result<()> {
+    let path = server_log_path(dc)?;
+    let mut file = File::open(&path).into_diagnostic().wrap_err_with(|| {
+        miette!(
+            help = "start one with `dockim neovim --background`",
+            "failed to open {}",
+            path.display()
+        )
+    })?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+    let all_lines = contents.lines().collect_vec();
+    for line in &all_lines[all_lines.len().saturating_sub(lines)..] {
+        println!("{line}");
+    }
+
+    if !follow {
+        return Ok(());
+    }
+
+    let mut pos = contents.len() as u64;
+    loop {
+        thread::sleep(Duration::from_millis(500));
+
+        let len = fs::metadata(&path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        if len < pos {
+            // The log has been rotated
+            file = File::open(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| miette!("failed to open {}", path.display()))?;
+            pos = 0;
+        }
+
+        let mut appended = String::new();
+        file.seek(SeekFrom::Start(pos)).into_diagnostic()?;
+        file.read_to_string(&mut appended)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+        pos += appended.len() as u64;
+        print!("{appended}");
+    }
+}
+
+fn server_pidfile(container_port: &str) -> String {
+    format!("/tmp/dockim-nvim-{container_port}.pid")
+}
+
+/// Returns a shell command that runs a headless Neovim server on `container_port`, recording its
+/// pid so that a stale server can be told apart from other processes later.
+pub fn server_command(container_port: &str, nvim: &str, args: &[String]) -> String {
+    let listen = format!("0.0.0.0:{container_port}");
+    let nvim = chain!(
+        [nvim, "--headless", "--listen", &*listen],
+        args.iter().map(|arg| arg.as_str())
+    )
+    .map(exec::shell_quote)
+    .join(" ");
+
+    format!(
+        "echo $$ > {} && exec {nvim}",
+        server_pidfile(container_port)
+    )
+}
+
+/// Makes sure `container_port` can be listened on, stopping a stale server started by dockim or
+/// choosing another port if something else occupies it.
+pub fn ensure_server_port(dc: &DevContainer, container_port: &str) -> Result<String> {
+    let port: u16 = container_port
+        .parse()
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("invalid container port: {container_port}"))?;
+
+    if !dc.listening_ports()?.contains(&port) {
+        return Ok(container_port.to_string());
+    }
+
+    let pidfile = server_pidfile(container_port);
+    let kill_stale_server = format!(
+        "pid=$(cat {pidfile} 2>/dev/null) && tr '\\0' ' ' < /proc/$pid/cmdline | grep -q -- --headless && kill $pid && rm -f {pidfile}"
+    );
+    if dc
+        .exec_capturing_stdout(&["sh", "-c", &kill_stale_server])
+        .is_ok()
+    {
+        log!("Stopped": "stale Neovim server on container port {port}");
+        thread::sleep(Duration::from_millis(500));
+    }
+
+    let listening_ports = dc.listening_ports()?;
+    if !listening_ports.contains(&port) {
+        return Ok(container_port.to_string());
+    }
+
+    let free_port = (port.saturating_add(1)..=port.saturating_add(100))
+        .find(|port| !listening_ports.contains(port))
+        .ok_or_else(|| miette!("no free container port found near {port}"))?;
+    log!("Warning": "container port {port} is in use, using {free_port} instead");
+
+    Ok(free_port.to_string())
 }
@@ -0,0 +1,200 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    cli::{neovim, Args, PairArgs},
+    config::{Config, TunnelBackend},
+    devcontainer::DevContainer,
+    log, pairing, secret, tunnel,
+};
+
+/// The peer must present the token within this time after connecting
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn main(config: &Config, args: &Args, pair_args: &PairArgs) -> Result<()> {
+    if let Some(invite) = &pair_args.join {
+        return join(invite);
+    }
+
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let Some(server) = neovim::running_server(&dc)? else {
+        bail!(
+            help = "start one with `dockim neovim --background`",
+            "no Neovim server is running for this workspace"
+        );
+    };
+    let backend = pair_args.backend.unwrap_or(config.tunnel.backend);
+
+    // The tunnel ends at a gate that lets through only the first connection with the token
+    let gate = TcpListener::bind("127.0.0.1:0")
+        .into_diagnostic()
+        .wrap_err("failed to listen for the peer")?;
+    let gate_port = gate.local_addr().into_diagnostic()?.port();
+    let token = secret::random_token()?;
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let interrupted = interrupted.clone();
+        move || interrupted.store(true, Ordering::SeqCst)
+    })
+    .into_diagnostic()?;
+
+    pairing::begin(&dc.workspace_folder().to_string_lossy())?;
+    let result = host(backend, gate, gate_port, &token, &server, &interrupted);
+    pairing::end()?;
+    log!("Stopped" ("pair"): "the clipboard and git credentials are available again");
+
+    result
+}
+
+fn host(
+    backend: TunnelBackend,
+    gate: TcpListener,
+    gate_port: u16,
+    token: &str,
+    server: &neovim::Server,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    log!("Restricted": "reading the host clipboard and git credentials until pairing ends");
+    log!("Starting" ("pair"): "{} tunnel to the Neovim server on localhost:{}", backend.name(), server.host_port);
+    let mut tunnel = tunnel::start_tcp(backend, gate_port)?;
+
+    let invite = format!("{}#{token}", tunnel.url());
+    log!("Invite": "the peer runs `dockim pair --join {invite}` (press Ctrl-C to stop)");
+    println!("{invite}");
+
+    let finished = Arc::new(AtomicBool::new(false));
+    let claimed = Arc::new(AtomicBool::new(false));
+    thread::spawn({
+        let (token, finished) = (token.to_string(), finished.clone());
+        let server_address = format!("localhost:{}", server.host_port);
+        move || {
+            for stream in gate.incoming().flatten() {
+                let (token, claimed, finished) = (token.clone(), claimed.clone(), finished.clone());
+                let server_address = server_address.clone();
+                thread::spawn(move || {
+                    if admit(stream, &token, &claimed, &server_address).unwrap_or(false) {
+                        finished.store(true, Ordering::SeqCst);
+                    }
+                });
+            }
+        }
+    });
+
+    while !interrupted.load(Ordering::SeqCst) && !finished.load(Ordering::SeqCst) {
+        if let Some(status) = tunnel.try_wait()? {
+            log!("Warning": "`{}` exited with {status}", backend.name());
+            break;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    Ok(())
+}
+
+/// Connects the peer to the server if it presents the token first. Returns whether it did, once it
+/// disconnects. Others are turned away, including anyone presenting the token again.
+fn admit(peer: TcpStream, token: &str, claimed: &AtomicBool, server: &str) -> Result<bool> {
+    peer.set_read_timeout(Some(TOKEN_TIMEOUT))
+        .into_diagnostic()?;
+    let mut reader = BufReader::new(peer.try_clone().into_diagnostic()?);
+    let mut line = String::new();
+    reader.read_line(&mut line).into_diagnostic()?;
+    if !secret::token_matches(token, line.trim_end()) || claimed.swap(true, Ordering::SeqCst) {
+        return Ok(false);
+    }
+    peer.set_read_timeout(None).into_diagnostic()?;
+
+    let mut server = TcpStream::connect(server)
+        .into_diagnostic()
+        .wrap_err("failed to connect to the Neovim server")?;
+    // Whatever the peer sent after the token is already buffered
+    server.write_all(reader.buffer()).into_diagnostic()?;
+
+    log!("Joined" ("pair"): "the peer is attached to the Neovim server");
+    splice(peer, server)?;
+    log!("Left" ("pair"): "the peer disconnected");
+
+    Ok(true)
+}
+
+/// Joins a session: presents the token through the tunnel and attaches Neovim on this machine.
+fn join(invite: &str) -> Result<()> {
+    let Some((url, token)) = invite.rsplit_once('#') else {
+        bail!(
+            help = "pass the whole line printed by `dockim pair`",
+            "invalid invite `{invite}`"
+        );
+    };
+    let endpoint = tunnel::connect_tcp(url)?;
+
+    // Neovim connects to a local relay, which presents the token before relaying
+    let relay = TcpListener::bind("127.0.0.1:0")
+        .into_diagnostic()
+        .wrap_err("failed to listen for Neovim")?;
+    let relay_port = relay.local_addr().into_diagnostic()?.port();
+    thread::spawn({
+        let (address, token) = (endpoint.address().to_string(), token.to_string());
+        move || {
+            let Ok((nvim, _)) = relay.accept() else {
+                return;
+            };
+            let relayed = TcpStream::connect(&address)
+                .and_then(|mut host| {
+                    writeln!(host, "{token}")?;
+                    Ok(host)
+                })
+                .into_diagnostic()
+                .and_then(|host| splice(nvim, host));
+            if let Err(e) = relayed {
+                log!("Warning": "lost the connection to the host: {e}");
+            }
+        }
+    });
+
+    log!("Joining": "{url}");
+    let status = Command::new("nvim")
+        .args([
+            "--server",
+            &format!("localhost:{relay_port}"),
+            "--remote-ui",
+        ])
+        .status()
+        .into_diagnostic()
+        .wrap_err_with(|| miette!(help = "install Neovim to join", "failed to run `nvim`"))?;
+    if !status.success() {
+        bail!("nvim exited with {status}; the invite may have been used already");
+    }
+
+    Ok(())
+}
+
+/// Copies data both ways until both sides are done.
+fn splice(a: TcpStream, b: TcpStream) -> Result<()> {
+    let (mut a_read, mut b_write) = (
+        a.try_clone().into_diagnostic()?,
+        b.try_clone().into_diagnostic()?,
+    );
+    let forward = thread::spawn(move || {
+        let _ = io::copy(&mut a_read, &mut b_write);
+        let _ = b_write.shutdown(Shutdown::Write);
+    });
+
+    let (mut b_read, mut a_write) = (b, a);
+    let _ = io::copy(&mut b_read, &mut a_write);
+    let _ = a_write.shutdown(Shutdown::Write);
+    let _ = forward.join();
+
+    Ok(())
+}
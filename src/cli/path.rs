@@ -0,0 +1,34 @@
+use std::env;
+
+use miette::{miette, IntoDiagnostic, Result};
+
+use crate::{
+    cli::{Args, PathArgs, PathCommand},
+    config::Config,
+    devcontainer::DevContainer,
+    host_path, path_mapping,
+};
+
+pub fn main(config: &Config, args: &Args, path_args: &PathArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let mappings = dc.path_mappings()?;
+
+    match &path_args.command {
+        PathCommand::ToContainer { path } => {
+            let path = match host_path::canonicalize(path) {
+                Ok(path) => path,
+                Err(_) => env::current_dir().into_diagnostic()?.join(path),
+            };
+            let container_path = path_mapping::to_container(&mappings, &path)
+                .ok_or_else(|| miette!("{} is not mounted in the container", path.display()))?;
+            println!("{container_path}");
+        }
+        PathCommand::ToHost { path } => {
+            let host_path = path_mapping::to_host(&mappings, path)
+                .ok_or_else(|| miette!("{path} is not mounted from the host"))?;
+            println!("{}", host_path.display());
+        }
+    }
+
+    Ok(())
+}
@@ -1,19 +1,53 @@
-use std::mem;
+use std::{
+    collections::HashMap,
+    mem,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
 
 use itertools::Itertools;
-use miette::{bail, Result};
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
 
 use crate::{
-    cli::{Args, PortArgs},
-    config::Config,
+    cli::{Args, PortArgs, PortCommand},
+    config::{Config, TunnelBackend},
     devcontainer::DevContainer,
+    exec, host_port, log, notify, tunnel,
 };
 
-pub fn main(_config: &Config, args: &Args, port_args: &PortArgs) -> Result<()> {
-    let dc = DevContainer::new(args.workspace_folder.clone());
+pub fn main(config: &Config, args: &Args, port_args: &PortArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config).with_no_up(port_args.no_up);
+    match &port_args.command {
+        Some(PortCommand::Share {
+            container_port,
+            backend,
+        }) => {
+            return share(
+                &dc,
+                *container_port,
+                backend.unwrap_or(config.tunnel.backend),
+            )
+        }
+        Some(PortCommand::PreloadImage { tar, save }) => {
+            return preload_image(config, tar.as_deref(), save.as_deref())
+        }
+        Some(PortCommand::Ls { watch, interval }) => {
+            return list(config, &dc, *watch, Duration::from_secs(*interval))
+        }
+        None => {}
+    }
+
+    let _lock = dc.lock()?;
+    dc.ensure_up()?;
 
     if port_args.remove_all {
         dc.remove_all_forwarded_ports()?;
+        dc.clear_registered_forwards()?;
         return Ok(());
     }
 
@@ -24,13 +58,255 @@ pub fn main(_config: &Config, args: &Args, port_args: &PortArgs) -> Result<()> {
         _ => bail!("Invalid port descriptor: {port_descriptor}"),
     };
 
+    let healthcheck = port_args.healthcheck.as_deref().map(|path| {
+        if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{path}")
+        }
+    });
+
+    // An empty or `auto` host port picks a free one
+    if !port_args.remove && matches!(host_port, "" | "auto") {
+        let mut reservation = host_port::reserve()?;
+        let service = port_args.service.as_deref();
+        mem::forget(dc.forward_reserved_port(
+            &mut reservation,
+            container_port,
+            port_args.https,
+            service,
+        )?);
+        let host_port = reservation.port().to_string();
+        dc.register_forward_to(&host_port, container_port, port_args.https, service)?;
+        dc.set_forward_healthcheck(&host_port, healthcheck.as_deref())?;
+        log!("Forwarding": "localhost:{host_port} -> container port {container_port}");
+        println!("{host_port}");
+        return Ok(());
+    }
+
     if port_args.remove {
         dc.stop_forward_port(host_port)?;
+        dc.unregister_forward(host_port)?;
     } else {
         // We need to forget because forward_port() returns a guard that will stop forwarding on
         // drop
-        mem::forget(dc.forward_port(host_port, container_port)?);
+        let service = port_args.service.as_deref();
+        mem::forget(dc.forward_port_to(host_port, container_port, port_args.https, service)?);
+        dc.register_forward_to(host_port, container_port, port_args.https, service)?;
+        dc.set_forward_healthcheck(host_port, healthcheck.as_deref())?;
+        if let Some(service) = service {
+            log!("Forwarding": "localhost:{host_port} -> {service} port {container_port}");
+        } else if port_args.https {
+            log!("Forwarding": "https://localhost:{host_port} -> container port {container_port}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Exposes the container port on a public URL until interrupted, forwarding it to the host first
+/// if it isn't already.
+fn share(dc: &DevContainer, container_port: u16, backend: TunnelBackend) -> Result<()> {
+    let lock = dc.lock()?;
+    dc.ensure_up()?;
+
+    let container_port_str = container_port.to_string();
+    let registered = dc.registered_forwards()?.into_iter().find(|forward| {
+        forward.container_port == container_port_str && forward.service.is_none() && !forward.https
+    });
+    let (host_port, _forward) = match registered {
+        Some(forward) if dc.is_forwarding(&forward.host_port)? => (forward.host_port, None),
+        _ => {
+            let mut reservation = host_port::reserve()?;
+            let guard =
+                dc.forward_reserved_port(&mut reservation, &container_port_str, false, None)?;
+            (reservation.port().to_string(), Some((reservation, guard)))
+        }
+    };
+    drop(lock);
+    let host_port: u16 = host_port.parse().into_diagnostic()?;
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let interrupted = interrupted.clone();
+        move || interrupted.store(true, Ordering::SeqCst)
+    })
+    .into_diagnostic()?;
+
+    log!("Starting" ("share"): "{} tunnel to localhost:{host_port}", backend.name());
+    let mut tunnel = tunnel::start(backend, host_port)?;
+    log!("Sharing": "{} -> container port {container_port} (press Ctrl-C to stop)", tunnel.url());
+    println!("{}", tunnel.url());
+
+    while !interrupted.load(Ordering::SeqCst) {
+        if let Some(status) = tunnel.try_wait()? {
+            log!("Warning": "`{}` exited with {status}", backend.name());
+            break;
+        }
+        thread::sleep(Duration::from_millis(200));
     }
 
+    // The tunnel and then the temporary forward are stopped on drop
+    drop(tunnel);
+    log!("Stopped" ("share"): "container port {container_port}");
+
     Ok(())
 }
+
+fn preload_image(config: &Config, tar: Option<&Path>, save: Option<&Path>) -> Result<()> {
+    let image = &config.port.socat_image;
+
+    if let Some(tar) = tar {
+        let tar_str = tar.to_string_lossy();
+        exec::exec(&["docker", "load", "--input", &tar_str])
+            .wrap_err_with(|| miette!("failed to load {}", tar.display()))?;
+        if !DevContainer::is_image_available(image) {
+            bail!(
+                help = "set `port.socat_image` to the image in the archive",
+                "{} does not contain `{image}`",
+                tar.display()
+            );
+        }
+        log!("Loaded" ("socat image"): "{image} from {}", tar.display());
+    } else if DevContainer::is_image_available(image) {
+        log!("Skipping" ("socat image"): "{image} is already available");
+    } else {
+        exec::exec(&["docker", "pull", image]).wrap_err(miette!(
+            help = "pass an archive created by `docker save` on a machine that can pull it",
+            "failed to pull `{image}`"
+        ))?;
+        log!("Pulled" ("socat image"): "{image}");
+    }
+
+    if let Some(save) = save {
+        let save_str = save.to_string_lossy();
+        exec::exec(&["docker", "save", "--output", &save_str, image])
+            .wrap_err_with(|| miette!("failed to save `{image}` to {}", save.display()))?;
+        log!("Saved" ("socat image"): "{image} to {}", save.display());
+    }
+
+    Ok(())
+}
+
+/// Result of probing a forwarded app over HTTP.
+struct Health {
+    /// `None` if no response was received
+    status: Option<u16>,
+    latency: Duration,
+}
+
+impl Health {
+    fn is_up(&self) -> bool {
+        matches!(self.status, Some(200..=399))
+    }
+}
+
+fn list(config: &Config, dc: &DevContainer, watch: bool, interval: Duration) -> Result<()> {
+    if !watch {
+        return render_forwards(dc).map(drop);
+    }
+
+    let mut was_up = HashMap::new();
+    loop {
+        // Clear the screen and move the cursor home
+        print!("\x1b[2J\x1b[H");
+        for (host_port, is_up) in render_forwards(dc)? {
+            let went_down = was_up.insert(host_port.clone(), is_up) == Some(true) && !is_up;
+            if went_down && config.notifications.enabled {
+                let body = format!("the app on localhost:{host_port} stopped responding");
+                if let Err(e) = notify::send("dockim: app is down", &body) {
+                    log!("Warning": "failed to send a notification: {e}");
+                }
+            }
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Prints the registered forwards, returning whether each app with a healthcheck is up.
+fn render_forwards(dc: &DevContainer) -> Result<Vec<(String, bool)>> {
+    let forwards = dc.registered_forwards()?;
+    if forwards.is_empty() {
+        log!("Forwards": "none");
+        return Ok(vec![]);
+    }
+
+    let forwarding = dc.forwarding_host_ports()?;
+    let mut health = vec![];
+    println!(
+        "{:<6}  {:<16}  {:<10}  {:<6}  {:>4}  {:>8}  CHECK",
+        "HOST", "TARGET", "FORWARD", "HEALTH", "CODE", "LATENCY"
+    );
+    for forward in &forwards {
+        let target = match &forward.service {
+            Some(service) => format!("{service}:{}", forward.container_port),
+            None => forward.container_port.clone(),
+        };
+        let is_forwarding = forwarding.contains(&forward.host_port);
+        let (state, code, latency) = match &forward.healthcheck {
+            Some(path) if is_forwarding => {
+                let probed = probe(&forward.host_port, forward.https, path);
+                health.push((forward.host_port.clone(), probed.is_up()));
+                (
+                    if probed.is_up() { "UP" } else { "DOWN" },
+                    probed
+                        .status
+                        .map(|s| s.to_string())
+                        .unwrap_or("-".to_string()),
+                    format!("{}ms", probed.latency.as_millis()),
+                )
+            }
+            Some(_) => {
+                health.push((forward.host_port.clone(), false));
+                ("DOWN", "-".to_string(), "-".to_string())
+            }
+            None => ("-", "-".to_string(), "-".to_string()),
+        };
+        println!(
+            "{:<6}  {:<16}  {:<10}  {:<6}  {:>4}  {:>8}  {}",
+            forward.host_port,
+            target,
+            if is_forwarding { "active" } else { "stopped" },
+            state,
+            code,
+            latency,
+            forward.healthcheck.as_deref().unwrap_or("-")
+        );
+    }
+
+    Ok(health)
+}
+
+/// Requests the path through the host port, as a browser on the host would.
+fn probe(host_port: &str, https: bool, path: &str) -> Health {
+    let scheme = if https { "https" } else { "http" };
+    let url = format!("{scheme}://localhost:{host_port}{path}");
+    let null = if cfg!(windows) { "NUL" } else { "/dev/null" };
+    // The certificate of `--https` forwards is our own, so don't verify it
+    let output = exec::capturing_stdout(&[
+        "curl",
+        "--silent",
+        "--insecure",
+        "--max-time",
+        "5",
+        "--output",
+        null,
+        "--write-out",
+        "%{http_code} %{time_total}",
+        &url,
+    ])
+    .unwrap_or_default();
+
+    let mut fields = output.split_whitespace();
+    let status = fields
+        .next()
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| *code != 0);
+    let latency = fields
+        .next()
+        .and_then(|secs| secs.parse::<f64>().ok())
+        .map(Duration::from_secs_f64)
+        .unwrap_or_default();
+
+    Health { status, latency }
+}
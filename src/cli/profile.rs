@@ -0,0 +1,149 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{Args, ProfileArgs, ProfileCommand},
+    config::Config,
+    devcontainer::{DevContainer, RegisteredForward},
+    log, state,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Profile {
+    /// Raw contents of config.toml, if any
+    config: Option<String>,
+
+    workspaces: Vec<WorkspaceProfile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkspaceProfile {
+    path: PathBuf,
+    forwards: Vec<RegisteredForward>,
+}
+
+pub fn main(config: &Config, _args: &Args, profile_args: &ProfileArgs) -> Result<()> {
+    match &profile_args.command {
+        ProfileCommand::Export { file } => export(config, file),
+        ProfileCommand::Import {
+            file,
+            config: only_config,
+            forwards: only_forwards,
+        } => {
+            // Import everything unless some parts are explicitly selected
+            let all = !only_config && !only_forwards;
+            import(config, file, all || *only_config, all || *only_forwards)
+        }
+    }
+}
+
+fn export(config: &Config, file: &Path) -> Result<()> {
+    let config_path = Config::config_file_path()?;
+    let raw_config = if config_path.exists() {
+        Some(
+            fs::read_to_string(&config_path)
+                .into_diagnostic()
+                .wrap_err("failed to read config file contents")?,
+        )
+    } else {
+        None
+    };
+
+    let mut workspaces = vec![];
+    for dir in state::workspace_state_dirs()? {
+        let Some(path) = state::recorded_workspace_folder(&dir) else {
+            continue;
+        };
+        if !path.exists() {
+            continue;
+        }
+
+        let forwards = DevContainer::new(Some(path.clone()), config).registered_forwards()?;
+        workspaces.push(WorkspaceProfile { path, forwards });
+    }
+
+    let profile = Profile {
+        config: raw_config,
+        workspaces,
+    };
+    let contents = serde_json::to_string_pretty(&profile).into_diagnostic()?;
+    fs::write(file, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", file.display()))?;
+
+    log!(
+        "Exported": "config and {} workspace(s) to {}",
+        profile.workspaces.len(),
+        file.display()
+    );
+
+    Ok(())
+}
+
+fn import(config: &Config, file: &Path, import_config: bool, import_forwards: bool) -> Result<()> {
+    let contents = fs::read_to_string(file)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", file.display()))?;
+    let profile: Profile = serde_json::from_str(&contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to parse {}", file.display()))?;
+
+    if import_config {
+        if let Some(raw_config) = &profile.config {
+            // Refuse to install a config we would fail to load afterwards
+            toml::from_str::<Config>(raw_config)
+                .into_diagnostic()
+                .wrap_err("config in the profile is invalid")?;
+
+            let config_path = Config::config_file_path()?;
+            if config_path.exists() {
+                let backup_path = config_path.with_extension("toml.bak");
+                fs::copy(&config_path, &backup_path)
+                    .into_diagnostic()
+                    .wrap_err("failed to back up current config file")?;
+                log!("Backed up": "{}", backup_path.display());
+            }
+
+            if let Some(config_dir) = config_path.parent() {
+                fs::create_dir_all(config_dir).into_diagnostic()?;
+            }
+            fs::write(&config_path, raw_config)
+                .into_diagnostic()
+                .wrap_err("failed to write config file")?;
+            log!("Imported": "{}", config_path.display());
+        }
+    }
+
+    if import_forwards {
+        for workspace in &profile.workspaces {
+            if !workspace.path.exists() {
+                log!("Skipping": "{} (not found on this machine)", workspace.path.display());
+                continue;
+            }
+
+            let dc = DevContainer::new(Some(workspace.path.clone()), config);
+            for forward in &workspace.forwards {
+                dc.register_forward_to(
+                    &forward.host_port,
+                    &forward.container_port,
+                    forward.https,
+                    forward.service.as_deref(),
+                )?;
+                dc.set_forward_healthcheck(&forward.host_port, forward.healthcheck.as_deref())?;
+            }
+            log!(
+                "Imported" ("port forwards"):
+                "{} for {}",
+                workspace.forwards.len(),
+                workspace.path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,17 @@
+use std::io::{self, BufRead, Write};
+
+use miette::{IntoDiagnostic, Result};
+
+/// Asks a yes/no question on stderr; anything but an explicit yes, including EOF, is a no.
+pub fn confirm(prompt: &str) -> Result<bool> {
+    eprint!("{prompt} [y/N] ");
+    io::stderr().flush().into_diagnostic()?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .into_diagnostic()?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
@@ -0,0 +1,219 @@
+use std::{collections::BTreeMap, env, fs, process};
+
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{build, Args, ShareConfigArgs},
+    config::Config,
+    devcontainer::{DevContainer, RegisteredForward},
+    exec, log, override_config, package_file, state, trust,
+};
+
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// File name of the bundle in gists, read back by `dockim apply`
+pub const BUNDLE_FILE_NAME: &str = "dockim-bundle.json";
+
+/// Files larger than this are build inputs rather than configuration, and are left out
+const MAX_FILE_SIZE: u64 = 256 * 1024;
+
+/// A workspace's dockim setup as written by `dockim share-config` and read by `dockim apply`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+
+    /// Contents of the shared files, by path relative to the workspace folder
+    pub files: BTreeMap<String, String>,
+
+    /// Port forwards registered by `dockim port`
+    #[serde(default)]
+    pub ports: Vec<RegisteredForward>,
+
+    /// Locked nixpkgs revision of the `nix` build backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nix_flake_lock: Option<String>,
+}
+
+pub fn main(config: &Config, args: &Args, share_config_args: &ShareConfigArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let bundle = collect(&dc)?;
+    let contents = serde_json::to_string_pretty(&bundle).into_diagnostic()? + "\n";
+
+    if share_config_args.gist {
+        let url = create_gist(&contents)?;
+        log!("Created" ("gist"): "{url}");
+        log!("Hint": "run `dockim apply {url}` in another repository to reproduce this setup");
+    } else if let Some(output) = &share_config_args.output {
+        fs::write(output, contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", output.display()))?;
+        log!("Saved" ("bundle"): "{}", output.display());
+        log!(
+            "Hint": "run `dockim apply {}` in another repository to reproduce this setup",
+            exec::shell_quote(&output.to_string_lossy())
+        );
+    } else {
+        print!("{contents}");
+    }
+
+    Ok(())
+}
+
+fn collect(dc: &DevContainer) -> Result<Bundle> {
+    let workspace_folder = dc.workspace_folder();
+    let Some(config_path) = override_config::devcontainer_json_path(workspace_folder) else {
+        bail!(
+            help = "run `dockim init` to create one",
+            "no devcontainer.json found in {}",
+            workspace_folder.display()
+        );
+    };
+
+    // The whole directory of devcontainer.json, which holds its Dockerfile, compose files and
+    // package file, unless devcontainer.json sits in the workspace folder itself
+    let config_dir = config_path.parent().unwrap_or(workspace_folder);
+    let mut paths = if config_dir == workspace_folder {
+        vec![config_path.clone()]
+    } else {
+        let entries = fs::read_dir(config_dir)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", config_dir.display()))?;
+        entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect()
+    };
+    let local_config = workspace_folder
+        .join(trust::LOCAL_CONFIG_DIR)
+        .join("config.toml");
+    if local_config.is_file() {
+        paths.push(local_config);
+    }
+    paths.sort();
+
+    let mut files = BTreeMap::new();
+    for path in paths {
+        let relative = path
+            .strip_prefix(workspace_folder)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !is_shareable(&file_name) {
+            log!("Skipping" ("share-config"): "{relative}, which is not devcontainer configuration");
+            continue;
+        }
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > MAX_FILE_SIZE) {
+            log!("Skipping" ("share-config"): "{relative}, which is larger than {} KiB", MAX_FILE_SIZE / 1024);
+            continue;
+        }
+        let Ok(contents) = fs::read_to_string(&path) else {
+            log!("Skipping" ("share-config"): "{relative}, which is not text");
+            continue;
+        };
+        log!("Adding" ("share-config"): "{relative}");
+        files.insert(relative, contents);
+    }
+
+    let ports = dc.registered_forwards()?;
+    if !ports.is_empty() {
+        log!("Adding" ("share-config"): "{} port forward(s)", ports.len());
+    }
+
+    let lock_path =
+        state::workspace_state_dir(workspace_folder)?.join(build::NIX_FLAKE_LOCK_STATE_FILE);
+    let nix_flake_lock = fs::read_to_string(lock_path).ok();
+    if nix_flake_lock.is_some() {
+        log!("Adding" ("share-config"): "the locked nixpkgs revision");
+    }
+
+    Ok(Bundle {
+        version: BUNDLE_VERSION,
+        files,
+        ports,
+        nix_flake_lock,
+    })
+}
+
+/// Whether a file of this name is devcontainer or dockim configuration, rather than something
+/// that may hold secrets like `.env` or credentials next to devcontainer.json.
+pub fn is_shareable(file_name: &str) -> bool {
+    let is_dockerfile = file_name == "Dockerfile"
+        || file_name.starts_with("Dockerfile.")
+        || file_name.ends_with(".Dockerfile");
+    let is_compose_file = (file_name.starts_with("docker-compose")
+        || file_name.starts_with("compose"))
+        && (file_name.ends_with(".yml") || file_name.ends_with(".yaml"));
+
+    matches!(
+        file_name,
+        "devcontainer.json" | ".devcontainer.json" | "config.toml"
+    ) || is_dockerfile
+        || is_compose_file
+        || package_file::FILE_NAMES.contains(&file_name)
+}
+
+/// Uploads the bundle as a secret gist with the GitHub CLI, returning its URL.
+fn create_gist(contents: &str) -> Result<String> {
+    let dir = env::temp_dir().join(format!("dockim-share-config-{}", process::id()));
+    fs::create_dir_all(&dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+    let path = dir.join(BUNDLE_FILE_NAME);
+    fs::write(&path, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    let output = exec::capturing_stdout(&[
+        "gh",
+        "gist",
+        "create",
+        "--desc",
+        "dockim setup",
+        &path.to_string_lossy(),
+    ]);
+    let _ = fs::remove_dir_all(&dir);
+    let output = output.wrap_err_with(|| {
+        miette!(
+            help = "install the GitHub CLI and run `gh auth login`",
+            "failed to create a gist"
+        )
+    })?;
+
+    Ok(output.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_shareable_accepts_config_files() {
+        for file_name in [
+            "devcontainer.json",
+            "Dockerfile",
+            "Dockerfile.dev",
+            "app.Dockerfile",
+            "docker-compose.yml",
+            "compose.override.yaml",
+            "Brewfile",
+        ] {
+            assert!(is_shareable(file_name), "{file_name}");
+        }
+    }
+
+    #[test]
+    fn is_shareable_rejects_other_files() {
+        for file_name in [
+            ".env",
+            "env.toml",
+            "hook",
+            "compose.json",
+            "post-create.sh",
+            "",
+        ] {
+            assert!(!is_shareable(file_name), "{file_name}");
+        }
+    }
+}
@@ -1,19 +1,71 @@
+use std::process;
+
 use crate::{
     cli::{Args, ShellArgs},
     config::Config,
     devcontainer::DevContainer,
+    git_credentials, log,
+    recording::Recording,
 };
 use miette::{miette, Result, WrapErr};
 
 pub fn main(config: &Config, args: &Args, shell_args: &ShellArgs) -> Result<()> {
-    let dc = DevContainer::new(args.workspace_folder.clone());
+    let dc = DevContainer::new(args.workspace_folder.clone(), config)
+        .with_user(shell_args.user.clone())
+        .with_no_up(shell_args.no_up)
+        .with_saved_env()?;
+    // Wait for a concurrent `up` instead of racing it, but don't block others while attached
+    let lock = dc.lock()?;
+    dc.ensure_up()?;
+    drop(lock);
+    git_credentials::spawn_bridge(config, &dc);
+    let workdir = dc.current_dir_in_container()?;
+    let dc = dc.with_workdir(workdir);
+
+    let shell = dc
+        .find_shell(&[&config.shell, "bash", "sh"])
+        .ok_or_else(|| miette!("no usable shell found on the container"))?;
+    if shell != config.shell {
+        log!(
+            "Warning": "`{}` is not available on the container, falling back to `{}`",
+            config.shell,
+            shell
+        );
+    }
+
+    let recording = shell_args
+        .record
+        .as_deref()
+        .map(|path| Recording::prepare(&dc, path))
+        .transpose()?;
+
+    if let Some(command) = &shell_args.command {
+        let status = dc
+            .exec_status(&Recording::wrap_if(
+                recording.as_ref(),
+                &[shell, "-c", command],
+            ))
+            .wrap_err(miette!("failed to execute `{}` on the container", shell))?;
+        if let Some(recording) = recording {
+            recording.finish(&dc)?;
+        }
+        if !status.success() {
+            process::exit(status.code().unwrap_or(1));
+        }
+
+        return Ok(());
+    }
 
-    let mut args = vec![&*config.shell];
+    let mut args = vec![shell];
     args.extend(shell_args.args.iter().map(|s| s.as_str()));
-    dc.exec(&args).wrap_err(miette!(
+    let result = dc.exec(&Recording::wrap_if(recording.as_ref(), &args));
+    if let Some(recording) = recording {
+        recording.finish(&dc)?;
+    }
+    result.wrap_err(miette!(
         help = "try `dockim build --rebuild` first",
         "failed to execute `{}` on the container",
-        config.shell
+        shell
     ))?;
 
     Ok(())
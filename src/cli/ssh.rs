@@ -0,0 +1,261 @@
+use std::{
+    fs, mem,
+    path::{Path, PathBuf},
+};
+
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{Args, SshArgs, SshCommand},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, host_path, log, state,
+};
+
+pub const SSH_STATE_FILE: &str = "ssh.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SshState {
+    pub host_alias: String,
+    pub host_port: String,
+    pub user: String,
+    pub key_path: PathBuf,
+}
+
+pub fn main(config: &Config, args: &Args, ssh_args: &SshArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+
+    match &ssh_args.command {
+        SshCommand::Enable {
+            host_port,
+            container_port,
+            host_alias,
+        } => enable(&dc, host_port, container_port, host_alias.as_deref()),
+    }
+}
+
+fn enable(
+    dc: &DevContainer,
+    host_port: &str,
+    container_port: &str,
+    host_alias: Option<&str>,
+) -> Result<()> {
+    let _lock = dc.lock()?;
+    let up_output = dc
+        .up_and_inspect()
+        .wrap_err("failed to get devcontainer status")?;
+    let needs_sudo = up_output.remote_user != "root";
+
+    let key_path = state::ensure_workspace_state_dir(dc.workspace_folder())?
+        .join("ssh")
+        .join("id_ed25519");
+    ensure_key_pair(&key_path)?;
+    let public_key = fs::read_to_string(key_path.with_extension("pub"))
+        .into_diagnostic()
+        .wrap_err("failed to read generated public key")?;
+
+    install_sshd(dc, needs_sudo)?;
+    authorize_key(dc, public_key.trim())?;
+    start_sshd(dc, needs_sudo, container_port)?;
+
+    if !dc.is_forwarding(host_port)? {
+        // We need to forget because forward_port() returns a guard that will stop forwarding on
+        // drop
+        mem::forget(dc.forward_port(host_port, container_port)?);
+    }
+    dc.register_forward(host_port, container_port, false)?;
+
+    let host_alias = match host_alias {
+        Some(host_alias) => host_alias.to_string(),
+        None => default_host_alias(dc)?,
+    };
+    let ssh_state = SshState {
+        host_alias,
+        host_port: host_port.to_string(),
+        user: up_output.remote_user,
+        key_path,
+    };
+    write_ssh_config(&ssh_state)?;
+    state::save(dc.workspace_folder(), SSH_STATE_FILE, &ssh_state)?;
+
+    log!("Enabled" ("ssh"): "connect with `ssh {}`", ssh_state.host_alias);
+
+    Ok(())
+}
+
+fn ensure_key_pair(key_path: &Path) -> Result<()> {
+    if key_path.exists() {
+        return Ok(());
+    }
+
+    let key_dir = key_path
+        .parent()
+        .ok_or_else(|| miette!("invalid key path: {}", key_path.display()))?;
+    fs::create_dir_all(key_dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", key_dir.display()))?;
+
+    exec::exec(&[
+        "ssh-keygen",
+        "-q",
+        "-t",
+        "ed25519",
+        "-N",
+        "",
+        "-C",
+        "dockim",
+        "-f",
+        &key_path.to_string_lossy(),
+    ])
+    .wrap_err("failed to generate ssh key pair")
+}
+
+fn install_sshd(dc: &DevContainer, needs_sudo: bool) -> Result<()> {
+    if dc.exec(&["test", "-x", "/usr/sbin/sshd"]).is_ok() {
+        return Ok(());
+    }
+
+    let sudo = if needs_sudo { "sudo " } else { "" };
+    dc.exec(&[
+        "sh",
+        "-c",
+        &format!("{sudo}apt-get update && {sudo}apt-get -y install openssh-server"),
+    ])
+    .wrap_err("failed to install openssh-server on the container")
+}
+
+fn authorize_key(dc: &DevContainer, public_key: &str) -> Result<()> {
+    let script = concat!(
+        "mkdir -p ~/.ssh && chmod 700 ~/.ssh && ",
+        "key=$(cat) && ",
+        "(grep -qxF \"$key\" ~/.ssh/authorized_keys 2>/dev/null || ",
+        "echo \"$key\" >> ~/.ssh/authorized_keys) && ",
+        "chmod 600 ~/.ssh/authorized_keys",
+    );
+    dc.exec_with_bytes_stdin(&["sh", "-c", script], public_key.as_bytes())
+        .wrap_err("failed to authorize ssh key on the container")
+}
+
+fn start_sshd(dc: &DevContainer, needs_sudo: bool, container_port: &str) -> Result<()> {
+    let sudo = if needs_sudo { "sudo " } else { "" };
+    let cmds = [
+        format!("{sudo}mkdir -p /run/sshd"),
+        format!(
+            "(pgrep -f 'sshd -p {container_port}' >/dev/null || {sudo}/usr/sbin/sshd -p {container_port} -o PasswordAuthentication=no -o PubkeyAuthentication=yes)"
+        ),
+    ];
+    dc.exec(&["sh", "-c", &cmds.join(" && ")])
+        .wrap_err("failed to start sshd on the container")
+}
+
+fn default_host_alias(dc: &DevContainer) -> Result<String> {
+    let workspace_folder = host_path::canonicalize(dc.workspace_folder())
+        .into_diagnostic()
+        .wrap_err("failed to resolve workspace folder")?;
+    let name = workspace_folder
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "workspace".to_string());
+
+    Ok(format!("dockim-{name}"))
+}
+
+/// Returns the ~/.ssh/config stanza for connecting to the container.
+pub fn ssh_config_entry(ssh_state: &SshState) -> String {
+    [
+        format!("Host {}", ssh_state.host_alias),
+        "    HostName localhost".to_string(),
+        format!("    Port {}", ssh_state.host_port),
+        // Quoted, since the state dir is under "Application Support" on macOS
+        format!("    User \"{}\"", ssh_state.user),
+        format!("    IdentityFile \"{}\"", ssh_state.key_path.display()),
+        "    IdentitiesOnly yes".to_string(),
+        "    StrictHostKeyChecking no".to_string(),
+        "    UserKnownHostsFile /dev/null".to_string(),
+    ]
+    .join("\n")
+}
+
+/// Marks the start of an entry dockim wrote to ~/.ssh/config, followed by its host alias
+pub const SSH_CONFIG_BEGIN_MARKER: &str = "# dockim: begin ";
+
+pub fn ssh_config_path() -> Result<PathBuf> {
+    Ok(dirs::home_dir()
+        .ok_or_else(|| miette!("failed to get local home directory"))?
+        .join(".ssh")
+        .join("config"))
+}
+
+fn write_ssh_config(ssh_state: &SshState) -> Result<()> {
+    update_ssh_config(&ssh_state.host_alias, Some(&ssh_config_entry(ssh_state)))
+}
+
+/// Removes the ~/.ssh/config entry of the workspace, whose key goes with the workspace state.
+pub fn remove_ssh_config(dc: &DevContainer) -> Result<()> {
+    let ssh_state: Option<SshState> = state::load(dc.workspace_folder(), SSH_STATE_FILE)?;
+    let Some(ssh_state) = ssh_state else {
+        return Ok(());
+    };
+
+    update_ssh_config(&ssh_state.host_alias, None)?;
+    log!("Removed" ("ssh"): "{} from {}", ssh_state.host_alias, ssh_config_path()?.display());
+
+    Ok(())
+}
+
+/// Replaces our entry for `host_alias` with `entry`, or removes it.
+fn update_ssh_config(host_alias: &str, entry: Option<&str>) -> Result<()> {
+    let config_path = ssh_config_path()?;
+    if entry.is_none() && !config_path.exists() {
+        return Ok(());
+    }
+    if let Some(ssh_dir) = config_path.parent() {
+        fs::create_dir_all(ssh_dir)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to create {}", ssh_dir.display()))?;
+    }
+
+    let current = if config_path.exists() {
+        fs::read_to_string(&config_path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", config_path.display()))?
+    } else {
+        String::new()
+    };
+
+    let begin_marker = format!("{SSH_CONFIG_BEGIN_MARKER}{host_alias}");
+    let end_marker = format!("# dockim: end {host_alias}");
+
+    // Drop our previous entry for the same alias, if any
+    let mut lines = vec![];
+    let mut in_entry = false;
+    for line in current.lines() {
+        if line == begin_marker {
+            in_entry = true;
+        } else if line == end_marker {
+            in_entry = false;
+        } else if !in_entry {
+            lines.push(line);
+        }
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let mut updated = lines.join("\n");
+    if let Some(entry) = entry {
+        if !updated.is_empty() {
+            updated.push_str("\n\n");
+        }
+        updated.push_str(&[begin_marker.as_str(), entry, end_marker.as_str()].join("\n"));
+    }
+    if !updated.is_empty() {
+        updated.push('\n');
+    }
+
+    fs::write(&config_path, updated)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", config_path.display()))
+}
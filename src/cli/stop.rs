@@ -0,0 +1,131 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    cli::{
+        jobs::{self, Job, JOBS_STATE_FILE},
+        neovim, Args, StopArgs,
+    },
+    config::Config,
+    devcontainer::DevContainer,
+    exec, log, state,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn main(config: &Config, args: &Args, stop_args: &StopArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    let _lock = dc.lock()?;
+    if dc.container_status()?.as_deref() != Some("running") {
+        log!("Skipping": "the devcontainer of this workspace is not running");
+        return Ok(());
+    }
+
+    if !stop_args.force {
+        shut_down_gracefully(config, &dc)?;
+    }
+    for container_id in dc.find_container_ids()? {
+        exec::exec(&["docker", "stop", &container_id]).wrap_err("failed to stop devcontainer")?;
+    }
+    // Nothing started by dockim survives the stop
+    state::save(dc.workspace_folder(), JOBS_STATE_FILE, &Vec::<Job>::new())?;
+    log!("Stopped": "the devcontainer; `dockim up` starts it again");
+
+    Ok(())
+}
+
+/// Asks what runs on the container to exit before it is stopped: the Neovim server,
+/// `stop.commands` and the jobs of `dockim exec --detach`. Whatever is still running after
+/// `stop.timeout_secs` is left to `docker stop`.
+pub fn shut_down_gracefully(config: &Config, dc: &DevContainer) -> Result<()> {
+    if dc.container_status()?.as_deref() != Some("running") {
+        return Ok(());
+    }
+    let deadline = Instant::now() + Duration::from_secs(config.stop.timeout_secs);
+
+    if let Some(server) = neovim::running_server(dc)? {
+        log!("Stopping" ("neovim"): "the server on localhost:{}", server.host_port);
+        // `:qa` refuses to quit with unsaved changes, which then stay in the swap files
+        let _ = dc.exec_capturing_stdout(&[
+            &server.nvim,
+            "--headless",
+            "--server",
+            &server.address(),
+            "--remote-send",
+            "<C-\\><C-N>:qa<CR>",
+        ]);
+    }
+
+    for command in &config.stop.commands {
+        log!("Running" ("stop"): "{command}");
+        let mut child = dc
+            .spawn(&["sh", "-c", command])
+            .wrap_err_with(|| miette!("failed to run `{command}` on the container"))?;
+        let status = loop {
+            if let Some(status) = child.try_wait().into_diagnostic()? {
+                break Some(status);
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        match status {
+            Some(status) if status.success() => {}
+            Some(status) => log!("Warning": "`{command}` failed with {status}"),
+            None => log!("Warning": "`{command}` timed out"),
+        }
+    }
+
+    let jobs: Vec<Job> = state::load(dc.workspace_folder(), JOBS_STATE_FILE)?;
+    if !jobs.is_empty() {
+        // Signal the whole process group of each job, as `dockim kill` does
+        let pids = jobs.iter().map(|job| job.pid).join(" ");
+        dc.exec_capturing_stdout(&[
+            "sh",
+            "-c",
+            &format!("for pid in {pids}; do kill -TERM -- -$pid 2>/dev/null; done; true"),
+        ])
+        .wrap_err("failed to stop jobs on the container")?;
+        log!("Stopping" ("jobs"): "{}", jobs.iter().map(|job| job.id).join(", "));
+    }
+
+    loop {
+        let running_jobs = if jobs.is_empty() {
+            vec![]
+        } else {
+            jobs::running_pids(dc, &jobs)?
+        };
+        let server = neovim::running_server(dc)?;
+        if running_jobs.is_empty() && server.is_none() {
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            let mut remaining = jobs
+                .iter()
+                .filter(|job| running_jobs.contains(&job.pid))
+                .map(|job| format!("job {}", job.id))
+                .collect_vec();
+            if server.is_some() {
+                remaining.push("the Neovim server (unsaved changes?)".to_string());
+            }
+            log!(
+                "Warning": "still running after {}s: {}; stopping anyway",
+                config.stop.timeout_secs,
+                remaining.join(", ")
+            );
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(())
+}
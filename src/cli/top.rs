@@ -0,0 +1,163 @@
+use std::{collections::BTreeMap, thread, time::Duration};
+
+use itertools::Itertools;
+use miette::{miette, Result, WrapErr};
+
+use crate::{
+    cli::{
+        jobs::{Job, JOBS_STATE_FILE},
+        Args, TopArgs,
+    },
+    config::Config,
+    devcontainer::DevContainer,
+    log, state,
+};
+
+/// Lists processes as `pid ppid sid %cpu %mem elapsed command`, from ps if the image has a full
+/// one and from /proc otherwise. The first line is the pid of the script itself.
+const PROCESS_LIST_SCRIPT: &str = r#"echo $$
+ps -eo pid=,ppid=,sid=,pcpu=,pmem=,etime=,args= 2>/dev/null && exit
+for dir in /proc/[0-9]*; do
+    read -r stat < "$dir/stat" 2>/dev/null || continue
+    set -- ${stat##*) }
+    cmd=$(tr '\0' ' ' < "$dir/cmdline" 2>/dev/null || true)
+    [ -n "$cmd" ] || cmd="[$(cat "$dir/comm" 2>/dev/null || true)]"
+    echo "${dir#/proc/} $2 $4 - - - $cmd"
+done"#;
+
+/// Commands longer than this are cut off
+const MAX_COMMAND_WIDTH: usize = 100;
+
+const SHELLS: &[&str] = &["bash", "zsh", "fish", "sh", "dash", "ash", "nu"];
+
+#[derive(Debug, Clone)]
+struct Process {
+    pid: u32,
+    ppid: u32,
+    sid: u32,
+    cpu: String,
+    mem: String,
+    elapsed: String,
+    command: String,
+}
+
+pub fn main(config: &Config, args: &Args, top_args: &TopArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config);
+    dc.ensure_up()?;
+
+    if !top_args.watch {
+        return render(&dc);
+    }
+
+    loop {
+        // Clear the screen and move the cursor home
+        print!("\x1b[2J\x1b[H");
+        render(&dc)?;
+        thread::sleep(Duration::from_secs(top_args.interval));
+    }
+}
+
+fn render(dc: &DevContainer) -> Result<()> {
+    let processes = list_processes(dc)?;
+    let jobs: Vec<Job> = state::load(dc.workspace_folder(), JOBS_STATE_FILE)?;
+
+    let sessions = processes.iter().into_group_map_by(|process| process.sid);
+    let mut groups: BTreeMap<String, Vec<&Process>> = BTreeMap::new();
+    for (sid, processes) in sessions {
+        groups
+            .entry(session_kind(sid, &processes, &jobs))
+            .or_default()
+            .extend(processes);
+    }
+
+    println!(
+        "{:>7} {:>7} {:>5} {:>5} {:>11}  COMMAND",
+        "PID", "PPID", "%CPU", "%MEM", "ELAPSED"
+    );
+    for (kind, mut processes) in groups {
+        processes.sort_by_key(|process| process.pid);
+        println!("# {kind}");
+        for process in processes {
+            let mut command = process.command.clone();
+            if command.chars().count() > MAX_COMMAND_WIDTH {
+                command = command
+                    .chars()
+                    .take(MAX_COMMAND_WIDTH - 1)
+                    .collect::<String>()
+                    + "…";
+            }
+            println!(
+                "{:>7} {:>7} {:>5} {:>5} {:>11}  {command}",
+                process.pid, process.ppid, process.cpu, process.mem, process.elapsed
+            );
+        }
+    }
+    log!("Hint": "stop a process with `dockim exec kill <PID>`");
+
+    Ok(())
+}
+
+fn list_processes(dc: &DevContainer) -> Result<Vec<Process>> {
+    let output = dc
+        .exec_script_capturing_stdout(PROCESS_LIST_SCRIPT)
+        .wrap_err("failed to list processes on the container")?;
+    let mut lines = output.lines();
+    let script_pid: u32 = lines
+        .next()
+        .and_then(|pid| pid.trim().parse().ok())
+        .ok_or_else(|| miette!("unexpected process list from the container"))?;
+
+    Ok(lines
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse().ok()?;
+            let ppid = fields.next()?.parse().ok()?;
+            let sid = fields.next()?.parse().ok()?;
+            let cpu = fields.next()?.to_string();
+            let mem = fields.next()?.to_string();
+            let elapsed = fields.next()?.to_string();
+            let command = fields.join(" ");
+
+            Some(Process {
+                pid,
+                ppid,
+                sid,
+                cpu,
+                mem,
+                elapsed,
+                command,
+            })
+        })
+        .filter(|process| process.pid != script_pid && process.ppid != script_pid)
+        .collect())
+}
+
+/// Tells what started the processes of a session.
+fn session_kind(sid: u32, processes: &[&Process], jobs: &[Job]) -> String {
+    if processes
+        .iter()
+        .any(|process| process.command.contains("nvim --headless --listen"))
+    {
+        return "Neovim servers".to_string();
+    }
+
+    if let Some(job) = jobs.iter().find(|job| job.pid == sid) {
+        return format!("job {}", job.id);
+    }
+
+    let leader = processes
+        .iter()
+        .find(|process| process.pid == sid)
+        .and_then(|leader| leader.command.split_whitespace().next())
+        .map(|program| {
+            program
+                .rsplit('/')
+                .next()
+                .unwrap_or(program)
+                .trim_start_matches('-')
+        });
+    match leader {
+        Some(program) if SHELLS.contains(&program) => "shells".to_string(),
+        _ => "other".to_string(),
+    }
+}
@@ -0,0 +1,21 @@
+use std::path::PathBuf;
+
+use miette::Result;
+
+use crate::{
+    cli::{Args, TrustArgs},
+    config::Config,
+    log, trust,
+};
+
+pub fn main(_config: &Config, args: &Args, _trust_args: &TrustArgs) -> Result<()> {
+    let workspace_folder = args
+        .workspace_folder
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    trust::set_trusted(&workspace_folder, true)?;
+
+    log!("Trusted": "{}", workspace_folder.display());
+
+    Ok(())
+}
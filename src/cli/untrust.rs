@@ -0,0 +1,21 @@
+use std::path::PathBuf;
+
+use miette::Result;
+
+use crate::{
+    cli::{Args, UntrustArgs},
+    config::Config,
+    log, trust,
+};
+
+pub fn main(_config: &Config, args: &Args, _untrust_args: &UntrustArgs) -> Result<()> {
+    let workspace_folder = args
+        .workspace_folder
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    trust::set_trusted(&workspace_folder, false)?;
+
+    log!("Untrusted": "{}", workspace_folder.display());
+
+    Ok(())
+}
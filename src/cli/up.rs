@@ -1,12 +1,219 @@
-use miette::Result;
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use crate::{config::Config, devcontainer::DevContainer};
+use miette::{bail, Result};
 
-use super::{Args, UpArgs};
+use crate::{
+    config::{Config, ConfigChangeAction, NetworkMode},
+    devcontainer::{DevContainer, ServiceStatus},
+    devcontainer_config, log, notify, platform,
+    scripting::{self, Scripts},
+    shared_services,
+    vm_provider::VmProvider,
+};
 
-pub fn main(_config: &Config, args: &Args, up_args: &UpArgs) -> Result<()> {
-    let dc = DevContainer::new(args.workspace_folder.clone());
-    dc.up(up_args.rebuild, up_args.build_no_cache)?;
+use super::{build, Args, UpArgs};
+
+pub fn main(config: &Config, args: &Args, up_args: &UpArgs) -> Result<()> {
+    let result = up(config, args, up_args);
+    if up_args.rebuild {
+        notify::finished(config, "dockim up --rebuild", result)
+    } else {
+        result
+    }
+}
+
+fn up(config: &Config, args: &Args, up_args: &UpArgs) -> Result<()> {
+    let mut config = config.clone();
+    if up_args.offline {
+        config.network.mode = NetworkMode::None;
+    }
+    if up_args.gui {
+        config.container.gui = true;
+    }
+    if up_args.readable_name {
+        config.container.readable_name = true;
+    }
+    let config = &config;
+
+    let dc = DevContainer::new(args.workspace_folder.clone(), config)
+        .with_no_initialize(up_args.no_initialize);
+    let _lock = dc.lock()?;
+    build::restore_cpu_limit(&dc)?;
+    let applies_to_new_only = up_args.offline || up_args.gui || up_args.readable_name;
+    if applies_to_new_only && !up_args.rebuild && !dc.find_container_ids()?.is_empty() {
+        log!("Warning": "--offline, --gui and --readable-name only apply to new containers; pass --rebuild to recreate it");
+    }
+
+    if let Some(shared) = &config.services.shared {
+        shared_services::ensure_running(shared)?;
+    }
+
+    let mut rebuild = up_args.rebuild;
+    if !rebuild && config.up.on_config_change != ConfigChangeAction::Ignore {
+        if let Some(true) = dc.is_config_changed()? {
+            if config.up.on_config_change == ConfigChangeAction::Rebuild {
+                log!("Rebuilding": "devcontainer configuration changed since the container was created");
+                rebuild = true;
+            } else {
+                log!("Warning": "devcontainer configuration changed since the container was created");
+                log!("Hint": "run `dockim up --rebuild` to apply it");
+            }
+        }
+    }
+
+    if up_args.blue_green {
+        dc.up_blue_green(up_args.build_no_cache)?;
+    } else {
+        dc.up(rebuild, up_args.build_no_cache)?;
+    }
+    check_clock_skew(config, &dc)?;
+    platform::warn_if_emulated(&dc);
+    dc.grant_docker_socket_access()?;
+    dc.own_excluded_mounts()?;
+
+    if let Some(shared) = &config.services.shared {
+        let up_output = dc.up_and_inspect()?;
+        shared_services::attach(shared, &up_output.container_id)?;
+    }
+
+    if devcontainer_config::load(dc.workspace_folder())?.is_compose() {
+        let services = if up_args.wait_healthy {
+            wait_healthy(&dc)?
+        } else {
+            dc.compose_services()?
+        };
+        print_services(&services);
+    }
+
+    let summary = dc.reconcile_forwards()?;
+    if !summary.is_empty() {
+        log!(
+            "Reconciled" ("port forwards"):
+            "{} restored, {} dropped, {} unchanged",
+            summary.restored,
+            summary.dropped,
+            summary.unchanged
+        );
+    }
+
+    Scripts::load()?.run_hook(scripting::ON_UP, &dc)?;
 
     Ok(())
 }
+
+/// Clocks further apart than this break TLS certificate checks and build caches
+const CLOCK_SKEW_TOLERANCE_SECS: u64 = 5;
+
+fn check_clock_skew(config: &Config, dc: &DevContainer) -> Result<()> {
+    let Some(skew) = clock_skew(dc) else {
+        return Ok(());
+    };
+    if skew.unsigned_abs() <= CLOCK_SKEW_TOLERANCE_SECS {
+        return Ok(());
+    }
+
+    let direction = if skew > 0 { "ahead of" } else { "behind" };
+    log!(
+        "Warning": "the container's clock is {}s {direction} the host's, which breaks TLS and build caches",
+        skew.unsigned_abs()
+    );
+
+    let vm_provider = VmProvider::detect();
+    if !vm_provider.can_sync_clock() {
+        log!("Hint": "restart {} to resync its clock", vm_provider.name());
+        return Ok(());
+    }
+    if !config.up.fix_clock_skew {
+        log!(
+            "Hint": "set `up.fix_clock_skew = true` to let `dockim up` set the clock of the {} VM",
+            vm_provider.name()
+        );
+        return Ok(());
+    }
+
+    vm_provider.sync_clock()?;
+    match clock_skew(dc) {
+        Some(skew) if skew.unsigned_abs() > CLOCK_SKEW_TOLERANCE_SECS => {
+            log!(
+                "Warning": "the container's clock is still {}s off; restart {} to resync it",
+                skew.unsigned_abs(),
+                vm_provider.name()
+            );
+        }
+        _ => log!("Synchronized" ("clock"): "{} VM", vm_provider.name()),
+    }
+
+    Ok(())
+}
+
+/// Seconds the container's clock is ahead of the host's, if the container can tell its time.
+fn clock_skew(dc: &DevContainer) -> Option<i64> {
+    let now = || SystemTime::now().duration_since(UNIX_EPOCH).ok();
+    let before = now()?;
+    let container = dc.exec_capturing_stdout(&["date", "+%s"]).ok()?;
+    let after = now()?;
+
+    let container = container.trim().parse::<i64>().ok()?;
+    // Compare with the middle of the round trip
+    let host = ((before + after) / 2).as_secs() as i64;
+    Some(container - host)
+}
+
+const WAIT_HEALTHY_TIMEOUT: Duration = Duration::from_secs(300);
+
+fn wait_healthy(dc: &DevContainer) -> Result<Vec<ServiceStatus>> {
+    let started = Instant::now();
+    log!("Waiting": "compose services to become healthy");
+    loop {
+        let services = dc.compose_services()?;
+        let unhealthy = services
+            .iter()
+            .filter(|service| service.health.as_deref() == Some("unhealthy"))
+            .map(|service| service.service.as_str())
+            .collect::<Vec<_>>();
+        if !unhealthy.is_empty() {
+            print_services(&services);
+            bail!(
+                help = "see `dockim compose logs` for details",
+                "unhealthy services: {}",
+                unhealthy.join(", ")
+            );
+        }
+
+        let is_ready = services
+            .iter()
+            .all(|service| service.health.as_deref().unwrap_or("healthy") == "healthy");
+        if is_ready {
+            return Ok(services);
+        }
+
+        if started.elapsed() > WAIT_HEALTHY_TIMEOUT {
+            print_services(&services);
+            bail!(
+                "services did not become healthy within {} seconds",
+                WAIT_HEALTHY_TIMEOUT.as_secs()
+            );
+        }
+        thread::sleep(Duration::from_secs(2));
+    }
+}
+
+fn print_services(services: &[ServiceStatus]) {
+    println!(
+        "{:<20}  {:<10}  {:<10}  {:>8}  PORTS",
+        "SERVICE", "STATE", "HEALTH", "RESTARTS"
+    );
+    for service in services {
+        println!(
+            "{:<20}  {:<10}  {:<10}  {:>8}  {}",
+            service.service,
+            service.state,
+            service.health.as_deref().unwrap_or("-"),
+            service.restart_count,
+            service.ports.join(", ")
+        );
+    }
+}
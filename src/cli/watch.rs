@@ -0,0 +1,332 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Child},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{
+    cli::{Args, WatchArgs},
+    config::Config,
+    devcontainer::DevContainer,
+    exec, git_credentials, host_path, log,
+};
+
+/// Runs the command in a session of its own, so that cancelling it stops its children too.
+/// `setsid` doesn't fork since a background job is never a process group leader.
+const RUN_SCRIPT: &str = r#"pid_file=$1
+shift
+setsid "$@" &
+echo $! >"$pid_file"
+wait $!
+status=$?
+rm -f "$pid_file"
+exit $status"#;
+
+pub fn main(config: &Config, args: &Args, watch_args: &WatchArgs) -> Result<()> {
+    let dc = DevContainer::new(args.workspace_folder.clone(), config).with_saved_env()?;
+    let lock = dc.lock()?;
+    dc.ensure_up()?;
+    drop(lock);
+    git_credentials::spawn_bridge(config, &dc);
+    let workdir = dc.current_dir_in_container()?;
+    let dc = dc.with_workdir(workdir);
+
+    let workspace_folder = host_path::canonicalize(dc.workspace_folder())
+        .into_diagnostic()
+        .wrap_err("failed to resolve workspace folder")?;
+    // Absolute, so that they can be compared with what git reports
+    let paths = if watch_args.path.is_empty() {
+        vec![workspace_folder.clone()]
+    } else {
+        watch_args
+            .path
+            .iter()
+            .map(|path| {
+                host_path::canonicalize(path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| miette!("failed to resolve {}", path.display()))
+            })
+            .collect::<Result<_>>()?
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })
+    .into_diagnostic()
+    .wrap_err("failed to watch files")?;
+    let ignored = ignored_dirs(&workspace_folder);
+    for path in &paths {
+        watch_tree(&mut watcher, path, &ignored)?;
+    }
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let interrupted = interrupted.clone();
+        move || interrupted.store(true, Ordering::SeqCst)
+    })
+    .into_diagnostic()?;
+
+    let debounce = Duration::from_millis(watch_args.debounce);
+    let mut run = Some(Run::start(&dc, &watch_args.command)?);
+    log!(
+        "Watching": "{} (press Ctrl-C to stop)",
+        paths.iter().map(|path| path.display()).join(", ")
+    );
+
+    while !interrupted.load(Ordering::SeqCst) {
+        let first = match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(finished) = &mut run {
+                    if finished.try_finish()? {
+                        run = None;
+                    }
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // Wait for the burst of changes, e.g. from saving several files, to settle
+        let mut changed = BTreeSet::new();
+        let mut created_dirs = BTreeSet::new();
+        let mut pending = Some(first);
+        let mut last_change = Instant::now();
+        while let Some(event) = pending.take() {
+            match event {
+                Ok(event) if !event.kind.is_access() => {
+                    if event.kind.is_create() {
+                        created_dirs
+                            .extend(event.paths.iter().filter(|path| path.is_dir()).cloned());
+                    }
+                    changed.extend(event.paths);
+                    last_change = Instant::now();
+                }
+                Ok(_) => {}
+                Err(e) => log!("Warning": "file watcher: {e}"),
+            }
+
+            let remaining = debounce.saturating_sub(last_change.elapsed());
+            pending = rx.recv_timeout(remaining).ok();
+        }
+
+        // Directories aren't watched recursively, so new ones need watches of their own
+        let created_dirs = relevant_changes(&workspace_folder, created_dirs);
+        if !created_dirs.is_empty() {
+            let ignored = ignored_dirs(&workspace_folder);
+            for dir in &created_dirs {
+                watch_tree(&mut watcher, dir, &ignored)?;
+            }
+        }
+
+        let changed = relevant_changes(&workspace_folder, changed);
+        let Some(first) = changed.first() else {
+            continue;
+        };
+        match changed.len() {
+            1 => log!("Changed": "{}", first.display()),
+            n => log!("Changed": "{} and {} more", first.display(), n - 1),
+        }
+
+        if let Some(running) = run.take() {
+            running.cancel(&dc)?;
+        }
+        run = Some(Run::start(&dc, &watch_args.command)?);
+    }
+
+    if let Some(running) = run {
+        running.cancel(&dc)?;
+    }
+    log!("Stopped" ("watch"): "{}", watch_args.command.join(" "));
+
+    Ok(())
+}
+
+/// One run of the command on the container.
+struct Run {
+    child: Child,
+    pid_file: String,
+    started_at: Instant,
+}
+
+impl Run {
+    fn start(dc: &DevContainer, command: &[String]) -> Result<Self> {
+        // A file of its own for each run, so that cancelling a run never reads the pid of another
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let pid_file = format!(
+            "/tmp/dockim-watch-{}-{}.pid",
+            process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed)
+        );
+        let args = ["sh", "-c", RUN_SCRIPT, "sh", &pid_file]
+            .into_iter()
+            .map(str::to_string)
+            .chain(command.iter().cloned())
+            .collect_vec();
+        let child = dc
+            .spawn(&args)
+            .wrap_err("failed to run the command on the container")?;
+
+        Ok(Run {
+            child,
+            pid_file,
+            started_at: Instant::now(),
+        })
+    }
+
+    /// Reports the result if the command has finished.
+    fn try_finish(&mut self) -> Result<bool> {
+        let Some(status) = self.child.try_wait().into_diagnostic()? else {
+            return Ok(false);
+        };
+
+        let elapsed = self.started_at.elapsed().as_secs_f64();
+        if status.success() {
+            log!("Finished" ("watch"): "in {elapsed:.1}s; waiting for changes");
+        } else {
+            log!("Failed" ("watch"): "{status} after {elapsed:.1}s; waiting for changes");
+        }
+
+        Ok(true)
+    }
+
+    /// Stops the command and its children on the container. Killing `devcontainer exec` on the
+    /// host alone would leave them running.
+    fn cancel(mut self, dc: &DevContainer) -> Result<()> {
+        if self.child.try_wait().into_diagnostic()?.is_none() {
+            log!("Cancelling" ("watch"): "the previous run");
+            dc.exec(&[
+                "sh",
+                "-c",
+                &format!(
+                    "pid=$(cat {pid_file} 2>/dev/null) && kill -TERM -- -$pid 2>/dev/null; true",
+                    pid_file = self.pid_file
+                ),
+            ])
+            .wrap_err("failed to stop the command on the container")?;
+        }
+        let _ = self.child.wait();
+
+        Ok(())
+    }
+}
+
+/// Watches `path` and the directories below it, except `.git` and `ignored`. Ignored directories
+/// such as `target/` and `node_modules/` can hold enough directories to exhaust inotify watches.
+fn watch_tree(
+    watcher: &mut RecommendedWatcher,
+    path: &Path,
+    ignored: &BTreeSet<PathBuf>,
+) -> Result<()> {
+    watcher
+        .watch(path, RecursiveMode::NonRecursive)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to watch {}", path.display()))?;
+
+    let mut dirs = subdirs(path, ignored);
+    while let Some(dir) = dirs.pop() {
+        // It may be gone already
+        if watcher.watch(&dir, RecursiveMode::NonRecursive).is_ok() {
+            dirs.extend(subdirs(&dir, ignored));
+        }
+    }
+
+    Ok(())
+}
+
+fn subdirs(dir: &Path, ignored: &BTreeSet<PathBuf>) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name().is_some_and(|name| name != ".git") && !ignored.contains(path)
+        })
+        .collect()
+}
+
+/// Directories ignored by git as a whole, which is empty outside a git repository.
+fn ignored_dirs(workspace_folder: &Path) -> BTreeSet<PathBuf> {
+    let workspace = workspace_folder.to_string_lossy();
+    let candidates = exec::capturing_stdout(&[
+        "git",
+        "-C",
+        &workspace,
+        "ls-files",
+        "--others",
+        "--ignored",
+        "--exclude-standard",
+        "--directory",
+    ])
+    .unwrap_or_default();
+    let candidates = candidates
+        .lines()
+        .filter_map(|line| line.strip_suffix('/'))
+        .collect_vec();
+    if candidates.is_empty() {
+        return BTreeSet::new();
+    }
+
+    // `ls-files` also lists directories that merely contain nothing but ignored files
+    let ignored = exec::capturing_stdout(
+        &["git", "-C", &workspace, "check-ignore", "--"]
+            .into_iter()
+            .chain(candidates)
+            .collect_vec(),
+    )
+    .unwrap_or_default();
+
+    ignored
+        .lines()
+        .map(|dir| host_path::join(workspace_folder, dir))
+        .collect()
+}
+
+/// Drops changes inside `.git` and to files ignored by git, asking git itself so that nested and
+/// global ignore files are respected.
+fn relevant_changes(workspace_folder: &Path, changed: BTreeSet<PathBuf>) -> Vec<PathBuf> {
+    let changed = changed
+        .into_iter()
+        .filter(|path| !path.components().any(|c| c.as_os_str() == ".git"))
+        .collect_vec();
+    if changed.is_empty() {
+        return changed;
+    }
+
+    // Fails when nothing is ignored or outside a git repository
+    let workspace_folder = workspace_folder.to_string_lossy();
+    let ignored = exec::capturing_stdout(
+        &["git", "-C", &workspace_folder, "check-ignore", "--"]
+            .into_iter()
+            .map(str::to_string)
+            .chain(
+                changed
+                    .iter()
+                    .map(|path| path.to_string_lossy().to_string()),
+            )
+            .collect_vec(),
+    )
+    .unwrap_or_default();
+    let ignored: BTreeSet<&Path> = ignored.lines().map(Path::new).collect();
+
+    changed
+        .into_iter()
+        .filter(|path| !ignored.contains(path.as_path()))
+        .collect()
+}
@@ -1,10 +1,33 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-use miette::{miette, Context, IntoDiagnostic, Result};
+use itertools::Itertools;
+
+use miette::{bail, miette, Context, IntoDiagnostic, Result};
 use serde::{Deserialize, Serialize};
+use toml::{Table, Value as TomlValue};
+
+use crate::{log, trust};
+
+/// Version of the config file layout written by this dockim. Files without `schema_version`
+/// predate versioning and count as 0.
+pub const CONFIG_SCHEMA_VERSION: u32 = 1;
+
+/// Prefix of the environment variables overriding config keys, e.g.
+/// `DOCKIM_CONFIG__UP__IMPLICIT` for `up.implicit`
+pub const ENV_OVERRIDE_PREFIX: &str = "DOCKIM_CONFIG__";
+
+/// Keys renamed since versioning began, by the version that renamed them
+const RENAMED_KEYS: &[(u32, &str, &str)] = &[];
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub schema_version: u32,
+
     #[serde(default = "default_shell")]
     pub shell: String,
 
@@ -16,19 +39,653 @@ pub struct Config {
 
     #[serde(default = "default_dotfiles_install_command")]
     pub dotfiles_install_command: String,
+
+    #[serde(default)]
+    pub auth: AuthConfig,
+
+    #[serde(default)]
+    pub build: BuildConfig,
+
+    #[serde(default)]
+    pub cli: CliConfig,
+
+    #[serde(default)]
+    pub container: ContainerConfig,
+
+    #[serde(default)]
+    pub diff: DiffConfig,
+
+    #[serde(default)]
+    pub direnv: DirenvConfig,
+
+    #[serde(default)]
+    pub docker: DockerConfig,
+
+    #[serde(default)]
+    pub events: EventsConfig,
+
+    #[serde(default)]
+    pub network: NetworkConfig,
+
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+
+    #[serde(default)]
+    pub port: PortConfig,
+
+    #[serde(default)]
+    pub remote: RemoteConfig,
+
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+
+    #[serde(default)]
+    pub services: ServicesConfig,
+
+    #[serde(default)]
+    pub stop: StopConfig,
+
+    #[serde(default)]
+    pub tunnel: TunnelConfig,
+
+    #[serde(default)]
+    pub up: UpConfig,
+
+    #[serde(default)]
+    pub gc: GcConfig,
+
+    #[serde(default)]
+    pub vscode: VscodeConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// Let git on the container use the host's stored credentials over HTTPS, through a bridge
+    /// that runs while dockim sessions are open (or `dockim auth serve`)
+    #[serde(default)]
+    pub git_credentials: bool,
+
+    /// Host port of the git credential bridge
+    #[serde(default = "default_auth_credential_port")]
+    pub credential_port: u16,
+
+    /// Also let git on the container store and erase credentials on the host, which it can
+    /// otherwise only read
+    #[serde(default)]
+    pub git_credentials_store: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            git_credentials: false,
+            credential_port: default_auth_credential_port(),
+            git_credentials_store: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BuildConfig {
+    /// How the prerequisites, Neovim and GitHub CLI are installed
+    #[serde(default)]
+    pub backend: BuildBackend,
+
+    /// Flake reference of the nixpkgs used by the `nix` backend. The resolved revision is locked
+    /// per workspace.
+    #[serde(default = "default_build_nixpkgs")]
+    pub nixpkgs: String,
+
+    /// Extra nixpkgs attributes installed by the `nix` backend (e.g. `jq`, `nodejs_22`)
+    #[serde(default)]
+    pub nix_packages: Vec<String>,
+
+    /// Where Neovim is installed (`<prefix>/bin/nvim`); it must be on PATH for `dockim neovim`
+    #[serde(default = "default_build_neovim_prefix")]
+    pub neovim_prefix: String,
+
+    /// Install each Neovim version into /opt/nvim/<version> and link the configured one into the
+    /// prefix, so that `dockim neovim --use-version` can switch between them
+    #[serde(default)]
+    pub neovim_keep_versions: bool,
+
+    /// Download plugins, parsers and language servers by running `plugin_sync_command` after the
+    /// dotfiles are installed
+    #[serde(default)]
+    pub plugin_sync: bool,
+
+    #[serde(default = "default_build_plugin_sync_command")]
+    pub plugin_sync_command: String,
+
+    #[serde(default)]
+    pub retries: RetryConfig,
+
+    /// Fail instead of warning when a downloaded release has no published checksum
+    #[serde(default)]
+    pub require_checksums: bool,
+
+    /// Free space (MB) to keep on top of what the build is estimated to use; warns below it
+    #[serde(default = "default_build_min_free_mb")]
+    pub min_free_mb: u64,
+
+    /// Register secret patterns and commit hooks with git-secrets in the container
+    #[serde(default)]
+    pub git_security: bool,
+
+    /// Also install gitleaks when `git_security` is enabled
+    #[serde(default)]
+    pub gitleaks: bool,
+
+    /// Preinstall the prerequisites into a derived image, as `dockim build --apt-layer` does, and
+    /// create containers from it until devcontainer.json or its Dockerfile change
+    #[serde(default)]
+    pub apt_layer: bool,
+
+    /// CPUs the devcontainer may use while it is being built, as for `docker update --cpus`
+    /// (e.g. `"1.5"`); the previous limit is restored afterwards
+    #[serde(default)]
+    pub cpu_limit: Option<String>,
+
+    /// Niceness (0-19) of the commands the build runs on the container
+    #[serde(default)]
+    pub nice: Option<u8>,
+
+    /// Best-effort I/O priority (0-7, 7 being the lowest) of the commands the build runs on the
+    /// container
+    #[serde(default)]
+    pub ionice: Option<u8>,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        BuildConfig {
+            backend: BuildBackend::default(),
+            nixpkgs: default_build_nixpkgs(),
+            nix_packages: vec![],
+            neovim_prefix: default_build_neovim_prefix(),
+            neovim_keep_versions: false,
+            plugin_sync: false,
+            plugin_sync_command: default_build_plugin_sync_command(),
+            retries: RetryConfig::default(),
+            require_checksums: false,
+            min_free_mb: default_build_min_free_mb(),
+            git_security: false,
+            gitleaks: false,
+            apt_layer: false,
+            cpu_limit: None,
+            nice: None,
+            ionice: None,
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildBackend {
+    /// apt packages, Neovim release tarballs and the GitHub CLI installer
+    #[default]
+    Apt,
+    /// A Nix flake generated from the config, which also works on non-Debian images
+    Nix,
+}
+
+/// Retry policy for network-bound build steps
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RetryConfig {
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+
+    /// Wait before the first retry, doubled on every subsequent one
+    #[serde(default = "default_retry_initial_backoff_secs")]
+    pub initial_backoff_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: default_retry_attempts(),
+            initial_backoff_secs: default_retry_initial_backoff_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ContainerConfig {
+    /// Mount the workspace with an SELinux label so that the container can access it
+    #[serde(default)]
+    pub selinux_relabel: bool,
+
+    /// Run the container without an AppArmor profile
+    #[serde(default)]
+    pub apparmor_unconfined: bool,
+
+    /// Let GUI apps in the container use the host's display server
+    #[serde(default)]
+    pub gui: bool,
+
+    /// How the container can use Docker
+    #[serde(default)]
+    pub docker_access: DockerAccess,
+
+    /// Workspace directories (e.g. `target`, `node_modules`) hidden behind anonymous volumes, which
+    /// are much faster than the bind mount on macOS and Windows but not visible from the host
+    #[serde(default)]
+    pub exclude_mounts: Vec<String>,
+
+    /// Name new containers `dockim_<workspace>_<config>` instead of Docker's random names
+    #[serde(default)]
+    pub readable_name: bool,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DockerAccess {
+    #[default]
+    None,
+    /// The host's Docker daemon through its socket (docker-outside-of-docker)
+    Socket,
+    /// A Docker daemon of its own (docker-in-docker)
+    Dind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DiffConfig {
+    /// Paths hidden by `dockim diff`; `*` matches within a path component and `**` across them
+    #[serde(default = "default_diff_ignore")]
+    pub ignore: Vec<String>,
+}
+
+impl Default for DiffConfig {
+    fn default() -> Self {
+        DiffConfig {
+            ignore: default_diff_ignore(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DirenvConfig {
+    /// Commands that get a shim from `dockim direnv install` in addition to the ones of the
+    /// devcontainer's features (e.g. `make`, `pytest`)
+    #[serde(default)]
+    pub shims: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DockerConfig {
+    /// Detach key sequence written to ~/.docker/config.json by `dockim init-docker`
+    #[serde(default = "default_docker_detach_keys")]
+    pub detach_keys: String,
+}
+
+impl Default for DockerConfig {
+    fn default() -> Self {
+        DockerConfig {
+            detach_keys: default_docker_detach_keys(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct EventsConfig {
+    /// Host commands to run by container event action (e.g. `start`, `die`, `oom`)
+    #[serde(default)]
+    pub hooks: BTreeMap<String, String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
+            schema_version: CONFIG_SCHEMA_VERSION,
             shell: default_shell(),
             neovim_version: default_neovim_version(),
             dotfiles_repository_name: default_dotfiles_repository_name(),
             dotfiles_install_command: default_dotfiles_install_command(),
+            auth: AuthConfig::default(),
+            build: BuildConfig::default(),
+            cli: CliConfig::default(),
+            container: ContainerConfig::default(),
+            diff: DiffConfig::default(),
+            direnv: DirenvConfig::default(),
+            docker: DockerConfig::default(),
+            events: EventsConfig::default(),
+            network: NetworkConfig::default(),
+            notifications: NotificationsConfig::default(),
+            port: PortConfig::default(),
+            remote: RemoteConfig::default(),
+            runtime: RuntimeConfig::default(),
+            services: ServicesConfig::default(),
+            stop: StopConfig::default(),
+            tunnel: TunnelConfig::default(),
+            up: UpConfig::default(),
+            vscode: VscodeConfig::default(),
+            gc: GcConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct GcConfig {
+    /// Exited devcontainers older than this are removed by `dockim gc`
+    #[serde(default = "default_gc_max_age_days")]
+    pub max_age_days: u64,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            max_age_days: default_gc_max_age_days(),
         }
     }
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    #[serde(default)]
+    pub mode: NetworkMode,
+
+    /// Hosts reachable in the `restricted` mode; `*.example.com` also matches subdomains
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// Whatever devcontainer.json specifies
+    #[default]
+    Default,
+    /// No network access at all
+    None,
+    /// Only HTTP(S) to the allowed hosts, through a filtering proxy
+    Restricted,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NotificationsConfig {
+    /// Show a desktop notification when `build` or `up --rebuild` finishes or fails
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PortConfig {
+    /// Image of the containers forwarding ports; it must provide `socat` as its entrypoint, and
+    /// BusyBox-like `sh`, `tail`, `ps` and `pkill` for the `multiplex` backend
+    #[serde(default = "default_port_socat_image")]
+    pub socat_image: String,
+
+    #[serde(default)]
+    pub backend: ForwardBackend,
+}
+
+impl Default for PortConfig {
+    fn default() -> Self {
+        PortConfig {
+            socat_image: default_port_socat_image(),
+            backend: ForwardBackend::default(),
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardBackend {
+    /// `multiplex` where host-network listeners reach the host, `container` elsewhere
+    #[default]
+    Auto,
+    /// One host-network helper container per devcontainer, running a socat listener per port
+    Multiplex,
+    /// One container per forwarded port, publishing it with `-p`
+    Container,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    /// Run containers on this host over SSH (e.g. `user@server`). The workspace must exist at the
+    /// same path on it.
+    #[serde(default)]
+    pub ssh_host: Option<String>,
+}
+
+/// Defaults of `dockim neovim`, each of which can be overridden per invocation
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RemoteConfig {
+    /// Run Neovim as a headless server instead of in the terminal
+    #[serde(default)]
+    pub background: bool,
+
+    /// Serve the host clipboard to Neovim on the container
+    #[serde(default = "default_remote_clipboard")]
+    pub clipboard: bool,
+
+    /// Extra arguments passed to Neovim, separated by whitespace
+    #[serde(default)]
+    pub args: String,
+
+    /// Save the session when Neovim exits or a UI detaches from the server
+    #[serde(default)]
+    pub save_session: bool,
+
+    /// Restore the saved session on start
+    #[serde(default)]
+    pub restore_session: bool,
+
+    /// What `dockim neovim` does while the workspace's Neovim server is running
+    #[serde(default)]
+    pub existing_server: ExistingServer,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExistingServer {
+    /// Attach another UI to the server, opening the given files in a new tab
+    #[default]
+    AttachNewUi,
+    /// Open the given files (or an empty buffer) in a new tab of the server and return
+    OpenInExisting,
+    Error,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        RemoteConfig {
+            background: false,
+            clipboard: default_remote_clipboard(),
+            args: String::new(),
+            save_session: false,
+            restore_session: false,
+            existing_server: ExistingServer::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ServicesConfig {
+    /// Compose project shared by every workspace (e.g. a single database server)
+    #[serde(default)]
+    pub shared: Option<SharedServicesConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SharedServicesConfig {
+    #[serde(default = "default_shared_services_project")]
+    pub project: String,
+
+    /// Compose files of the shared stack; `~/` is expanded to the home directory
+    pub compose_files: Vec<String>,
+
+    /// Network the devcontainer is attached to (defaults to `<project>_default`)
+    #[serde(default)]
+    pub network: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TunnelConfig {
+    /// Provider of the public URL for `dockim port share`
+    #[serde(default)]
+    pub backend: TunnelBackend,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelBackend {
+    /// A quick tunnel on trycloudflare.com, no account needed
+    #[default]
+    Cloudflared,
+    Ngrok,
+    /// Tailscale Funnel, which must be enabled for the tailnet
+    Tailscale,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct StopConfig {
+    /// Shell commands run on the container before `stop` and `down` stop it (e.g. `pg_ctl stop`)
+    #[serde(default)]
+    pub commands: Vec<String>,
+
+    /// How long to wait for the commands and for dockim's processes to exit, before stopping the
+    /// container anyway
+    #[serde(default = "default_stop_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for StopConfig {
+    fn default() -> Self {
+        StopConfig {
+            commands: vec![],
+            timeout_secs: default_stop_timeout_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct UpConfig {
+    /// Start the devcontainer before `shell`, `exec` and `port` if it isn't running
+    #[serde(default = "default_up_implicit")]
+    pub implicit: bool,
+
+    /// What `up` does when devcontainer.json, the Dockerfile or compose files changed since the
+    /// container was created
+    #[serde(default)]
+    pub on_config_change: ConfigChangeAction,
+
+    /// How long to wait for another dockim that is starting or changing the same workspace
+    #[serde(default = "default_up_lock_timeout_secs")]
+    pub lock_timeout_secs: u64,
+
+    /// Set the clock of the Docker VM from its hardware clock when the container's clock drifted
+    /// from the host's (e.g. after the host slept), where dockim knows how
+    #[serde(default)]
+    pub fix_clock_skew: bool,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigChangeAction {
+    #[default]
+    Warn,
+    Rebuild,
+    Ignore,
+}
+
+impl Default for UpConfig {
+    fn default() -> Self {
+        UpConfig {
+            implicit: default_up_implicit(),
+            on_config_change: ConfigChangeAction::default(),
+            lock_timeout_secs: default_up_lock_timeout_secs(),
+            fix_clock_skew: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CliConfig {
+    /// Subcommand, with its arguments, that a bare `dockim` runs (e.g. `"neovim"`). Arguments are
+    /// split as in a POSIX shell, so they may be quoted (e.g. `"exec 'cargo test'"`).
+    #[serde(default)]
+    pub default_command: Option<String>,
+}
+
+/// `.dockim/config.toml` of a workspace, overriding some settings for it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LocalConfig {
+    #[serde(default)]
+    pub cli: CliConfig,
+}
+
+impl LocalConfig {
+    /// Reads the local configuration of the workspace, which is only used if it is trusted.
+    pub fn load(workspace_folder: &Path) -> Result<Self> {
+        let path = workspace_folder
+            .join(trust::LOCAL_CONFIG_DIR)
+            .join("config.toml");
+        if !path.exists() {
+            return Ok(LocalConfig::default());
+        }
+        if !trust::is_trusted(workspace_folder)? {
+            log!("Warning": "ignoring {} since the workspace is not trusted", path.display());
+            log!("Hint": "run `dockim trust` to use it");
+            return Ok(LocalConfig::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+        toml::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to parse {}", path.display()))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct VscodeConfig {
+    /// Extensions synced by `dockim config sync-vscode` instead of the ones installed on the host
+    #[serde(default)]
+    pub extensions: Option<Vec<String>>,
+}
+
+fn default_auth_credential_port() -> u16 {
+    54322
+}
+
 fn default_shell() -> String {
     "/usr/bin/bash".to_string()
 }
@@ -45,6 +702,78 @@ fn default_dotfiles_install_command() -> String {
     "echo 'no dotfiles install command configured'".to_string()
 }
 
+fn default_build_nixpkgs() -> String {
+    "github:NixOS/nixpkgs/nixos-unstable".to_string()
+}
+
+fn default_build_neovim_prefix() -> String {
+    "/usr/local".to_string()
+}
+
+fn default_build_plugin_sync_command() -> String {
+    r#"nvim --headless "+Lazy! sync" +qa"#.to_string()
+}
+
+fn default_build_min_free_mb() -> u64 {
+    1024
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_backoff_secs() -> u64 {
+    2
+}
+
+fn default_diff_ignore() -> Vec<String> {
+    [
+        "/tmp/**",
+        "/var/tmp/**",
+        "/var/cache/**",
+        "/var/log/**",
+        "/var/lib/apt/lists/**",
+        "/run/**",
+        "/root/.cache/**",
+        "/home/*/.cache/**",
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+fn default_docker_detach_keys() -> String {
+    // Docker's default ctrl-p,ctrl-q swallows ctrl-p in Neovim and shells
+    "ctrl-q".to_string()
+}
+
+fn default_gc_max_age_days() -> u64 {
+    7
+}
+
+fn default_remote_clipboard() -> bool {
+    true
+}
+
+fn default_shared_services_project() -> String {
+    "dockim-shared".to_string()
+}
+
+fn default_port_socat_image() -> String {
+    "alpine/socat".to_string()
+}
+
+fn default_stop_timeout_secs() -> u64 {
+    10
+}
+
+fn default_up_implicit() -> bool {
+    true
+}
+
+fn default_up_lock_timeout_secs() -> u64 {
+    300
+}
+
 impl Config {
     pub fn config_file_path() -> Result<PathBuf> {
         Ok(dirs::config_dir()
@@ -55,20 +784,167 @@ impl Config {
 
     pub fn load_config() -> Result<Self> {
         let path = Self::config_file_path()?;
+        let env_overrides = env_overrides();
 
-        if !path.exists() {
+        if !path.exists() && env_overrides.is_empty() {
             let config = Config::default();
             return Ok(config);
         }
 
-        let contents = fs::read_to_string(&path)
+        let mut table = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .into_diagnostic()
+                .wrap_err("failed to read config file contents")?;
+            toml::from_str(&contents)
+                .into_diagnostic()
+                .wrap_err("failed to parse config file")?
+        } else {
+            Table::new()
+        };
+
+        // Old layouts keep working, but only until their keys are removed for good
+        let changes = migrate(&mut table);
+        if !changes.is_empty() && path.exists() {
+            log!("Warning": "the config file uses an old layout");
+            log!("Hint": "run `dockim config migrate` to update it");
+        }
+
+        for (key, value) in &env_overrides {
+            set_key(&mut table, key, parse_value(value));
+        }
+
+        let config = table.try_into().into_diagnostic().wrap_err(miette!(
+            help = format!("check the config file and {ENV_OVERRIDE_PREFIX}* variables"),
+            "failed to parse config file"
+        ))?;
+
+        Ok(config)
+    }
+
+    /// Applies the `KEY=VALUE` pairs given with `--config`, which take precedence over the rest.
+    pub fn with_cli_overrides(self, overrides: &[String]) -> Result<Self> {
+        if overrides.is_empty() {
+            return Ok(self);
+        }
+
+        let mut table = Table::try_from(&self)
             .into_diagnostic()
-            .wrap_err("failed to read config file contents")?;
+            .wrap_err("failed to serialize config")?;
+        for arg in overrides {
+            let (key, value) = parse_cli_override(arg)?;
+            set_key(&mut table, &key, parse_value(&value));
+        }
 
-        let config = toml::from_str(&contents)
+        table
+            .try_into()
             .into_diagnostic()
-            .wrap_err("failed to parse config file")?;
+            .wrap_err("invalid --config value")
+    }
+}
 
-        Ok(config)
+/// Config keys set by `DOCKIM_CONFIG__*` environment variables, with their values.
+pub fn env_overrides() -> Vec<(String, String)> {
+    env::vars()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENV_OVERRIDE_PREFIX)?;
+            Some((key.split("__").map(str::to_lowercase).join("."), value))
+        })
+        .collect()
+}
+
+/// Splits a `KEY=VALUE` of `--config`.
+pub fn parse_cli_override(arg: &str) -> Result<(String, String)> {
+    let Some((key, value)) = arg.split_once('=') else {
+        bail!(
+            help = "pass it as `--config up.implicit=false`",
+            "invalid --config `{arg}`: expected KEY=VALUE"
+        );
+    };
+
+    Ok((key.trim().to_string(), value.to_string()))
+}
+
+/// Parses a value given on the command line or in the environment as TOML, falling back to a
+/// string so that plain words need no quotes.
+pub fn parse_value(value: &str) -> TomlValue {
+    toml::from_str::<Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| TomlValue::String(value.to_string()))
+}
+
+/// Sets the dotted `key` in `table`, creating the tables on the way.
+pub fn set_key(table: &mut Table, key: &str, value: TomlValue) {
+    let (parents, name) = match key.rsplit_once('.') {
+        Some((parents, name)) => (parents.split('.').collect_vec(), name),
+        None => (vec![], key),
+    };
+    let mut table = table;
+    for parent in parents {
+        let entry = table
+            .entry(parent)
+            .or_insert_with(|| TomlValue::Table(Table::new()));
+        if !entry.is_table() {
+            *entry = TomlValue::Table(Table::new());
+        }
+        table = entry.as_table_mut().unwrap();
     }
+    table.insert(name.to_string(), value);
+}
+
+/// Rewrites a config file table to the current schema and describes each change. Unknown keys
+/// are left alone.
+pub fn migrate(table: &mut Table) -> Vec<String> {
+    let version = table
+        .get("schema_version")
+        .and_then(TomlValue::as_integer)
+        .unwrap_or(0);
+    let mut changes = vec![];
+
+    for &(since, from, to) in RENAMED_KEYS {
+        if version < i64::from(since) {
+            rename_key(table, from, to, &mut changes);
+        }
+    }
+
+    if version < i64::from(CONFIG_SCHEMA_VERSION) {
+        table.insert(
+            "schema_version".to_string(),
+            TomlValue::Integer(CONFIG_SCHEMA_VERSION.into()),
+        );
+    }
+
+    changes
+}
+
+fn rename_key(table: &mut Table, from: &str, to: &str, changes: &mut Vec<String>) {
+    let (from_parent, from_name) = split_key(from);
+    let (to_parent, to_name) = split_key(to);
+    let Some(value) = table_at(table, from_parent).and_then(|t| t.remove(from_name)) else {
+        return;
+    };
+    let Some(target) = table_at(table, to_parent) else {
+        return;
+    };
+
+    if target.contains_key(to_name) {
+        changes.push(format!("removed {from} in favor of {to}"));
+    } else {
+        target.insert(to_name.to_string(), value);
+        changes.push(format!("renamed {from} to {to}"));
+    }
+}
+
+fn split_key(key: &str) -> (&str, &str) {
+    key.rsplit_once('.').unwrap_or(("", key))
+}
+
+fn table_at<'a>(table: &'a mut Table, parent: &str) -> Option<&'a mut Table> {
+    if parent.is_empty() {
+        return Some(table);
+    }
+
+    parent
+        .split('.')
+        .try_fold(table, |table, key| table.get_mut(key)?.as_table_mut())
 }
@@ -1,15 +1,44 @@
-use miette::{miette, IntoDiagnostic, WrapErr};
+use itertools::{chain, Itertools};
+use miette::{bail, miette, IntoDiagnostic, WrapErr};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
     fs::File,
+    io::{self, IsTerminal},
+    mem,
     path::{Path, PathBuf},
-    process::{Child, Stdio},
+    process::{Child, ExitStatus, Stdio},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use miette::Result;
 
-use crate::exec;
+use crate::{
+    config::{Config, DockerAccess, ForwardBackend, NetworkMode},
+    devcontainer_config, exec, host_path,
+    host_port::Reservation,
+    log, network,
+    orchestrator::Orchestrator,
+    override_config::{self, Overrides, DOCKER_SOCKET},
+    path_mapping::{self, PathMapping},
+    progress::{self, Operation},
+    remote, state, tls, trust, variant,
+    vm_provider::VmProvider,
+    workspace_lock::{self, WorkspaceLock},
+};
+
+const FORWARDS_STATE_FILE: &str = "forwards.json";
+
+/// Variables saved by `dockim env --save`
+const SAVED_ENV_STATE_FILE: &str = "exec-env.json";
+
+/// Label telling apart the containers started side by side by [`DevContainer::up_blue_green`]
+const GENERATION_LABEL: &str = "dockim.generation";
+
+/// Environment of execs whose output is parsed, so that it doesn't depend on the user's locale
+const CAPTURE_ENV: &[&str] = &["LANG=C.UTF-8", "LC_ALL=C.UTF-8"];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpOutput {
@@ -25,9 +54,96 @@ pub struct UpOutput {
     pub remote_workspace_folder: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct ComposeProject {
+    pub name: String,
+    pub working_dir: Option<String>,
+    pub files: Vec<String>,
+}
+
+impl ComposeProject {
+    /// Returns `docker compose` arguments selecting this project.
+    pub fn compose_args(&self) -> Vec<String> {
+        let mut args = vec!["-p".to_string(), self.name.clone()];
+        if let Some(working_dir) = &self.working_dir {
+            args.extend(["--project-directory".to_string(), working_dir.clone()]);
+        }
+        for file in &self.files {
+            args.extend(["-f".to_string(), file.clone()]);
+        }
+
+        args
+    }
+}
+
+/// State of a container in the devcontainer's compose project.
+#[derive(Debug, Clone)]
+pub struct ServiceStatus {
+    pub service: String,
+    pub state: String,
+    /// `None` if the service has no healthcheck
+    pub health: Option<String>,
+    /// "host:container/proto" for each published port
+    pub ports: Vec<String>,
+    pub restart_count: u64,
+}
+
+/// A devcontainer of any workspace, as found by its labels.
+#[derive(Debug, Clone)]
+pub struct ContainerSummary {
+    pub id: String,
+    pub name: String,
+    pub state: String,
+    pub status: String,
+    pub workspace_folder: PathBuf,
+    /// devcontainer.json the container was created from, if known
+    pub config: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisteredForward {
+    pub host_port: String,
+    pub container_port: String,
+
+    #[serde(default)]
+    pub https: bool,
+
+    /// Compose service the port belongs to, if not the devcontainer itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+
+    /// HTTP path probed through the host port by `dockim port ls`, e.g. `/healthz`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReconcileSummary {
+    pub restored: usize,
+    pub dropped: usize,
+    pub unchanged: usize,
+}
+
+impl ReconcileSummary {
+    pub fn is_empty(&self) -> bool {
+        self.restored == 0 && self.dropped == 0 && self.unchanged == 0
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DevContainer {
     workspace_folder: PathBuf,
+    config: Config,
+    user: Option<String>,
+    /// Runs as root with `docker exec`, for dockim's own non-interactive commands
+    direct_root: bool,
+    implicit_up: bool,
+    initialize: bool,
+    workdir: Option<String>,
+    /// Command that runs each command on the container, e.g. to lower its priority
+    command_prefix: Vec<String>,
+    /// Extra variables for the commands run on the container
+    env: BTreeMap<String, String>,
 }
 
 impl DevContainer {
@@ -35,110 +151,937 @@ impl DevContainer {
         exec::exec(&["devcontainer", "--version"]).is_ok()
     }
 
-    pub fn new(workspace_folder: Option<PathBuf>) -> Self {
+    /// Whether the image is present locally, without pulling it.
+    pub fn is_image_available(image: &str) -> bool {
+        exec::capturing_stdout(&["docker", "image", "inspect", "--format", "{{ .Id }}", image])
+            .is_ok()
+    }
+
+    pub fn new(workspace_folder: Option<PathBuf>, config: &Config) -> Self {
         DevContainer {
             workspace_folder: workspace_folder.unwrap_or_else(|| PathBuf::from(".")),
+            config: config.clone(),
+            user: None,
+            direct_root: false,
+            implicit_up: config.up.implicit,
+            initialize: true,
+            workdir: None,
+            command_prefix: vec![],
+            env: BTreeMap::new(),
         }
     }
 
+    /// Runs subsequent commands on the container as `user` instead of the configured remote user.
+    pub fn with_user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// Runs subsequent commands as root with `docker exec`, without a terminal or the environment
+    /// of `devcontainer exec`. Only for dockim's own setup commands.
+    fn as_direct_root(&self) -> Self {
+        let mut dc = self.clone().with_user(Some("root".to_string()));
+        dc.direct_root = true;
+        dc
+    }
+
+    /// Skips `initializeCommand` of devcontainer.json when `no_initialize` is set.
+    pub fn with_no_initialize(mut self, no_initialize: bool) -> Self {
+        self.initialize = !no_initialize;
+        self
+    }
+
+    /// Makes [`DevContainer::ensure_up`] fail instead of starting the container when `no_up` is set.
+    pub fn with_no_up(mut self, no_up: bool) -> Self {
+        if no_up {
+            self.implicit_up = false;
+        }
+        self
+    }
+
+    /// Runs subsequent commands in `workdir` on the container instead of the workspace folder.
+    pub fn with_workdir(mut self, workdir: Option<String>) -> Self {
+        self.workdir = workdir;
+        self
+    }
+
+    /// Runs subsequent commands on the container as arguments of `prefix`, e.g. `nice -n 10`.
+    pub fn with_command_prefix(mut self, prefix: Vec<String>) -> Self {
+        self.command_prefix = prefix;
+        self
+    }
+
+    /// Applies the variables saved by `dockim env --save` to the commands run on the container.
+    pub fn with_saved_env(mut self) -> Result<Self> {
+        self.env = self.saved_env()?;
+        Ok(self)
+    }
+
+    /// Sets `name` to `value` in the commands run on the container, over any saved value.
+    pub fn with_env_var(mut self, name: &str, value: String) -> Self {
+        self.env.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn workspace_folder(&self) -> &Path {
+        &self.workspace_folder
+    }
+
+    /// Keeps other dockim processes from starting or changing this workspace until dropped. Not
+    /// reentrant: take it once per command.
+    pub fn lock(&self) -> Result<WorkspaceLock> {
+        workspace_lock::acquire(
+            &self.workspace_folder,
+            Duration::from_secs(self.config.up.lock_timeout_secs),
+        )
+    }
+
     pub fn up(&self, rebuild: bool, build_no_cache: bool) -> Result<()> {
-        let workspace_folder = self.workspace_folder.to_string_lossy();
+        self.run_initialize_command()?;
+
+        exec::exec(&self.up_args(rebuild, build_no_cache)?)
+    }
+
+    /// Like [`DevContainer::up`], but runs in the background for frontends embedding dockim.
+    pub fn spawn_up(&self, rebuild: bool, build_no_cache: bool) -> Operation<()> {
+        let dc = self.clone();
+        progress::spawn(move |progress| {
+            progress.steps(2);
+            progress.step("run initializeCommand")?;
+            dc.run_initialize_command()?;
+            progress.step("start the devcontainer")?;
+            exec::cancellable(&dc.up_args(rebuild, build_no_cache)?, progress.token())
+        })
+    }
+
+    fn up_args(&self, rebuild: bool, build_no_cache: bool) -> Result<Vec<String>> {
         let mut args = vec![
-            "devcontainer",
-            "up",
-            "--workspace-folder",
-            &*workspace_folder,
+            "devcontainer".to_string(),
+            "up".to_string(),
+            "--workspace-folder".to_string(),
+            self.workspace_folder.to_string_lossy().to_string(),
         ];
+        args.extend(self.variant_args());
+        args.extend(self.compose_path_args()?);
+
+        if let Some(override_config) = self.override_config()? {
+            args.extend(["--override-config".to_string(), override_config]);
+        }
 
         if rebuild {
-            args.push("--remove-existing-container");
+            args.push("--remove-existing-container".to_string());
         }
 
         if build_no_cache {
-            args.push("--build-no-cache");
+            args.push("--build-no-cache".to_string());
         }
 
-        exec::exec(&args)
+        Ok(args)
+    }
+
+    /// Recreates the container without downtime: the new one is built and started next to the old
+    /// one, which is only removed once the new one is up. Port forwards follow with
+    /// [`DevContainer::reconcile_forwards`].
+    pub fn up_blue_green(&self, build_no_cache: bool) -> Result<()> {
+        let old_ids = self.find_container_ids()?;
+        if old_ids.is_empty() {
+            log!("Skipping" ("blue-green"): "no container to keep running");
+            return self.up(false, build_no_cache);
+        }
+        if devcontainer_config::load(&self.workspace_folder)?.is_compose() {
+            bail!(
+                help = "run `dockim up --rebuild` instead",
+                "blue-green rebuilds are not supported for Docker Compose based devcontainers"
+            );
+        }
+
+        self.run_initialize_command()?;
+
+        // The devcontainer CLI reuses any container with its default labels. An extra label keeps
+        // it from finding the old one, while the new one still gets the default labels.
+        let local_folder = host_path::canonicalize(&self.workspace_folder)
+            .into_diagnostic()
+            .wrap_err("failed to resolve workspace folder")?;
+        let config_path = override_config::devcontainer_json_path(&local_folder)
+            .ok_or_else(|| miette!("devcontainer.json not found"))?;
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .to_string();
+        let generation_label = format!("{GENERATION_LABEL}={generation}");
+
+        // The old container still holds the readable name; the new one takes it over after the swap
+        let mut config = self.config.clone();
+        config.container.readable_name = false;
+        let next = DevContainer {
+            config,
+            ..self.clone()
+        };
+        let mut args = next.up_args(false, build_no_cache)?;
+        for label in [
+            format!("devcontainer.local_folder={}", local_folder.display()),
+            format!("devcontainer.config_file={}", config_path.display()),
+            generation_label.clone(),
+        ] {
+            args.extend(["--id-label".to_string(), label]);
+        }
+        log!("Starting" ("blue-green"): "new container next to {}", short_id(&old_ids[0]));
+        exec::exec(&args).wrap_err("failed to start the new devcontainer; the old one is kept")?;
+
+        let new_id = exec::capturing_stdout(&[
+            "docker",
+            "ps",
+            "-q",
+            "--no-trunc",
+            "--filter",
+            &format!("label={generation_label}"),
+        ])
+        .wrap_err("failed to find the new devcontainer")?;
+        let Some(new_id) = new_id.split_whitespace().next() else {
+            bail!("the new devcontainer is not running; the old one is kept");
+        };
+
+        for old_id in &old_ids {
+            log!("Removing" ("blue-green"): "old container {}", short_id(old_id));
+            Self::remove_container(old_id)?;
+        }
+        if self.config.container.readable_name {
+            let name = override_config::readable_container_name(&self.workspace_folder)?;
+            exec::exec(&["docker", "rename", new_id, &name])
+                .wrap_err_with(|| miette!("failed to rename the new devcontainer to {name}"))?;
+        }
+        log!("Swapped" ("blue-green"): "now running {}", short_id(new_id));
+
+        Ok(())
     }
 
     pub fn up_and_inspect(&self) -> Result<UpOutput> {
+        // Only when the container is about to be (re)started, like `dockim up`
+        if override_config::initialize_command(&self.workspace_folder)?.is_some()
+            && self.container_status()?.as_deref() != Some("running")
+        {
+            self.run_initialize_command()?;
+        }
+
         let workspace_folder = self.workspace_folder.to_string_lossy();
-        let args = [
+        let override_config = self.override_config()?;
+        let cli_args = chain!(self.variant_args(), self.compose_path_args()?).collect_vec();
+        let mut args = vec![
             "devcontainer",
             "up",
             "--workspace-folder",
             &*workspace_folder,
         ];
+        args.extend(cli_args.iter().map(String::as_str));
+
+        if let Some(override_config) = &override_config {
+            args.extend(["--override-config", override_config]);
+        }
 
         exec::capturing_stdout(&args)
             .and_then(|output| serde_json::from_str(&output).into_diagnostic())
     }
 
-    pub fn spawn<S: AsRef<str>>(&self, command: &[S]) -> Result<Child> {
-        let workspace_folder = self.workspace_folder.to_string_lossy();
+    /// Runs `initializeCommand` of devcontainer.json on the host, which dockim takes over from the
+    /// devcontainer CLI so that it can be shown, gated by trust and skipped.
+    fn run_initialize_command(&self) -> Result<()> {
+        let Some(command) = override_config::initialize_command(&self.workspace_folder)? else {
+            return Ok(());
+        };
+
+        if !self.initialize {
+            log!("Skipping" ("initializeCommand"): "{command}");
+            return Ok(());
+        }
+        if !trust::is_trusted(&self.workspace_folder)? {
+            bail!(
+                help = "run `dockim trust` to allow it, or pass --no-initialize to skip it",
+                "refusing to run initializeCommand of an untrusted workspace: {command}"
+            );
+        }
+
+        // A string runs through the shell, an array directly, and an object holds named commands
+        let commands = match command {
+            Value::Object(commands) => commands.into_iter().collect_vec(),
+            command => vec![("initializeCommand".to_string(), command)],
+        };
+        for (name, command) in commands {
+            let args = match &command {
+                Value::String(command) if cfg!(windows) => {
+                    vec!["cmd".to_string(), "/C".to_string(), command.clone()]
+                }
+                Value::String(command) => {
+                    vec!["sh".to_string(), "-c".to_string(), command.clone()]
+                }
+                Value::Array(args) => args
+                    .iter()
+                    .map(|arg| arg.as_str().map(str::to_string).unwrap_or(arg.to_string()))
+                    .collect_vec(),
+                command => bail!("unsupported initializeCommand: {command}"),
+            };
+
+            log!("Initialize" ("host"): "{name}: {command}");
+            // Like the devcontainer CLI, relative paths in the command are of the workspace folder
+            exec::exec_in(&self.workspace_folder, &args)
+                .wrap_err_with(|| miette!("initializeCommand `{name}` failed"))?;
+        }
+
+        Ok(())
+    }
+
+    fn override_config(&self) -> Result<Option<String>> {
+        if self.config.network.mode == NetworkMode::Restricted {
+            network::ensure_restricted(&self.workspace_folder, &self.config.network.allow)?;
+        }
+
+        let overrides = Overrides::load(&self.workspace_folder, &self.config)?;
+        let path = override_config::write(&self.workspace_folder, &overrides)
+            .wrap_err("failed to generate override devcontainer.json")?;
+
+        Ok(path.map(|path| path.to_string_lossy().to_string()))
+    }
+
+    /// Points the devcontainer CLI at the selected variant, which it would not find by itself.
+    fn variant_args(&self) -> Vec<String> {
+        match variant::selected() {
+            Some(variant) => vec![
+                "--config".to_string(),
+                variant::devcontainer_json_path(&self.workspace_folder, variant)
+                    .to_string_lossy()
+                    .to_string(),
+            ],
+            None => vec![],
+        }
+    }
+
+    /// Points the devcontainer CLI at a Compose implementation it wouldn't find by itself.
+    fn compose_path_args(&self) -> Result<Vec<String>> {
+        if !devcontainer_config::load(&self.workspace_folder)?.is_compose() {
+            return Ok(vec![]);
+        }
+
+        Ok(match Orchestrator::detect()?.devcontainer_cli_path()? {
+            Some(path) => vec!["--docker-compose-path".to_string(), path.to_string()],
+            None => vec![],
+        })
+    }
+
+    fn exec_args<S: AsRef<str>>(&self, command: &[S]) -> Result<Vec<String>> {
+        self.exec_args_with_env(&[], command)
+    }
+
+    fn exec_args_with_env<S: AsRef<str>>(
+        &self,
+        remote_env: &[&str],
+        command: &[S],
+    ) -> Result<Vec<String>> {
+        let mut args = match self.root_exec_args(remote_env)? {
+            Some(args) => args,
+            None => self.devcontainer_exec_args(remote_env)?,
+        };
+        args.extend(self.command_prefix.iter().cloned());
+
+        // devcontainer exec has no option for the working directory. Stay in the workspace folder if
+        // the directory doesn't exist on the container.
+        if let Some(workdir) = &self.workdir {
+            args.extend(
+                [
+                    "sh",
+                    "-c",
+                    "cd \"$1\" 2>/dev/null; shift; exec \"$@\"",
+                    "sh",
+                    workdir,
+                ]
+                .map(str::to_string),
+            );
+        }
+        args.extend(command.iter().map(|s| s.as_ref().to_string()));
+
+        Ok(args)
+    }
+
+    /// Runs dockim's own commands as root with `docker exec` on the running container. An override
+    /// configuration for `devcontainer exec` would change `remoteUser` for everything the CLI
+    /// derives from it, which breaks features keyed on it. Returns `None` where dockim can't find
+    /// the container and its workspace folder by itself, leaving it to the override. User sessions
+    /// always go through `devcontainer exec`, which sets up the terminal and `remoteEnv`.
+    fn root_exec_args(&self, remote_env: &[&str]) -> Result<Option<Vec<String>>> {
+        if !self.direct_root || devcontainer_config::load(&self.workspace_folder)?.is_compose() {
+            return Ok(None);
+        }
+        let [container_id] = &*self.find_container_ids()? else {
+            return Ok(None);
+        };
+        let status = exec::capturing_stdout(&[
+            "docker",
+            "inspect",
+            "--format",
+            "{{ .State.Status }}",
+            container_id,
+        ])
+        .wrap_err("failed to get devcontainer status")?;
+        if status.trim() != "running" {
+            return Ok(None);
+        }
+        let workspace_folder = host_path::canonicalize(&self.workspace_folder)
+            .into_diagnostic()
+            .wrap_err("failed to resolve workspace folder")?;
+        let Some(remote_workspace_folder) =
+            path_mapping::to_container(&self.path_mappings()?, &workspace_folder)
+        else {
+            return Ok(None);
+        };
+
         let mut args = vec![
-            "devcontainer",
-            "exec",
-            "--workspace-folder",
-            &*workspace_folder,
+            "docker".to_string(),
+            "exec".to_string(),
+            "--interactive".to_string(),
+            "--user".to_string(),
+            "root".to_string(),
+            "--workdir".to_string(),
+            remote_workspace_folder,
         ];
-        args.extend(command.iter().map(|s| s.as_ref()));
+        let env = self.env.iter().map(|(key, value)| format!("{key}={value}"));
+        for env in env.chain(remote_env.iter().map(|env| env.to_string())) {
+            args.extend(["--env".to_string(), env]);
+        }
+        args.push(container_id.clone());
 
-        exec::spawn(&args)
+        Ok(Some(args))
     }
 
-    pub fn exec<S: AsRef<str>>(&self, command: &[S]) -> Result<()> {
-        let workspace_folder = self.workspace_folder.to_string_lossy();
+    fn devcontainer_exec_args(&self, remote_env: &[&str]) -> Result<Vec<String>> {
         let mut args = vec![
-            "devcontainer",
-            "exec",
-            "--workspace-folder",
-            &*workspace_folder,
+            "devcontainer".to_string(),
+            "exec".to_string(),
+            "--workspace-folder".to_string(),
+            self.workspace_folder.to_string_lossy().to_string(),
         ];
-        args.extend(command.iter().map(|s| s.as_ref()));
+        args.extend(self.variant_args());
+        args.extend(self.compose_path_args()?);
 
-        exec::exec(&args)
+        let env = self.env.iter().map(|(key, value)| format!("{key}={value}"));
+        for env in env.chain(remote_env.iter().map(|env| env.to_string())) {
+            args.extend(["--remote-env".to_string(), env]);
+        }
+
+        if let Some(user) = &self.user {
+            let overrides = Overrides {
+                remote_user: Some(user.clone()),
+                ..Overrides::load(&self.workspace_folder, &self.config)?
+            };
+            let path = override_config::write(&self.workspace_folder, &overrides)
+                .wrap_err_with(|| {
+                    miette!("failed to generate override devcontainer.json for {user}")
+                })?
+                .expect("overrides with a remote user are never empty");
+            args.extend([
+                "--override-config".to_string(),
+                path.to_string_lossy().to_string(),
+            ]);
+        }
+
+        Ok(args)
     }
 
-    pub fn exec_capturing_stdout<S: AsRef<str>>(&self, command: &[S]) -> Result<String> {
-        let workspace_folder = self.workspace_folder.to_string_lossy();
+    /// Lets the remote user use the host's Docker socket mounted for `docker_access = "socket"`. The
+    /// socket's group on the container usually isn't one the user belongs to.
+    pub fn grant_docker_socket_access(&self) -> Result<()> {
+        if self.config.container.docker_access != DockerAccess::Socket {
+            return Ok(());
+        }
+
+        let remote_user = self.up_and_inspect()?.remote_user;
+        if remote_user == "root" {
+            return Ok(());
+        }
+
+        let user = exec::shell_quote(&remote_user);
+        let script = format!(
+            r#"socket={DOCKER_SOCKET}
+[ -S "$socket" ] || {{ echo missing; exit 0; }}
+gid=$(stat -c %g "$socket")
+[ "$gid" != 0 ] || {{ echo root; exit 0; }}
+group=$(getent group "$gid" | cut -d: -f1)
+if [ -z "$group" ]; then
+    group=docker-host
+    groupadd -g "$gid" "$group" 2>/dev/null || addgroup -g "$gid" "$group"
+fi
+id -nG {user} | tr ' ' '
+' | grep -qx "$group" || usermod -aG "$group" {user} 2>/dev/null || addgroup {user} "$group"
+echo "$group""#
+        );
+        let group = self
+            .as_direct_root()
+            .exec_script_capturing_stdout(&script)
+            .wrap_err("failed to grant access to the Docker socket")?;
+
+        match group.trim() {
+            "missing" => {
+                log!("Warning": "{DOCKER_SOCKET} is not mounted; recreate the container with `dockim up --rebuild`")
+            }
+            "root" => {
+                log!("Warning": "{DOCKER_SOCKET} belongs to the root group, so only root can use it")
+            }
+            group => log!("Configured" ("docker socket"): "{remote_user} is in group {group}"),
+        }
+
+        Ok(())
+    }
+
+    /// Hands the volumes of `container.exclude_mounts` to the remote user, since Docker creates
+    /// them owned by root.
+    pub fn own_excluded_mounts(&self) -> Result<()> {
+        let paths = override_config::exclude_mounts(&self.config);
+        if paths.is_empty() || devcontainer_config::load(&self.workspace_folder)?.is_compose() {
+            return Ok(());
+        }
+
+        let up_output = self.up_and_inspect()?;
+        if up_output.remote_user == "root" {
+            return Ok(());
+        }
+
+        let dirs = paths
+            .iter()
+            .map(|path| exec::shell_quote(&format!("{}/{path}", up_output.remote_workspace_folder)))
+            .join(" ");
+        let user = exec::shell_quote(&up_output.remote_user);
+        self.as_direct_root()
+            .exec_script(&format!(
+                "for dir in {dirs}; do [ -d \"$dir\" ] && chown {user}: \"$dir\"; done; true"
+            ))
+            .wrap_err("failed to hand excluded directories to the remote user")?;
+
+        Ok(())
+    }
+
+    /// Makes sure the container is running, starting it unless implicit up is disabled.
+    pub fn ensure_up(&self) -> Result<()> {
+        let status = self.container_status()?;
+        // A running container needs no `devcontainer up`, which is slow even when it does nothing
+        if self.implicit_up && status.as_deref() != Some("running") {
+            self.up_and_inspect()
+                .wrap_err("failed to start devcontainer")?;
+            return Ok(());
+        }
+
+        match status {
+            Some(status) if status == "running" => Ok(()),
+            Some(status) => bail!(
+                help = "run `dockim up` to start it",
+                "devcontainer is not running (status: {status})"
+            ),
+            None => bail!(
+                help = "run `dockim up` to create it",
+                "devcontainer does not exist"
+            ),
+        }
+    }
+
+    /// Returns the Docker status (e.g. `running`, `exited`) of the container, if it exists.
+    pub fn container_status(&self) -> Result<Option<String>> {
+        let Some(container_id) = self.find_container_ids()?.into_iter().next() else {
+            return Ok(None);
+        };
+        let status = exec::capturing_stdout(&[
+            "docker",
+            "inspect",
+            "--format",
+            "{{ .State.Status }}",
+            &container_id,
+        ])
+        .wrap_err("failed to get devcontainer status")?;
+
+        Ok(Some(status.trim().to_string()))
+    }
+
+    pub fn down(&self) -> Result<()> {
+        let up_output = self
+            .up_and_inspect()
+            .wrap_err("failed to get devcontainer status")?;
+
+        self.remove_all_forwarded_ports()?;
+        exec::exec(&["docker", "rm", "-fv", &up_output.container_id])
+            .wrap_err("failed to remove devcontainer")?;
+
+        network::remove_restricted(&self.workspace_folder)
+    }
+
+    /// Removes the workspace's containers without going through the devcontainer CLI, for when the
+    /// container is too broken for `devcontainer up` to inspect it.
+    pub fn force_down(&self) -> Result<()> {
+        let container_ids = self.find_container_ids()?;
+        if container_ids.is_empty() {
+            log!("Skipping": "no container found for this workspace");
+        }
+
+        for container_id in &container_ids {
+            Self::remove_container(container_id)?;
+        }
+
+        network::remove_restricted(&self.workspace_folder)?;
+        state::clear(&self.workspace_folder).wrap_err("failed to clear workspace state")
+    }
+
+    /// Removes a devcontainer and its port-forwarding containers by ID.
+    pub fn remove_container(container_id: &str) -> Result<()> {
+        let name_filter = format!("name=dockim-{container_id}-socat");
+        let socat_containers =
+            exec::capturing_stdout(&["docker", "ps", "-aq", "--filter", &name_filter])
+                .wrap_err("failed to enumerate port-forwarding containers")?;
+        for socat_container in socat_containers.split_whitespace() {
+            exec::exec(&["docker", "rm", "-f", socat_container])
+                .wrap_err("failed to remove port-forwarding container")?;
+        }
+
+        // With its anonymous volumes, such as those hiding `container.exclude_mounts`
+        exec::exec(&["docker", "rm", "-fv", container_id]).wrap_err("failed to remove devcontainer")
+    }
+
+    /// Lists the devcontainers created by dockim for all workspaces, found by dockim's labels so
+    /// that the ones of VS Code and other tools are left alone. The config falls back to the
+    /// devcontainer CLI's label.
+    pub fn list_all() -> Result<Vec<ContainerSummary>> {
+        let format = format!(
+            "{{{{.ID}}}}\t{{{{.Names}}}}\t{{{{.State}}}}\t{{{{.Status}}}}\t{{{{.Label \"{}\"}}}}\t{{{{.Label \"{}\"}}}}\t{{{{.Label \"devcontainer.config_file\"}}}}",
+            devcontainer_config::WORKSPACE_LABEL,
+            devcontainer_config::CONFIG_LABEL,
+        );
+        let output = exec::capturing_stdout(&[
+            "docker",
+            "ps",
+            "-a",
+            "--no-trunc",
+            "--filter",
+            &format!("label={}", devcontainer_config::WORKSPACE_LABEL),
+            "--format",
+            &format,
+        ])
+        .wrap_err("failed to enumerate devcontainers")?;
+
+        Ok(output
+            .lines()
+            .filter_map(|line| {
+                let [id, name, state, status, workspace, config, config_file] =
+                    *line.split('\t').collect_vec()
+                else {
+                    return None;
+                };
+                let config = if config.is_empty() {
+                    config_file
+                } else {
+                    config
+                };
+                Some(ContainerSummary {
+                    id: id.to_string(),
+                    name: name.to_string(),
+                    state: state.to_string(),
+                    status: status.to_string(),
+                    workspace_folder: PathBuf::from(workspace),
+                    config: (!config.is_empty()).then(|| config.to_string()),
+                })
+            })
+            .collect())
+    }
+
+    /// Finds the workspace's containers by the `devcontainer.local_folder` and
+    /// `devcontainer.config_file` labels that the devcontainer CLI attaches to them, so that the
+    /// containers of other variants are left out.
+    pub fn find_container_ids(&self) -> Result<Vec<String>> {
+        let local_folder = host_path::canonicalize(&self.workspace_folder)
+            .into_diagnostic()
+            .wrap_err("failed to resolve workspace folder")?;
+        let label_filter = format!("label=devcontainer.local_folder={}", local_folder.display());
         let mut args = vec![
-            "devcontainer",
-            "exec",
-            "--workspace-folder",
-            &*workspace_folder,
+            "docker".to_string(),
+            "ps".to_string(),
+            "-aq".to_string(),
+            "--no-trunc".to_string(),
+            "--filter".to_string(),
+            label_filter,
         ];
-        args.extend(command.iter().map(|s| s.as_ref()));
+        if let Some(config_path) = override_config::devcontainer_json_path(&local_folder) {
+            args.extend([
+                "--filter".to_string(),
+                format!("label=devcontainer.config_file={}", config_path.display()),
+            ]);
+        }
+        let container_ids =
+            exec::capturing_stdout(&args).wrap_err("failed to enumerate devcontainers")?;
 
-        exec::capturing_stdout(&args)
+        Ok(container_ids
+            .split_whitespace()
+            .map(|id| id.to_string())
+            .collect())
     }
 
-    pub fn exec_with_stdin<S: AsRef<str>>(&self, command: &[S], stdin: Stdio) -> Result<()> {
-        let workspace_folder = self.workspace_folder.to_string_lossy();
+    /// Returns whether the container was created from devcontainer inputs other than the current
+    /// ones, or `None` if unknown (no container, or created without the hash label).
+    pub fn is_config_changed(&self) -> Result<Option<bool>> {
+        let Some(container_id) = self.find_container_ids()?.into_iter().next() else {
+            return Ok(None);
+        };
+
+        let format = format!(
+            "{{{{ index .Config.Labels \"{}\" }}}}",
+            devcontainer_config::INPUTS_HASH_LABEL
+        );
+        let created_from =
+            exec::capturing_stdout(&["docker", "inspect", "--format", &format, &container_id])?;
+        let created_from = created_from.trim();
+        if created_from.is_empty() || created_from == "<no value>" {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            created_from != devcontainer_config::inputs_hash(&self.workspace_folder)?,
+        ))
+    }
+
+    /// Resolves the Docker Compose project of a compose-based devcontainer, preferring the labels
+    /// of the running container since they include the files generated by the devcontainer CLI.
+    pub fn compose_project(&self) -> Result<ComposeProject> {
+        if let Some(container_id) = self.find_container_ids()?.first() {
+            let labels = exec::capturing_stdout(&[
+                "docker",
+                "inspect",
+                "--format",
+                "{{ json .Config.Labels }}",
+                container_id,
+            ])
+            .wrap_err("failed to inspect devcontainer")?;
+            let labels: HashMap<String, String> =
+                serde_json::from_str(&labels)
+                    .into_diagnostic()
+                    .wrap_err("failed to parse devcontainer labels")?;
+
+            if let Some(name) = labels.get("com.docker.compose.project") {
+                let files = labels
+                    .get("com.docker.compose.project.config_files")
+                    .map(|files| files.split(',').map(|file| file.to_string()).collect())
+                    .unwrap_or_default();
+
+                return Ok(ComposeProject {
+                    name: name.clone(),
+                    working_dir: labels
+                        .get("com.docker.compose.project.working_dir")
+                        .cloned(),
+                    files,
+                });
+            }
+        }
+
+        let devcontainer_config = devcontainer_config::load(&self.workspace_folder)?;
+        if !devcontainer_config.is_compose() {
+            bail!("devcontainer is not based on Docker Compose");
+        }
+        let files = devcontainer_config.compose_files;
+        let working_dir = Path::new(&files[0])
+            .parent()
+            .map(|dir| dir.to_string_lossy().to_string());
+
+        // Same default as the devcontainer CLI
+        let workspace_folder = host_path::canonicalize(&self.workspace_folder)
+            .into_diagnostic()
+            .wrap_err("failed to resolve workspace folder")?;
+        let name = format!(
+            "{}_devcontainer",
+            workspace_folder
+                .file_name()
+                .map(|name| name.to_string_lossy().to_lowercase())
+                .unwrap_or_default()
+        );
+
+        Ok(ComposeProject {
+            name,
+            working_dir,
+            files,
+        })
+    }
+
+    /// Returns the bind mounts of the container, which include the workspace mount as well as any
+    /// extra mounts.
+    pub fn path_mappings(&self) -> Result<Vec<PathMapping>> {
+        #[derive(Debug, Deserialize)]
+        struct Mount {
+            #[serde(rename = "Type")]
+            kind: String,
+
+            #[serde(rename = "Source")]
+            source: String,
+
+            #[serde(rename = "Destination")]
+            destination: String,
+        }
+
+        let container_id = match self.find_container_ids()?.into_iter().next() {
+            Some(container_id) => container_id,
+            None => self.up_and_inspect()?.container_id,
+        };
+        let mounts: Vec<Mount> = serde_json::from_str(&exec::capturing_stdout(&[
+            "docker",
+            "inspect",
+            "--format",
+            "{{ json .Mounts }}",
+            &container_id,
+        ])?)
+        .into_diagnostic()
+        .wrap_err("failed to parse container mounts")?;
+
+        Ok(mounts
+            .into_iter()
+            .filter(|mount| mount.kind == "bind")
+            .map(|mount| PathMapping {
+                host: host_path::from_mount_source(&mount.source),
+                container: mount.destination,
+            })
+            .collect())
+    }
+
+    /// Returns the container path of the host's current directory if it is a subdirectory of the
+    /// workspace.
+    pub fn current_dir_in_container(&self) -> Result<Option<String>> {
+        let (Ok(current_dir), Ok(workspace_folder)) = (
+            env::current_dir().and_then(|dir| host_path::canonicalize(&dir)),
+            host_path::canonicalize(&self.workspace_folder),
+        ) else {
+            return Ok(None);
+        };
+        if current_dir == workspace_folder || !current_dir.starts_with(&workspace_folder) {
+            return Ok(None);
+        }
+
+        Ok(path_mapping::to_container(
+            &self.path_mappings()?,
+            &current_dir,
+        ))
+    }
+
+    pub fn spawn<S: AsRef<str>>(&self, command: &[S]) -> Result<Child> {
+        let args = self.exec_args(command)?;
+
+        exec::spawn(&args)
+    }
+
+    pub fn spawn_with_output<S: AsRef<str>>(&self, command: &[S], output: File) -> Result<Child> {
+        let args = self.exec_args(command)?;
+
+        exec::spawn_with_output(&args, output)
+    }
+
+    pub fn spawn_detached<S: AsRef<str>>(&self, command: &[S], output: File) -> Result<Child> {
+        let args = self.exec_args(command)?;
+
+        exec::spawn_detached(&args, output)
+    }
+
+    pub fn exec<S: AsRef<str>>(&self, command: &[S]) -> Result<()> {
+        let args = self.exec_args(command)?;
+
+        exec::exec(&args)
+    }
+
+    /// Runs `command` with a terminal of its own through `docker exec --tty`, which switches this
+    /// terminal to raw mode and follows its size. Ctrl+C and Ctrl+Z then reach the command rather
+    /// than dockim. Unlike the other execs, `remoteEnv` of devcontainer.json is not applied.
+    pub fn exec_interactive<S: AsRef<str>>(&self, command: &[S]) -> Result<ExitStatus> {
+        if !io::stdin().is_terminal() {
+            bail!(
+                help = "run it without --interactive",
+                "interactive exec needs a terminal"
+            );
+        }
+
+        let up_output = self.up_and_inspect()?;
         let mut args = vec![
-            "devcontainer",
-            "exec",
-            "--workspace-folder",
-            &*workspace_folder,
+            "docker".to_string(),
+            "exec".to_string(),
+            "--interactive".to_string(),
+            "--tty".to_string(),
+            "--detach-keys".to_string(),
+            self.config.docker.detach_keys.clone(),
+            "--user".to_string(),
+            self.user.clone().unwrap_or(up_output.remote_user),
+            "--workdir".to_string(),
+            self.workdir
+                .clone()
+                .unwrap_or(up_output.remote_workspace_folder),
         ];
-        args.extend(command.iter().map(|s| s.as_ref()));
+        if let Ok(term) = env::var("TERM") {
+            args.extend(["--env".to_string(), format!("TERM={term}")]);
+        }
+        for (key, value) in &self.env {
+            args.extend(["--env".to_string(), format!("{key}={value}")]);
+        }
+        args.push(up_output.container_id);
+        args.extend(command.iter().map(|s| s.as_ref().to_string()));
+
+        exec::status(&args)
+    }
+
+    pub fn exec_status<S: AsRef<str>>(&self, command: &[S]) -> Result<ExitStatus> {
+        let args = self.exec_args(command)?;
+
+        exec::status(&args)
+    }
+
+    /// Runs `command` in the container and returns its output with ANSI escape sequences removed.
+    pub fn exec_capturing_stdout<S: AsRef<str>>(&self, command: &[S]) -> Result<String> {
+        let stdout = self.exec_capturing_stdout_bytes(command)?;
+
+        Ok(exec::strip_ansi(&String::from_utf8_lossy(&stdout)))
+    }
+
+    pub fn exec_capturing_stdout_bytes<S: AsRef<str>>(&self, command: &[S]) -> Result<Vec<u8>> {
+        let args = self.exec_args_with_env(CAPTURE_ENV, command)?;
+
+        exec::capturing_stdout_bytes(&args)
+    }
+
+    pub fn exec_with_stdin<S: AsRef<str>>(&self, command: &[S], stdin: Stdio) -> Result<()> {
+        let args = self.exec_args(command)?;
 
         exec::with_stdin(&args, stdin)
     }
 
     pub fn exec_with_bytes_stdin<S: AsRef<str>>(&self, command: &[S], stdin: &[u8]) -> Result<()> {
-        let workspace_folder = self.workspace_folder.to_string_lossy();
-        let mut args = vec![
-            "devcontainer",
-            "exec",
-            "--workspace-folder",
-            &*workspace_folder,
-        ];
-        args.extend(command.iter().map(|s| s.as_ref()));
+        let args = self.exec_args(command)?;
 
         exec::with_bytes_stdin(&args, stdin)
     }
 
+    /// Runs a multi-line shell script in a single exec, stopping at the first failing command.
+    pub fn exec_script(&self, script: &str) -> Result<()> {
+        self.exec(&["sh", "-c", &format!("set -e\n{script}")])
+    }
+
+    /// Like [`DevContainer::exec_script`], but the output is only shown if the script fails.
+    /// Suitable for verbose steps such as package installation and compilation.
+    pub fn exec_script_quietly(&self, script: &str) -> Result<()> {
+        let args = self.exec_args(&["sh", "-c", &format!("set -e\n{script}")])?;
+
+        exec::quietly(&args)
+    }
+
+    /// Like [`DevContainer::exec_script`], but returns the standard output of the script.
+    pub fn exec_script_capturing_stdout(&self, script: &str) -> Result<String> {
+        self.exec_capturing_stdout(&["sh", "-c", &format!("set -e\n{script}")])
+    }
+
+    /// Returns the first of `candidates` that is executable on the container.
+    pub fn find_shell<'a>(&self, candidates: &[&'a str]) -> Option<&'a str> {
+        candidates.iter().copied().find(|shell| {
+            self.exec_capturing_stdout(&[
+                "sh",
+                "-c",
+                &format!("command -v {}", exec::shell_quote(shell)),
+            ])
+            .is_ok()
+        })
+    }
+
     pub fn copy_file_host_to_container(&self, src_host: &Path, dst_container: &str) -> Result<()> {
         let src_host_file = File::open(src_host)
             .into_diagnostic()
@@ -163,12 +1106,147 @@ impl DevContainer {
     }
 
     pub fn forward_port(&self, host_port: &str, container_port: &str) -> Result<PortForwardGuard> {
-        let socat_container_name = self
-            .socat_container_name(host_port)
-            .wrap_err("failed to determine port-forwarding container name")?;
+        self.forward_port_with_tls(host_port, container_port, false)
+    }
+
+    /// Forwards `host_port` to `container_port`, terminating TLS on the host side when `https` is
+    /// set so that a plain HTTP service is reachable at https://localhost:<host_port>.
+    pub fn forward_port_with_tls(
+        &self,
+        host_port: &str,
+        container_port: &str,
+        https: bool,
+    ) -> Result<PortForwardGuard> {
+        self.forward_port_to(host_port, container_port, https, None)
+    }
+
+    /// Like [`Self::forward_port_with_tls`], but forwards to another service of the compose
+    /// project when `service` is given.
+    pub fn forward_port_to(
+        &self,
+        host_port: &str,
+        container_port: &str,
+        https: bool,
+        service: Option<&str>,
+    ) -> Result<PortForwardGuard> {
+        self.launch_forward(host_port, container_port, https, service, None)
+    }
+
+    /// Like [`Self::forward_port_to`], but runs in the background for frontends embedding dockim.
+    pub fn spawn_forward_port(
+        &self,
+        host_port: &str,
+        container_port: &str,
+        https: bool,
+        service: Option<&str>,
+    ) -> Operation<PortForwardGuard> {
+        let dc = self.clone();
+        let (host_port, container_port) = (host_port.to_string(), container_port.to_string());
+        let service = service.map(str::to_string);
+        progress::spawn(move |progress| {
+            progress.steps(1);
+            progress.step(&format!("forward port {host_port} to {container_port}"))?;
+            let guard =
+                dc.forward_port_to(&host_port, &container_port, https, service.as_deref())?;
+            // The guard stops the forward if it's dropped here
+            progress.token().check()?;
+
+            Ok(guard)
+        })
+    }
+
+    /// Like [`Self::forward_port_to`], but forwards from a reserved host port, which is held until
+    /// the forwarding container is about to bind it.
+    pub fn forward_reserved_port(
+        &self,
+        reservation: &mut Reservation,
+        container_port: &str,
+        https: bool,
+        service: Option<&str>,
+    ) -> Result<PortForwardGuard> {
+        let host_port = reservation.port().to_string();
+        self.launch_forward(
+            &host_port,
+            container_port,
+            https,
+            service,
+            Some(reservation),
+        )
+    }
+
+    /// Pulls the port-forwarding image unless present, so that a missing image is reported as
+    /// such rather than as a failed forward.
+    fn ensure_socat_image(&self) -> Result<()> {
+        let image = &self.config.port.socat_image;
+        if Self::is_image_available(image) {
+            return Ok(());
+        }
+
+        log!("Pulling" ("socat image"): "{image}");
+        exec::exec(&["docker", "pull", image]).wrap_err(miette!(
+            help = "load it with `dockim port preload-image <tar>`, or set `port.socat_image` to an image available here",
+            "port forwarding needs the image `{image}`, which is not available"
+        ))
+    }
+
+    fn uses_multiplexer(&self) -> bool {
+        match self.config.port.backend {
+            ForwardBackend::Auto => VmProvider::detect().forwards_host_network_ports(),
+            ForwardBackend::Multiplex => true,
+            ForwardBackend::Container => false,
+        }
+    }
+
+    /// Starts the helper container running a socat listener per forwarded port, unless it's
+    /// running. It uses the host network, as published ports can't be added to a container later.
+    fn ensure_multiplexer(&self, up_output: &UpOutput) -> Result<String> {
+        let multiplexer = multiplexer_name_of(up_output);
+        if is_container_running(&multiplexer)? {
+            return Ok(multiplexer);
+        }
+
+        self.ensure_socat_image()?;
+        exec::exec(&[
+            "docker",
+            "run",
+            "-d",
+            "--rm",
+            "--init",
+            "--net",
+            "host",
+            "--name",
+            &multiplexer,
+            "--entrypoint",
+            "tail",
+            &self.config.port.socat_image,
+            "-f",
+            "/dev/null",
+        ])
+        .wrap_err("failed to launch port-forwarding container")?;
+
+        Ok(multiplexer)
+    }
+
+    fn launch_forward(
+        &self,
+        host_port: &str,
+        container_port: &str,
+        https: bool,
+        service: Option<&str>,
+        reservation: Option<&mut Reservation>,
+    ) -> Result<PortForwardGuard> {
+        let vm_provider = VmProvider::detect();
+        if let Ok(host_port) = host_port.parse() {
+            vm_provider.check_host_port(host_port)?;
+        }
+
         let up_output = self
             .up_and_inspect()
             .wrap_err("failed to get devcontainer status")?;
+        let target_container_id = match service {
+            Some(service) => self.service_container_id(service)?,
+            None => up_output.container_id.clone(),
+        };
 
         #[derive(Debug, Deserialize)]
         struct ContainerNetwork {
@@ -182,7 +1260,7 @@ impl DevContainer {
                 "inspect",
                 "--format",
                 "{{ json .NetworkSettings.Networks }}",
-                &up_output.container_id,
+                &target_container_id,
             ])?)
             .into_diagnostic()
             .wrap_err("failed to parse container network settings")?;
@@ -192,78 +1270,565 @@ impl DevContainer {
             .next()
             .ok_or_else(|| miette!("failed to get container network"))?;
 
-        let docker_publish_port = format!("{}:1234", host_port);
         let socat_target = format!(
             "TCP-CONNECT:{}:{}",
             container_network.ip_address, container_port
         );
 
-        exec::exec(&[
-            "docker",
-            "run",
-            "-d",
-            "--rm",
-            "--net",
-            container_network_name,
-            "--name",
-            &socat_container_name,
-            "-p",
-            &docker_publish_port,
-            "alpine/socat",
-            "TCP-LISTEN:1234,fork",
-            &socat_target,
-        ])
-        .context("failed to launch port-forwarding container")?;
+        // The multiplexer can't mount the certificates after the fact, so TLS gets a container
+        let forward = if self.uses_multiplexer() && !https {
+            let multiplexer = self.ensure_multiplexer(&up_output)?;
+            if let Some(reservation) = reservation {
+                reservation.release_listener();
+            }
+            add_listener(&multiplexer, host_port, &socat_target)?;
+            Forward::Listener { multiplexer }
+        } else {
+            let socat_container_name = socat_container_name_of(&up_output, host_port);
+            let docker_publish_port = format!("{}:1234", host_port);
+
+            let mut args = vec![
+                "docker".to_string(),
+                "run".to_string(),
+                "-d".to_string(),
+                "--rm".to_string(),
+                "--net".to_string(),
+                container_network_name.clone(),
+                "--name".to_string(),
+                socat_container_name.clone(),
+                "-p".to_string(),
+                docker_publish_port,
+            ];
+            let socat_listen = if https {
+                let cert_dir = tls::localhost_certificate_dir()?;
+                args.extend([
+                    "-v".to_string(),
+                    format!("{}:/certs:ro", cert_dir.display()),
+                ]);
+                format!(
+                    "OPENSSL-LISTEN:1234,fork,reuseaddr,verify=0,cert=/certs/{},key=/certs/{}",
+                    tls::CERT_FILE,
+                    tls::KEY_FILE
+                )
+            } else {
+                "TCP-LISTEN:1234,fork".to_string()
+            };
+            self.ensure_socat_image()?;
+            args.extend([
+                self.config.port.socat_image.clone(),
+                socat_listen,
+                socat_target,
+            ]);
+
+            if let Some(reservation) = reservation {
+                reservation.release_listener();
+            }
+            exec::exec(&args).context("failed to launch port-forwarding container")?;
+            Forward::Container(socat_container_name)
+        };
+
+        // The socat container publishes the port on the remote host, so bring it over SSH
+        if let Some(ssh_host) = &self.config.runtime.ssh_host {
+            remote::forward(ssh_host, host_port)?;
+        } else if let Ok(host_port) = host_port.parse() {
+            vm_provider.wait_for_forward(host_port);
+        }
 
         Ok(PortForwardGuard {
-            socat_container_name,
+            forward,
+            ssh_host: self.config.runtime.ssh_host.clone(),
+            host_port: host_port.to_string(),
         })
     }
 
+    /// Returns the state of every container in the devcontainer's compose project.
+    pub fn compose_services(&self) -> Result<Vec<ServiceStatus>> {
+        #[derive(Debug, Deserialize)]
+        struct Inspect {
+            #[serde(rename = "Config")]
+            config: InspectConfig,
+            #[serde(rename = "State")]
+            state: InspectState,
+            #[serde(rename = "RestartCount")]
+            restart_count: u64,
+            #[serde(rename = "NetworkSettings")]
+            network_settings: InspectNetworkSettings,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct InspectConfig {
+            #[serde(rename = "Labels", default)]
+            labels: HashMap<String, String>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct InspectState {
+            #[serde(rename = "Status")]
+            status: String,
+            #[serde(rename = "Health")]
+            health: Option<InspectHealth>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct InspectHealth {
+            #[serde(rename = "Status")]
+            status: String,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct InspectNetworkSettings {
+            #[serde(rename = "Ports", default)]
+            ports: Option<HashMap<String, Option<Vec<PortBinding>>>>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct PortBinding {
+            #[serde(rename = "HostPort")]
+            host_port: String,
+        }
+
+        let project = self.compose_project()?;
+        let container_ids = exec::capturing_stdout(&[
+            "docker",
+            "ps",
+            "-aq",
+            "--no-trunc",
+            "--filter",
+            &format!("label=com.docker.compose.project={}", project.name),
+        ])
+        .wrap_err("failed to enumerate compose containers")?;
+        let container_ids = container_ids.split_whitespace().collect::<Vec<_>>();
+        if container_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let inspected: Vec<Inspect> = serde_json::from_str(&exec::capturing_stdout(
+            &chain!(["docker", "inspect"], container_ids.iter().copied()).collect_vec(),
+        )?)
+        .into_diagnostic()
+        .wrap_err("failed to parse compose containers")?;
+
+        let mut services = inspected
+            .into_iter()
+            .map(|inspect| {
+                let mut ports = inspect
+                    .network_settings
+                    .ports
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flat_map(|(container_port, bindings)| {
+                        bindings
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(move |binding| format!("{}:{container_port}", binding.host_port))
+                    })
+                    .collect_vec();
+                ports.sort();
+                ports.dedup();
+
+                ServiceStatus {
+                    service: inspect
+                        .config
+                        .labels
+                        .get("com.docker.compose.service")
+                        .cloned()
+                        .unwrap_or_default(),
+                    state: inspect.state.status,
+                    health: inspect.state.health.map(|health| health.status),
+                    ports,
+                    restart_count: inspect.restart_count,
+                }
+            })
+            .collect_vec();
+        services.sort_by(|a, b| a.service.cmp(&b.service));
+
+        Ok(services)
+    }
+
+    /// Returns the running container of another service in the devcontainer's compose project.
+    pub fn service_container_id(&self, service: &str) -> Result<String> {
+        let project = self.compose_project()?;
+        let container_ids = exec::capturing_stdout(&[
+            "docker",
+            "ps",
+            "-q",
+            "--no-trunc",
+            "--filter",
+            &format!("label=com.docker.compose.project={}", project.name),
+            "--filter",
+            &format!("label=com.docker.compose.service={service}"),
+        ])
+        .wrap_err_with(|| miette!("failed to find the container of service `{service}`"))?;
+
+        container_ids
+            .split_whitespace()
+            .next()
+            .map(str::to_string)
+            .ok_or_else(|| {
+                miette!(
+                    help = "check the service name in the compose files and that it is running",
+                    "service `{service}` is not running in compose project `{}`",
+                    project.name
+                )
+            })
+    }
+
+    pub fn is_forwarding(&self, host_port: &str) -> Result<bool> {
+        Ok(self.forwarding_host_ports()?.contains(host_port))
+    }
+
     pub fn stop_forward_port(&self, host_port: &str) -> Result<()> {
-        let socat_container_name = self
-            .socat_container_name(host_port)
-            .wrap_err("failed to determine port-forwarding container name")?;
-        exec::exec(&["docker", "stop", &socat_container_name])
+        let up_output = self
+            .up_and_inspect()
+            .wrap_err("failed to get devcontainer status")?;
+        let multiplexer = multiplexer_name_of(&up_output);
+        if multiplexed_host_ports(&multiplexer)?.contains(host_port) {
+            remove_listener(&multiplexer, host_port)?;
+        } else {
+            exec::exec(&[
+                "docker",
+                "stop",
+                &socat_container_name_of(&up_output, host_port),
+            ])?;
+        }
+        if let Some(ssh_host) = &self.config.runtime.ssh_host {
+            let _ = remote::cancel_forward(ssh_host, host_port);
+        }
+
+        Ok(())
+    }
+
+    /// Host ports with a running port-forwarding container or multiplexed listener.
+    pub fn forwarding_host_ports(&self) -> Result<HashSet<String>> {
+        let up_output = self
+            .up_and_inspect()
+            .wrap_err("failed to get devcontainer status")?;
+        let mut host_ports = multiplexed_host_ports(&multiplexer_name_of(&up_output))?;
+
+        let socat_container_name_prefix = socat_container_name_of(&up_output, "");
+
+        let name_filter = format!("name={socat_container_name_prefix}");
+        let port_forward_containers = exec::capturing_stdout(&[
+            "docker",
+            "ps",
+            "--filter",
+            &name_filter,
+            "--format",
+            "{{ .Names }}",
+        ])
+        .wrap_err("failed to enumerate port-forwarding containers")?;
+
+        host_ports.extend(
+            port_forward_containers
+                .split_whitespace()
+                .filter_map(|name| name.strip_prefix(&socat_container_name_prefix))
+                .map(str::to_string),
+        );
+
+        Ok(host_ports)
     }
 
     pub fn remove_all_forwarded_ports(&self) -> Result<()> {
-        let socat_container_name_prefix = self
-            .socat_container_name("")
-            .wrap_err("failed to determine port-forwarding container name")?;
+        let up_output = self
+            .up_and_inspect()
+            .wrap_err("failed to get devcontainer status")?;
+
+        let multiplexer = multiplexer_name_of(&up_output);
+        if is_container_running(&multiplexer)? {
+            if let Some(ssh_host) = &self.config.runtime.ssh_host {
+                for host_port in multiplexed_host_ports(&multiplexer)? {
+                    let _ = remote::cancel_forward(ssh_host, &host_port);
+                }
+            }
+            exec::exec(&["docker", "rm", "-f", &multiplexer])
+                .wrap_err("failed to stop port-forwarding container")?;
+        }
+
+        let socat_container_name_prefix = socat_container_name_of(&up_output, "");
 
         let name_filter = format!("name={socat_container_name_prefix}");
-        let port_forward_containers =
-            exec::capturing_stdout(&["docker", "ps", "-aq", "--filter", &name_filter])
-                .wrap_err("failed to enumerate port-forwarding containers")?;
+        let port_forward_containers = exec::capturing_stdout(&[
+            "docker",
+            "ps",
+            "-a",
+            "--filter",
+            &name_filter,
+            "--format",
+            "{{ .Names }}",
+        ])
+        .wrap_err("failed to enumerate port-forwarding containers")?;
 
-        let stop = |container_id: &str| exec::exec(&["docker", "stop", container_id]);
+        let stop = |container_name: &str| exec::exec(&["docker", "stop", container_name]);
         for port_forward_container in port_forward_containers.split_whitespace() {
             stop(port_forward_container).wrap_err("failed to stop port-forwarding container")?;
+            if let (Some(ssh_host), Some(host_port)) = (
+                &self.config.runtime.ssh_host,
+                port_forward_container.strip_prefix(&socat_container_name_prefix),
+            ) {
+                let _ = remote::cancel_forward(ssh_host, host_port);
+            }
         }
 
         Ok(())
     }
 
-    fn socat_container_name(&self, host_port: &str) -> Result<String> {
-        let up_output = self
-            .up_and_inspect()
-            .wrap_err("failed to get devcontainer status")?;
+    pub fn saved_env(&self) -> Result<BTreeMap<String, String>> {
+        state::load(&self.workspace_folder, SAVED_ENV_STATE_FILE)
+            .wrap_err("failed to load saved environment variables")
+    }
 
-        Ok(format!(
-            "dockim-{}-socat-{}",
-            up_output.container_id, host_port
-        ))
+    pub fn save_env(&self, env: &BTreeMap<String, String>) -> Result<()> {
+        state::save(&self.workspace_folder, SAVED_ENV_STATE_FILE, env)
+            .wrap_err("failed to save environment variables")
     }
+
+    pub fn registered_forwards(&self) -> Result<Vec<RegisteredForward>> {
+        state::load(&self.workspace_folder, FORWARDS_STATE_FILE)
+            .wrap_err("failed to load registered port forwards")
+    }
+
+    pub fn register_forward(
+        &self,
+        host_port: &str,
+        container_port: &str,
+        https: bool,
+    ) -> Result<()> {
+        self.register_forward_to(host_port, container_port, https, None)
+    }
+
+    pub fn register_forward_to(
+        &self,
+        host_port: &str,
+        container_port: &str,
+        https: bool,
+        service: Option<&str>,
+    ) -> Result<()> {
+        let mut forwards = self.registered_forwards()?;
+        forwards.retain(|forward| forward.host_port != host_port);
+        forwards.push(RegisteredForward {
+            host_port: host_port.to_string(),
+            container_port: container_port.to_string(),
+            https,
+            service: service.map(str::to_string),
+            healthcheck: None,
+        });
+
+        self.save_registered_forwards(&forwards)
+    }
+
+    pub fn set_forward_healthcheck(
+        &self,
+        host_port: &str,
+        healthcheck: Option<&str>,
+    ) -> Result<()> {
+        let mut forwards = self.registered_forwards()?;
+        for forward in forwards.iter_mut().filter(|f| f.host_port == host_port) {
+            forward.healthcheck = healthcheck.map(str::to_string);
+        }
+
+        self.save_registered_forwards(&forwards)
+    }
+
+    pub fn unregister_forward(&self, host_port: &str) -> Result<()> {
+        let mut forwards = self.registered_forwards()?;
+        forwards.retain(|forward| forward.host_port != host_port);
+
+        self.save_registered_forwards(&forwards)
+    }
+
+    pub fn clear_registered_forwards(&self) -> Result<()> {
+        self.save_registered_forwards(&[])
+    }
+
+    fn save_registered_forwards(&self, forwards: &[RegisteredForward]) -> Result<()> {
+        state::save(&self.workspace_folder, FORWARDS_STATE_FILE, &forwards)
+            .wrap_err("failed to save registered port forwards")
+    }
+
+    /// Re-creates registered forwards whose socat listener has disappeared (e.g. after a host
+    /// reboot) and forgets the ones whose container port is no longer listening.
+    pub fn reconcile_forwards(&self) -> Result<ReconcileSummary> {
+        let mut summary = ReconcileSummary::default();
+
+        let forwards = self.registered_forwards()?;
+        if forwards.is_empty() {
+            return Ok(summary);
+        }
+
+        let forwarding = self.forwarding_host_ports()?;
+        // If we cannot tell which ports are listening, keep every entry rather than dropping them
+        let listening_ports = self.listening_ports().ok();
+
+        let mut kept = vec![];
+        for forward in forwards {
+            if forwarding.contains(&forward.host_port) {
+                // The SSH connection may not have survived as long as the forward
+                if let Some(ssh_host) = &self.config.runtime.ssh_host {
+                    let _ = remote::forward(ssh_host, &forward.host_port);
+                }
+                summary.unchanged += 1;
+                kept.push(forward);
+                continue;
+            }
+
+            // Ports of other services can't be checked from the devcontainer
+            let is_listening = match (&listening_ports, forward.container_port.parse::<u16>()) {
+                _ if forward.service.is_some() => true,
+                (Some(listening_ports), Ok(port)) => listening_ports.contains(&port),
+                _ => true,
+            };
+            if !is_listening {
+                log!(
+                    "Dropping" ("port forward"):
+                    "{}:{} (container port is not listening)",
+                    forward.host_port,
+                    forward.container_port
+                );
+                summary.dropped += 1;
+                continue;
+            }
+
+            // The guard would stop forwarding on drop, but registered forwards should outlive us
+            mem::forget(
+                self.forward_port_to(
+                    &forward.host_port,
+                    &forward.container_port,
+                    forward.https,
+                    forward.service.as_deref(),
+                )
+                .wrap_err_with(|| {
+                    miette!(
+                        "failed to restore port forward {}:{}",
+                        forward.host_port,
+                        forward.container_port
+                    )
+                })?,
+            );
+            summary.restored += 1;
+            kept.push(forward);
+        }
+
+        self.save_registered_forwards(&kept)?;
+
+        Ok(summary)
+    }
+
+    pub fn listening_ports(&self) -> Result<HashSet<u16>> {
+        let tables = self
+            .exec_capturing_stdout(&["sh", "-c", "cat /proc/net/tcp /proc/net/tcp6 2>/dev/null"])
+            .wrap_err("failed to read listening ports on container")?;
+
+        const TCP_LISTEN: &str = "0A";
+        let ports = tables
+            .lines()
+            .filter_map(|line| {
+                let fields = line.split_whitespace().collect::<Vec<_>>();
+                if fields.len() < 4 || fields[3] != TCP_LISTEN {
+                    return None;
+                }
+
+                let (_, port) = fields[1].rsplit_once(':')?;
+                u16::from_str_radix(port, 16).ok()
+            })
+            .collect();
+
+        Ok(ports)
+    }
+}
+
+/// Abbreviates a container ID the way `docker ps` does.
+fn short_id(container_id: &str) -> &str {
+    &container_id[..container_id.len().min(12)]
+}
+
+fn socat_container_name_of(up_output: &UpOutput, host_port: &str) -> String {
+    format!("dockim-{}-socat-{}", up_output.container_id, host_port)
+}
+
+fn multiplexer_name_of(up_output: &UpOutput) -> String {
+    format!("dockim-{}-socat", up_output.container_id)
+}
+
+/// Starts socat in the background so that it outlives `docker exec`, and fails if it exits right
+/// away, e.g. because the port is taken. The subshell leaves reaping it to the container's init,
+/// but it may not have done so yet, hence the check for a zombie.
+const ADD_LISTENER_SCRIPT: &str = r#"(socat "$1" "$2" </dev/null >/dev/null 2>"/tmp/forward-$3.log" & echo $! >"/tmp/forward-$3.pid")
+sleep 0.2
+grep -Eqs "^State:[[:space:]]+[^ZX]" "/proc/$(cat "/tmp/forward-$3.pid")/status" || { cat "/tmp/forward-$3.log" >&2; exit 1; }"#;
+
+fn add_listener(multiplexer: &str, host_port: &str, socat_target: &str) -> Result<()> {
+    let socat_listen = format!("TCP-LISTEN:{host_port},fork,reuseaddr");
+    exec::exec(&[
+        "docker",
+        "exec",
+        multiplexer,
+        "sh",
+        "-c",
+        ADD_LISTENER_SCRIPT,
+        "sh",
+        &socat_listen,
+        socat_target,
+        host_port,
+    ])
+    .wrap_err_with(|| miette!("failed to listen on port {host_port} for forwarding"))
+}
+
+fn remove_listener(multiplexer: &str, host_port: &str) -> Result<()> {
+    let pattern = format!("LISTEN:{host_port},");
+    exec::exec(&["docker", "exec", multiplexer, "pkill", "-f", &pattern])
+        .wrap_err_with(|| miette!("failed to stop forwarding port {host_port}"))
+}
+
+/// Host ports the multiplexer listens on; none if it isn't running.
+fn multiplexed_host_ports(multiplexer: &str) -> Result<HashSet<String>> {
+    if !is_container_running(multiplexer)? {
+        return Ok(HashSet::new());
+    }
+
+    let processes = exec::capturing_stdout(&["docker", "exec", multiplexer, "ps", "-o", "args"])
+        .wrap_err("failed to enumerate port-forwarding listeners")?;
+
+    Ok(processes
+        .lines()
+        .filter_map(|line| {
+            let (_, listen) = line.split_once("socat TCP-LISTEN:")?;
+            let (host_port, _) = listen.split_once(',')?;
+            Some(host_port.to_string())
+        })
+        .collect())
+}
+
+fn is_container_running(container_name: &str) -> Result<bool> {
+    let name_filter = format!("name=^{container_name}$");
+    let running = exec::capturing_stdout(&["docker", "ps", "-q", "--filter", &name_filter])
+        .wrap_err_with(|| miette!("failed to query status of container `{container_name}`"))?;
+
+    Ok(!running.trim().is_empty())
+}
+
+#[derive(Debug)]
+enum Forward {
+    Container(String),
+    Listener { multiplexer: String },
 }
 
 #[derive(Debug)]
 pub struct PortForwardGuard {
-    socat_container_name: String,
+    forward: Forward,
+    ssh_host: Option<String>,
+    host_port: String,
 }
 
 impl Drop for PortForwardGuard {
     fn drop(&mut self) {
-        let _ = exec::exec(&["docker", "stop", &self.socat_container_name]);
+        let _ = match &self.forward {
+            Forward::Container(socat_container_name) => {
+                exec::exec(&["docker", "stop", socat_container_name])
+            }
+            Forward::Listener { multiplexer } => remove_listener(multiplexer, &self.host_port),
+        };
+        if let Some(ssh_host) = &self.ssh_host {
+            let _ = remote::cancel_forward(ssh_host, &self.host_port);
+        }
     }
 }
@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{host_path, override_config, state};
+
+const CACHE_STATE_FILE: &str = "devcontainer-config.json";
+
+/// Label holding the [`inputs_hash`] the container was created from
+pub const INPUTS_HASH_LABEL: &str = "dockim.inputs-hash";
+
+/// Label holding the canonical workspace folder the container belongs to
+pub const WORKSPACE_LABEL: &str = "dockim.workspace";
+
+/// Label holding the devcontainer.json the container was created from
+pub const CONFIG_LABEL: &str = "dockim.config";
+
+/// Settings of devcontainer.json that dockim needs on most commands.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DevcontainerConfig {
+    /// Absolute paths of the compose files, empty if not based on Docker Compose
+    pub compose_files: Vec<String>,
+    pub service: Option<String>,
+    pub remote_user: Option<String>,
+    pub forward_ports: Vec<String>,
+}
+
+impl DevcontainerConfig {
+    pub fn is_compose(&self) -> bool {
+        !self.compose_files.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cache {
+    /// Modification times of the files the config was read from
+    sources: BTreeMap<PathBuf, u128>,
+    config: DevcontainerConfig,
+}
+
+/// Returns the parsed configuration, reusing the cached one while its source files are unchanged.
+pub fn load(workspace_folder: &Path) -> Result<DevcontainerConfig> {
+    let cache: Cache = state::load(workspace_folder, CACHE_STATE_FILE).unwrap_or_default();
+    let is_fresh = !cache.sources.is_empty()
+        && cache
+            .sources
+            .iter()
+            .all(|(path, mtime)| modified_nanos(path) == Some(*mtime));
+    if is_fresh {
+        return Ok(cache.config);
+    }
+
+    let (config_path, config) = override_config::read_devcontainer_json(workspace_folder)?;
+    let config_path = host_path::canonicalize(&config_path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to resolve {}", config_path.display()))?;
+    let config_dir = config_path.parent().unwrap_or(Path::new("/"));
+
+    let compose_files: Vec<String> = match config.get("dockerComposeFile") {
+        Some(Value::String(file)) => vec![file.clone()],
+        Some(Value::Array(files)) => files
+            .iter()
+            .filter_map(|file| file.as_str().map(|file| file.to_string()))
+            .collect(),
+        _ => vec![],
+    }
+    .into_iter()
+    .map(|file| config_dir.join(file).to_string_lossy().to_string())
+    .collect();
+
+    let parsed = DevcontainerConfig {
+        service: config
+            .get("service")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        remote_user: config
+            .get("remoteUser")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        forward_ports: config
+            .get("forwardPorts")
+            .and_then(Value::as_array)
+            .map(|ports| {
+                ports
+                    .iter()
+                    .map(|port| match port {
+                        Value::String(port) => port.clone(),
+                        port => port.to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        compose_files,
+    };
+
+    let sources = std::iter::once(config_path)
+        .chain(parsed.compose_files.iter().map(PathBuf::from))
+        .filter_map(|path| modified_nanos(&path).map(|mtime| (path, mtime)))
+        .collect();
+    // The cache is only an optimization, so failing to write it is not an error
+    let _ = state::save(
+        workspace_folder,
+        CACHE_STATE_FILE,
+        &Cache {
+            sources,
+            config: parsed.clone(),
+        },
+    );
+
+    Ok(parsed)
+}
+
+/// Hashes devcontainer.json (including the feature versions it references), its Dockerfile and
+/// compose files, so that a container created from different inputs can be detected.
+pub fn inputs_hash(workspace_folder: &Path) -> Result<String> {
+    let (config_path, config) = override_config::read_devcontainer_json(workspace_folder)?;
+    let config_dir = config_path.parent().unwrap_or(Path::new("/"));
+
+    let dockerfile = config
+        .get("build")
+        .and_then(|build| build.get("dockerfile"))
+        .or_else(|| config.get("dockerFile"))
+        .and_then(Value::as_str)
+        .map(|dockerfile| config_dir.join(dockerfile));
+    let compose_files = load(workspace_folder)?.compose_files;
+
+    let mut hash = Fnv1a::new();
+    let inputs = std::iter::once(config_path.clone())
+        .chain(dockerfile)
+        .chain(compose_files.iter().map(PathBuf::from));
+    for path in inputs {
+        let contents = fs::read(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+        hash.write(&contents);
+        // Separate the files so that moving bytes between them changes the hash
+        hash.write(&[0]);
+    }
+
+    Ok(format!("{:016x}", hash.finish()))
+}
+
+/// FNV-1a, used because the hash must stay the same across builds of dockim.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn modified_nanos(path: &Path) -> Option<u128> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_nanos())
+}
@@ -0,0 +1,130 @@
+use std::{collections::BTreeMap, env, path::Path};
+
+/// Where GUI apps in the container should connect to, as detected on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisplayServer {
+    /// WSLg, which serves both X11 and Wayland from /mnt/wslg
+    Wslg,
+    Wayland {
+        socket: String,
+    },
+    X11 {
+        display: String,
+    },
+    /// XQuartz or an X server on Windows, reached over TCP through host.docker.internal
+    RemoteX11,
+}
+
+impl DisplayServer {
+    pub fn detect() -> Option<Self> {
+        if cfg!(target_os = "macos") || cfg!(windows) {
+            return Some(DisplayServer::RemoteX11);
+        }
+
+        if is_wsl() && Path::new("/mnt/wslg").exists() {
+            return Some(DisplayServer::Wslg);
+        }
+
+        if let (Ok(runtime_dir), Ok(wayland_display)) =
+            (env::var("XDG_RUNTIME_DIR"), env::var("WAYLAND_DISPLAY"))
+        {
+            let socket = Path::new(&runtime_dir).join(&wayland_display);
+            if socket.exists() {
+                return Some(DisplayServer::Wayland {
+                    socket: socket.to_string_lossy().to_string(),
+                });
+            }
+        }
+
+        env::var("DISPLAY")
+            .ok()
+            .filter(|display| !display.is_empty())
+            .map(|display| DisplayServer::X11 { display })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisplayServer::Wslg => "WSLg",
+            DisplayServer::Wayland { .. } => "Wayland",
+            DisplayServer::X11 { .. } => "X11",
+            DisplayServer::RemoteX11 => {
+                if cfg!(target_os = "macos") {
+                    "XQuartz"
+                } else {
+                    "X server on the host"
+                }
+            }
+        }
+    }
+
+    /// Returns the `docker run` arguments and container environment that expose the display
+    /// server to the container.
+    pub fn forwarding(&self) -> (Vec<String>, BTreeMap<String, String>) {
+        let mut run_args = vec![];
+        let mut env = BTreeMap::new();
+
+        match self {
+            DisplayServer::Wslg => {
+                run_args.extend([
+                    "--volume=/mnt/wslg:/mnt/wslg".to_string(),
+                    "--volume=/tmp/.X11-unix:/tmp/.X11-unix".to_string(),
+                ]);
+                env.insert("DISPLAY".to_string(), ":0".to_string());
+                env.insert("WAYLAND_DISPLAY".to_string(), "wayland-0".to_string());
+                env.insert(
+                    "XDG_RUNTIME_DIR".to_string(),
+                    "/mnt/wslg/runtime-dir".to_string(),
+                );
+                env.insert(
+                    "PULSE_SERVER".to_string(),
+                    "/mnt/wslg/PulseServer".to_string(),
+                );
+            }
+            DisplayServer::Wayland { socket } => {
+                run_args.push(format!(
+                    "--volume={socket}:{CONTAINER_RUNTIME_DIR}/wayland-0"
+                ));
+                env.insert("WAYLAND_DISPLAY".to_string(), "wayland-0".to_string());
+                env.insert(
+                    "XDG_RUNTIME_DIR".to_string(),
+                    CONTAINER_RUNTIME_DIR.to_string(),
+                );
+                // Most compositors also run Xwayland for apps without Wayland support
+                if let Ok(display) = env::var("DISPLAY") {
+                    run_args.extend(x11_volumes());
+                    env.insert("DISPLAY".to_string(), display);
+                }
+            }
+            DisplayServer::X11 { display } => {
+                run_args.extend(x11_volumes());
+                env.insert("DISPLAY".to_string(), display.clone());
+            }
+            DisplayServer::RemoteX11 => {
+                env.insert("DISPLAY".to_string(), "host.docker.internal:0".to_string());
+            }
+        }
+
+        if matches!(
+            self,
+            DisplayServer::Wayland { .. } | DisplayServer::X11 { .. }
+        ) {
+            if let Ok(xauthority) = env::var("XAUTHORITY") {
+                run_args.push(format!("--volume={xauthority}:/tmp/.Xauthority:ro"));
+                env.insert("XAUTHORITY".to_string(), "/tmp/.Xauthority".to_string());
+            }
+        }
+
+        (run_args, env)
+    }
+}
+
+const CONTAINER_RUNTIME_DIR: &str = "/tmp/dockim-runtime";
+
+fn x11_volumes() -> Vec<String> {
+    vec!["--volume=/tmp/.X11-unix:/tmp/.X11-unix".to_string()]
+}
+
+fn is_wsl() -> bool {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .is_ok_and(|release| release.to_lowercase().contains("microsoft"))
+}
@@ -1,12 +1,16 @@
 use std::{
     fmt::Debug,
-    io::Write,
-    process::{Child, Command, Stdio},
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::Duration,
 };
 
-use miette::{ensure, IntoDiagnostic, Result, WrapErr};
+use miette::{bail, ensure, IntoDiagnostic, Result, WrapErr};
 
-use crate::log;
+use crate::{log, progress::CancellationToken};
 
 pub fn spawn<S: AsRef<str> + Debug>(args: &[S]) -> Result<Child> {
     ensure!(!args.is_empty(), "No command provided to exec");
@@ -19,8 +23,8 @@ pub fn spawn<S: AsRef<str> + Debug>(args: &[S]) -> Result<Child> {
     let child = Command::new(command)
         .args(args.iter().map(|s| s.as_ref()))
         .stdin(Stdio::null())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
+        .stdout(terminal_or_capture())
+        .stderr(terminal_or_capture())
         .spawn()
         .into_diagnostic()
         .wrap_err("spawn failed")?;
@@ -28,6 +32,78 @@ pub fn spawn<S: AsRef<str> + Debug>(args: &[S]) -> Result<Child> {
     Ok(child)
 }
 
+pub fn spawn_capturing_stdout<S: AsRef<str> + Debug>(args: &[S]) -> Result<Child> {
+    ensure!(!args.is_empty(), "No command provided to exec");
+
+    log!("Running" ("with capture"): "{args:?}");
+
+    let command = args[0].as_ref();
+    let args = &args[1..];
+
+    let child = Command::new(command)
+        .args(args.iter().map(|s| s.as_ref()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(terminal_or_capture())
+        .spawn()
+        .into_diagnostic()
+        .wrap_err("spawn failed")?;
+
+    Ok(child)
+}
+
+/// Spawns a process whose stdout and stderr are written to `output`.
+pub fn spawn_with_output<S: AsRef<str> + Debug>(args: &[S], output: File) -> Result<Child> {
+    ensure!(!args.is_empty(), "no command provided to exec");
+
+    log!("Running" ("with output"): "{args:?}");
+
+    output_command(args, output)?
+        .spawn()
+        .into_diagnostic()
+        .wrap_err("spawn failed")
+}
+
+/// Like [`spawn_with_output`], but the process keeps running after the terminal is closed.
+pub fn spawn_detached<S: AsRef<str> + Debug>(args: &[S], output: File) -> Result<Child> {
+    ensure!(!args.is_empty(), "no command provided to exec");
+
+    log!("Running" ("detached"): "{args:?}");
+
+    let mut command = output_command(args, output)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    command.spawn().into_diagnostic().wrap_err("spawn failed")
+}
+
+fn output_command<S: AsRef<str>>(args: &[S], output: File) -> Result<Command> {
+    let stderr = output
+        .try_clone()
+        .into_diagnostic()
+        .wrap_err("failed to duplicate output file handle")?;
+
+    let mut command = Command::new(args[0].as_ref());
+    command
+        .args(args[1..].iter().map(|s| s.as_ref()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(output))
+        .stderr(Stdio::from(stderr));
+
+    Ok(command)
+}
+
+/// The terminal, or the file capturing the output of commands while [`log::capture`] is in effect.
+fn terminal_or_capture() -> Stdio {
+    match log::captured_output() {
+        Some(file) => Stdio::from(file),
+        None => Stdio::inherit(),
+    }
+}
+
 pub fn exec<S: AsRef<str> + Debug>(args: &[S]) -> Result<()> {
     ensure!(!args.is_empty(), "No command provided to exec");
 
@@ -38,6 +114,8 @@ pub fn exec<S: AsRef<str> + Debug>(args: &[S]) -> Result<()> {
 
     let status = Command::new(command)
         .args(args.iter().map(|s| s.as_ref()))
+        .stdout(terminal_or_capture())
+        .stderr(terminal_or_capture())
         .status()
         .into_diagnostic()
         .wrap_err("exec failed")?;
@@ -49,6 +127,126 @@ pub fn exec<S: AsRef<str> + Debug>(args: &[S]) -> Result<()> {
     Ok(())
 }
 
+/// Like [`exec`], but runs the command in `dir`.
+pub fn exec_in<S: AsRef<str> + Debug>(dir: &Path, args: &[S]) -> Result<()> {
+    ensure!(!args.is_empty(), "no command provided to exec");
+
+    log!("Running": "{args:?} in {}", dir.display());
+
+    let status = Command::new(args[0].as_ref())
+        .args(args[1..].iter().map(|s| s.as_ref()))
+        .current_dir(dir)
+        .stdout(terminal_or_capture())
+        .stderr(terminal_or_capture())
+        .status()
+        .into_diagnostic()
+        .wrap_err("exec failed")?;
+    ensure!(
+        status.success(),
+        "{} returned non-successful status ({status})",
+        args[0].as_ref()
+    );
+
+    Ok(())
+}
+
+/// Like [`exec`], but kills the process as soon as `token` is cancelled.
+pub fn cancellable<S: AsRef<str> + Debug>(args: &[S], token: &CancellationToken) -> Result<()> {
+    let mut child = spawn(args)?;
+    loop {
+        if let Some(status) = child.try_wait().into_diagnostic().wrap_err("exec failed")? {
+            ensure!(
+                status.success(),
+                "{} returned non-successful status",
+                args[0].as_ref()
+            );
+            return Ok(());
+        }
+
+        if token.is_cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return token.check();
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Like [`exec`], but returns the exit status instead of failing when it is non-successful.
+pub fn status<S: AsRef<str> + Debug>(args: &[S]) -> Result<ExitStatus> {
+    ensure!(!args.is_empty(), "no command provided to exec");
+
+    log!("Running": "{args:?}");
+
+    Command::new(args[0].as_ref())
+        .args(args[1..].iter().map(|s| s.as_ref()))
+        .stdout(terminal_or_capture())
+        .stderr(terminal_or_capture())
+        .status()
+        .into_diagnostic()
+        .wrap_err("exec failed")
+}
+
+/// At most this much of the end of the output is shown when a quiet command fails
+pub const QUIET_TAIL_BYTES: u64 = 8 * 1024;
+
+/// Runs a command whose output is only interesting if it fails. The output is streamed to a
+/// temporary file instead of memory; on failure its tail is shown and the file is kept.
+pub fn quietly<S: AsRef<str> + Debug>(args: &[S]) -> Result<()> {
+    ensure!(!args.is_empty(), "no command provided to exec");
+
+    let log = tempfile::Builder::new()
+        .prefix("dockim-")
+        .suffix(".log")
+        .tempfile()
+        .into_diagnostic()
+        .wrap_err("failed to create a log file")?;
+    let output = log
+        .reopen()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to open {}", log.path().display()))?;
+
+    log!("Running" ("quietly"): "{args:?}, output goes to {}", log.path().display());
+
+    let status = output_command(args, output)?
+        .status()
+        .into_diagnostic()
+        .wrap_err("exec failed")?;
+    if status.success() {
+        return Ok(());
+    }
+
+    let (_, log_path) = log
+        .keep()
+        .into_diagnostic()
+        .wrap_err("failed to keep the log file")?;
+    let tail = read_tail(&log_path, QUIET_TAIL_BYTES).unwrap_or_default();
+    eprintln!("{}", tail.trim_end());
+    bail!(
+        help = format!("the full output is in {}", log_path.display()),
+        "command returned non-successful status"
+    );
+}
+
+/// Reads up to the last `max_bytes` of a log, starting at a line boundary.
+pub fn read_tail(path: &Path, max_bytes: u64) -> Result<String> {
+    let mut file = File::open(path).into_diagnostic()?;
+    let len = file.metadata().into_diagnostic()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(max_bytes)))
+        .into_diagnostic()?;
+
+    let mut tail = vec![];
+    file.read_to_end(&mut tail).into_diagnostic()?;
+    let tail = strip_ansi(&String::from_utf8_lossy(&tail));
+
+    // Drop the partial first line unless the whole file fits
+    Ok(match tail.split_once('\n') {
+        Some((_, rest)) if len > max_bytes => rest.to_string(),
+        _ => tail,
+    })
+}
+
 pub fn with_stdin<S: AsRef<str> + Debug>(args: &[S], stdin: Stdio) -> Result<()> {
     ensure!(!args.is_empty(), "no command provided to exec");
 
@@ -60,6 +258,8 @@ pub fn with_stdin<S: AsRef<str> + Debug>(args: &[S], stdin: Stdio) -> Result<()>
     let status = Command::new(command)
         .args(args.iter().map(|s| s.as_ref()))
         .stdin(stdin)
+        .stdout(terminal_or_capture())
+        .stderr(terminal_or_capture())
         .status()
         .into_diagnostic()
         .wrap_err("exec failed")?;
@@ -82,6 +282,8 @@ pub fn with_bytes_stdin<S: AsRef<str> + Debug>(args: &[S], bytes: &[u8]) -> Resu
     let mut child = Command::new(command)
         .args(args.iter().map(|s| s.as_ref()))
         .stdin(Stdio::piped())
+        .stdout(terminal_or_capture())
+        .stderr(terminal_or_capture())
         .spawn()
         .into_diagnostic()?;
     child
@@ -97,31 +299,191 @@ pub fn with_bytes_stdin<S: AsRef<str> + Debug>(args: &[S], bytes: &[u8]) -> Resu
         .wrap_err("failed to wait child process to finish")?;
     ensure!(
         status.success(),
-        "devcontainer CLI returned non-successful status"
+        "{command} returned non-successful status ({status})"
     );
 
     Ok(())
 }
 
+/// Runs a command and returns its standard output.
 pub fn capturing_stdout<S: AsRef<str> + Debug>(args: &[S]) -> Result<String> {
+    let stdout = capturing_stdout_bytes(args)?;
+
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Runs a command and returns its standard output as is.
+pub fn capturing_stdout_bytes<S: AsRef<str> + Debug>(args: &[S]) -> Result<Vec<u8>> {
+    capture(args)
+}
+
+/// Captured output beyond this fails the command rather than growing without bound; commands
+/// with verbose output should go through [`quietly`] instead
+const MAX_CAPTURE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// At most this much of the end of stderr is kept to explain a failed capture
+const CAPTURE_STDERR_TAIL_BYTES: usize = 4 * 1024;
+
+fn capture<S: AsRef<str> + Debug>(args: &[S]) -> Result<Vec<u8>> {
     ensure!(!args.is_empty(), "no command provided to exec");
 
     log!("Running" ("with capture"): "{args:?}");
 
-    let command = args[0].as_ref();
+    let program = args[0].as_ref();
     let args = &args[1..];
 
-    let out = Command::new(command)
+    let mut child = Command::new(program)
         .args(args.iter().map(|s| s.as_ref()))
-        .output()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
         .into_diagnostic()
         .wrap_err("exec failed")?;
-    ensure!(
-        out.status.success(),
-        "devcontainer CLI returned non-successful status"
-    );
 
-    let stdout = String::from_utf8_lossy(&out.stdout).to_string();
+    // Drained concurrently so that a chatty stderr can't block the child while stdout is read
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_tail = thread::spawn(move || {
+        let mut tail = vec![];
+        let mut buf = [0u8; 8192];
+        while let Ok(n @ 1..) = stderr.read(&mut buf) {
+            tail.extend_from_slice(&buf[..n]);
+            if tail.len() > 2 * CAPTURE_STDERR_TAIL_BYTES {
+                tail.drain(..tail.len() - CAPTURE_STDERR_TAIL_BYTES);
+            }
+        }
+        let start = tail.len().saturating_sub(CAPTURE_STDERR_TAIL_BYTES);
+        strip_ansi(&String::from_utf8_lossy(&tail[start..]))
+            .trim()
+            .to_string()
+    });
+
+    let mut stdout = vec![];
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .take(MAX_CAPTURE_BYTES + 1)
+        .read_to_end(&mut stdout)
+        .into_diagnostic()
+        .wrap_err("failed to read the output")?;
+    if stdout.len() as u64 > MAX_CAPTURE_BYTES {
+        let _ = child.kill();
+        let _ = child.wait();
+        bail!(
+            "{program} wrote more than {} MiB of output",
+            MAX_CAPTURE_BYTES / 1024 / 1024
+        );
+    }
+
+    let status = child
+        .wait()
+        .into_diagnostic()
+        .wrap_err("failed to wait child process to finish")?;
+    let stderr_tail = stderr_tail.join().unwrap_or_default();
+    if !status.success() {
+        if stderr_tail.is_empty() {
+            bail!("{program} returned non-successful status ({status})");
+        }
+        bail!("{program} returned non-successful status ({status}): {stderr_tail}");
+    }
 
     Ok(stdout)
 }
+
+/// Removes ANSI escape sequences (colors, cursor movements, window titles) from `s`.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // CSI: parameters and intermediates up to a final byte in @..~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: terminated by BEL or ST (ESC \)
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Other two-character sequences
+            _ => {}
+        }
+    }
+
+    out
+}
+
+/// Runs `command` through the host shell.
+pub fn shell(command: &str) -> Result<()> {
+    if cfg!(windows) {
+        exec(&["cmd", "/C", command])
+    } else {
+        exec(&["sh", "-c", command])
+    }
+}
+
+/// Quotes `s` for safe use as a single word in a POSIX shell command line.
+pub fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c))
+    {
+        return s.to_string();
+    }
+
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_ansi_removes_colors_and_cursor_movements() {
+        assert_eq!(strip_ansi("\x1b[1;31merror\x1b[0m: x"), "error: x");
+        assert_eq!(strip_ansi("\x1b[2K\x1b[1Gdone"), "done");
+    }
+
+    #[test]
+    fn strip_ansi_removes_window_titles() {
+        assert_eq!(strip_ansi("\x1b]0;title\x07text"), "text");
+        assert_eq!(strip_ansi("\x1b]0;title\x1b\\text"), "text");
+    }
+
+    #[test]
+    fn strip_ansi_keeps_plain_text() {
+        assert_eq!(strip_ansi("plain\ttext\n"), "plain\ttext\n");
+        assert_eq!(strip_ansi("日本語"), "日本語");
+    }
+
+    #[test]
+    fn shell_quote_leaves_safe_words_alone() {
+        assert_eq!(shell_quote("/usr/bin/nvim"), "/usr/bin/nvim");
+        assert_eq!(shell_quote("KEY=value"), "KEY=value");
+    }
+
+    #[test]
+    fn shell_quote_quotes_everything_else() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
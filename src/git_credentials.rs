@@ -0,0 +1,217 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    config::Config, devcontainer::DevContainer, exec, host_port, log, pairing, remote, secret,
+    state,
+};
+
+/// Secret of each workspace's helper, so that only our containers can ask for credentials
+const TOKEN_STATE_FILE: &str = "git-credential-token.json";
+
+/// Where the helper is installed on the container
+pub const HELPER_PATH: &str = "$HOME/.local/bin/git-credential-dockim";
+
+/// A request must arrive within this time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Configures git on the container to ask git on the host for credentials through the bridge.
+pub fn install(config: &Config, dc: &DevContainer) -> Result<()> {
+    if dc
+        .exec_capturing_stdout(&["sh", "-c", "command -v bash"])
+        .is_err()
+    {
+        bail!(
+            help = "install bash on the container or disable `auth.git_credentials`",
+            "the git credential helper needs bash on the container"
+        );
+    }
+
+    dc.exec_with_bytes_stdin(
+        &[
+            "sh",
+            "-c",
+            &format!("mkdir -p \"$(dirname {HELPER_PATH})\" && cat > {HELPER_PATH} && chmod 700 {HELPER_PATH}"),
+        ],
+        helper_script(config, dc)?.as_bytes(),
+    )
+    .wrap_err("failed to install the git credential helper")?;
+    dc.exec_script(&format!(
+        "git config --global --replace-all credential.helper \"{HELPER_PATH}\""
+    ))
+    .wrap_err("failed to configure the git credential helper")?;
+
+    log!("Configured" ("git credentials"): "the container asks the host through port {}", config.auth.credential_port);
+
+    Ok(())
+}
+
+/// Starts the bridge on a background thread for the rest of this process. If the port is taken,
+/// another dockim is serving it already.
+pub fn spawn_bridge(config: &Config, dc: &DevContainer) {
+    if !config.auth.git_credentials {
+        return;
+    }
+
+    let Ok(listener) = bind(config, dc) else {
+        return;
+    };
+    if let Some(ssh_host) = &config.runtime.ssh_host {
+        if let Err(e) = remote::reverse_forward(ssh_host, &config.auth.credential_port.to_string())
+        {
+            log!("Warning": "git credentials won't be forwarded: {e}");
+            return;
+        }
+    }
+
+    let allow_store = config.auth.git_credentials_store;
+    thread::spawn(move || serve_listener(listener, allow_store));
+}
+
+/// Runs the bridge in the foreground.
+pub fn serve(config: &Config, dc: &DevContainer) -> Result<()> {
+    let listener = bind(config, dc).wrap_err_with(|| {
+        miette!(
+            help = "another dockim may be serving git credentials already",
+            "failed to listen on port {}",
+            config.auth.credential_port
+        )
+    })?;
+    if let Some(ssh_host) = &config.runtime.ssh_host {
+        remote::reverse_forward(ssh_host, &config.auth.credential_port.to_string())?;
+    }
+
+    log!("Serving" ("git credentials"): "on port {}", config.auth.credential_port);
+    serve_listener(listener, config.auth.git_credentials_store);
+
+    Ok(())
+}
+
+/// Binds the Docker bridge where possible, keeping the host's credentials off the network.
+fn bind(config: &Config, dc: &DevContainer) -> Result<TcpListener> {
+    TcpListener::bind((
+        host_port::container_listen_address(config, dc),
+        config.auth.credential_port,
+    ))
+    .into_diagnostic()
+}
+
+fn serve_listener(listener: TcpListener, allow_store: bool) {
+    for stream in listener.incoming().flatten() {
+        thread::spawn(move || {
+            let _ = handle(stream, allow_store);
+        });
+    }
+}
+
+/// Answers a request of the helper: a line with the token and the operation, then the
+/// attributes of the git credential protocol up to an empty line.
+fn handle(stream: TcpStream, allow_store: bool) -> Result<()> {
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .into_diagnostic()?;
+    let mut reader = BufReader::new(stream.try_clone().into_diagnostic()?);
+
+    let mut header = String::new();
+    reader.read_line(&mut header).into_diagnostic()?;
+    let Some((request_token, operation)) = header.trim_end().split_once(' ') else {
+        return Ok(());
+    };
+    // One bridge serves the containers of all workspaces, each with a token of its own
+    if !is_known_token(request_token) {
+        return Ok(());
+    }
+    // Whoever pairs on the container could use the host's credentials too
+    if pairing::is_active() {
+        return Ok(());
+    }
+    let git_operation = match operation {
+        "get" => "fill",
+        "store" if allow_store => "approve",
+        "erase" if allow_store => "reject",
+        _ => return Ok(()),
+    };
+
+    let mut input = String::new();
+    for line in reader.lines() {
+        let line = line.into_diagnostic()?;
+        if line.is_empty() {
+            break;
+        }
+        input.push_str(&line);
+        input.push('\n');
+    }
+
+    // Never prompt on the terminal of whoever runs dockim
+    let mut git = Command::new("git")
+        .args(["credential", git_operation])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .into_diagnostic()?;
+    if let Some(mut stdin) = git.stdin.take() {
+        stdin.write_all(input.as_bytes()).into_diagnostic()?;
+    }
+    let output = git.wait_with_output().into_diagnostic()?;
+
+    if operation == "get" && output.status.success() {
+        (&stream).write_all(&output.stdout).into_diagnostic()?;
+    }
+
+    Ok(())
+}
+
+fn helper_script(config: &Config, dc: &DevContainer) -> Result<String> {
+    let token = token(dc)?;
+    let port = config.auth.credential_port;
+
+    Ok(format!(
+        r#"#!/usr/bin/env bash
+# Installed by dockim: asks git on the host for credentials
+exec 3<>/dev/tcp/host.docker.internal/{port} 2>/dev/null || exit 0
+{{ printf '%s %s\n' {token} "$1"; cat; printf '\n'; }} >&3
+[ "$1" = get ] && cat <&3
+exit 0
+"#,
+        token = exec::shell_quote(&token)
+    ))
+}
+
+/// Returns the secret of the workspace's helper, generating it on first use.
+fn token(dc: &DevContainer) -> Result<String> {
+    let token: Option<String> = state::load(dc.workspace_folder(), TOKEN_STATE_FILE)?;
+    if let Some(token) = token {
+        return Ok(token);
+    }
+
+    let token = secret::random_token()?;
+    state::save(dc.workspace_folder(), TOKEN_STATE_FILE, &Some(&token))?;
+
+    Ok(token)
+}
+
+/// Whether a workspace's helper was installed with `token`. Read on every request, since helpers
+/// installed by other dockim processes use the same bridge.
+fn is_known_token(token: &str) -> bool {
+    let Ok(dirs) = state::workspace_state_dirs() else {
+        return false;
+    };
+
+    dirs.iter().any(|dir| {
+        fs::read_to_string(dir.join(TOKEN_STATE_FILE))
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Option<String>>(&contents).ok())
+            .flatten()
+            .is_some_and(|known| secret::token_matches(&known, token))
+    })
+}
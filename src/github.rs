@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::exec;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+
+    /// Size in bytes
+    #[serde(default)]
+    pub size: u64,
+
+    /// Checksum computed by GitHub (e.g. `sha256:<hex>`), for assets uploaded since mid-2025
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+impl Release {
+    /// Returns the first asset whose name matches one of `candidates`, in order of preference.
+    pub fn find_asset(&self, candidates: &[&str]) -> Option<&Asset> {
+        candidates
+            .iter()
+            .find_map(|candidate| self.assets.iter().find(|asset| asset.name == *candidate))
+    }
+
+    /// Returns the published SHA-256 of `asset`: GitHub's digest, or an entry of a checksum file
+    /// released along with it.
+    pub fn sha256(&self, asset: &Asset) -> Result<Option<String>> {
+        if let Some(digest) = asset
+            .digest
+            .as_deref()
+            .and_then(|digest| digest.strip_prefix("sha256:"))
+        {
+            return Ok(Some(digest.to_ascii_lowercase()));
+        }
+
+        let own_checksum_files = [
+            format!("{}.sha256sum", asset.name),
+            format!("{}.sha256", asset.name),
+        ];
+        let Some(checksum_file) = self.assets.iter().find(|candidate| {
+            own_checksum_files.contains(&candidate.name)
+                || candidate.name == "shasum.txt"
+                || candidate.name.ends_with("checksums.txt")
+        }) else {
+            return Ok(None);
+        };
+
+        let contents =
+            exec::capturing_stdout(&["curl", "-fsSL", &checksum_file.browser_download_url])
+                .wrap_err_with(|| format!("failed to download {}", checksum_file.name))?;
+
+        Ok(find_checksum(&contents, &asset.name))
+    }
+}
+
+/// Finds the checksum of `name` in the output of `sha256sum`, or the only checksum of a file
+/// for a single asset.
+fn find_checksum(contents: &str, name: &str) -> Option<String> {
+    let is_sha256 = |hash: &str| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit());
+
+    contents.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let hash = parts.next().filter(|hash| is_sha256(hash))?;
+        match parts.next() {
+            Some(file) => {
+                let file = file.trim_start_matches('*');
+                (file == name || file.ends_with(&format!("/{name}")))
+                    .then(|| hash.to_ascii_lowercase())
+            }
+            None => Some(hash.to_ascii_lowercase()),
+        }
+    })
+}
+
+/// Fetches the release of `repo` (e.g. `neovim/neovim`) tagged `tag`. `latest` resolves to the
+/// latest non-prerelease release.
+pub fn release(repo: &str, tag: &str) -> Result<Release> {
+    // Releases are looked up ahead of time while the container starts
+    static RELEASES: OnceLock<Mutex<HashMap<(String, String), Release>>> = OnceLock::new();
+    let releases = RELEASES.get_or_init(Default::default);
+    let key = (repo.to_string(), tag.to_string());
+    if let Some(release) = releases.lock().unwrap().get(&key) {
+        return Ok(release.clone());
+    }
+
+    let endpoint = if tag == "latest" {
+        format!("repos/{repo}/releases/latest")
+    } else {
+        format!("repos/{repo}/releases/tags/{tag}")
+    };
+
+    let output = exec::capturing_stdout(&["gh", "api", &endpoint])
+        .wrap_err_with(|| format!("failed to fetch release `{tag}` of {repo}"))?;
+
+    let release: Release = serde_json::from_str(&output)
+        .into_diagnostic()
+        .wrap_err("failed to parse GitHub release")?;
+    releases.lock().unwrap().insert(key, release.clone());
+
+    Ok(release)
+}
+
+/// Resolves a Neovim version setting to a release. Besides explicit tags, `stable` resolves to
+/// the latest stable release and `nightly` to the current nightly build.
+pub fn neovim_release(version: &str) -> Result<Release> {
+    match version {
+        "stable" => release("neovim/neovim", "latest"),
+        version => release("neovim/neovim", version),
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Like [`Path::canonicalize`], but without the `\\?\` prefix Windows adds to the result.
+///
+/// The devcontainer CLI, Docker and the `devcontainer.local_folder` label all use plain paths such
+/// as `C:\src\app` or `\\server\share\app`, so verbatim paths would neither work as arguments nor
+/// match containers.
+pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    path.canonicalize().map(strip_verbatim)
+}
+
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    let s = path.to_string_lossy();
+    if let Some(rest) = s.strip_prefix(r"\\?\UNC\") {
+        return PathBuf::from(format!(r"\\{rest}"));
+    }
+    match s.strip_prefix(r"\\?\") {
+        // Paths longer than MAX_PATH need the prefix, and so do other kinds of verbatim paths
+        Some(rest) if rest.len() < 260 && has_drive_letter(rest) => PathBuf::from(rest),
+        _ => path,
+    }
+}
+
+fn has_drive_letter(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// Translates the source of a bind mount reported by `docker inspect` to a host path. Docker
+/// Desktop on Windows reports them as paths in its VM, e.g. `/run/desktop/mnt/host/c/src/app`.
+pub fn from_mount_source(source: &str) -> PathBuf {
+    if !cfg!(windows) {
+        return PathBuf::from(source);
+    }
+
+    let rest = ["/run/desktop/mnt/host/", "/host_mnt/", "/mnt/"]
+        .iter()
+        .find_map(|prefix| source.strip_prefix(prefix));
+    let Some((drive, rest)) = rest.map(|rest| rest.split_once('/').unwrap_or((rest, ""))) else {
+        return PathBuf::from(source);
+    };
+    if drive.len() != 1 || !drive.as_bytes()[0].is_ascii_alphabetic() {
+        return PathBuf::from(source);
+    }
+
+    join(
+        Path::new(&format!("{}:\\", drive.to_ascii_uppercase())),
+        rest,
+    )
+}
+
+/// Joins a relative path as found in configuration files, which use `/` even on Windows, to a host
+/// directory using the host's separator.
+pub fn join(base: &Path, relative: &str) -> PathBuf {
+    relative
+        .split(is_separator)
+        .filter(|part| !part.is_empty() && *part != ".")
+        .fold(base.to_path_buf(), |path, part| path.join(part))
+}
+
+/// Expands a leading `~/` (or `~\` on Windows) to the host's home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    let rest = path
+        .strip_prefix('~')
+        .filter(|rest| rest.starts_with(is_separator))
+        .map(|rest| &rest[1..]);
+    match (rest, dirs::home_dir()) {
+        (Some(rest), Some(home)) => join(&home, rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(windows) && c == '\\')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_verbatim_drive() {
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"\\?\C:\src\app")),
+            PathBuf::from(r"C:\src\app")
+        );
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"C:\src\app")),
+            PathBuf::from(r"C:\src\app")
+        );
+    }
+
+    #[test]
+    fn strip_verbatim_unc() {
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"\\?\UNC\server\share\app")),
+            PathBuf::from(r"\\server\share\app")
+        );
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"\\server\share\app")),
+            PathBuf::from(r"\\server\share\app")
+        );
+    }
+
+    #[test]
+    fn strip_verbatim_keeps_long_and_other_paths() {
+        let long = format!(r"\\?\C:\{}", "a".repeat(300));
+        assert_eq!(strip_verbatim(PathBuf::from(&long)), PathBuf::from(&long));
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"\\?\Volume{1234}\app")),
+            PathBuf::from(r"\\?\Volume{1234}\app")
+        );
+    }
+
+    #[test]
+    fn join_splits_config_paths() {
+        let base = Path::new("base");
+        assert_eq!(join(base, "./src//app/"), base.join("src").join("app"));
+        assert_eq!(join(base, ""), base.to_path_buf());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn join_windows() {
+        assert_eq!(
+            join(Path::new(r"C:\"), r"src/app\main"),
+            PathBuf::from(r"C:\src\app\main")
+        );
+        assert_eq!(
+            join(Path::new(r"\\server\share"), "src/app"),
+            PathBuf::from(r"\\server\share\src\app")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn from_mount_source_windows() {
+        assert_eq!(
+            from_mount_source("/run/desktop/mnt/host/c/src/app"),
+            PathBuf::from(r"C:\src\app")
+        );
+        assert_eq!(from_mount_source("/host_mnt/d"), PathBuf::from(r"D:\"));
+        assert_eq!(
+            from_mount_source("/var/lib/docker/volumes/x"),
+            PathBuf::from("/var/lib/docker/volumes/x")
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn from_mount_source_is_identity() {
+        assert_eq!(
+            from_mount_source("/run/desktop/mnt/host/c/src/app"),
+            PathBuf::from("/run/desktop/mnt/host/c/src/app")
+        );
+    }
+
+    #[test]
+    fn expand_home_only_expands_leading_tilde() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_home("~/src/app"), home.join("src").join("app"));
+        assert_eq!(expand_home("~user/app"), PathBuf::from("~user/app"));
+        assert_eq!(expand_home("src/~/app"), PathBuf::from("src/~/app"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn expand_home_windows() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_home(r"~\src\app"), home.join("src").join("app"));
+        assert_eq!(expand_home(r"C:\src\app"), PathBuf::from(r"C:\src\app"));
+    }
+}
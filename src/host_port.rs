@@ -0,0 +1,263 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, OpenOptions},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener},
+    path::Path,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use miette::{bail, IntoDiagnostic, Result, WrapErr};
+
+use crate::{config::Config, devcontainer::DevContainer, exec, state, vm_provider::VmProvider};
+
+const LEASES_STATE_FILE: &str = "port-leases.json";
+const LEASES_LOCK_FILE: &str = "port-leases.lock";
+
+/// Leases keep other dockim processes off a port between choosing it and actually binding it
+const LEASE_TTL: Duration = Duration::from_secs(60);
+
+/// A lock older than this is considered left behind by a crashed process
+const STALE_LOCK_AGE: Duration = Duration::from_secs(10);
+
+/// Address for servers on the host that containers connect to through host.docker.internal.
+/// Containers reach loopback only where the VM forwards it.
+pub fn listen_address(config: &Config) -> &'static str {
+    if config.runtime.ssh_host.is_some() || VmProvider::detect().has_host_docker_internal() {
+        "127.0.0.1"
+    } else {
+        "0.0.0.0"
+    }
+}
+
+/// Like [`listen_address`], but narrowed down to the Docker bridge of the devcontainer on Docker
+/// Engine, so that the server isn't exposed to the network. Falls back to all interfaces where
+/// the bridge can't be bound, e.g. with rootless Docker.
+pub fn container_listen_address(config: &Config, dc: &DevContainer) -> IpAddr {
+    if listen_address(config) == "127.0.0.1" {
+        return IpAddr::V4(Ipv4Addr::LOCALHOST);
+    }
+
+    if matches!(VmProvider::detect(), VmProvider::Native { .. }) {
+        if let Some(gateway) = bridge_gateway(dc) {
+            if TcpListener::bind((gateway, 0)).is_ok() {
+                return gateway;
+            }
+        }
+    }
+
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+/// The gateway of the devcontainer's network, or of the default bridge before it is created.
+fn bridge_gateway(dc: &DevContainer) -> Option<IpAddr> {
+    let gateways = match dc.find_container_ids().ok()?.first() {
+        Some(container_id) => exec::capturing_stdout(&[
+            "docker",
+            "inspect",
+            "--format",
+            "{{ range .NetworkSettings.Networks }}{{ .Gateway }} {{ end }}",
+            container_id,
+        ]),
+        None => exec::capturing_stdout(&[
+            "docker",
+            "network",
+            "inspect",
+            "--format",
+            "{{ range .IPAM.Config }}{{ .Gateway }} {{ end }}",
+            "bridge",
+        ]),
+    }
+    .ok()?;
+
+    gateways
+        .split_whitespace()
+        .find_map(|gateway| gateway.parse().ok())
+}
+
+/// The subnets of the devcontainer's networks, or of the default bridge before it is created.
+pub fn bridge_subnets(dc: &DevContainer) -> Vec<Subnet> {
+    let subnets = match dc
+        .find_container_ids()
+        .ok()
+        .and_then(|ids| ids.first().cloned())
+    {
+        Some(container_id) => exec::capturing_stdout(&[
+            "docker",
+            "inspect",
+            "--format",
+            "{{ range .NetworkSettings.Networks }}{{ .IPAddress }}/{{ .IPPrefixLen }} {{ end }}",
+            &container_id,
+        ]),
+        None => exec::capturing_stdout(&[
+            "docker",
+            "network",
+            "inspect",
+            "--format",
+            "{{ range .IPAM.Config }}{{ .Subnet }} {{ end }}",
+            "bridge",
+        ]),
+    }
+    .unwrap_or_default();
+
+    subnets
+        .split_whitespace()
+        .filter_map(Subnet::parse)
+        .collect()
+}
+
+/// An address range such as `172.17.0.0/16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    pub const LOOPBACK_V4: Subnet = Subnet {
+        address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)),
+        prefix_len: 8,
+    };
+    pub const LOOPBACK_V6: Subnet = Subnet {
+        address: IpAddr::V6(Ipv6Addr::LOCALHOST),
+        prefix_len: 128,
+    };
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let (address, prefix_len) = s.split_once('/')?;
+        let address: IpAddr = address.parse().ok()?;
+        let prefix_len: u8 = prefix_len.parse().ok()?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+
+        (prefix_len <= max).then_some(Subnet {
+            address,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// A free host port held by this process: bound by a listener until the forwarding container is
+/// about to bind it, and leased in the shared state until dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    port: u16,
+    listener: Option<TcpListener>,
+}
+
+impl Reservation {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Lets the port go so that another process (the forwarding container) can bind it. The lease
+    /// still keeps concurrent dockim invocations away.
+    pub fn release_listener(&mut self) {
+        self.listener = None;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let port = self.port;
+        let _ = with_leases(|leases| {
+            leases.remove(&port);
+            Ok(())
+        });
+    }
+}
+
+/// Picks a free host port that no other dockim process is about to use.
+pub fn reserve() -> Result<Reservation> {
+    with_leases(|leases| {
+        for _ in 0..20 {
+            let listener = TcpListener::bind("0.0.0.0:0")
+                .into_diagnostic()
+                .wrap_err("failed to find a free host port")?;
+            let port = listener.local_addr().into_diagnostic()?.port();
+            if leases.contains_key(&port) {
+                continue;
+            }
+
+            leases.insert(port, now() + LEASE_TTL.as_secs());
+            return Ok(Reservation {
+                port,
+                listener: Some(listener),
+            });
+        }
+
+        bail!("failed to find a free host port");
+    })
+}
+
+/// Runs `f` on the unexpired leases while holding the lock, then saves them.
+fn with_leases<T>(f: impl FnOnce(&mut BTreeMap<u16, u64>) -> Result<T>) -> Result<T> {
+    let lock = state::ensure_shared_state_dir("")?.join(LEASES_LOCK_FILE);
+    acquire_lock(&lock)?;
+    scopeguard::defer! {
+        let _ = fs::remove_file(&lock);
+    }
+
+    // Unix timestamps at which the leases expire, by port
+    let mut leases: BTreeMap<u16, u64> = state::load_shared(LEASES_STATE_FILE).unwrap_or_default();
+    let now = now();
+    leases.retain(|_, expires_at| *expires_at > now);
+
+    let result = f(&mut leases)?;
+    state::save_shared(LEASES_STATE_FILE, &leases)?;
+
+    Ok(result)
+}
+
+fn acquire_lock(lock: &Path) -> Result<()> {
+    for _ in 0..100 {
+        match OpenOptions::new().write(true).create_new(true).open(lock) {
+            Ok(_) => return Ok(()),
+            Err(_) => {
+                let is_stale = fs::metadata(lock)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age > STALE_LOCK_AGE);
+                if is_stale {
+                    let _ = fs::remove_file(lock);
+                    continue;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    bail!("timed out waiting for {}", lock.display());
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
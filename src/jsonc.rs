@@ -0,0 +1,541 @@
+use miette::{bail, IntoDiagnostic, Result};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+/// Parses JSON with comments and trailing commas, as used by devcontainer.json.
+pub fn from_str<T: DeserializeOwned>(s: &str) -> Result<T> {
+    serde_json::from_str(&strip(s)).into_diagnostic()
+}
+
+/// Removes comments and trailing commas so that the result can be parsed as plain JSON.
+pub fn strip(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        out.push('\n');
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = None;
+                for c in chars.by_ref() {
+                    if prev == Some('*') && c == '/' {
+                        break;
+                    }
+                    prev = Some(c);
+                }
+            }
+            (',', _) => {
+                // Drop the comma if only whitespace (or comments) stand before a closing bracket
+                if !matches!(next_significant(chars.clone()), Some('}' | ']')) {
+                    out.push(c);
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn next_significant(chars: impl Iterator<Item = char>) -> Option<char> {
+    let mut chars = chars.peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            (c, _) if c.is_whitespace() => {}
+            ('/', Some('/')) => {
+                chars.find(|&c| c == '\n');
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = None;
+                for c in chars.by_ref() {
+                    if prev == Some('*') && c == '/' {
+                        break;
+                    }
+                    prev = Some(c);
+                }
+            }
+            (c, _) => return Some(c),
+        }
+    }
+
+    None
+}
+
+/// Returns the object at `key`, replacing whatever else is there with an empty one.
+pub fn object_entry<'a>(
+    object: &'a mut Map<String, Value>,
+    key: &str,
+) -> &'a mut Map<String, Value> {
+    let entry = object
+        .entry(key)
+        .or_insert_with(|| Value::Object(Map::new()));
+    if !entry.is_object() {
+        *entry = Value::Object(Map::new());
+    }
+
+    entry.as_object_mut().unwrap()
+}
+
+/// Rewrites the top-level object in `original` so that it represents `updated`, touching only the
+/// members whose values changed. Comments, formatting and key order are kept everywhere else.
+pub fn update_top_level(original: &str, updated: &Map<String, Value>) -> Result<String> {
+    let current: Map<String, Value> = from_str(original)?;
+    let (members, close) = top_level_members(original)?;
+    let bytes = original.as_bytes();
+
+    let newline = if original.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let indent = members
+        .first()
+        .map(|member| line_indent(original, member.key_start))
+        .unwrap_or_else(|| "  ".to_string());
+    let render = |value: &Value| -> Result<String> {
+        let pretty = serde_json::to_string_pretty(value).into_diagnostic()?;
+        Ok(pretty.replace('\n', &format!("{newline}{indent}")))
+    };
+
+    let is_kept = |member: &Member| updated.contains_key(&member.key);
+    let last_kept = members.iter().rposition(is_kept);
+
+    // (start, end, replacement); removals may overlap and are merged below
+    let mut removals: Vec<(usize, usize)> = vec![];
+    let mut edits: Vec<(usize, usize, String)> = vec![];
+    for (i, member) in members.iter().enumerate() {
+        match updated.get(&member.key) {
+            Some(value) if current.get(&member.key) == Some(value) => {}
+            Some(value) => edits.push((member.value_start, member.value_end, render(value)?)),
+            None => {
+                removals.push(member_range(original, member));
+                // Without a comma the member is the last one, so the comma before it has to go
+                if member.comma.is_none() {
+                    if let Some(comma) = last_kept.filter(|&j| j < i).and_then(|j| members[j].comma)
+                    {
+                        removals.push((comma, comma + 1));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut added = vec![];
+    for (key, value) in updated {
+        if !current.contains_key(key) {
+            let key = serde_json::to_string(key).into_diagnostic()?;
+            added.push(format!("{indent}{key}: {}", render(value)?));
+        }
+    }
+    if !added.is_empty() {
+        let added = added.join(&format!(",{newline}"));
+        match last_kept.map(|j| &members[j]) {
+            None => edits.push((close, close, format!("{newline}{added}{newline}"))),
+            Some(last) => {
+                // The comma of the last kept member survives only if the file uses trailing commas
+                let has_trailing_comma = last.comma.is_some()
+                    && members.last().is_some_and(|member| member.comma.is_some());
+                let after = last.comma.map(|comma| comma + 1).unwrap_or(last.value_end);
+                let at = end_of_line(bytes, after).min(close);
+                if has_trailing_comma {
+                    edits.push((at, at, format!("{newline}{added},")));
+                } else if last_kept == Some(members.len() - 1) {
+                    edits.push((last.value_end, last.value_end, ",".to_string()));
+                    edits.push((at, at, format!("{newline}{added}")));
+                } else {
+                    // Whatever follows the value is being removed along with the later members
+                    let at = last.value_end;
+                    edits.push((at, at, format!(",{newline}{added}")));
+                }
+            }
+        }
+    }
+
+    removals.sort();
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (start, end) in removals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    edits.extend(
+        merged
+            .into_iter()
+            .map(|(start, end)| (start, end, String::new())),
+    );
+
+    // Apply from the end so that earlier offsets stay valid. On ties, removals (which end later)
+    // are applied before insertions at the same position.
+    edits.sort_by_key(|(start, end, _)| (*start, *end));
+    let mut out = original.to_string();
+    for (start, end, replacement) in edits.into_iter().rev() {
+        out.replace_range(start..end, &replacement);
+    }
+
+    Ok(out)
+}
+
+/// Returns the range to remove along with `member`: its whole line(s) and the comments just
+/// above it if nothing else is on them, otherwise just the member, its comma and a trailing
+/// comment.
+fn member_range(s: &str, member: &Member) -> (usize, usize) {
+    let bytes = s.as_bytes();
+    let end = member
+        .comma
+        .map(|comma| comma + 1)
+        .unwrap_or(member.value_end);
+
+    let line_start = s[..member.key_start]
+        .rfind('\n')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let starts_line = s[line_start..member.key_start].trim().is_empty();
+    let line_end = s[end..].find('\n').map(|i| end + i).unwrap_or(s.len());
+    let ends_line = is_comment(&s[end..line_end]);
+
+    match (starts_line, ends_line) {
+        (true, true) => (
+            leading_comments_start(s, line_start),
+            (line_end + 1).min(s.len()),
+        ),
+        (false, true) => {
+            let start = s[..member.key_start].trim_end_matches([' ', '\t']).len();
+            (start, line_end - usize::from(s[..line_end].ends_with('\r')))
+        }
+        _ if member.comma.is_some() => (member.key_start, skip_blanks(bytes, end)),
+        _ => (
+            s[..member.key_start].trim_end_matches([' ', '\t']).len(),
+            end,
+        ),
+    }
+}
+
+/// Returns the start of the comment lines directly above `line_start`.
+fn leading_comments_start(s: &str, mut line_start: usize) -> usize {
+    while line_start > 0 {
+        let prev_start = s[..line_start - 1].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line = s[prev_start..line_start - 1].trim();
+        if line.is_empty() || !is_comment(line) {
+            break;
+        }
+        line_start = prev_start;
+    }
+
+    line_start
+}
+
+/// Whether `s` is blank or a single comment.
+fn is_comment(s: &str) -> bool {
+    let s = s.trim();
+    s.is_empty()
+        || s.starts_with("//")
+        || (s.len() >= 4 && s.starts_with("/*") && s[2..].find("*/") == Some(s.len() - 4))
+}
+
+#[derive(Debug)]
+struct Member {
+    key: String,
+    key_start: usize,
+    value_start: usize,
+    value_end: usize,
+    /// Position of the comma following the value, if any
+    comma: Option<usize>,
+}
+
+/// Locates the members of the top-level object and its closing brace.
+fn top_level_members(s: &str) -> Result<(Vec<Member>, usize)> {
+    let bytes = s.as_bytes();
+    let mut pos = skip_trivia(bytes, 0);
+    if bytes.get(pos) != Some(&b'{') {
+        bail!("expected an object at the top level");
+    }
+    pos += 1;
+
+    let mut members = vec![];
+    loop {
+        pos = skip_trivia(bytes, pos);
+        match bytes.get(pos) {
+            Some(b'}') => return Ok((members, pos)),
+            Some(b'"') => {}
+            _ => bail!("unexpected character at offset {pos}"),
+        }
+
+        let key_start = pos;
+        let key_end = skip_value(bytes, pos)?;
+        let key: String = serde_json::from_str(&s[key_start..key_end]).into_diagnostic()?;
+
+        pos = skip_trivia(bytes, key_end);
+        if bytes.get(pos) != Some(&b':') {
+            bail!("expected `:` at offset {pos}");
+        }
+        let value_start = skip_trivia(bytes, pos + 1);
+        let value_end = skip_value(bytes, value_start)?;
+
+        pos = skip_trivia(bytes, value_end);
+        let comma = (bytes.get(pos) == Some(&b',')).then_some(pos);
+        if comma.is_some() {
+            pos += 1;
+        }
+
+        members.push(Member {
+            key,
+            key_start,
+            value_start,
+            value_end,
+            comma,
+        });
+    }
+}
+
+/// Returns the position just after the value starting at `pos`.
+fn skip_value(bytes: &[u8], mut pos: usize) -> Result<usize> {
+    match bytes.get(pos) {
+        Some(b'"') => {
+            pos += 1;
+            while let Some(&b) = bytes.get(pos) {
+                match b {
+                    b'\\' => pos += 2,
+                    b'"' => return Ok(pos + 1),
+                    _ => pos += 1,
+                }
+            }
+            bail!("unterminated string");
+        }
+        Some(b'{' | b'[') => {
+            let mut depth = 0;
+            while let Some(&b) = bytes.get(pos) {
+                match b {
+                    b'"' => {
+                        pos = skip_value(bytes, pos)?;
+                        continue;
+                    }
+                    b'/' if matches!(bytes.get(pos + 1), Some(b'/' | b'*')) => {
+                        pos = skip_trivia(bytes, pos);
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Ok(pos + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                pos += 1;
+            }
+            bail!("unterminated object or array");
+        }
+        Some(_) => {
+            while let Some(&b) = bytes.get(pos) {
+                if b.is_ascii_whitespace() || matches!(b, b',' | b'}' | b']' | b'/') {
+                    break;
+                }
+                pos += 1;
+            }
+            Ok(pos)
+        }
+        None => bail!("unexpected end of input"),
+    }
+}
+
+/// Skips whitespace and comments.
+fn skip_trivia(bytes: &[u8], mut pos: usize) -> usize {
+    loop {
+        match (bytes.get(pos), bytes.get(pos + 1)) {
+            (Some(b), _) if b.is_ascii_whitespace() => pos += 1,
+            (Some(b'/'), Some(b'/')) => {
+                while bytes.get(pos).is_some_and(|&b| b != b'\n') {
+                    pos += 1;
+                }
+            }
+            (Some(b'/'), Some(b'*')) => {
+                pos += 2;
+                while pos + 1 < bytes.len() && !(bytes[pos] == b'*' && bytes[pos + 1] == b'/') {
+                    pos += 1;
+                }
+                pos += 2;
+            }
+            _ => return pos,
+        }
+    }
+}
+
+/// Returns the end of the line if only blanks and a line comment follow `pos`, otherwise `pos`.
+fn end_of_line(bytes: &[u8], pos: usize) -> usize {
+    let mut end = pos;
+    while matches!(bytes.get(end), Some(b' ' | b'\t')) {
+        end += 1;
+    }
+    if bytes.get(end) == Some(&b'/') && bytes.get(end + 1) == Some(&b'/') {
+        while bytes.get(end).is_some_and(|&b| b != b'\n') {
+            end += 1;
+        }
+    }
+
+    match bytes.get(end) {
+        // Stay before the `\r` of a CRLF
+        Some(b'\n') if end > pos && bytes[end - 1] == b'\r' => end - 1,
+        Some(b'\n') | None => end,
+        _ => pos,
+    }
+}
+
+fn skip_blanks(bytes: &[u8], mut pos: usize) -> usize {
+    while matches!(bytes.get(pos), Some(b' ' | b'\t' | b'\r')) {
+        pos += 1;
+    }
+
+    pos
+}
+
+fn line_indent(s: &str, pos: usize) -> String {
+    let line_start = s[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    s[line_start..pos]
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(original: &str, updated: Value) -> String {
+        let Value::Object(updated) = updated else {
+            panic!("not an object");
+        };
+        update_top_level(original, &updated).unwrap()
+    }
+
+    #[test]
+    fn removes_the_only_member() {
+        assert_eq!(
+            update("{\n  \"a\": 1\n}\n", serde_json::json!({})),
+            "{\n}\n"
+        );
+    }
+
+    #[test]
+    fn removes_the_first_and_last_members() {
+        let original = "{\n  \"a\": 1,\n  \"b\": 2\n}";
+        assert_eq!(
+            update(original, serde_json::json!({ "b": 2 })),
+            "{\n  \"b\": 2\n}"
+        );
+        assert_eq!(
+            update(original, serde_json::json!({ "a": 1 })),
+            "{\n  \"a\": 1\n}"
+        );
+    }
+
+    #[test]
+    fn keeps_trailing_commas() {
+        assert_eq!(
+            update(
+                "{\n  \"a\": 1,\n  \"b\": 2,\n}",
+                serde_json::json!({ "a": 1 })
+            ),
+            "{\n  \"a\": 1,\n}"
+        );
+        assert_eq!(
+            update("{\n  \"a\": 1,\n}", serde_json::json!({ "a": 1, "b": 2 })),
+            "{\n  \"a\": 1,\n  \"b\": 2,\n}"
+        );
+    }
+
+    #[test]
+    fn removes_comments_along_with_their_member() {
+        let original = "{\n  // about a\n  \"a\": 1, // after a\n  \"b\": 2 /* after b */\n}";
+        assert_eq!(
+            update(original, serde_json::json!({ "b": 2 })),
+            "{\n  \"b\": 2 /* after b */\n}"
+        );
+        assert_eq!(
+            update(original, serde_json::json!({ "a": 1 })),
+            "{\n  // about a\n  \"a\": 1 // after a\n}"
+        );
+    }
+
+    #[test]
+    fn removes_members_sharing_a_line() {
+        let original = "{\"a\": 1, \"b\": 2}";
+        assert_eq!(
+            update(original, serde_json::json!({ "b": 2 })),
+            "{\"b\": 2}"
+        );
+        assert_eq!(
+            update(original, serde_json::json!({ "a": 1 })),
+            "{\"a\": 1}"
+        );
+    }
+
+    #[test]
+    fn adds_to_an_empty_object() {
+        assert_eq!(
+            update("{}", serde_json::json!({ "a": [1] })),
+            "{\n  \"a\": [\n    1\n  ]\n}"
+        );
+    }
+
+    #[test]
+    fn adds_after_removals() {
+        assert_eq!(
+            update(
+                "{\n  \"a\": 1,\n  \"b\": 2\n}",
+                serde_json::json!({ "a": 5, "c": 3 })
+            ),
+            "{\n  \"a\": 5,\n  \"c\": 3\n}"
+        );
+        assert_eq!(
+            update(
+                "{\n  \"a\": 1 // after a\n}",
+                serde_json::json!({ "a": 1, "c": 3 })
+            ),
+            "{\n  \"a\": 1, // after a\n  \"c\": 3\n}"
+        );
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        assert_eq!(
+            update(
+                "{\r\n  \"a\": 1,\r\n  \"b\": 2\r\n}\r\n",
+                serde_json::json!({ "a": 1, "c": 3 })
+            ),
+            "{\r\n  \"a\": 1,\r\n  \"c\": 3\r\n}\r\n"
+        );
+        assert_eq!(
+            update(
+                "{\r\n  \"a\": 1 // after a\r\n}\r\n",
+                serde_json::json!({ "a": 1, "c": 3 })
+            ),
+            "{\r\n  \"a\": 1, // after a\r\n  \"c\": 3\r\n}\r\n"
+        );
+    }
+}
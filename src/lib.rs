@@ -1,5 +1,33 @@
 pub mod cli;
 pub mod config;
 pub mod devcontainer;
+pub mod devcontainer_config;
+pub mod display;
 pub mod exec;
+pub mod git_credentials;
+pub mod github;
+pub mod host_path;
+pub mod host_port;
+pub mod jsonc;
 pub mod log;
+pub mod network;
+pub mod notify;
+pub mod orchestrator;
+pub mod override_config;
+pub mod package_file;
+pub mod pairing;
+pub mod path_mapping;
+pub mod platform;
+pub mod progress;
+pub mod recording;
+pub mod remote;
+pub mod scripting;
+pub mod secret;
+pub mod shared_services;
+pub mod state;
+pub mod tls;
+pub mod trust;
+pub mod tunnel;
+pub mod variant;
+pub mod vm_provider;
+pub mod workspace_lock;
@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{fmt::Display, fs::File, io::Write, sync::Mutex};
 
 use colored::Colorize;
 
@@ -12,7 +12,30 @@ macro_rules! log {
     };
 }
 
+/// Receives log lines and the output of commands instead of the terminal while set
+static CAPTURE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Starts sending log lines and the output of commands to `file`, or back to the terminal.
+pub fn capture(file: Option<File>) {
+    *CAPTURE.lock().unwrap() = file;
+}
+
+/// A handle to the file capturing the output, if any.
+pub fn captured_output() -> Option<File> {
+    CAPTURE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|file| file.try_clone().ok())
+}
+
 pub fn log<D: Display>(kind: &str, note: Option<&str>, msg: D) {
+    if let Some(mut file) = captured_output() {
+        let note = note.map(|note| format!(" ({note})")).unwrap_or_default();
+        let _ = writeln!(file, "{kind:>10}{note} {msg}");
+        return;
+    }
+
     eprint!("{:>10}", kind.bright_green());
     if let Some(note) = note {
         eprint!("{}", format!(" ({note})").bright_black());
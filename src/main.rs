@@ -1,46 +1,178 @@
-use clap::Parser;
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::{CommandFactory, FromArgMatches, Parser};
 use dockim::{
-    cli::{bash, build, exec as cli_exec, neovide, neovim, port, shell, up, Args, Subcommand},
-    config::Config,
+    cli::{
+        apply, audit, auth, bash, build, clipboard, clone, compose, config as cli_config, describe,
+        diff, direnv, doctor, down, env, events, exec as cli_exec, gc, init, init_docker, jobs,
+        kill, list, neovide, neovim, pair, path, port, profile, share_config, shell, ssh, stop,
+        top, trust, untrust, up, watch, Args, Subcommand,
+    },
+    config::{Config, LocalConfig},
     devcontainer::DevContainer,
-    exec,
+    exec, override_config, remote, state, variant,
 };
 use miette::{bail, Result};
+use serde::{Deserialize, Serialize};
 
 fn main() -> Result<()> {
-    check_requirements()?;
+    let mut args = parse_args()?;
+    if args.workspace_folder.is_none() && args.subcommand.searches_workspace_folder() {
+        args.workspace_folder = override_config::find_workspace_folder(Path::new("."));
+    }
+    variant::select(args.variant.as_deref())?;
 
-    let config = Config::load_config()?;
+    if !args.no_check {
+        check_requirements(&args.subcommand)?;
+    }
+
+    let config = if args.subcommand.needs_config() {
+        Config::load_config()?.with_cli_overrides(&args.config_overrides)?
+    } else {
+        Config::default()
+    };
+    remote::activate(&config);
 
-    let args = Args::parse();
     match &args.subcommand {
         Subcommand::Up(up_args) => up::main(&config, &args, up_args),
         Subcommand::Build(build_args) => build::main(&config, &args, build_args),
+        Subcommand::Apply(apply_args) => apply::main(&config, &args, apply_args),
+        Subcommand::Audit(audit_args) => audit::main(&config, &args, audit_args),
+        Subcommand::Auth(auth_args) => auth::main(&config, &args, auth_args),
+        Subcommand::Clipboard(clipboard_args) => clipboard::main(&config, &args, clipboard_args),
+        Subcommand::Clone(clone_args) => clone::main(&config, &args, clone_args),
+        Subcommand::Compose(compose_args) => compose::main(&config, &args, compose_args),
+        Subcommand::Config(config_args) => cli_config::main(&config, &args, config_args),
+        Subcommand::Describe(describe_args) => describe::main(&config, &args, describe_args),
+        Subcommand::Diff(diff_args) => diff::main(&config, &args, diff_args),
+        Subcommand::Direnv(direnv_args) => direnv::main(&config, &args, direnv_args),
+        Subcommand::Doctor(doctor_args) => doctor::main(&config, &args, doctor_args),
+        Subcommand::Down(down_args) => down::main(&config, &args, down_args),
+        Subcommand::Env(env_args) => env::main(&config, &args, env_args),
         Subcommand::Neovim(neovim_args) => neovim::main(&config, &args, neovim_args),
         Subcommand::Neovide(neovide_args) => neovide::main(&config, &args, neovide_args),
         Subcommand::Shell(shell_args) => shell::main(&config, &args, shell_args),
         Subcommand::Bash(bash_args) => bash::main(&config, &args, bash_args),
         Subcommand::Exec(exec_args) => cli_exec::main(&config, &args, exec_args),
+        Subcommand::Events(events_args) => events::main(&config, &args, events_args),
+        Subcommand::Gc(gc_args) => gc::main(&config, &args, gc_args),
+        Subcommand::Init(init_args) => init::main(&config, &args, init_args),
+        Subcommand::InitDocker(init_docker_args) => {
+            init_docker::main(&config, &args, init_docker_args)
+        }
+        Subcommand::Jobs(jobs_args) => jobs::main(&config, &args, jobs_args),
+        Subcommand::Kill(kill_args) => kill::main(&config, &args, kill_args),
+        Subcommand::List(list_args) => list::main(&config, &args, list_args),
+        Subcommand::Pair(pair_args) => pair::main(&config, &args, pair_args),
+        Subcommand::Path(path_args) => path::main(&config, &args, path_args),
         Subcommand::Port(port_args) => port::main(&config, &args, port_args),
+        Subcommand::Profile(profile_args) => profile::main(&config, &args, profile_args),
+        Subcommand::ShareConfig(share_config_args) => {
+            share_config::main(&config, &args, share_config_args)
+        }
+        Subcommand::Ssh(ssh_args) => ssh::main(&config, &args, ssh_args),
+        Subcommand::Stop(stop_args) => stop::main(&config, &args, stop_args),
+        Subcommand::Top(top_args) => top::main(&config, &args, top_args),
+        Subcommand::Trust(trust_args) => trust::main(&config, &args, trust_args),
+        Subcommand::Untrust(untrust_args) => untrust::main(&config, &args, untrust_args),
+        Subcommand::Watch(watch_args) => watch::main(&config, &args, watch_args),
     }
 }
 
-fn check_requirements() -> Result<()> {
-    if !DevContainer::is_cli_installed() {
-        bail!(
-            help = concat!(
-                "run `npm install -g @devcontainers/cli` to install it\n",
-                "see also: https://github.com/devcontainers/cli",
-            ),
-            "devcontainer CLI is not installed",
-        );
+/// Parses the command line. Without a subcommand, the one configured by `cli.default_command` runs,
+/// preferring the workspace's local configuration.
+fn parse_args() -> Result<Args> {
+    let matches = Args::command()
+        .subcommand_required(false)
+        .arg_required_else_help(false)
+        .get_matches();
+    if matches.subcommand().is_some() {
+        return Ok(Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()));
     }
 
-    if exec::exec(&["docker", "--version"]).is_err() {
+    let workspace_folder = matches
+        .get_one::<PathBuf>("workspace_folder")
+        .cloned()
+        .or_else(|| override_config::find_workspace_folder(Path::new(".")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    let default_command = LocalConfig::load(&workspace_folder)?
+        .cli
+        .default_command
+        .or(Config::load_config()?.cli.default_command);
+    let Some(default_command) = default_command else {
+        // Fails as usual for a missing subcommand
+        return Ok(Args::parse());
+    };
+
+    let Some(default_command) = shlex::split(&default_command) else {
         bail!(
-            help = "install or start Docker Desktop first",
-            "Docker is not installed or not running",
+            help = "quote arguments as in a POSIX shell",
+            "failed to parse cli.default_command `{default_command}`"
         );
+    };
+
+    Ok(Args::parse_from(
+        std::env::args_os().chain(default_command.into_iter().map(OsString::from)),
+    ))
+}
+
+const REQUIREMENTS_STATE_FILE: &str = "requirements.json";
+
+/// Successful checks are trusted for this long since running the devcontainer CLI is slow
+const REQUIREMENTS_CHECK_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Unix timestamps of the last successful checks
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RequirementsState {
+    devcontainer_cli: Option<u64>,
+    docker: Option<u64>,
+}
+
+fn check_requirements(subcommand: &Subcommand) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let is_fresh = |checked_at: Option<u64>| {
+        checked_at.is_some_and(|t| now.saturating_sub(t) < REQUIREMENTS_CHECK_TTL.as_secs())
+    };
+
+    // A broken cache must not stop us; just check again
+    let mut requirements: RequirementsState =
+        state::load_shared(REQUIREMENTS_STATE_FILE).unwrap_or_default();
+    let mut checked = false;
+
+    if subcommand.needs_devcontainer_cli() && !is_fresh(requirements.devcontainer_cli) {
+        if !DevContainer::is_cli_installed() {
+            bail!(
+                help = concat!(
+                    "run `npm install -g @devcontainers/cli` to install it\n",
+                    "see also: https://github.com/devcontainers/cli",
+                ),
+                "devcontainer CLI is not installed",
+            );
+        }
+        requirements.devcontainer_cli = Some(now);
+        checked = true;
+    }
+
+    if subcommand.needs_docker() && !is_fresh(requirements.docker) {
+        if exec::exec(&["docker", "--version"]).is_err() {
+            bail!(
+                help = "install or start Docker Desktop first",
+                "Docker is not installed or not running",
+            );
+        }
+        requirements.docker = Some(now);
+        checked = true;
+    }
+
+    if checked {
+        let _ = state::save_shared(REQUIREMENTS_STATE_FILE, &requirements);
     }
 
     Ok(())
@@ -0,0 +1,151 @@
+use std::{fs, path::Path};
+
+use itertools::Itertools;
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::{exec, log, state};
+
+const PROXY_IMAGE: &str = "alpine:3.19";
+pub const PROXY_PORT: u16 = 8888;
+
+/// Internal Docker network without a route to the outside
+pub fn restricted_network_name(workspace_folder: &Path) -> Result<String> {
+    Ok(format!(
+        "dockim-{}-restricted",
+        state::workspace_id(workspace_folder)?
+    ))
+}
+
+/// The only container on the restricted network that can reach the outside
+pub fn proxy_container_name(workspace_folder: &Path) -> Result<String> {
+    Ok(format!(
+        "dockim-{}-proxy",
+        state::workspace_id(workspace_folder)?
+    ))
+}
+
+/// Creates the restricted network and (re)starts its filtering proxy when the allow list changed.
+pub fn ensure_restricted(workspace_folder: &Path, allow: &[String]) -> Result<()> {
+    let network = restricted_network_name(workspace_folder)?;
+    if exec::capturing_stdout(&["docker", "network", "inspect", &network]).is_err() {
+        exec::exec(&["docker", "network", "create", "--internal", &network])
+            .wrap_err_with(|| miette!("failed to create network `{network}`"))?;
+    }
+
+    let dir = state::ensure_workspace_state_dir(workspace_folder)?.join("proxy");
+    fs::create_dir_all(&dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+
+    let filter_path = dir.join("filter");
+    let filter = allow.iter().map(|host| host_pattern(host)).join("\n") + "\n";
+    let filter_changed = fs::read_to_string(&filter_path).ok().as_deref() != Some(&*filter);
+    fs::write(&filter_path, &filter)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", filter_path.display()))?;
+    let conf_path = dir.join("tinyproxy.conf");
+    fs::write(&conf_path, tinyproxy_conf())
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", conf_path.display()))?;
+
+    let proxy = proxy_container_name(workspace_folder)?;
+    let is_running = exec::capturing_stdout(&[
+        "docker",
+        "inspect",
+        "--format",
+        "{{ .State.Running }}",
+        &proxy,
+    ])
+    .is_ok_and(|running| running.trim() == "true");
+    if is_running && !filter_changed {
+        return Ok(());
+    }
+
+    let _ = exec::capturing_stdout(&["docker", "rm", "-f", &proxy]);
+    exec::exec(&[
+        "docker",
+        "run",
+        "-d",
+        "--restart",
+        "unless-stopped",
+        "--name",
+        &proxy,
+        "-v",
+        &format!("{}:/etc/dockim-proxy:ro", dir.display()),
+        PROXY_IMAGE,
+        "sh",
+        "-c",
+        "apk add --no-cache tinyproxy >/dev/null && exec tinyproxy -d -c /etc/dockim-proxy/tinyproxy.conf",
+    ])
+    .wrap_err("failed to start filtering proxy")?;
+    exec::exec(&["docker", "network", "connect", &network, &proxy])
+        .wrap_err_with(|| miette!("failed to connect proxy to network `{network}`"))?;
+    log!("Started" ("network"): "proxy allowing {}", if allow.is_empty() { "no hosts".to_string() } else { allow.join(", ") });
+
+    Ok(())
+}
+
+/// Removes the proxy and the restricted network if they exist.
+pub fn remove_restricted(workspace_folder: &Path) -> Result<()> {
+    let proxy = proxy_container_name(workspace_folder)?;
+    if exec::capturing_stdout(&["docker", "inspect", &proxy]).is_ok() {
+        exec::exec(&["docker", "rm", "-f", &proxy]).wrap_err("failed to remove filtering proxy")?;
+    }
+
+    let network = restricted_network_name(workspace_folder)?;
+    if exec::capturing_stdout(&["docker", "network", "inspect", &network]).is_ok() {
+        exec::exec(&["docker", "network", "rm", &network])
+            .wrap_err_with(|| miette!("failed to remove network `{network}`"))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the environment variables that route HTTP(S) through the proxy.
+pub fn proxy_env(workspace_folder: &Path) -> Result<Vec<(String, String)>> {
+    let url = format!(
+        "http://{}:{PROXY_PORT}",
+        proxy_container_name(workspace_folder)?
+    );
+
+    Ok(["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"]
+        .into_iter()
+        .map(|name| (name.to_string(), url.clone()))
+        .chain(
+            ["NO_PROXY", "no_proxy"]
+                .into_iter()
+                .map(|name| (name.to_string(), "localhost,127.0.0.1".to_string())),
+        )
+        .collect())
+}
+
+fn host_pattern(host: &str) -> String {
+    match host.strip_prefix("*.") {
+        Some(domain) => format!("(^|\\.){}$", regex_escape(domain)),
+        None => format!("^{}$", regex_escape(host)),
+    }
+}
+
+fn regex_escape(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| {
+            let escape = "\\.+*?()|[]{}^$".contains(c);
+            escape.then_some('\\').into_iter().chain([c])
+        })
+        .collect()
+}
+
+fn tinyproxy_conf() -> String {
+    [
+        format!("Port {PROXY_PORT}"),
+        "Listen 0.0.0.0".to_string(),
+        "Timeout 600".to_string(),
+        "FilterDefaultDeny Yes".to_string(),
+        "FilterType ere".to_string(),
+        "FilterURLs Off".to_string(),
+        "Filter \"/etc/dockim-proxy/filter\"".to_string(),
+        "ConnectPort 443".to_string(),
+    ]
+    .join("\n")
+        + "\n"
+}
@@ -0,0 +1,73 @@
+use miette::Result;
+
+use crate::{config::Config, exec, log};
+
+/// Notifies the host desktop of how a long operation ended, if enabled, and passes the result
+/// through.
+pub fn finished<T>(config: &Config, operation: &str, result: Result<T>) -> Result<T> {
+    if !config.notifications.enabled {
+        return result;
+    }
+
+    let (title, body) = match &result {
+        Ok(_) => (format!("{operation} finished"), "Done".to_string()),
+        Err(e) => (format!("{operation} failed"), e.to_string()),
+    };
+    if let Err(e) = send(&title, &body) {
+        log!("Warning": "failed to send a notification: {e}");
+    }
+
+    result
+}
+
+/// Shows a desktop notification on the host.
+pub fn send(title: &str, body: &str) -> Result<()> {
+    if cfg!(target_os = "macos") {
+        let script = format!(
+            "display notification {} with title {}",
+            applescript_quote(body),
+            applescript_quote(title)
+        );
+        exec::capturing_stdout(&["osascript", "-e", &script])?;
+    } else if cfg!(windows) || is_wsl() {
+        exec::capturing_stdout(&[
+            "powershell.exe",
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            &toast_script(title, body),
+        ])?;
+    } else {
+        exec::capturing_stdout(&["notify-send", "--app-name=dockim", title, body])?;
+    }
+
+    Ok(())
+}
+
+fn is_wsl() -> bool {
+    exec::capturing_stdout(&["uname", "-r"]).is_ok_and(|out| out.contains("microsoft"))
+}
+
+fn applescript_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn toast_script(title: &str, body: &str) -> String {
+    let escape = |s: &str| {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('\'', "''")
+    };
+
+    format!(
+        r#"[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] | Out-Null
+[Windows.Data.Xml.Dom.XmlDocument, Windows.Data.Xml.Dom.XmlDocument, ContentType = WindowsRuntime] | Out-Null
+$xml = New-Object Windows.Data.Xml.Dom.XmlDocument
+$xml.LoadXml('<toast><visual><binding template="ToastGeneric"><text>{}</text><text>{}</text></binding></visual></toast>')
+$toast = New-Object Windows.UI.Notifications.ToastNotification $xml
+[Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('dockim').Show($toast)"#,
+        escape(title),
+        escape(body)
+    )
+}
@@ -0,0 +1,104 @@
+use std::sync::OnceLock;
+
+use miette::{miette, Report, Result};
+
+use crate::{exec, vm_provider::VmProvider};
+
+/// The Compose implementation that runs multi-container setups next to the Docker CLI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orchestrator {
+    /// The `docker compose` plugin (Compose v2)
+    DockerCompose,
+    /// The standalone `docker-compose` (Compose v1)
+    DockerComposeV1,
+    PodmanCompose,
+    NerdctlCompose,
+}
+
+impl Orchestrator {
+    /// Finds the Compose implementation to use, preferring the one of the current runtime. The
+    /// result is cached for the process.
+    pub fn detect() -> Result<Orchestrator> {
+        static ORCHESTRATOR: OnceLock<Option<Orchestrator>> = OnceLock::new();
+        if let Some(orchestrator) = ORCHESTRATOR.get_or_init(detect_uncached) {
+            return Ok(*orchestrator);
+        }
+
+        Err(expected_error("no Docker Compose implementation found"))
+    }
+
+    /// The implementation that comes with the current runtime.
+    fn expected() -> Orchestrator {
+        match VmProvider::detect() {
+            VmProvider::PodmanMachine { .. } => Orchestrator::PodmanCompose,
+            _ => Orchestrator::DockerCompose,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Orchestrator::DockerCompose => "Docker Compose",
+            Orchestrator::DockerComposeV1 => "Docker Compose v1",
+            Orchestrator::PodmanCompose => "podman-compose",
+            Orchestrator::NerdctlCompose => "nerdctl compose",
+        }
+    }
+
+    /// The command to prepend to Compose arguments such as `-p <project> up -d`.
+    pub fn command(&self) -> Vec<String> {
+        let command: &[&str] = match self {
+            Orchestrator::DockerCompose => &["docker", "compose"],
+            Orchestrator::DockerComposeV1 => &["docker-compose"],
+            Orchestrator::PodmanCompose => &["podman-compose"],
+            Orchestrator::NerdctlCompose => &["nerdctl", "compose"],
+        };
+
+        command.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// The binary to pass as `--docker-compose-path` to the devcontainer CLI, which finds
+    /// `docker compose` and `docker-compose` by itself but can't run a subcommand of another CLI,
+    /// so nerdctl compose is no use to it.
+    pub fn devcontainer_cli_path(&self) -> Result<Option<&'static str>> {
+        match self {
+            Orchestrator::PodmanCompose => Ok(Some("podman-compose")),
+            Orchestrator::NerdctlCompose => Err(expected_error(
+                "the devcontainer CLI can't run compose configurations with nerdctl compose",
+            )),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn expected_error(problem: &str) -> Report {
+    let expected = Orchestrator::expected();
+    miette!(
+        help = format!("install {}, or make sure it is on PATH", expected.name()),
+        "{problem}; {} expects `{}`",
+        VmProvider::detect().name(),
+        expected.command().join(" ")
+    )
+}
+
+fn detect_uncached() -> Option<Orchestrator> {
+    let candidates = match Orchestrator::expected() {
+        Orchestrator::PodmanCompose => [
+            Orchestrator::PodmanCompose,
+            Orchestrator::DockerCompose,
+            Orchestrator::DockerComposeV1,
+            Orchestrator::NerdctlCompose,
+        ],
+        _ => [
+            Orchestrator::DockerCompose,
+            Orchestrator::DockerComposeV1,
+            Orchestrator::PodmanCompose,
+            Orchestrator::NerdctlCompose,
+        ],
+    };
+
+    candidates.into_iter().find(|orchestrator| {
+        let mut args = orchestrator.command();
+        args.push("version".to_string());
+        exec::capturing_stdout(&args).is_ok()
+    })
+}
@@ -0,0 +1,622 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    config::{Config, DockerAccess, NetworkMode},
+    devcontainer_config,
+    display::DisplayServer,
+    exec, host_path, jsonc, log, network, state, trust, variant,
+};
+
+const OVERRIDE_CONFIG_FILE: &str = "override.devcontainer.json";
+/// Compose file in the workspace state adding dockim's labels to the devcontainer's service
+const COMPOSE_LABELS_FILE: &str = "compose-labels.yml";
+
+pub const APT_LAYER_STATE_FILE: &str = "apt-layer.json";
+
+/// Environment of the workspace in `.dockim`, which may hold secrets
+pub const WORKSPACE_ENV_FILE: &str = "env.toml";
+
+pub const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+const DIND_FEATURE: &str = "ghcr.io/devcontainers/features/docker-in-docker:2";
+
+/// Image derived by `dockim build --apt-layer` with the prerequisites preinstalled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AptLayer {
+    pub base_image: String,
+    pub image: String,
+    /// [`devcontainer_config::inputs_hash`] of the configuration the layer was built from
+    #[serde(default)]
+    pub inputs_hash: String,
+}
+
+impl AptLayer {
+    /// Loads the layer if `build.apt_layer` asks for it and it was built from the current
+    /// devcontainer.json and Dockerfile. A stale layer is forgotten, so that changes to them take
+    /// effect.
+    fn load_current(workspace_folder: &Path, config: &Config) -> Result<Option<Self>> {
+        if !config.build.apt_layer {
+            return Ok(None);
+        }
+        let apt_layer: Option<AptLayer> = state::load(workspace_folder, APT_LAYER_STATE_FILE)?;
+        let Some(apt_layer) = apt_layer else {
+            return Ok(None);
+        };
+        if apt_layer.inputs_hash == devcontainer_config::inputs_hash(workspace_folder)? {
+            return Ok(Some(apt_layer));
+        }
+
+        log!("Warning": "devcontainer.json or its Dockerfile changed since the apt layer was built; ignoring it");
+        log!("Hint": "run `dockim build --apt-layer` to build it again");
+        state::save(workspace_folder, APT_LAYER_STATE_FILE, &None::<AptLayer>)?;
+
+        Ok(None)
+    }
+}
+
+/// Adjustments dockim makes to the workspace's devcontainer.json. They are applied to a copy of
+/// the configuration which is passed to the devcontainer CLI through `--override-config`.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub container_env: BTreeMap<String, String>,
+    pub image: Option<String>,
+    pub selinux_relabel: bool,
+    pub run_args: Vec<String>,
+    /// Dev Container Features added to the ones in devcontainer.json
+    pub features: Map<String, Value>,
+    /// Workspace-relative directories to cover with anonymous volumes
+    pub exclude_mounts: Vec<String>,
+    pub remote_user: Option<String>,
+    /// dockim runs `initializeCommand` itself, so the devcontainer CLI must not
+    pub strip_initialize_command: bool,
+    /// Compose file added to the ones in devcontainer.json, labelling the service
+    pub compose_override: Option<PathBuf>,
+}
+
+impl Overrides {
+    pub fn load(workspace_folder: &Path, config: &Config) -> Result<Self> {
+        let apt_layer = AptLayer::load_current(workspace_folder, config)?;
+
+        let mut run_args = vec![];
+        if config.container.apparmor_unconfined {
+            run_args.extend([
+                "--security-opt".to_string(),
+                "apparmor=unconfined".to_string(),
+            ]);
+        }
+
+        let devcontainer_config = devcontainer_config::load(workspace_folder)?;
+        let is_compose = devcontainer_config.is_compose();
+        let labels = labels(workspace_folder)?;
+        // Labels of compose services can only be set by another compose file
+        let compose_override = match &devcontainer_config.service {
+            Some(service) if is_compose => {
+                Some(write_compose_labels(workspace_folder, service, &labels)?)
+            }
+            _ => None,
+        };
+        if !is_compose {
+            run_args.extend(
+                labels
+                    .iter()
+                    .map(|(name, value)| format!("--label={name}={value}")),
+            );
+        }
+        if config.container.readable_name {
+            if is_compose {
+                log!("Warning": "set `container_name` in the compose file to name the container");
+            } else {
+                run_args.push(format!(
+                    "--name={}",
+                    readable_container_name(workspace_folder)?
+                ));
+            }
+        }
+
+        let mut container_env = load_workspace_env(workspace_folder)?;
+        if config.network.mode != NetworkMode::Default && is_compose {
+            log!("Warning": "network mode is not supported for Docker Compose based devcontainers");
+        } else {
+            match config.network.mode {
+                NetworkMode::Default => {}
+                NetworkMode::None => run_args.push("--network=none".to_string()),
+                NetworkMode::Restricted => {
+                    run_args.push(format!(
+                        "--network={}",
+                        network::restricted_network_name(workspace_folder)?
+                    ));
+                    container_env.extend(network::proxy_env(workspace_folder)?);
+                }
+            }
+        }
+
+        if config.container.gui {
+            match DisplayServer::detect() {
+                _ if is_compose => {
+                    log!("Warning": "GUI forwarding is not supported for Docker Compose based devcontainers");
+                }
+                Some(display_server) => {
+                    let (gui_run_args, gui_env) = display_server.forwarding();
+                    run_args.extend(gui_run_args);
+                    container_env.extend(gui_env);
+                }
+                None => {
+                    log!("Warning": "no display server found on the host; GUI forwarding is disabled");
+                }
+            }
+        }
+
+        let mut features = Map::new();
+        match config.container.docker_access {
+            DockerAccess::None => {}
+            DockerAccess::Socket if is_compose => {
+                log!("Warning": "mount {DOCKER_SOCKET} in the compose file to use the host's Docker from the container");
+            }
+            DockerAccess::Socket => {
+                run_args.push(format!("--volume={DOCKER_SOCKET}:{DOCKER_SOCKET}"));
+            }
+            DockerAccess::Dind => {
+                features.insert(DIND_FEATURE.to_string(), Value::Object(Map::new()));
+            }
+        }
+
+        let exclude_mounts = exclude_mounts(config);
+        if is_compose && !exclude_mounts.is_empty() {
+            log!("Warning": "add volumes to the compose file to exclude directories from the workspace mount");
+        }
+
+        Ok(Overrides {
+            container_env,
+            image: apt_layer.map(|apt_layer| apt_layer.image),
+            selinux_relabel: config.container.selinux_relabel,
+            run_args,
+            features,
+            exclude_mounts: if is_compose { vec![] } else { exclude_mounts },
+            remote_user: None,
+            strip_initialize_command: initialize_command(workspace_folder)?.is_some(),
+            compose_override,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.container_env.is_empty()
+            && self.image.is_none()
+            && !self.selinux_relabel
+            && self.run_args.is_empty()
+            && self.features.is_empty()
+            && self.exclude_mounts.is_empty()
+            && self.remote_user.is_none()
+            && !self.strip_initialize_command
+            && self.compose_override.is_none()
+    }
+
+    pub fn apply(&self, config: &mut Map<String, Value>) {
+        if self.strip_initialize_command {
+            config.remove("initializeCommand");
+        }
+
+        if let Some(image) = &self.image {
+            config.remove("build");
+            config.remove("dockerFile");
+            config.insert("image".to_string(), Value::String(image.clone()));
+        }
+
+        if let Some(remote_user) = &self.remote_user {
+            config.insert("remoteUser".to_string(), Value::String(remote_user.clone()));
+        }
+
+        if !self.features.is_empty() {
+            let features = jsonc::object_entry(config, "features");
+            for (feature, options) in &self.features {
+                features
+                    .entry(feature.clone())
+                    .or_insert_with(|| options.clone());
+            }
+        }
+
+        if !self.container_env.is_empty() {
+            let container_env = jsonc::object_entry(config, "containerEnv");
+            for (key, value) in &self.container_env {
+                container_env.insert(key.clone(), Value::String(value.clone()));
+            }
+        }
+
+        if !self.exclude_mounts.is_empty() {
+            let entry = config
+                .entry("mounts")
+                .or_insert_with(|| Value::Array(vec![]));
+            if !entry.is_array() {
+                *entry = Value::Array(vec![]);
+            }
+            let mounts = entry.as_array_mut().unwrap();
+            // Without a source, Docker creates an anonymous volume
+            mounts.extend(self.exclude_mounts.iter().map(|path| {
+                Value::String(format!(
+                    "type=volume,target=${{containerWorkspaceFolder}}/{path}"
+                ))
+            }));
+        }
+
+        if let Some(compose_override) = &self.compose_override {
+            let compose_override = Value::String(compose_override.to_string_lossy().to_string());
+            match config.get_mut("dockerComposeFile") {
+                Some(Value::Array(files)) => files.push(compose_override),
+                Some(file) => *file = Value::Array(vec![file.clone(), compose_override]),
+                None => {}
+            }
+        }
+
+        let mut run_args = self.run_args.clone();
+
+        // `--mount` can't relabel, so replace the workspace mount with an equivalent `--volume`.
+        // Compose-based configs mount the workspace in their compose files instead.
+        if self.selinux_relabel && !config.contains_key("dockerComposeFile") {
+            let (source, target) = config
+                .get("workspaceMount")
+                .and_then(|mount| mount.as_str())
+                .and_then(parse_mount)
+                .unwrap_or_else(|| {
+                    (
+                        "${localWorkspaceFolder}".to_string(),
+                        "/workspaces/${localWorkspaceFolderBasename}".to_string(),
+                    )
+                });
+            run_args.push(format!("--volume={source}:{target}:z"));
+            config.insert("workspaceMount".to_string(), Value::String(String::new()));
+            config
+                .entry("workspaceFolder")
+                .or_insert_with(|| Value::String(target));
+        }
+
+        if !run_args.is_empty() {
+            let entry = config
+                .entry("runArgs")
+                .or_insert_with(|| Value::Array(vec![]));
+            if !entry.is_array() {
+                *entry = Value::Array(vec![]);
+            }
+            let existing = entry.as_array_mut().unwrap();
+            existing.extend(run_args.into_iter().map(Value::String));
+        }
+    }
+}
+
+/// Labels by which dockim finds the devcontainer and tells whether it is out of date.
+fn labels(workspace_folder: &Path) -> Result<Vec<(&'static str, String)>> {
+    let (config_path, _) = read_devcontainer_json(workspace_folder)?;
+    let workspace_folder = host_path::canonicalize(workspace_folder)
+        .into_diagnostic()
+        .wrap_err("failed to resolve workspace folder")?;
+
+    Ok(vec![
+        (
+            devcontainer_config::INPUTS_HASH_LABEL,
+            devcontainer_config::inputs_hash(&workspace_folder)?,
+        ),
+        (
+            devcontainer_config::WORKSPACE_LABEL,
+            workspace_folder.display().to_string(),
+        ),
+        (
+            devcontainer_config::CONFIG_LABEL,
+            config_path.display().to_string(),
+        ),
+    ])
+}
+
+/// Writes a compose file that adds `labels` to `service`, returning its path. JSON is also YAML,
+/// so no YAML writer is needed.
+fn write_compose_labels(
+    workspace_folder: &Path,
+    service: &str,
+    labels: &[(&str, String)],
+) -> Result<PathBuf> {
+    let labels: Map<String, Value> = labels
+        .iter()
+        .map(|(name, value)| (name.to_string(), Value::String(value.clone())))
+        .collect();
+    let compose = serde_json::json!({ "services": { service: { "labels": labels } } });
+
+    let path = state::ensure_workspace_state_dir(workspace_folder)?.join(COMPOSE_LABELS_FILE);
+    fs::write(
+        &path,
+        serde_json::to_string_pretty(&compose).into_diagnostic()? + "\n",
+    )
+    .into_diagnostic()
+    .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    Ok(path)
+}
+
+/// Returns `container.exclude_mounts` normalized, skipping paths outside the workspace.
+pub fn exclude_mounts(config: &Config) -> Vec<String> {
+    config
+        .container
+        .exclude_mounts
+        .iter()
+        .filter_map(|path| {
+            let normalized = path.trim_start_matches("./").trim_end_matches('/');
+            let is_inside = !normalized.is_empty()
+                && !normalized.starts_with('/')
+                && !normalized.split('/').any(|component| component == "..");
+            if !is_inside {
+                log!("Warning": "ignoring `{path}` in container.exclude_mounts; it must be a directory inside the workspace");
+                return None;
+            }
+
+            Some(normalized.to_string())
+        })
+        .collect()
+}
+
+/// Extracts source and target from a `--mount` style specification.
+fn parse_mount(mount: &str) -> Option<(String, String)> {
+    let mut source = None;
+    let mut target = None;
+    for option in mount.split(',') {
+        match option.split_once('=') {
+            Some(("source" | "src", value)) => source = Some(value.to_string()),
+            Some(("target" | "dst" | "destination", value)) => target = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Some((source?, target?))
+}
+
+pub fn devcontainer_json_path(workspace_folder: &Path) -> Option<PathBuf> {
+    if let Some(variant) = variant::selected() {
+        return Some(variant::devcontainer_json_path(workspace_folder, variant))
+            .filter(|path| path.exists());
+    }
+
+    [
+        workspace_folder
+            .join(".devcontainer")
+            .join("devcontainer.json"),
+        workspace_folder.join(".devcontainer.json"),
+    ]
+    .into_iter()
+    .find(|path| path.exists())
+}
+
+/// Finds the workspace folder containing `dir`: the nearest ancestor with a `.devcontainer`
+/// directory or `.devcontainer.json`.
+pub fn find_workspace_folder(dir: &Path) -> Option<PathBuf> {
+    let dir = host_path::canonicalize(dir).ok()?;
+
+    dir.ancestors()
+        .find(|dir| dir.join(".devcontainer").is_dir() || dir.join(".devcontainer.json").is_file())
+        .map(Path::to_path_buf)
+}
+
+/// Returns `dockim_<workspace>_<config>`, where `<config>` is the `name` in devcontainer.json and
+/// `<workspace>` includes the variant, if any. Falls back to the full workspace path if another workspace already took the short name.
+pub fn readable_container_name(workspace_folder: &Path) -> Result<String> {
+    let (_, config) = read_devcontainer_json(workspace_folder)?;
+    let config_name = config
+        .get("name")
+        .and_then(Value::as_str)
+        .map(sanitize_container_name)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "devcontainer".to_string());
+    let workspace_folder = host_path::canonicalize(workspace_folder)
+        .into_diagnostic()
+        .wrap_err("failed to resolve workspace folder")?;
+    let mut workspace_name = workspace_folder
+        .file_name()
+        .map(|name| sanitize_container_name(&name.to_string_lossy()))
+        .unwrap_or_default();
+    if let Some(variant) = variant::selected() {
+        workspace_name = format!("{workspace_name}-{}", sanitize_container_name(variant));
+    }
+
+    let name = format!("dockim_{workspace_name}_{config_name}");
+    let owner = exec::capturing_stdout(&[
+        "docker",
+        "ps",
+        "-a",
+        "--filter",
+        &format!("name=^/{name}$"),
+        "--format",
+        &format!(
+            "{{{{.Label \"{}\"}}}}",
+            devcontainer_config::WORKSPACE_LABEL
+        ),
+    ])
+    .unwrap_or_default();
+    let owner = owner.trim();
+    if owner.is_empty() || Path::new(owner) == workspace_folder {
+        return Ok(name);
+    }
+
+    Ok(format!(
+        "dockim_{}_{config_name}",
+        state::workspace_id(&workspace_folder)?
+    ))
+}
+
+/// Keeps the characters Docker allows in container names.
+fn sanitize_container_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '.' | '-' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect::<String>()
+        .trim_matches(['-', '.'])
+        .to_string()
+}
+
+pub fn read_devcontainer_json(workspace_folder: &Path) -> Result<(PathBuf, Map<String, Value>)> {
+    let Some(path) = devcontainer_json_path(workspace_folder) else {
+        if let Some(variant) = variant::selected() {
+            bail!(
+                help = variant::not_found_help(workspace_folder),
+                "devcontainer.json of variant {variant} not found"
+            );
+        }
+        bail!("devcontainer.json not found");
+    };
+    let contents = fs::read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+    let config = jsonc::from_str(&contents)
+        .wrap_err_with(|| miette!("failed to parse {}", path.display()))?;
+
+    Ok((path, config))
+}
+
+/// Returns `initializeCommand` of devcontainer.json, which runs on the host.
+pub fn initialize_command(workspace_folder: &Path) -> Result<Option<Value>> {
+    let (_, config) = read_devcontainer_json(workspace_folder)?;
+
+    Ok(config
+        .get("initializeCommand")
+        .filter(|command| !command.is_null())
+        .cloned())
+}
+
+/// Writes the overridden configuration into the workspace state directory and returns its path,
+/// or `None` if there is nothing to override.
+pub fn write(workspace_folder: &Path, overrides: &Overrides) -> Result<Option<PathBuf>> {
+    if overrides.is_empty() {
+        return Ok(None);
+    }
+
+    let (config_path, mut config) = read_devcontainer_json(workspace_folder)?;
+    let original = fs::read_to_string(&config_path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", config_path.display()))?;
+    let config_dir = host_path::canonicalize(&config_path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to resolve {}", config_path.display()))?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    // The override file lives elsewhere, so relative paths must not depend on its location
+    absolutize_paths(&mut config, &config_dir);
+    overrides.apply(&mut config);
+
+    // Overrides for another user are only used by exec, so keep them apart from the one for up
+    let file_name = match &overrides.remote_user {
+        Some(user) => {
+            // The name comes from the command line, so it must not reach out of the state dir
+            let user: String = user
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            format!("override.{user}.devcontainer.json")
+        }
+        None => OVERRIDE_CONFIG_FILE.to_string(),
+    };
+    let dir = state::ensure_workspace_state_dir(workspace_folder)?;
+    let path = dir.join(file_name);
+    // Edit the original text so that the override is a minimal diff of devcontainer.json
+    let contents = jsonc::update_top_level(&original, &config)
+        .wrap_err_with(|| miette!("failed to update {}", config_path.display()))?;
+    fs::write(&path, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    Ok(Some(path))
+}
+
+fn absolutize_paths(config: &mut Map<String, Value>, config_dir: &Path) {
+    let absolutize = |value: &mut Value| {
+        if let Value::String(path) = value {
+            if Path::new(path).is_relative() {
+                *path = host_path::join(config_dir, path)
+                    .to_string_lossy()
+                    .to_string();
+            }
+        }
+    };
+
+    if let Some(docker_file) = config.get_mut("dockerFile") {
+        absolutize(docker_file);
+    }
+
+    if let Some(Value::Object(build)) = config.get_mut("build") {
+        for key in ["dockerfile", "dockerFile", "context"] {
+            if let Some(value) = build.get_mut(key) {
+                absolutize(value);
+            }
+        }
+    }
+
+    match config.get_mut("dockerComposeFile") {
+        Some(Value::Array(files)) => files.iter_mut().for_each(absolutize),
+        Some(file) => absolutize(file),
+        None => {}
+    }
+}
+
+/// Reads `.dockim/env.toml` of the workspace, expanding `${env:NAME}` from the host environment.
+pub fn load_workspace_env(workspace_folder: &Path) -> Result<BTreeMap<String, String>> {
+    let path = workspace_folder
+        .join(trust::LOCAL_CONFIG_DIR)
+        .join(WORKSPACE_ENV_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    if !trust::is_trusted(workspace_folder)? {
+        log!("Warning": "ignoring {} since the workspace is not trusted", path.display());
+        log!("Hint": "run `dockim trust` to use it");
+        return Ok(BTreeMap::new());
+    }
+
+    let contents = fs::read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+    let table: toml::Table = toml::from_str(&contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to parse {}", path.display()))?;
+
+    Ok(table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(s) => interpolate_env(&s),
+                value => value.to_string(),
+            };
+            (key, value)
+        })
+        .collect())
+}
+
+pub fn interpolate_env(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${env:") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+
+        out.push_str(&rest[..start]);
+        let name = &rest[start + "${env:".len()..start + len];
+        match env::var(name) {
+            Ok(value) => out.push_str(&value),
+            Err(_) => log!("Warning": "environment variable `{name}` is not set on the host"),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+
+    out
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::{log, override_config};
+
+/// Files next to devcontainer.json listing the project's system packages, in order of preference.
+/// All of them are read the same way, so either syntax works in any of them.
+pub const FILE_NAMES: &[&str] = &["packages.txt", "Aptfile", "Brewfile"];
+
+/// System packages the project declares in version control, installed by `dockim build` with the
+/// configured backend.
+#[derive(Debug, Clone)]
+pub struct PackageFile {
+    pub path: PathBuf,
+    pub packages: Vec<String>,
+}
+
+impl PackageFile {
+    /// Reads the first package file found next to devcontainer.json, if any.
+    pub fn load(workspace_folder: &Path) -> Result<Option<Self>> {
+        let Some(config_path) = override_config::devcontainer_json_path(workspace_folder) else {
+            return Ok(None);
+        };
+        let config_dir = config_path.parent().unwrap_or(workspace_folder);
+        let Some(path) = FILE_NAMES
+            .iter()
+            .map(|name| config_dir.join(name))
+            .find(|path| path.is_file())
+        else {
+            return Ok(None);
+        };
+
+        let contents = fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+        let packages =
+            parse(&contents).wrap_err_with(|| miette!("failed to parse {}", path.display()))?;
+
+        Ok(Some(PackageFile { path, packages }))
+    }
+}
+
+/// Parses package names, one or more per line (Aptfile), or `brew "name"` lines (Brewfile).
+/// Comments start with `#`.
+fn parse(contents: &str) -> Result<Vec<String>> {
+    let mut packages = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match keyword {
+            "brew" => {
+                // Options such as `brew "name", args: [...]` follow the name
+                let name = unquote(rest.split(',').next().unwrap_or_default());
+                packages.push(check_name(name, i)?);
+            }
+            "tap" | "cask" | "mas" | "vscode" | "whalebrew" => {
+                log!("Skipping" ("packages"): "`{keyword}` on line {} has no equivalent in the container", i + 1);
+            }
+            _ if line.starts_with(':') => {
+                log!("Skipping" ("packages"): "Aptfile directive on line {}", i + 1);
+            }
+            _ => {
+                for name in line.split_whitespace() {
+                    packages.push(check_name(name, i)?);
+                }
+            }
+        }
+    }
+
+    Ok(packages)
+}
+
+fn unquote(s: &str) -> &str {
+    s.trim().trim_matches(['"', '\''])
+}
+
+/// Accepts the characters of apt package names with an optional `=version`, and of nixpkgs
+/// attribute paths.
+fn check_name(name: &str, line_index: usize) -> Result<String> {
+    let is_valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-._=:~".contains(c));
+    if !is_valid {
+        bail!("line {}: invalid package name: {name}", line_index + 1);
+    }
+
+    Ok(name.to_string())
+}
@@ -0,0 +1,39 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use miette::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::state;
+
+/// The `dockim pair` session in progress, if any
+const PAIRING_STATE_FILE: &str = "pairing.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Session {
+    workspace: String,
+    started_at: u64,
+}
+
+/// Whether a `dockim pair` session is in progress. Meanwhile the clipboard server refuses to
+/// paste and copy, and the git credential bridge does nothing, since the peer can ask them too.
+/// A session left behind by a crashed dockim keeps them restricted until the next `dockim pair`
+/// ends.
+pub fn is_active() -> bool {
+    // Fail closed if the state can't be read
+    state::load_shared::<Option<Session>>(PAIRING_STATE_FILE)
+        .map_or(true, |session| session.is_some())
+}
+
+pub fn begin(workspace: &str) -> Result<()> {
+    let session = Session {
+        workspace: workspace.to_string(),
+        started_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+    };
+    state::save_shared(PAIRING_STATE_FILE, &Some(session))
+}
+
+pub fn end() -> Result<()> {
+    state::save_shared(PAIRING_STATE_FILE, &None::<Session>)
+}
@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+
+/// A host directory bind-mounted into the container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathMapping {
+    pub host: PathBuf,
+    pub container: String,
+}
+
+/// Translates an absolute host path into the container, using the most specific mount.
+pub fn to_container(mappings: &[PathMapping], host_path: &Path) -> Option<String> {
+    mappings
+        .iter()
+        .filter_map(|mapping| {
+            let rest = host_path.strip_prefix(&mapping.host).ok()?;
+            Some((mapping, rest))
+        })
+        .max_by_key(|(mapping, _)| mapping.host.components().count())
+        .map(|(mapping, rest)| {
+            let rest = rest
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .join("/");
+            join_container_path(&mapping.container, &rest)
+        })
+}
+
+/// Translates an absolute container path to the host, using the most specific mount.
+pub fn to_host(mappings: &[PathMapping], container_path: &str) -> Option<PathBuf> {
+    mappings
+        .iter()
+        .filter_map(|mapping| {
+            let rest = strip_container_prefix(container_path, &mapping.container)?;
+            Some((mapping, rest))
+        })
+        .max_by_key(|(mapping, _)| mapping.container.len())
+        .map(|(mapping, rest)| {
+            rest.split('/')
+                .filter(|part| !part.is_empty())
+                .fold(mapping.host.clone(), |path, part| path.join(part))
+        })
+}
+
+fn strip_container_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+fn join_container_path(base: &str, rest: &str) -> String {
+    if rest.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}/{rest}", base.trim_end_matches('/'))
+    }
+}
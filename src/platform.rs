@@ -0,0 +1,124 @@
+use std::fs;
+
+use crate::{devcontainer::DevContainer, exec, log, vm_provider::VmProvider};
+
+/// Image that registers QEMU binfmt handlers in the kernel running Docker. It runs privileged, so
+/// it is pinned rather than following `latest`.
+const BINFMT_IMAGE: &str = "tonistiigi/binfmt:qemu-v8.1.5";
+
+/// Architecture of the Docker engine, in the naming of image platforms (`amd64`, `arm64`).
+pub fn engine_arch() -> Option<String> {
+    let arch =
+        exec::capturing_stdout(&["docker", "info", "--format", "{{ .Architecture }}"]).ok()?;
+    let arch = normalize(arch.trim());
+
+    (!arch.is_empty()).then_some(arch)
+}
+
+/// Architecture the devcontainer's image was built for.
+pub fn container_arch(container_id: &str) -> Option<String> {
+    let image = exec::capturing_stdout(&[
+        "docker",
+        "inspect",
+        "--format",
+        "{{ .Image }}",
+        container_id,
+    ])
+    .ok()?;
+    let arch = exec::capturing_stdout(&[
+        "docker",
+        "image",
+        "inspect",
+        "--format",
+        "{{ .Architecture }}",
+        image.trim(),
+    ])
+    .ok()?;
+    let arch = normalize(arch.trim());
+
+    (!arch.is_empty()).then_some(arch)
+}
+
+fn normalize(arch: &str) -> String {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+    .to_string()
+}
+
+/// The architecture most often emulated on an engine of `arch`.
+pub fn foreign_arch(arch: &str) -> &'static str {
+    if arch == "arm64" {
+        "amd64"
+    } else {
+        "arm64"
+    }
+}
+
+/// Warns when the devcontainer runs under emulation, which is silent but several times slower.
+pub fn warn_if_emulated(dc: &DevContainer) {
+    let Some(engine) = engine_arch() else {
+        return;
+    };
+    let Ok(container_ids) = dc.find_container_ids() else {
+        return;
+    };
+    let Some(container) = container_ids.first().and_then(|id| container_arch(id)) else {
+        return;
+    };
+    if container == engine {
+        return;
+    }
+
+    log!(
+        "Warning": "the devcontainer image is linux/{container} and runs emulated on this linux/{engine} Docker engine, which is much slower"
+    );
+    log!(
+        "Hint": "use an image published for linux/{engine}, or pin it with `\"runArgs\": [\"--platform=linux/{engine}\"]` in devcontainer.json"
+    );
+}
+
+/// Architectures the engine can run through QEMU binfmt handlers, if dockim can tell: only where
+/// the engine shares the host's kernel.
+pub fn emulated_archs() -> Option<Vec<String>> {
+    if !matches!(VmProvider::detect(), VmProvider::Native { .. }) {
+        return None;
+    }
+    let entries = fs::read_dir("/proc/sys/fs/binfmt_misc").ok()?;
+
+    Some(
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_prefix("qemu-").map(normalize)
+            })
+            .collect(),
+    )
+}
+
+/// Whether the provider runs foreign images without binfmt handlers installed by the user.
+pub fn has_builtin_emulation() -> bool {
+    // QEMU or Rosetta, set up by the provider itself
+    matches!(
+        VmProvider::detect(),
+        VmProvider::DockerDesktop | VmProvider::OrbStack
+    )
+}
+
+/// Command registering the QEMU handler for `arch` in the kernel running Docker.
+pub fn install_binfmt_command(arch: &str) -> Vec<String> {
+    [
+        "docker",
+        "run",
+        "--privileged",
+        "--rm",
+        BINFMT_IMAGE,
+        "--install",
+        arch,
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
@@ -0,0 +1,274 @@
+use std::{
+    fs::File,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use colored::Colorize;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::log;
+
+/// What a long-running operation reports while it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// The operation consists of this many steps
+    Steps(usize),
+    /// A step began; steps are numbered from 1
+    Step { index: usize, description: String },
+}
+
+/// Asks an operation to stop before its next step. Only commands run through
+/// [`exec::cancellable`](crate::exec::cancellable), such as `devcontainer up`, are killed; others
+/// finish first.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fails if cancellation has been requested.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!("operation cancelled");
+        }
+
+        Ok(())
+    }
+}
+
+/// Handed to an operation so that it can report its steps and notice cancellation.
+#[derive(Debug)]
+pub struct Progress {
+    sender: Option<Sender<ProgressEvent>>,
+    token: CancellationToken,
+    index: AtomicUsize,
+    /// Receives the output of each step in its own file, when running quietly
+    log_dir: Option<PathBuf>,
+}
+
+impl Progress {
+    /// Reports nowhere and is never cancelled, for the command line.
+    pub fn none() -> Self {
+        Progress {
+            sender: None,
+            token: CancellationToken::new(),
+            index: AtomicUsize::new(0),
+            log_dir: None,
+        }
+    }
+
+    /// Reports to the returned receiver, for example for [`render`], and captures the output of
+    /// each step to a file in `log_dir` (see [`step_log_path`]) instead of the terminal.
+    pub fn quiet(log_dir: PathBuf) -> (Self, Receiver<ProgressEvent>) {
+        let (sender, events) = mpsc::channel();
+        let progress = Progress {
+            sender: Some(sender),
+            token: CancellationToken::new(),
+            index: AtomicUsize::new(0),
+            log_dir: Some(log_dir),
+        };
+
+        (progress, events)
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn steps(&self, total: usize) {
+        self.send(ProgressEvent::Steps(total));
+    }
+
+    /// Reports the beginning of the next step, failing instead if the operation was cancelled.
+    pub fn step(&self, description: &str) -> Result<()> {
+        self.token.check()?;
+
+        let index = self.index.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(log_dir) = &self.log_dir {
+            let path = step_log_path(log_dir, index, description);
+            let file = File::create(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| miette!("failed to create {}", path.display()))?;
+            log::capture(Some(file));
+        }
+        self.send(ProgressEvent::Step {
+            index,
+            description: description.to_string(),
+        });
+
+        Ok(())
+    }
+
+    fn send(&self, event: ProgressEvent) {
+        if let Some(sender) = &self.sender {
+            // Nobody may be listening anymore, which is fine
+            let _ = sender.send(event);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.log_dir.is_some() {
+            log::capture(None);
+        }
+    }
+}
+
+/// The file receiving the output of a step of a quiet [`Progress`].
+pub fn step_log_path(log_dir: &Path, index: usize, description: &str) -> PathBuf {
+    let slug = description
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("-");
+    log_dir.join(format!("{index:02}-{slug}.log"))
+}
+
+/// A step as shown by [`render`].
+#[derive(Debug, Clone)]
+pub struct RenderedStep {
+    pub index: usize,
+    pub description: String,
+    total: Option<usize>,
+}
+
+impl RenderedStep {
+    fn label(&self) -> String {
+        match self.total {
+            Some(total) => format!("[{}/{}] {}", self.index, total, self.description),
+            None => format!("[{}] {}", self.index, self.description),
+        }
+    }
+
+    /// Prints the line the step leaves behind.
+    pub fn finish(&self, succeeded: bool) {
+        let mark = if succeeded {
+            "✓".bright_green()
+        } else {
+            "✗".bright_red()
+        };
+        eprintln!("{mark} {}", self.label());
+    }
+}
+
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Shows the steps of an operation on stderr, with a spinner on the running one if it's a
+/// terminal. Returns the step that was running when the events ended, which is left for the
+/// caller to [finish](RenderedStep::finish).
+pub fn render(events: Receiver<ProgressEvent>) -> Option<RenderedStep> {
+    let animates = io::stderr().is_terminal();
+    let mut total = None;
+    let mut current: Option<RenderedStep> = None;
+    for frame in SPINNER.iter().cycle() {
+        match events.recv_timeout(Duration::from_millis(100)) {
+            Ok(ProgressEvent::Steps(steps)) => total = Some(steps),
+            Ok(ProgressEvent::Step { index, description }) => {
+                if animates {
+                    eprint!("\r\x1b[2K");
+                }
+                if let Some(step) = &current {
+                    step.finish(true);
+                }
+                current = Some(RenderedStep {
+                    index,
+                    description,
+                    total,
+                });
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if let (true, Some(step)) = (animates, &current) {
+            eprint!(
+                "\r\x1b[2K{} {}",
+                frame.to_string().bright_cyan(),
+                step.label()
+            );
+            let _ = io::stderr().flush();
+        }
+    }
+
+    if animates {
+        eprint!("\r\x1b[2K");
+    }
+    current
+}
+
+/// An operation running on its own thread.
+#[derive(Debug)]
+pub struct Operation<T> {
+    events: Receiver<ProgressEvent>,
+    token: CancellationToken,
+    handle: JoinHandle<Result<T>>,
+}
+
+impl<T> Operation<T> {
+    /// Progress of the operation. Iterating ends when the operation finishes.
+    pub fn events(&self) -> &Receiver<ProgressEvent> {
+        &self.events
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Waits for the operation to finish and returns its result.
+    pub fn wait(self) -> Result<T> {
+        self.handle
+            .join()
+            .map_err(|_| miette!("operation panicked"))?
+    }
+}
+
+/// Runs `f` on a new thread, passing it a [`Progress`] connected to the returned [`Operation`].
+pub fn spawn<T, F>(f: F) -> Operation<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Progress) -> Result<T> + Send + 'static,
+{
+    let (sender, events) = mpsc::channel();
+    let token = CancellationToken::new();
+    let progress = Progress {
+        sender: Some(sender),
+        token: token.clone(),
+        index: AtomicUsize::new(0),
+        log_dir: None,
+    };
+    let handle = thread::spawn(move || f(&progress));
+
+    Operation {
+        events,
+        token,
+        handle,
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use itertools::Itertools;
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::{devcontainer::DevContainer, exec, log};
+
+/// A terminal session recorded on the container by `script` or asciinema, and copied to the host
+/// when it ends.
+pub struct Recording {
+    host_path: PathBuf,
+    container_path: String,
+    asciicast: bool,
+}
+
+impl Recording {
+    /// Prepares to record into `host_path`: in asciinema's format for `.cast` files, as a
+    /// `script` typescript otherwise.
+    pub fn prepare(dc: &DevContainer, host_path: &Path) -> Result<Self> {
+        let asciicast = host_path.extension().is_some_and(|ext| ext == "cast");
+        let (recorder, help) = if asciicast {
+            (
+                "asciinema",
+                "install asciinema on the container, or record to a file not ending with .cast",
+            )
+        } else {
+            (
+                "script",
+                "install util-linux (bsdutils on Debian) on the container",
+            )
+        };
+        if dc.find_shell(&[recorder]).is_none() {
+            bail!(
+                help = help,
+                "`{recorder}` is not available on the container"
+            );
+        }
+
+        let extension = if asciicast { "cast" } else { "log" };
+        Ok(Recording {
+            host_path: host_path.to_path_buf(),
+            container_path: format!("/tmp/dockim-record-{}.{extension}", process::id()),
+            asciicast,
+        })
+    }
+
+    /// Wraps `command` so that its session is recorded, keeping its exit status.
+    pub fn wrap<S: AsRef<str>>(&self, command: &[S]) -> Vec<String> {
+        let command = command
+            .iter()
+            .map(|arg| exec::shell_quote(arg.as_ref()))
+            .join(" ");
+        let recorder = if self.asciicast {
+            // asciinema 2 has no option to pass the exit status through
+            format!(
+                "asciinema rec --quiet --overwrite --command {} {}",
+                exec::shell_quote(&command),
+                self.container_path
+            )
+        } else {
+            format!(
+                "script --quiet --return --flush --command {} {}",
+                exec::shell_quote(&command),
+                self.container_path
+            )
+        };
+
+        ["sh", "-c", &recorder].map(str::to_string).to_vec()
+    }
+
+    /// Like [`Recording::wrap`], but leaves `command` as is without a recording.
+    pub fn wrap_if<S: AsRef<str>>(recording: Option<&Recording>, command: &[S]) -> Vec<String> {
+        match recording {
+            Some(recording) => recording.wrap(command),
+            None => command.iter().map(|arg| arg.as_ref().to_string()).collect(),
+        }
+    }
+
+    /// Copies the recording to the host and removes it from the container.
+    pub fn finish(self, dc: &DevContainer) -> Result<()> {
+        let recording = dc
+            .exec_capturing_stdout_bytes(&["cat", &self.container_path])
+            .wrap_err("failed to read the recording on the container")?;
+        let _ = dc.exec(&["rm", "-f", &self.container_path]);
+        fs::write(&self.host_path, recording)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to write {}", self.host_path.display()))?;
+
+        let replay = if self.asciicast {
+            "asciinema play"
+        } else {
+            "cat"
+        };
+        log!(
+            "Saved" ("recording"): "{}; replay it with `{replay} {}`",
+            self.host_path.display(),
+            exec::shell_quote(&self.host_path.to_string_lossy())
+        );
+
+        Ok(())
+    }
+}
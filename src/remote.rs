@@ -0,0 +1,121 @@
+use std::{env, net::Ipv4Addr, path::PathBuf, sync::OnceLock};
+
+use miette::{miette, Result, WrapErr};
+
+use crate::{config::Config, exec, log, state};
+
+/// Points the Docker CLI (and the devcontainer CLI, which uses it) at the configured remote host.
+/// An explicit `DOCKER_HOST` takes precedence.
+pub fn activate(config: &Config) {
+    let Some(ssh_host) = &config.runtime.ssh_host else {
+        return;
+    };
+
+    if env::var_os("DOCKER_HOST").is_none() {
+        env::set_var("DOCKER_HOST", format!("ssh://{ssh_host}"));
+    }
+}
+
+/// Makes `port` on the remote host reachable at the same port on localhost.
+pub fn forward(ssh_host: &str, port: &str) -> Result<()> {
+    control(ssh_host, "forward", &format!("-L{port}:localhost:{port}"))
+        .wrap_err_with(|| format!("failed to forward port {port} from {ssh_host}"))
+}
+
+/// Makes `port` on localhost reachable at the same port of the Docker bridge on the remote host,
+/// where containers reach it through host.docker.internal. Binding it needs
+/// `GatewayPorts clientspecified` in the remote sshd_config.
+pub fn reverse_forward(ssh_host: &str, port: &str) -> Result<()> {
+    control(ssh_host, "forward", &reverse_spec(port)).wrap_err_with(|| {
+        miette!(
+            help = "set `GatewayPorts clientspecified` in sshd_config on the remote host",
+            "failed to forward port {port} to {ssh_host}"
+        )
+    })
+}
+
+pub fn cancel_forward(ssh_host: &str, port: &str) -> Result<()> {
+    control(ssh_host, "cancel", &format!("-L{port}:localhost:{port}"))
+}
+
+pub fn cancel_reverse_forward(ssh_host: &str, port: &str) -> Result<()> {
+    control(ssh_host, "cancel", &reverse_spec(port))
+}
+
+/// Closes the shared SSH connection to `ssh_host`, and with it every forward, if it is open.
+pub fn disconnect(ssh_host: &str) -> Result<()> {
+    let socket = control_socket(ssh_host)?;
+    if !socket.exists() {
+        return Ok(());
+    }
+
+    let socket = socket.to_string_lossy();
+    if exec::capturing_stdout(&["ssh", "-S", &socket, "-O", "exit", ssh_host]).is_ok() {
+        log!("Disconnected": "{ssh_host}");
+    }
+
+    Ok(())
+}
+
+fn reverse_spec(port: &str) -> String {
+    format!("-R{}:{port}:localhost:{port}", remote_bridge_address())
+}
+
+/// The gateway of the default Docker bridge on the remote host (`DOCKER_HOST` points there).
+fn remote_bridge_address() -> &'static str {
+    static ADDRESS: OnceLock<String> = OnceLock::new();
+    ADDRESS.get_or_init(|| {
+        exec::capturing_stdout(&[
+            "docker",
+            "network",
+            "inspect",
+            "--format",
+            "{{ range .IPAM.Config }}{{ .Gateway }} {{ end }}",
+            "bridge",
+        ])
+        .ok()
+        .and_then(|gateways| {
+            gateways
+                .split_whitespace()
+                .find(|gateway| gateway.parse::<Ipv4Addr>().is_ok())
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| "localhost".to_string())
+    })
+}
+
+/// Sends a command to the shared SSH connection to `ssh_host`, starting it if needed.
+fn control(ssh_host: &str, command: &str, spec: &str) -> Result<()> {
+    let socket = control_socket(ssh_host)?;
+    let socket = socket.to_string_lossy();
+
+    let is_alive = exec::capturing_stdout(&["ssh", "-S", &socket, "-O", "check", ssh_host]).is_ok();
+    if !is_alive {
+        log!("Connecting": "{ssh_host}");
+        exec::exec(&[
+            "ssh",
+            "-f",
+            "-N",
+            "-M",
+            "-S",
+            &socket,
+            "-o",
+            "ControlPersist=yes",
+            "-o",
+            "ExitOnForwardFailure=yes",
+            ssh_host,
+        ])
+        .wrap_err_with(|| format!("failed to connect to {ssh_host}"))?;
+    }
+
+    exec::capturing_stdout(&["ssh", "-S", &socket, "-O", command, spec, ssh_host]).map(|_| ())
+}
+
+fn control_socket(ssh_host: &str) -> Result<PathBuf> {
+    let name = ssh_host
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+
+    Ok(state::ensure_shared_state_dir("ssh")?.join(format!("{name}.sock")))
+}
@@ -0,0 +1,183 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde_json::Value;
+
+use crate::{config::Config, devcontainer::DevContainer, host_path, log};
+
+/// Hook run at the end of `dockim build`
+pub const ON_BUILD: &str = "on_build";
+
+/// Hook run at the end of `dockim up`
+pub const ON_UP: &str = "on_up";
+
+/// Function returning a devcontainer.json for `dockim init --template <script name>`
+const TEMPLATE: &str = "template";
+
+/// User scripts in `~/.config/dockim/scripts/*.rhai`.
+///
+/// Scripts only see what is registered here: they can run commands on the container, copy files
+/// into it and log. `copy` reads any host file the user can, such as dotfiles under `~`, so
+/// scripts are as trusted as the user's own config.
+pub struct Scripts {
+    engine: Engine,
+    scripts: Vec<Script>,
+}
+
+struct Script {
+    name: String,
+    path: PathBuf,
+    ast: AST,
+}
+
+impl Scripts {
+    pub fn scripts_dir() -> Result<PathBuf> {
+        let config_path = Config::config_file_path()?;
+        let config_dir = config_path
+            .parent()
+            .ok_or_else(|| miette!("could not find config directory"))?;
+
+        Ok(config_dir.join("scripts"))
+    }
+
+    /// Compiles all scripts. A missing scripts directory means no scripts.
+    pub fn load() -> Result<Self> {
+        let engine = new_engine();
+        let dir = Self::scripts_dir()?;
+        if !dir.exists() {
+            return Ok(Scripts {
+                engine,
+                scripts: vec![],
+            });
+        }
+
+        let mut paths = fs::read_dir(&dir)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .into_diagnostic()?;
+        paths.retain(|path| path.extension().is_some_and(|ext| ext == "rhai"));
+        paths.sort();
+
+        let scripts = paths
+            .into_iter()
+            .map(|path| {
+                let ast = engine
+                    .compile_file(path.clone())
+                    .map_err(|e| miette!("failed to compile {}: {e}", path.display()))?;
+                let name = script_name(&path);
+                Ok(Script { name, path, ast })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Scripts { engine, scripts })
+    }
+
+    /// Calls `hook(dc)` in every script defining it, in the order of their file names.
+    pub fn run_hook(&self, hook: &str, dc: &DevContainer) -> Result<()> {
+        for script in self.scripts.iter().filter(|s| s.defines(hook, 1)) {
+            log!("Running" ("script"): "{} of {}", hook, script.path.display());
+            self.engine
+                .call_fn::<Dynamic>(&mut Scope::new(), &script.ast, hook, (dc.clone(),))
+                .map(drop)
+                .map_err(|e| miette!("{hook} of {} failed: {e}", script.path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Names of the scripts usable as init templates.
+    pub fn template_names(&self) -> Vec<&str> {
+        self.scripts
+            .iter()
+            .filter(|s| s.defines(TEMPLATE, 1))
+            .map(|s| s.name.as_str())
+            .collect()
+    }
+
+    /// Calls `template(workspace_name)` of the script `name`, which must return an object map.
+    pub fn template(&self, name: &str, workspace_name: &str) -> Result<Option<Value>> {
+        let Some(script) = self
+            .scripts
+            .iter()
+            .find(|s| s.name == name && s.defines(TEMPLATE, 1))
+        else {
+            return Ok(None);
+        };
+
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(
+                &mut Scope::new(),
+                &script.ast,
+                TEMPLATE,
+                (workspace_name.to_string(),),
+            )
+            .map_err(|e| miette!("template of {} failed: {e}", script.path.display()))?;
+        if !result.is_map() {
+            return Err(miette!(
+                "template of {} returned {} instead of an object map",
+                script.path.display(),
+                result.type_name()
+            ));
+        }
+
+        rhai::serde::from_dynamic(&result).map(Some).map_err(|e| {
+            miette!(
+                "template of {} is not valid JSON: {e}",
+                script.path.display()
+            )
+        })
+    }
+}
+
+impl Script {
+    fn defines(&self, function: &str, arity: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == function && f.params.len() == arity)
+    }
+}
+
+fn script_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+
+    engine.register_fn("log", |message: &str| log!("Script": "{message}"));
+    engine
+        .register_type_with_name::<DevContainer>("DevContainer")
+        .register_fn("exec", |dc: &mut DevContainer, script: &str| {
+            dc.exec_script(script).map_err(to_script_error)
+        })
+        .register_fn("exec_capture", |dc: &mut DevContainer, script: &str| {
+            dc.exec_script_capturing_stdout(script)
+                .map_err(to_script_error)
+        })
+        .register_fn(
+            "copy",
+            |dc: &mut DevContainer, src_host: &str, dst_container: &str| {
+                dc.copy_file_host_to_container(&host_path::expand_home(src_host), dst_container)
+                    .map_err(to_script_error)
+            },
+        )
+        .register_get("workspace_folder", |dc: &mut DevContainer| {
+            dc.workspace_folder().display().to_string()
+        });
+
+    engine
+}
+
+fn to_script_error(e: miette::Report) -> Box<EvalAltResult> {
+    e.chain().map(|cause| cause.to_string()).join(": ").into()
+}
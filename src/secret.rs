@@ -0,0 +1,45 @@
+use miette::{miette, Result};
+
+/// Returns 128 random bits from the OS in hex, for secrets shared with containers or peers.
+pub fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).map_err(|e| miette!("failed to generate a random token: {e}"))?;
+
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Compares a presented token with the expected one in time independent of where they differ, so
+/// that the token can't be guessed byte by byte.
+pub fn token_matches(expected: &str, presented: &str) -> bool {
+    let (expected, presented) = (expected.as_bytes(), presented.as_bytes());
+    if expected.len() != presented.len() {
+        return false;
+    }
+
+    expected
+        .iter()
+        .zip(presented)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_token_is_128_bits_of_hex() {
+        let token = random_token().unwrap();
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, random_token().unwrap());
+    }
+
+    #[test]
+    fn token_matches_only_the_same_token() {
+        assert!(token_matches("0123abcd", "0123abcd"));
+        assert!(!token_matches("0123abcd", "0123abce"));
+        assert!(!token_matches("0123abcd", "0123abc"));
+        assert!(!token_matches("0123abcd", ""));
+    }
+}
@@ -0,0 +1,58 @@
+use miette::{miette, Result, WrapErr};
+
+use crate::{config::SharedServicesConfig, exec, host_path, log, orchestrator::Orchestrator};
+
+fn compose_args(shared: &SharedServicesConfig) -> Result<Vec<String>> {
+    let mut args = Orchestrator::detect()?.command();
+    args.extend(["-p".to_string(), shared.project.clone()]);
+    for file in &shared.compose_files {
+        let file = host_path::expand_home(file);
+        args.extend(["-f".to_string(), file.to_string_lossy().to_string()]);
+    }
+
+    Ok(args)
+}
+
+pub fn network(shared: &SharedServicesConfig) -> String {
+    shared
+        .network
+        .clone()
+        .unwrap_or_else(|| format!("{}_default", shared.project))
+}
+
+/// Starts the shared stack. Services that are already running are left as they are.
+pub fn ensure_running(shared: &SharedServicesConfig) -> Result<()> {
+    let mut args = compose_args(shared)?;
+    args.extend(["up".to_string(), "-d".to_string()]);
+    exec::exec(&args)
+        .wrap_err_with(|| miette!("failed to start shared services `{}`", shared.project))
+}
+
+/// Connects `container_id` to the network of the shared stack.
+pub fn attach(shared: &SharedServicesConfig, container_id: &str) -> Result<()> {
+    let network = network(shared);
+    let connected = exec::capturing_stdout(&[
+        "docker",
+        "inspect",
+        "--format",
+        "{{ json .NetworkSettings.Networks }}",
+        container_id,
+    ])
+    .wrap_err("failed to inspect devcontainer networks")?;
+    if connected.contains(&format!("\"{network}\"")) {
+        return Ok(());
+    }
+
+    exec::exec(&["docker", "network", "connect", &network, container_id])
+        .wrap_err_with(|| miette!("failed to connect devcontainer to network `{network}`"))?;
+    log!("Attached" ("shared services"): "{network}");
+
+    Ok(())
+}
+
+pub fn stop(shared: &SharedServicesConfig) -> Result<()> {
+    let mut args = compose_args(shared)?;
+    args.push("down".to_string());
+    exec::exec(&args)
+        .wrap_err_with(|| miette!("failed to stop shared services `{}`", shared.project))
+}
@@ -0,0 +1,185 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{host_path, variant};
+
+const WORKSPACE_FILE: &str = "workspace";
+
+/// Holds state not tied to a workspace. Workspace keys never start with `_`.
+const SHARED_DIR: &str = "_shared";
+
+pub fn state_root() -> Result<PathBuf> {
+    Ok(dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .ok_or_else(|| miette!("could not find state directory"))?
+        .join("dockim"))
+}
+
+pub fn workspace_state_dir(workspace_folder: &Path) -> Result<PathBuf> {
+    let workspace_folder = host_path::canonicalize(workspace_folder)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to resolve {}", workspace_folder.display()))?;
+
+    Ok(state_root()?.join(workspace_key(&workspace_folder)))
+}
+
+/// Creates the workspace state directory, recording which workspace it belongs to so that stale
+/// directories can be found later.
+pub fn ensure_workspace_state_dir(workspace_folder: &Path) -> Result<PathBuf> {
+    let dir = workspace_state_dir(workspace_folder)?;
+    fs::create_dir_all(&dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+
+    let workspace_file = dir.join(WORKSPACE_FILE);
+    if !workspace_file.exists() {
+        let workspace_folder = host_path::canonicalize(workspace_folder)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to resolve {}", workspace_folder.display()))?;
+        fs::write(
+            &workspace_file,
+            workspace_folder.to_string_lossy().as_bytes(),
+        )
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", workspace_file.display()))?;
+    }
+
+    Ok(dir)
+}
+
+/// Creates a directory for state shared by all workspaces.
+pub fn ensure_shared_state_dir(name: &str) -> Result<PathBuf> {
+    let dir = state_root()?.join(SHARED_DIR).join(name);
+    fs::create_dir_all(&dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+
+    Ok(dir)
+}
+
+/// Lists the state directories of all workspaces.
+pub fn workspace_state_dirs() -> Result<Vec<PathBuf>> {
+    let root = state_root()?;
+    if !root.exists() {
+        return Ok(vec![]);
+    }
+
+    let entries = fs::read_dir(&root)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", root.display()))?;
+
+    let mut dirs = vec![];
+    for entry in entries {
+        let path = entry.into_diagnostic()?.path();
+        if path.is_dir() && !path.ends_with(SHARED_DIR) {
+            dirs.push(path);
+        }
+    }
+
+    Ok(dirs)
+}
+
+/// Returns the workspace folder a state directory belongs to, if recorded.
+pub fn recorded_workspace_folder(state_dir: &Path) -> Option<PathBuf> {
+    fs::read_to_string(state_dir.join(WORKSPACE_FILE))
+        .ok()
+        .map(|path| PathBuf::from(path.trim()))
+}
+
+/// Returns an identifier of the workspace usable in file and Docker object names.
+pub fn workspace_id(workspace_folder: &Path) -> Result<String> {
+    let workspace_folder = host_path::canonicalize(workspace_folder)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to resolve {}", workspace_folder.display()))?;
+
+    Ok(workspace_key(&workspace_folder).to_lowercase())
+}
+
+/// Variants of a workspace get keys of their own, so that their containers don't share state.
+fn workspace_key(workspace_folder: &Path) -> String {
+    let key = workspace_folder
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_string();
+
+    match variant::selected() {
+        Some(variant) => format!("{key}--{variant}"),
+        None => key,
+    }
+}
+
+pub fn load<T: DeserializeOwned + Default>(workspace_folder: &Path, name: &str) -> Result<T> {
+    let path = workspace_state_dir(workspace_folder)?.join(name);
+    if !path.exists() {
+        return Ok(T::default());
+    }
+
+    let contents = fs::read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+
+    serde_json::from_str(&contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to parse {}", path.display()))
+}
+
+pub fn save<T: Serialize>(workspace_folder: &Path, name: &str, value: &T) -> Result<()> {
+    let dir = ensure_workspace_state_dir(workspace_folder)?;
+
+    let path = dir.join(name);
+    let contents = serde_json::to_string_pretty(value).into_diagnostic()?;
+    fs::write(&path, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))
+}
+
+pub fn shared_state_dir() -> Result<PathBuf> {
+    Ok(state_root()?.join(SHARED_DIR))
+}
+
+pub fn load_shared<T: DeserializeOwned + Default>(name: &str) -> Result<T> {
+    let path = shared_state_dir()?.join(name);
+    if !path.exists() {
+        return Ok(T::default());
+    }
+
+    let contents = fs::read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+
+    serde_json::from_str(&contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to parse {}", path.display()))
+}
+
+pub fn save_shared<T: Serialize>(name: &str, value: &T) -> Result<()> {
+    let dir = shared_state_dir()?;
+    fs::create_dir_all(&dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to create {}", dir.display()))?;
+
+    let path = dir.join(name);
+    let contents = serde_json::to_string_pretty(value).into_diagnostic()?;
+    fs::write(&path, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))
+}
+
+pub fn clear(workspace_folder: &Path) -> Result<()> {
+    let dir = workspace_state_dir(workspace_folder)?;
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    fs::remove_dir_all(&dir)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to remove {}", dir.display()))
+}
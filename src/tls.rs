@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use miette::{Result, WrapErr};
+
+use crate::{exec, log, state};
+
+pub const CERT_FILE: &str = "cert.pem";
+pub const KEY_FILE: &str = "key.pem";
+
+/// Certificates expiring within this many seconds are generated again
+const RENEW_BEFORE_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Returns the directory holding a certificate for localhost, generating it on first use and when
+/// it is about to expire. mkcert is preferred since its CA is trusted by browsers; otherwise a
+/// self-signed one is created.
+pub fn localhost_certificate_dir() -> Result<PathBuf> {
+    let dir = state::ensure_shared_state_dir("tls")?;
+    let cert_path = dir.join(CERT_FILE);
+    let key_path = dir.join(KEY_FILE);
+    let cert = cert_path.to_string_lossy();
+    let key = key_path.to_string_lossy();
+    if cert_path.exists() && key_path.exists() {
+        if !expires_soon(&cert) {
+            return Ok(dir);
+        }
+        log!("Renewing" ("tls"): "the certificate for localhost, which expires soon");
+    }
+
+    if exec::capturing_stdout(&["mkcert", "-help"]).is_ok() {
+        exec::exec(&[
+            "mkcert",
+            "-cert-file",
+            &cert,
+            "-key-file",
+            &key,
+            "localhost",
+            "127.0.0.1",
+            "::1",
+        ])
+        .wrap_err("failed to generate certificate with mkcert")?;
+    } else {
+        exec::exec(&[
+            "openssl",
+            "req",
+            "-x509",
+            "-newkey",
+            "rsa:2048",
+            "-nodes",
+            "-days",
+            "825",
+            "-subj",
+            "/CN=localhost",
+            "-addext",
+            "subjectAltName=DNS:localhost,IP:127.0.0.1,IP:::1",
+            "-keyout",
+            &key,
+            "-out",
+            &cert,
+        ])
+        .wrap_err("failed to generate self-signed certificate with openssl")?;
+        log!("Warning": "mkcert is not installed, so browsers will not trust the generated certificate");
+    }
+
+    Ok(dir)
+}
+
+/// Whether the certificate expires within [`RENEW_BEFORE_SECS`]. Without openssl to tell, it is
+/// assumed not to.
+fn expires_soon(cert: &str) -> bool {
+    if exec::capturing_stdout(&["openssl", "version"]).is_err() {
+        return false;
+    }
+
+    // `-checkend` fails if the certificate expires within the given seconds
+    exec::capturing_stdout(&[
+        "openssl",
+        "x509",
+        "-checkend",
+        &RENEW_BEFORE_SECS.to_string(),
+        "-noout",
+        "-in",
+        cert,
+    ])
+    .is_err()
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{host_path, log, override_config, state};
+
+const TRUST_STATE_FILE: &str = "trust.json";
+
+/// Directory of the workspace-local configuration, which is only honored for trusted workspaces
+pub const LOCAL_CONFIG_DIR: &str = ".dockim";
+
+/// Decisions by canonical workspace path
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TrustState {
+    workspaces: BTreeMap<String, TrustEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TrustEntry {
+    /// Fingerprint of the local configuration the decision was made for
+    fingerprint: String,
+    trusted: bool,
+}
+
+/// Returns whether the local configuration of the workspace may be used. The first time it is
+/// seen, or after it changed, the user is asked if running interactively; otherwise it is not
+/// trusted.
+pub fn is_trusted(workspace_folder: &Path) -> Result<bool> {
+    let Some(fingerprint) = local_config_fingerprint(workspace_folder)? else {
+        return Ok(true);
+    };
+
+    let key = workspace_key(workspace_folder)?;
+    let state: TrustState = state::load_shared(TRUST_STATE_FILE)?;
+    if let Some(entry) = state.workspaces.get(&key) {
+        if entry.fingerprint == fingerprint {
+            return Ok(entry.trusted);
+        }
+    }
+
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Ok(false);
+    }
+
+    let trusted = ask(workspace_folder)?;
+    record(&key, fingerprint, trusted)?;
+
+    Ok(trusted)
+}
+
+pub fn set_trusted(workspace_folder: &Path, trusted: bool) -> Result<()> {
+    let fingerprint = local_config_fingerprint(workspace_folder)?.unwrap_or_default();
+
+    record(&workspace_key(workspace_folder)?, fingerprint, trusted)
+}
+
+fn record(key: &str, fingerprint: String, trusted: bool) -> Result<()> {
+    let mut state: TrustState = state::load_shared(TRUST_STATE_FILE)?;
+    state.workspaces.insert(
+        key.to_string(),
+        TrustEntry {
+            fingerprint,
+            trusted,
+        },
+    );
+
+    state::save_shared(TRUST_STATE_FILE, &state)
+}
+
+fn ask(workspace_folder: &Path) -> Result<bool> {
+    let local_config = workspace_folder.join(LOCAL_CONFIG_DIR);
+    if local_config.exists() {
+        log!("Found" ("local config"): "{}", local_config.display());
+    }
+    if let Ok(Some(command)) = override_config::initialize_command(workspace_folder) {
+        log!("Found" ("initializeCommand"): "{command}");
+    }
+    eprint!("These can run commands on the host and read host environment variables. Trust this workspace? [y/N] ");
+    io::stderr().flush().into_diagnostic()?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .into_diagnostic()?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Hashes the files under the local configuration directory and `initializeCommand` of
+/// devcontainer.json, or returns `None` if there are none.
+fn local_config_fingerprint(workspace_folder: &Path) -> Result<Option<String>> {
+    let dir = workspace_folder.join(LOCAL_CONFIG_DIR);
+    let mut files = Vec::new();
+    collect_files(&dir, &mut files);
+    let initialize_command = override_config::initialize_command(workspace_folder)
+        .ok()
+        .flatten();
+    if files.is_empty() && initialize_command.is_none() {
+        return Ok(None);
+    }
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    if let Some(command) = initialize_command {
+        hasher.update(command.to_string().as_bytes());
+        hasher.update([0]);
+    }
+    for path in files {
+        let contents = fs::read(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| miette!("failed to read {}", path.display()))?;
+        let relative = path.strip_prefix(&dir).unwrap_or(&path);
+        hasher.update(relative.to_string_lossy().replace('\\', "/").as_bytes());
+        hasher.update([0]);
+        // Length-prefixed, so that no file's contents can pass for the next file's name
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+/// Collects the files under `dir` recursively, without following symlinked directories.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files);
+        } else if entry.path().is_file() {
+            files.push(entry.path());
+        }
+    }
+}
+
+fn workspace_key(workspace_folder: &Path) -> Result<String> {
+    Ok(host_path::canonicalize(workspace_folder)
+        .into_diagnostic()
+        .wrap_err("failed to resolve workspace folder")?
+        .to_string_lossy()
+        .to_string())
+}
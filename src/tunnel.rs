@@ -0,0 +1,271 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+
+use crate::config::TunnelBackend;
+
+/// How long the provider may take to hand out a public URL
+const URL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the client on the joining side may take to start listening
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A running tunnel client exposing a host port on a public URL. Stopped on drop.
+#[derive(Debug)]
+pub struct Tunnel {
+    child: Child,
+    url: String,
+}
+
+impl Tunnel {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the exit status if the tunnel client has stopped on its own.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        self.child.try_wait().into_diagnostic()
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl TunnelBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            TunnelBackend::Cloudflared => "cloudflared",
+            TunnelBackend::Ngrok => "ngrok",
+            TunnelBackend::Tailscale => "tailscale",
+        }
+    }
+
+    fn command(self, host_port: u16) -> Vec<String> {
+        match self {
+            TunnelBackend::Cloudflared => vec![
+                "cloudflared".to_string(),
+                "tunnel".to_string(),
+                "--no-autoupdate".to_string(),
+                "--url".to_string(),
+                format!("http://localhost:{host_port}"),
+            ],
+            TunnelBackend::Ngrok => vec![
+                "ngrok".to_string(),
+                "http".to_string(),
+                host_port.to_string(),
+                "--log".to_string(),
+                "stdout".to_string(),
+            ],
+            TunnelBackend::Tailscale => vec![
+                "tailscale".to_string(),
+                "funnel".to_string(),
+                host_port.to_string(),
+            ],
+        }
+    }
+
+    /// Finds the public URL in a line of the client's output. Clients also print links to their
+    /// documentation, so only URLs of the tunnel's own domain count.
+    fn public_url(self, line: &str) -> Option<String> {
+        let is_public = |url: &str| match self {
+            TunnelBackend::Cloudflared => url.contains(".trycloudflare.com"),
+            TunnelBackend::Ngrok => line.contains("started tunnel"),
+            TunnelBackend::Tailscale => url.contains(".ts.net"),
+        };
+
+        line.split(|c: char| c.is_whitespace() || matches!(c, '=' | '"' | '|'))
+            .filter(|token| token.starts_with("https://") || token.starts_with("tcp://"))
+            .map(|token| token.trim_end_matches('/'))
+            .find(|url| is_public(url))
+            .map(str::to_string)
+    }
+}
+
+/// Starts a tunnel from the backend's public URL to `host_port` on this machine and waits for
+/// the URL.
+pub fn start(backend: TunnelBackend, host_port: u16) -> Result<Tunnel> {
+    launch(backend, &backend.command(host_port))
+}
+
+/// Like [`start`], but tunnels raw TCP. The other side reaches the port through [`connect_tcp`].
+pub fn start_tcp(backend: TunnelBackend, host_port: u16) -> Result<Tunnel> {
+    let command = match backend {
+        TunnelBackend::Cloudflared => vec![
+            "cloudflared".to_string(),
+            "tunnel".to_string(),
+            "--no-autoupdate".to_string(),
+            "--url".to_string(),
+            format!("tcp://localhost:{host_port}"),
+        ],
+        TunnelBackend::Ngrok => vec![
+            "ngrok".to_string(),
+            "tcp".to_string(),
+            host_port.to_string(),
+            "--log".to_string(),
+            "stdout".to_string(),
+        ],
+        TunnelBackend::Tailscale => bail!(
+            help = "choose cloudflared or ngrok with --backend",
+            "Tailscale Funnel can't expose raw TCP"
+        ),
+    };
+
+    launch(backend, &command)
+}
+
+fn launch(backend: TunnelBackend, command: &[String]) -> Result<Tunnel> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .into_diagnostic()
+        .wrap_err_with(|| {
+            miette!(
+                help = "install it or choose another backend with --backend",
+                "failed to run `{}`",
+                backend.name()
+            )
+        })?;
+
+    let (sender, lines) = mpsc::channel();
+    forward_lines(child.stdout.take(), sender.clone());
+    forward_lines(child.stderr.take(), sender);
+
+    // Kill the client if we give up on it
+    let mut tunnel = Tunnel {
+        child,
+        url: String::new(),
+    };
+    let mut output = vec![];
+    let start = Instant::now();
+    while let Some(remaining) = URL_TIMEOUT.checked_sub(start.elapsed()) {
+        match lines.recv_timeout(remaining) {
+            Ok(line) => {
+                if let Some(url) = backend.public_url(&line) {
+                    tunnel.url = url;
+                    return Ok(tunnel);
+                }
+                output.push(line);
+            }
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => {
+                let status = tunnel.child.wait().into_diagnostic()?;
+                bail!(
+                    "`{}` exited with {status} before printing a public URL:\n{}",
+                    backend.name(),
+                    output.join("\n")
+                );
+            }
+        }
+    }
+
+    bail!(
+        "`{}` printed no public URL within {} seconds",
+        backend.name(),
+        URL_TIMEOUT.as_secs()
+    );
+}
+
+/// The local end of a TCP tunnel started by [`start_tcp`] on another machine. The client it needs,
+/// if any, is stopped on drop.
+#[derive(Debug)]
+pub struct TcpEndpoint {
+    child: Option<Child>,
+    address: String,
+}
+
+impl TcpEndpoint {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl Drop for TcpEndpoint {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Reaches the public URL of a TCP tunnel. ngrok hands out a plain TCP address, while Cloudflare
+/// needs `cloudflared access tcp` to listen locally.
+pub fn connect_tcp(url: &str) -> Result<TcpEndpoint> {
+    if let Some(address) = url.strip_prefix("tcp://") {
+        return Ok(TcpEndpoint {
+            child: None,
+            address: address.to_string(),
+        });
+    }
+    if !url.starts_with("https://") {
+        bail!("unsupported tunnel URL `{url}`");
+    }
+
+    let local_port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .into_diagnostic()
+        .wrap_err("failed to find a free local port")?
+        .port();
+    let address = format!("localhost:{local_port}");
+    let child = Command::new("cloudflared")
+        .args(["access", "tcp", "--hostname", url, "--url", &address])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .into_diagnostic()
+        .wrap_err_with(|| {
+            miette!(
+                help = "install cloudflared to join tunnels on trycloudflare.com",
+                "failed to run `cloudflared`"
+            )
+        })?;
+    let endpoint = TcpEndpoint {
+        child: Some(child),
+        address,
+    };
+
+    let start = Instant::now();
+    while start.elapsed() < CONNECT_TIMEOUT {
+        if TcpStream::connect(("127.0.0.1", local_port)).is_ok() {
+            return Ok(endpoint);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    bail!(
+        "`cloudflared access tcp` did not listen within {} seconds",
+        CONNECT_TIMEOUT.as_secs()
+    );
+}
+
+/// Sends each line of `pipe` until it closes. Keeps reading after the receiver is gone so that
+/// the client never blocks on a full pipe.
+fn forward_lines(pipe: Option<impl Read + Send + 'static>, sender: Sender<String>) {
+    let Some(pipe) = pipe else {
+        return;
+    };
+
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let _ = sender.send(line);
+        }
+    });
+}
@@ -0,0 +1,85 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use miette::{bail, Result};
+
+/// The devcontainer.json variant chosen by `--variant`, e.g. `gpu` for
+/// `.devcontainer/gpu/devcontainer.json`. Each variant of a workspace gets its own container and
+/// state, so that several of them can be up at once.
+static SELECTED: OnceLock<Option<String>> = OnceLock::new();
+
+/// Chooses the variant for the rest of the process. Call once, before anything reads the
+/// workspace's devcontainer.json or state.
+pub fn select(name: Option<&str>) -> Result<()> {
+    if let Some(name) = name {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!(
+                help = "use letters, digits, `-` and `_`, as in `.devcontainer/<variant>`",
+                "invalid variant name: {name}"
+            );
+        }
+    }
+
+    let _ = SELECTED.set(name.map(str::to_string));
+    Ok(())
+}
+
+/// Explains a missing devcontainer.json of the selected variant.
+pub fn not_found_help(workspace_folder: &Path) -> String {
+    let available = list(workspace_folder);
+    if available.is_empty() {
+        "put it in .devcontainer/<variant>/devcontainer.json".to_string()
+    } else {
+        format!("available variants: {}", available.join(", "))
+    }
+}
+
+pub fn selected() -> Option<&'static str> {
+    SELECTED.get().and_then(Option::as_deref)
+}
+
+pub fn devcontainer_json_path(workspace_folder: &Path, name: &str) -> PathBuf {
+    workspace_folder
+        .join(".devcontainer")
+        .join(name)
+        .join("devcontainer.json")
+}
+
+/// Lists the variants of the workspace, i.e. the subdirectories of `.devcontainer` holding a
+/// devcontainer.json.
+pub fn list(workspace_folder: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(workspace_folder.join(".devcontainer")) else {
+        return vec![];
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("devcontainer.json").is_file())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Returns the variant a devcontainer.json belongs to, or `None` for the default one.
+pub fn of_config_path(config_path: &Path) -> Option<String> {
+    if config_path.file_name()? != "devcontainer.json" {
+        return None;
+    }
+    let dir = config_path.parent()?;
+    if dir.file_name()? == ".devcontainer" {
+        return None;
+    }
+    if dir.parent()?.file_name()? != ".devcontainer" {
+        return None;
+    }
+
+    Some(dir.file_name()?.to_string_lossy().to_string())
+}
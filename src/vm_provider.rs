@@ -0,0 +1,282 @@
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+use miette::{bail, miette, Result, WrapErr};
+
+use crate::{devcontainer::DevContainer, exec, log};
+
+/// What runs the Docker engine, as far as its quirks matter to dockim.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmProvider {
+    DockerDesktop,
+    OrbStack,
+    RancherDesktop,
+    Colima {
+        profile: String,
+    },
+    /// A plain Lima instance running dockerd
+    Lima {
+        instance: String,
+    },
+    PodmanMachine {
+        rootless: bool,
+    },
+    /// Docker Engine on this machine, without a VM. Rootless, it runs in rootlesskit's network
+    /// namespace.
+    Native {
+        rootless: bool,
+    },
+}
+
+/// Ports below this can't be published by rootless Podman
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+/// How long Lima's port forwarder may take to notice a newly published port
+const LIMA_FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl VmProvider {
+    /// Detects the provider of the current Docker context. The result is cached for the process.
+    pub fn detect() -> &'static VmProvider {
+        static PROVIDER: OnceLock<VmProvider> = OnceLock::new();
+        PROVIDER.get_or_init(detect_uncached)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VmProvider::DockerDesktop => "Docker Desktop",
+            VmProvider::OrbStack => "OrbStack",
+            VmProvider::RancherDesktop => "Rancher Desktop",
+            VmProvider::Colima { .. } => "Colima",
+            VmProvider::Lima { .. } => "Lima",
+            VmProvider::PodmanMachine { .. } => "Podman",
+            VmProvider::Native { .. } => "Docker Engine",
+        }
+    }
+
+    /// Whether containers can resolve host.docker.internal without help.
+    pub fn has_host_docker_internal(&self) -> bool {
+        matches!(self, VmProvider::DockerDesktop | VmProvider::OrbStack)
+    }
+
+    /// Whether published ports reach the host only after a VM-side forwarder notices them.
+    pub fn forwards_ports_lazily(&self) -> bool {
+        matches!(
+            self,
+            VmProvider::RancherDesktop | VmProvider::Colima { .. } | VmProvider::Lima { .. }
+        )
+    }
+
+    /// Whether a port listened on by a `--network host` container reaches the host. Docker
+    /// Desktop needs host networking enabled in its settings, Podman only forwards published
+    /// ports, and the host network of rootless Docker is rootlesskit's namespace.
+    pub fn forwards_host_network_ports(&self) -> bool {
+        matches!(
+            self,
+            VmProvider::Native { rootless: false }
+                | VmProvider::OrbStack
+                | VmProvider::RancherDesktop
+                | VmProvider::Colima { .. }
+                | VmProvider::Lima { .. }
+        )
+    }
+
+    /// Fails early for host ports the provider can't publish.
+    pub fn check_host_port(&self, host_port: u16) -> Result<()> {
+        if matches!(self, VmProvider::PodmanMachine { rootless: true })
+            && host_port < FIRST_UNPRIVILEGED_PORT
+        {
+            bail!(
+                help = "use a host port of {FIRST_UNPRIVILEGED_PORT} or above, or run `podman machine set --rootful`",
+                "rootless Podman can't publish host port {host_port}"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Waits for a just-published port to become reachable on the host where that takes a while.
+    pub fn wait_for_forward(&self, host_port: u16) {
+        if !self.forwards_ports_lazily() {
+            return;
+        }
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], host_port));
+        let start = Instant::now();
+        while start.elapsed() < LIMA_FORWARD_TIMEOUT {
+            if TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+
+        log!(
+            "Warning": "port {host_port} is not reachable on the host yet; {} may still be setting up the forward",
+            self.name()
+        );
+    }
+
+    /// Returns the address of the host as seen from the containers, if they need it spelled out.
+    pub fn host_gateway(&self, dc: &DevContainer) -> Result<Option<String>> {
+        let lima_hosts = match self {
+            VmProvider::DockerDesktop | VmProvider::OrbStack => return Ok(None),
+            VmProvider::RancherDesktop => {
+                exec::capturing_stdout(&["rdctl", "shell", "cat", "/etc/hosts"])
+                    .wrap_err("failed to read /etc/hosts on Rancher Desktop VM")?
+            }
+            VmProvider::Colima { profile } => {
+                exec::capturing_stdout(&["colima", "ssh", "-p", profile, "--", "cat", "/etc/hosts"])
+                    .wrap_err("failed to read /etc/hosts on Colima VM")?
+            }
+            VmProvider::Lima { instance } => {
+                exec::capturing_stdout(&["limactl", "shell", instance, "cat", "/etc/hosts"])
+                    .wrap_err("failed to read /etc/hosts on Lima VM")?
+            }
+            VmProvider::PodmanMachine { .. } => {
+                // Podman provides its own name for the host
+                let hosts = dc
+                    .exec_capturing_stdout(&["getent", "hosts", "host.containers.internal"])
+                    .unwrap_or_default();
+                return Ok(hosts.split_whitespace().next().map(str::to_string));
+            }
+            VmProvider::Native { .. } => {
+                // The bridge gateway is the host itself
+                let container_id = dc.up_and_inspect()?.container_id;
+                let gateway = exec::capturing_stdout(&[
+                    "docker",
+                    "inspect",
+                    "--format",
+                    "{{ range .NetworkSettings.Networks }}{{ .Gateway }} {{ end }}",
+                    &container_id,
+                ])?;
+                return Ok(gateway.split_whitespace().next().map(str::to_string));
+            }
+        };
+
+        Ok(lima_hosts.lines().find_map(|line| {
+            let mut parts = line.split_whitespace();
+            let ip_addr = parts.next()?;
+            parts
+                .any(|name| name == "host.lima.internal")
+                .then(|| ip_addr.to_string())
+        }))
+    }
+
+    /// Command prefix to run a command as root in the VM, where dockim knows how to.
+    fn vm_root_command(&self) -> Option<Vec<String>> {
+        let prefix = match self {
+            VmProvider::RancherDesktop => vec!["rdctl", "shell", "sudo"],
+            VmProvider::Colima { profile } => vec!["colima", "ssh", "-p", profile, "--", "sudo"],
+            VmProvider::Lima { instance } => vec!["limactl", "shell", instance, "sudo"],
+            VmProvider::PodmanMachine { .. } => vec!["podman", "machine", "ssh", "sudo"],
+            _ => return None,
+        };
+
+        Some(prefix.into_iter().map(str::to_string).collect())
+    }
+
+    pub fn can_sync_clock(&self) -> bool {
+        self.vm_root_command().is_some()
+    }
+
+    /// Sets the clock of the VM from its hardware clock, which keeps following the host's while
+    /// the VM is suspended.
+    pub fn sync_clock(&self) -> Result<()> {
+        let name = self.name();
+        let Some(mut command) = self.vm_root_command() else {
+            bail!(
+                help = "restart {name} to resync its clock",
+                "dockim can't set the clock of the {name} VM"
+            );
+        };
+        command.extend(["hwclock".to_string(), "-s".to_string()]);
+
+        exec::exec(&command).wrap_err_with(|| miette!("failed to set the clock of the {name} VM"))
+    }
+
+    /// Adds host.docker.internal to /etc/hosts of the container if the provider doesn't.
+    pub fn enable_host_docker_internal(&self, dc: &DevContainer, needs_sudo: bool) -> Result<()> {
+        if self.has_host_docker_internal() {
+            return Ok(());
+        }
+
+        let container_hosts = dc
+            .exec_capturing_stdout(&["cat", "/etc/hosts"])
+            .wrap_err("failed to read /etc/hosts")?;
+        if container_hosts.contains("host.docker.internal") {
+            return Ok(());
+        }
+
+        let Some(host_ip_addr) = self.host_gateway(dc)? else {
+            log!("Skipping" ("host.docker.internal"): "address of the host not found on {}", self.name());
+            return Ok(());
+        };
+
+        let sudo = if needs_sudo { "sudo " } else { "" };
+        dc.exec_script(&format!(
+            "echo '{host_ip_addr} host.docker.internal' | {sudo}tee -a /etc/hosts >/dev/null"
+        ))?;
+        log!("Added" ("host.docker.internal"): "{host_ip_addr} for {}", self.name());
+
+        Ok(())
+    }
+}
+
+fn detect_uncached() -> VmProvider {
+    let info = exec::capturing_stdout(&[
+        "docker",
+        "info",
+        "--format",
+        "{{ .OperatingSystem }}\n{{ .Name }}\n{{ json .SecurityOptions }}",
+    ])
+    .unwrap_or_default();
+    let mut lines = info.lines();
+    let os = lines.next().unwrap_or_default();
+    let name = lines.next().unwrap_or_default();
+    let security_options = lines.next().unwrap_or_default();
+
+    if os.contains("Docker Desktop") {
+        return VmProvider::DockerDesktop;
+    }
+    if os.contains("OrbStack") || name == "orbstack" {
+        return VmProvider::OrbStack;
+    }
+    if name == "lima-rancher-desktop" || exec::capturing_stdout(&["rdctl", "version"]).is_ok() {
+        return VmProvider::RancherDesktop;
+    }
+    if let Some(profile) = name.strip_prefix("colima") {
+        let profile = profile.trim_start_matches('-');
+        return VmProvider::Colima {
+            profile: if profile.is_empty() {
+                "default".to_string()
+            } else {
+                profile.to_string()
+            },
+        };
+    }
+    if let Some(instance) = name.strip_prefix("lima-") {
+        return VmProvider::Lima {
+            instance: instance.to_string(),
+        };
+    }
+
+    let components = exec::capturing_stdout(&[
+        "docker",
+        "version",
+        "--format",
+        "{{ range .Server.Components }}{{ .Name }} {{ end }}",
+    ])
+    .unwrap_or_default();
+    if components.contains("Podman") {
+        return VmProvider::PodmanMachine {
+            rootless: security_options.contains("rootless"),
+        };
+    }
+
+    VmProvider::Native {
+        rootless: security_options.contains("rootless"),
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::Path,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use itertools::{chain, Itertools};
+use miette::{bail, miette, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{log, state};
+
+const LOCK_FILE: &str = "dockim.lock";
+
+/// Held while a dockim command starts or changes a workspace: generating the override config,
+/// choosing ports and registering forwards. Released on drop, or by the OS if dockim dies.
+#[derive(Debug)]
+pub struct WorkspaceLock {
+    _file: File,
+}
+
+/// Who holds the lock, written into the lock file for the messages of those waiting
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Holder {
+    pid: u32,
+    command: String,
+}
+
+/// Takes the lock of the workspace, waiting up to `timeout` for another dockim to release it.
+pub fn acquire(workspace_folder: &Path, timeout: Duration) -> Result<WorkspaceLock> {
+    let path = state::ensure_workspace_state_dir(workspace_folder)?.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to open {}", path.display()))?;
+
+    let start = Instant::now();
+    let mut announced = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => {
+                return Err(e)
+                    .into_diagnostic()
+                    .wrap_err_with(|| miette!("failed to lock {}", path.display()));
+            }
+        }
+
+        let holder = read_holder(&path);
+        if start.elapsed() >= timeout {
+            bail!(
+                help = "wait for it to finish, or raise `up.lock_timeout_secs` in the config",
+                "another dockim is running on this workspace: {}",
+                describe(&holder)
+            );
+        }
+        if !announced {
+            log!("Waiting": "another dockim is running on this workspace: {}", describe(&holder));
+            announced = true;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    let holder = Holder {
+        pid: process::id(),
+        command: chain!(["dockim".to_string()], env::args().skip(1)).join(" "),
+    };
+    file.set_len(0).into_diagnostic()?;
+    file.write_all(&serde_json::to_vec(&holder).into_diagnostic()?)
+        .into_diagnostic()
+        .wrap_err_with(|| miette!("failed to write {}", path.display()))?;
+
+    Ok(WorkspaceLock { _file: file })
+}
+
+fn read_holder(path: &Path) -> Option<Holder> {
+    let contents = fs::read(path).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+fn describe(holder: &Option<Holder>) -> String {
+    match holder {
+        Some(holder) => format!("`{}` (pid {})", holder.command, holder.pid),
+        None => "unknown command".to_string(),
+    }
+}